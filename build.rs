@@ -1,8 +1,24 @@
+use std::io::Write;
+
+use flate2::{write::GzEncoder, Compression};
+
 fn main() {
     // Check if the `cfg.toml` file exists and has been filled out.
     if !std::path::Path::new("cfg.toml").exists() {
         panic!("You need to create a `cfg.toml` file with your Wi-Fi credentials! Use `cfg.toml.example` as a template.");
     }
 
+    // the control panel is served from flash, gzip keeps it small
+    println!("cargo:rerun-if-changed=web/index.html");
+    let html = std::fs::read("web/index.html").unwrap();
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(&html).unwrap();
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    std::fs::write(
+        std::path::Path::new(&out_dir).join("index.html.gz"),
+        encoder.finish().unwrap(),
+    )
+    .unwrap();
+
     embuild::espidf::sysenv::output();
 }
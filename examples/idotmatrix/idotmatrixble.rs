@@ -3,7 +3,10 @@ use anyhow::Result;
 use bstr::ByteSlice;
 use esp32_nimble::{uuid128, BLEClient, BLEDevice, BLEReturnCode};
 use esp_idf_sys::camera;
-use espcam::espcam::FrameBuffer;
+use espcam::{
+    espcam::FrameBuffer,
+    phash::{Dedup, HashKind},
+};
 use image::{ImageBuffer, ImageFormat, Rgb};
 use log::{error, info};
 
@@ -74,11 +77,20 @@ pub async fn idotmatrix_stream_task(camera: Camera<'_>) -> Result<()> {
         .await
         .unwrap();
 
+    // the display keeps showing the last picture, there is no point in sending it again
+    let mut dedup = Dedup::new(HashKind::Difference, 4);
+
     loop {
         camera.get_framebuffer();
         let framebuffer = camera.get_framebuffer();
 
         if let Some(framebuffer) = framebuffer {
+            if let Some(frame) = framebuffer.to_frame() {
+                if dedup.is_duplicate(&frame) {
+                    continue;
+                }
+            }
+
             info!("Creating image");
 
             let img = framebuffer_to_img(framebuffer);
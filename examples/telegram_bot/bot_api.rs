@@ -55,9 +55,46 @@ pub fn http_post(url: impl AsRef<str>, data: &[u8]) -> Result<Vec<u8>, EspBotErr
     }
 }
 
+/// A file uploaded along with the chat id, `field` is the name the method expects it under
+pub struct Upload<'a> {
+    pub field: &'a str,
+    pub filename: &'a str,
+    pub content_type: &'a str,
+    pub data: &'a [u8],
+}
+
+impl<'a> Upload<'a> {
+    pub fn photo(data: &'a [u8]) -> Self {
+        Self {
+            field: "photo",
+            filename: "esp32-cam.jpg",
+            content_type: "image/jpeg",
+            data,
+        }
+    }
+
+    pub fn png(data: &'a [u8]) -> Self {
+        Self {
+            field: "photo",
+            filename: "esp32-cam.png",
+            content_type: "image/png",
+            data,
+        }
+    }
+
+    pub fn animation(data: &'a [u8]) -> Self {
+        Self {
+            field: "animation",
+            filename: "esp32-cam.gif",
+            content_type: "image/gif",
+            data,
+        }
+    }
+}
+
 pub fn telegram_post_multipart(
     url: impl AsRef<str>,
-    data: &[u8],
+    upload: Upload,
     chat_id: i64,
 ) -> Result<Vec<u8>, EspBotError> {
     // 1. Create a new EspHttpConnection with default Configuration. (Check documentation)
@@ -71,7 +108,8 @@ pub fn telegram_post_multipart(
 
     let boundary = "esp32esp32esp32";
 
-    let head = format!("--{boundary}\r\nContent-Disposition: form-data; name=\"chat_id\"; \r\n\r\n{chat_id}\r\n--{boundary}\r\nContent-Disposition: form-data; name=\"photo\"; filename=\"esp32-cam.jpg\"\r\nContent-Type: image/jpeg\r\n\r\n");
+    let head = format!("--{boundary}\r\nContent-Disposition: form-data; name=\"chat_id\"; \r\n\r\n{chat_id}\r\n--{boundary}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n", upload.field, upload.filename, upload.content_type);
+    let data = upload.data;
     let tail = format!("\r\n--{boundary}--\r\n");
 
     let datalen = head.len() + data.len() + tail.len();
@@ -1,9 +1,40 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
 use anyhow::{bail, Result};
 
-use bot_api::{telegram_post_multipart, Esp32Api};
+use bot_api::{telegram_post_multipart, Esp32Api, Upload};
 use esp_idf_hal::gpio::PinDriver;
-use esp_idf_svc::{eventloop::EspSystemEventLoop, hal::peripherals::Peripherals};
-use espcam::{config::get_config, espcam::Camera, wifi_handler::my_wifi};
+use esp_idf_svc::{
+    eventloop::EspSystemEventLoop, hal::peripherals::Peripherals, nvs::EspDefaultNvsPartition,
+    sntp::EspSntp,
+};
+use espcam::{
+    animation::GifBuilder,
+    color::{ColorCalibration, COLOR_STORAGE_KEY},
+    config::get_config,
+    espcam::{device_serial, Camera},
+    events::EventBus,
+    exif::{ExifMetadata, GpsPosition},
+    frame::Frame,
+    heatmap::{HeatmapAccumulator, HeatmapHistory, HEATMAP_STORAGE_KEY},
+    motion::{MotionConfig, MotionDetector},
+    night::{bracket_shot, night_shot, Exposure, StackMode},
+    overlay::Overlay,
+    pipeline::FramePipeline,
+    privacy::PrivacyMasks,
+    scan::{scan, Scanner},
+    stats::{DayNightSwitch, FrameStats, LightProfile},
+    storage::Storage,
+    tamper::{TamperConfig, TamperDetector},
+    transform::{Crop, Orientation, ORIENTATION_STORAGE_KEY},
+    wifi_handler::my_wifi,
+};
 use frankenstein::{
     ForwardMessageParams, GetUpdatesParams, SendChatActionParams, SendMessageParams, TelegramApi,
 };
@@ -11,8 +42,19 @@ use log::{error, info};
 
 mod bot_api;
 
+/// Mean brightness below which the automatic flash fires
+const DARK_MEAN: f32 = 30.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FlashMode {
+    Off,
+    On,
+    /// Only when the scene monitor finds it dark
+    Auto,
+}
+
 struct BotState {
-    should_use_flash: bool,
+    flash: FlashMode,
     public_use: bool,
     owner_id: i64,
     bot_token: &'static str,
@@ -43,6 +85,27 @@ fn main() -> Result<()> {
         }
     };
 
+    // the overlay timestamp comes from the system clock
+    let _sntp = EspSntp::new_default()?;
+
+    let nvs = EspDefaultNvsPartition::take()?;
+    let mut storage = Storage::new(nvs.clone())?;
+    let orientation: Orientation = storage.load(ORIENTATION_STORAGE_KEY)?.unwrap_or_default();
+    let mut color: ColorCalibration = storage.load(COLOR_STORAGE_KEY)?.unwrap_or_default();
+
+    let mut pipeline = FramePipeline::new()
+        .orientation(orientation)
+        .privacy(PrivacyMasks::from_json(config.privacy_masks)?)
+        .overlay(Overlay::with_defaults(config.device_name).utc_offset(config.utc_offset_secs))
+        .exif(
+            ExifMetadata::new()
+                .make("Espressif")
+                .device_name(config.device_name)
+                .serial(device_serial())
+                .utc_offset(config.utc_offset_secs)
+                .gps(GpsPosition::parse(config.gps_position)?),
+        );
+
     let camera = Camera::new(
         peripherals.pins.gpio32,
         peripherals.pins.gpio0,
@@ -60,12 +123,63 @@ fn main() -> Result<()> {
         peripherals.pins.gpio26,
         peripherals.pins.gpio27,
         esp_idf_sys::camera::pixformat_t_PIXFORMAT_JPEG,
-        esp_idf_sys::camera::framesize_t_FRAMESIZE_UXGA,
+        // the pipeline decodes every frame, UXGA would not fit in PSRAM
+        esp_idf_sys::camera::framesize_t_FRAMESIZE_SVGA,
     )
     .unwrap();
+    let camera = Arc::new(camera);
+
+    // the white balance gains go to the sensor when possible, the rest is done in software
+    pipeline.set_color(color.apply_to_sensor(&camera.sensor()));
+
+    let events = EventBus::new();
+    let notifications = events.subscribe();
+    let dark = Arc::new(AtomicBool::new(false));
+
+    {
+        let camera = camera.clone();
+        let dark = dark.clone();
+        let events = events.clone();
+        std::thread::Builder::new()
+            .stack_size(16 * 1024)
+            .spawn(move || scene_monitor(&camera, &events, &dark))?;
+    }
+
+    let history: HeatmapHistory = storage.load(HEATMAP_STORAGE_KEY)?.unwrap_or_default();
+    let heatmap = Arc::new(Mutex::new(
+        HeatmapAccumulator::new(Duration::from_secs(config.heatmap_window_secs)).history(history),
+    ));
+
+    {
+        let camera = camera.clone();
+        let heatmap = heatmap.clone();
+        let mut scanner = Scanner::new(events, Duration::from_secs(60));
+        let mut storage = Storage::new(nvs)?;
+        std::thread::Builder::new()
+            .stack_size(16 * 1024)
+            .spawn(move || activity_monitor(&camera, &heatmap, &mut scanner, &mut storage))?;
+    }
+
+    {
+        let api = Esp32Api::new(config.bot_token);
+        let owner_id = config.bot_owner_id;
+        std::thread::Builder::new()
+            .stack_size(16 * 1024)
+            .spawn(move || {
+                for event in notifications {
+                    api.send_message(
+                        &SendMessageParams::builder()
+                            .chat_id(owner_id)
+                            .text(event.describe())
+                            .build(),
+                    )
+                    .ok();
+                }
+            })?;
+    }
 
     let mut bot_state = BotState {
-        should_use_flash: false,
+        flash: FlashMode::Off,
         public_use: false,
         owner_id: config.bot_owner_id,
         bot_token: config.bot_token,
@@ -111,7 +225,8 @@ fn main() -> Result<()> {
                     message.message_id, message.chat.id
                 );
 
-                match message.text.unwrap_or_default().as_str() {
+                let text = message.text.unwrap_or_default();
+                match text.as_str() {
                     "/photo" => {
                         if message.chat.id != bot_state.owner_id && !bot_state.public_use {
                             continue;
@@ -125,7 +240,9 @@ fn main() -> Result<()> {
                         )
                         .ok();
 
-                        if bot_state.should_use_flash {
+                        if bot_state.flash == FlashMode::On
+                            || (bot_state.flash == FlashMode::Auto && dark.load(Ordering::Relaxed))
+                        {
                             flash_led.set_high().unwrap();
                         }
 
@@ -135,12 +252,26 @@ fn main() -> Result<()> {
                         flash_led.set_low().unwrap();
 
                         if let Some(framebuffer) = framebuffer {
+                            // never fall back to the unprocessed frame, it could show masked areas
+                            let photo = match pipeline.process_capture(
+                                framebuffer.data(),
+                                SystemTime::now(),
+                                &camera.sensor().exif(),
+                            ) {
+                                Ok(photo) => photo,
+                                Err(err) => {
+                                    error!("frame processing error: {:?}", err);
+                                    continue;
+                                }
+                            };
+                            drop(framebuffer);
+
                             let res = telegram_post_multipart(
                                 format!(
                                     "https://api.telegram.org/bot{}/sendPhoto",
                                     bot_state.bot_token
                                 ),
-                                framebuffer.data(),
+                                Upload::photo(&photo),
                                 message.chat.id,
                             );
 
@@ -155,31 +286,145 @@ fn main() -> Result<()> {
                         }
                     }
 
+                    "/gif" => {
+                        if message.chat.id != bot_state.owner_id && !bot_state.public_use {
+                            continue;
+                        }
+
+                        api.send_chat_action(
+                            &SendChatActionParams::builder()
+                                .chat_id(message.chat.id)
+                                .action(frankenstein::ChatAction::UploadVideo)
+                                .build(),
+                        )
+                        .ok();
+
+                        if bot_state.flash == FlashMode::On
+                            || (bot_state.flash == FlashMode::Auto && dark.load(Ordering::Relaxed))
+                        {
+                            flash_led.set_high().unwrap();
+                        }
+                        let animation = record_gif(&camera, &pipeline);
+                        flash_led.set_low().unwrap();
+
+                        let res = animation.and_then(|animation| {
+                            Ok(telegram_post_multipart(
+                                format!(
+                                    "https://api.telegram.org/bot{}/sendAnimation",
+                                    bot_state.bot_token
+                                ),
+                                Upload::animation(&animation),
+                                message.chat.id,
+                            )?)
+                        });
+
+                        if let Err(err) = res {
+                            error!("gif error: {:?}", err);
+                        }
+                    }
+
+                    "/night" | "/hdr" => {
+                        if message.chat.id != bot_state.owner_id && !bot_state.public_use {
+                            continue;
+                        }
+
+                        api.send_chat_action(
+                            &SendChatActionParams::builder()
+                                .chat_id(message.chat.id)
+                                .action(frankenstein::ChatAction::UploadPhoto)
+                                .build(),
+                        )
+                        .ok();
+
+                        let hdr = text == "/hdr";
+                        let photo = still_shot(&camera, hdr)
+                            .and_then(|frame| pipeline.process(&frame, SystemTime::now()));
+
+                        let res = photo.and_then(|photo| {
+                            Ok(telegram_post_multipart(
+                                format!(
+                                    "https://api.telegram.org/bot{}/sendPhoto",
+                                    bot_state.bot_token
+                                ),
+                                Upload::photo(&photo),
+                                message.chat.id,
+                            )?)
+                        });
+
+                        if let Err(err) = res {
+                            error!("night shot error: {:?}", err);
+                        }
+                    }
+
                     "/flash" => {
                         if message.chat.id != bot_state.owner_id && !bot_state.public_use {
                             continue;
                         }
-                        if bot_state.should_use_flash {
-                            bot_state.should_use_flash = false;
+                        let (flash, reply) = match bot_state.flash {
+                            FlashMode::Off => (FlashMode::On, "Flash enabled!"),
+                            FlashMode::On => (FlashMode::Auto, "Automatic flash enabled!"),
+                            FlashMode::Auto => (FlashMode::Off, "Flash disabled!"),
+                        };
+                        bot_state.flash = flash;
 
-                            api.send_message(
-                                &SendMessageParams::builder()
-                                    .chat_id(message.chat.id)
-                                    .text("Flash disabled!")
-                                    .build(),
-                            )
-                            .unwrap();
-                        } else {
-                            bot_state.should_use_flash = true;
+                        api.send_message(
+                            &SendMessageParams::builder()
+                                .chat_id(message.chat.id)
+                                .text(reply)
+                                .build(),
+                        )
+                        .unwrap();
+                    }
 
-                            api.send_message(
-                                &SendMessageParams::builder()
-                                    .chat_id(message.chat.id)
-                                    .text("Flash enabled!")
-                                    .build(),
-                            )
-                            .unwrap();
+                    "/rotate" => {
+                        if message.chat.id != bot_state.owner_id {
+                            continue;
+                        }
+                        let mut orientation = pipeline.get_orientation();
+                        orientation.rotation = orientation.rotation.next();
+                        pipeline.set_orientation(orientation);
+
+                        if let Err(err) = storage.store(ORIENTATION_STORAGE_KEY, &orientation) {
+                            error!("could not save orientation: {:?}", err);
+                        }
+
+                        api.send_message(
+                            &SendMessageParams::builder()
+                                .chat_id(message.chat.id)
+                                .text(format!(
+                                    "Rotation set to {} degrees!",
+                                    orientation.rotation.degrees()
+                                ))
+                                .build(),
+                        )
+                        .unwrap();
+                    }
+
+                    "/calibrate" | "/calibrate_checker" => {
+                        if message.chat.id != bot_state.owner_id {
+                            continue;
                         }
+
+                        let reply = match calibrate(&camera, text == "/calibrate_checker") {
+                            Ok(calibration) => {
+                                color = calibration;
+                                if let Err(err) = storage.store(COLOR_STORAGE_KEY, &color) {
+                                    error!("could not save colour calibration: {:?}", err);
+                                }
+                                format!("Calibrated! gains {:.2?}", color.gains)
+                            }
+                            Err(err) => format!("Calibration failed: {}", err),
+                        };
+                        // on failure the previous calibration goes back to the sensor
+                        pipeline.set_color(color.apply_to_sensor(&camera.sensor()));
+
+                        api.send_message(
+                            &SendMessageParams::builder()
+                                .chat_id(message.chat.id)
+                                .text(reply)
+                                .build(),
+                        )
+                        .ok();
                     }
 
                     "/publish" => {
@@ -208,6 +453,95 @@ fn main() -> Result<()> {
                             .unwrap();
                         }
                     }
+                    "/scan" => {
+                        if message.chat.id != bot_state.owner_id && !bot_state.public_use {
+                            continue;
+                        }
+
+                        camera.get_framebuffer();
+                        let framebuffer = camera.get_framebuffer();
+
+                        let results = framebuffer
+                            .map(|fb| Frame::decode_jpeg(fb.data()).and_then(|frame| scan(&frame)));
+                        let text = match results {
+                            Some(Ok(results)) => {
+                                if results.is_empty() {
+                                    "No code found".to_string()
+                                } else {
+                                    results
+                                        .iter()
+                                        .map(|r| format!("{:?}: {}", r.symbology, r.content))
+                                        .collect::<Vec<_>>()
+                                        .join("\n")
+                                }
+                            }
+                            Some(Err(err)) => {
+                                error!("scan error: {:?}", err);
+                                "Could not scan the picture".to_string()
+                            }
+                            None => "no framebuffer".to_string(),
+                        };
+
+                        api.send_message(
+                            &SendMessageParams::builder()
+                                .chat_id(message.chat.id)
+                                .text(text)
+                                .build(),
+                        )
+                        .ok();
+                    }
+
+                    "/heatmap" => {
+                        if message.chat.id != bot_state.owner_id {
+                            continue;
+                        }
+
+                        api.send_chat_action(
+                            &SendChatActionParams::builder()
+                                .chat_id(message.chat.id)
+                                .action(frankenstein::ChatAction::UploadPhoto)
+                                .build(),
+                        )
+                        .ok();
+
+                        let current = heatmap.lock().unwrap().current().cloned();
+                        let Some(current) = current else {
+                            api.send_message(
+                                &SendMessageParams::builder()
+                                    .chat_id(message.chat.id)
+                                    .text("No activity recorded yet")
+                                    .build(),
+                            )
+                            .ok();
+                            continue;
+                        };
+
+                        // the heatmap is in sensor coordinates, so it is drawn before
+                        // the pipeline turns the picture upright
+                        camera.get_framebuffer();
+                        let res = camera
+                            .get_framebuffer()
+                            .ok_or_else(|| anyhow::anyhow!("no framebuffer"))
+                            .and_then(|fb| Frame::decode_jpeg(fb.data()))
+                            .and_then(|frame| current.render(&frame))
+                            .and_then(|frame| pipeline.apply(&frame, SystemTime::now()))
+                            .and_then(|frame| frame.to_png())
+                            .and_then(|png| {
+                                Ok(telegram_post_multipart(
+                                    format!(
+                                        "https://api.telegram.org/bot{}/sendPhoto",
+                                        bot_state.bot_token
+                                    ),
+                                    Upload::png(&png),
+                                    message.chat.id,
+                                )?)
+                            });
+
+                        if let Err(err) = res {
+                            error!("heatmap error: {:?}", err);
+                        }
+                    }
+
                     "/start" => {
                         api.send_message(
                             &SendMessageParams::builder()
@@ -234,3 +568,200 @@ fn main() -> Result<()> {
         }
     }
 }
+
+/// Looks at the scene every ten seconds: warns when the camera is tampered with, switches
+/// the sensor between its day and night profiles and tells the automatic flash when it is dark
+fn scene_monitor(camera: &Camera, events: &EventBus, dark: &AtomicBool) {
+    let mut detector = TamperDetector::new(TamperConfig::default());
+    let mut day_night = DayNightSwitch::default();
+
+    loop {
+        std::thread::sleep(Duration::from_secs(10));
+
+        camera.get_framebuffer();
+        let Some(framebuffer) = camera.get_framebuffer() else {
+            continue;
+        };
+
+        let frame = match Frame::decode_jpeg(framebuffer.data()) {
+            Ok(frame) => frame,
+            Err(err) => {
+                error!("scene monitor decode error: {:?}", err);
+                continue;
+            }
+        };
+        drop(framebuffer);
+
+        if let Some(event) = detector.update(&frame) {
+            events.publish(event);
+        }
+
+        let stats = match FrameStats::compute(&frame, 160) {
+            Ok(stats) => stats,
+            Err(err) => {
+                error!("scene monitor stats error: {:?}", err);
+                continue;
+            }
+        };
+        dark.store(stats.needs_flash(DARK_MEAN), Ordering::Relaxed);
+        if let Some(profile) = day_night.update(&stats) {
+            info!("switching to the {:?} profile", profile);
+            if let Err(err) = set_light_profile(camera, profile) {
+                error!("could not switch the light profile: {:?}", err);
+            }
+        }
+    }
+}
+
+/// At night the sensor may amplify more and use longer exposures, at the cost of noise
+fn set_light_profile(camera: &Camera, profile: LightProfile) -> Result<()> {
+    let sensor = camera.sensor();
+    match profile {
+        LightProfile::Day => {
+            sensor.set_gainceiling(esp_idf_sys::camera::gainceiling_t_GAINCEILING_2X)?;
+            sensor.set_aec2(false)?;
+        }
+        LightProfile::Night => {
+            sensor.set_gainceiling(esp_idf_sys::camera::gainceiling_t_GAINCEILING_32X)?;
+            sensor.set_aec2(true)?;
+        }
+    }
+    Ok(())
+}
+
+/// Sums what moves in the picture into the activity heatmap, saved when a window is
+/// completed and otherwise every ten minutes to spare the flash, and reports the codes
+/// that come into view. Frames are not turned upright, so that /rotate keeps the heatmap
+/// valid
+fn activity_monitor(
+    camera: &Camera,
+    heatmap: &Mutex<HeatmapAccumulator>,
+    scanner: &mut Scanner,
+    storage: &mut Storage,
+) {
+    let mut motion = MotionDetector::new(MotionConfig::default());
+    let mut last_save = Instant::now();
+
+    loop {
+        std::thread::sleep(Duration::from_secs(1));
+
+        let Some(framebuffer) = camera.get_framebuffer() else {
+            continue;
+        };
+        let frame = match Frame::decode_jpeg(framebuffer.data()) {
+            Ok(frame) => frame,
+            Err(err) => {
+                error!("activity monitor decode error: {:?}", err);
+                continue;
+            }
+        };
+        drop(framebuffer);
+
+        if let Err(err) = scanner.process(&frame) {
+            error!("activity monitor scan error: {:?}", err);
+        }
+
+        let Some(mask) = motion.update(&frame) else {
+            continue;
+        };
+
+        let mut heatmap = heatmap.lock().unwrap();
+        let completed = heatmap.add(&mask, SystemTime::now());
+        if completed || last_save.elapsed() > Duration::from_secs(600) {
+            let history = heatmap.get_history().clone();
+            drop(heatmap);
+            if let Err(err) = storage.store(HEATMAP_STORAGE_KEY, &history) {
+                error!("could not save the heatmap: {:?}", err);
+            }
+            last_save = Instant::now();
+        }
+    }
+}
+
+/// Burst of a couple of seconds, every frame goes through the pipeline like a photo
+fn record_gif(camera: &Camera, pipeline: &FramePipeline) -> Result<Vec<u8>> {
+    let mut gif = GifBuilder::new().delay(Duration::from_millis(200));
+    gif.capture_burst(camera, pipeline, 10)?;
+
+    if gif.is_empty() {
+        bail!("no framebuffer");
+    }
+    gif.encode()
+}
+
+/// Stacked or exposure fused still, taken at VGA so that the
+/// frames being merged fit in PSRAM
+fn still_shot(camera: &Camera, hdr: bool) -> Result<Frame> {
+    let sensor = camera.sensor();
+    let framesize = sensor.status().framesize;
+    sensor.set_framesize(esp_idf_sys::camera::framesize_t_FRAMESIZE_VGA)?;
+
+    let frame = if hdr {
+        bracket_shot(
+            camera,
+            &[
+                Exposure {
+                    aec_value: 50,
+                    agc_gain: 0,
+                },
+                Exposure {
+                    aec_value: 300,
+                    agc_gain: 0,
+                },
+                Exposure {
+                    aec_value: 1200,
+                    agc_gain: 5,
+                },
+            ],
+        )
+    } else {
+        night_shot(
+            camera,
+            6,
+            Exposure {
+                aec_value: 1200,
+                agc_gain: 10,
+            },
+            StackMode::Mean,
+        )
+    };
+
+    sensor.set_framesize(framesize)?;
+    frame
+}
+
+/// Measures a gray card in the middle fifth of the picture, or a colour checker filling
+/// the middle two thirds, with the sensor white balance fixed at unity
+fn calibrate(camera: &Camera, checker: bool) -> Result<ColorCalibration> {
+    camera.sensor().set_manual_wb_gains([0x40; 3])?;
+    camera.get_framebuffer();
+    camera.get_framebuffer();
+
+    let Some(framebuffer) = camera.get_framebuffer() else {
+        bail!("no framebuffer");
+    };
+    let frame = Frame::decode_jpeg(framebuffer.data())?;
+    drop(framebuffer);
+
+    if checker {
+        ColorCalibration::from_color_checker(
+            &frame,
+            Crop {
+                x: 1.0 / 6.0,
+                y: 1.0 / 6.0,
+                w: 2.0 / 3.0,
+                h: 2.0 / 3.0,
+            },
+        )
+    } else {
+        ColorCalibration::from_gray_card(
+            &frame,
+            Crop {
+                x: 0.4,
+                y: 0.4,
+                w: 0.2,
+                h: 0.2,
+            },
+        )
+    }
+}
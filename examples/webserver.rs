@@ -1,12 +1,54 @@
+use std::{
+    io::Cursor,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
 use anyhow::{bail, Result};
 
-use esp_idf_hal::io::Write;
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
-    hal::peripherals::Peripherals,
-    http::{server::EspHttpServer, Method},
+    hal::{
+        gpio::{OutputPin, PinDriver},
+        peripherals::Peripherals,
+    },
+    http::Method,
+    nvs::EspDefaultNvsPartition,
+    sntp::EspSntp,
+};
+use espcam::{
+    animation::GifBuilder,
+    auth::{AuthConfig, Authenticator, Role, AUTH_STORAGE_KEY},
+    avi::Recorder,
+    clip::{Clip, ClipFrame, EventRecorder},
+    color::{ColorCalibration, COLOR_STORAGE_KEY},
+    config::get_config,
+    counting::{CountTotals, Counter, CountingAreas, CountingConfig, COUNTS_STORAGE_KEY},
+    espcam::{device_serial, Camera},
+    exif::{ExifMetadata, GpsPosition},
+    focus::Sharpness,
+    frame::Frame,
+    heatmap::{Heatmap, HeatmapAccumulator, HeatmapHistory, HEATMAP_STORAGE_KEY},
+    http::{capture, respond, CameraServer, HttpError, Reply},
+    motion::{MotionConfig, MotionDetector},
+    onvif::OnvifDevice,
+    overlay::Overlay,
+    pipeline::FramePipeline,
+    privacy::PrivacyMasks,
+    rtsp::RtspConfig,
+    scan::scan,
+    stats::FrameStats,
+    storage::Storage,
+    transform::{Orientation, ORIENTATION_STORAGE_KEY},
+    wifi_handler::my_wifi,
+    ws::{WsEvent, WsHub},
 };
-use espcam::{config::get_config, espcam::Camera, wifi_handler::my_wifi};
+
+const CLIP_LENGTH: Duration = Duration::from_secs(5);
+const CLIP_FPS: u32 = 5;
 
 fn main() -> Result<()> {
     esp_idf_svc::sys::link_patches();
@@ -30,6 +72,31 @@ fn main() -> Result<()> {
         }
     };
 
+    // the overlay timestamp comes from the system clock
+    let _sntp = EspSntp::new_default()?;
+
+    let mut storage = Storage::new(EspDefaultNvsPartition::take()?)?;
+    let orientation: Orientation = storage.load(ORIENTATION_STORAGE_KEY)?.unwrap_or_default();
+    let color: ColorCalibration = storage.load(COLOR_STORAGE_KEY)?.unwrap_or_default();
+    // users and tokens saved in NVS replace the ones built in
+    let auth: AuthConfig = match storage.load(AUTH_STORAGE_KEY)? {
+        Some(auth) => auth,
+        None => AuthConfig::from_json(config.http_auth)?,
+    };
+
+    let mut pipeline = FramePipeline::new()
+        .orientation(orientation)
+        .privacy(PrivacyMasks::from_json(config.privacy_masks)?)
+        .overlay(Overlay::with_defaults(config.device_name).utc_offset(config.utc_offset_secs))
+        .exif(
+            ExifMetadata::new()
+                .make("Espressif")
+                .device_name(config.device_name)
+                .serial(device_serial())
+                .utc_offset(config.utc_offset_secs)
+                .gps(GpsPosition::parse(config.gps_position)?),
+        );
+
     let camera = Camera::new(
         peripherals.pins.gpio32,
         peripherals.pins.gpio0,
@@ -47,35 +114,162 @@ fn main() -> Result<()> {
         peripherals.pins.gpio26,
         peripherals.pins.gpio27,
         esp_idf_sys::camera::pixformat_t_PIXFORMAT_JPEG,
-        esp_idf_sys::camera::framesize_t_FRAMESIZE_UXGA,
+        // the pipeline decodes every frame, UXGA would not fit in PSRAM. The frame
+        // buffers are sized for this, larger sizes are refused afterwards
+        esp_idf_sys::camera::framesize_t_FRAMESIZE_SVGA,
     )
     .unwrap();
+    let camera = Arc::new(camera);
 
-    let mut server = EspHttpServer::new(&esp_idf_svc::http::server::Configuration::default())?;
+    // the white balance gains go to the sensor when possible, the rest is done in software
+    pipeline.set_color(color.apply_to_sensor(&camera.sensor()));
 
-    server.fn_handler("/camera.jpg", Method::Get, |request| {
-        let framebuffer = camera.get_framebuffer();
+    let trigger = Arc::new(AtomicBool::new(false));
+    let last_event = Arc::new(Mutex::new(None::<Vec<u8>>));
 
-        if let Some(framebuffer) = framebuffer {
-            let data = framebuffer.data();
+    let totals: CountTotals = storage.load(COUNTS_STORAGE_KEY)?.unwrap_or_default();
+    let counter = Arc::new(Mutex::new(
+        Counter::new(
+            CountingAreas::from_json(config.counting_areas)?,
+            CountingConfig::default(),
+        )
+        .totals(totals),
+    ));
 
-            let headers = [
-                ("Content-Type", "image/jpeg"),
-                ("Content-Length", &data.len().to_string()),
-            ];
-            let mut response = request.into_response(200, Some("OK"), &headers).unwrap();
-            response.write_all(data)?;
-        } else {
-            let mut response = request.into_ok_response()?;
-            response.write_all("no framebuffer".as_bytes())?;
-        }
+    let history: HeatmapHistory = storage.load(HEATMAP_STORAGE_KEY)?.unwrap_or_default();
+    let heatmap = Arc::new(Mutex::new(
+        HeatmapAccumulator::new(Duration::from_secs(config.heatmap_window_secs)).history(history),
+    ));
+
+    let mut camera_server = CameraServer::new(camera.clone(), pipeline.clone())?;
+    if auth.is_empty() {
+        log::warn!("http_auth is not set, anyone on the network can use the camera");
+    } else {
+        camera_server = camera_server.auth(Authenticator::new(auth));
+    }
+    camera_server
+        .default_routes()?
+        .flash(
+            "/flash",
+            PinDriver::output(peripherals.pins.gpio4.downgrade_output())?,
+        )?
+        .rtsp(554, "/stream", RtspConfig::default())?
+        .onvif(OnvifDevice::new(config.device_name, &device_serial()))?
+        .mdns(config.hostname, config.device_name)?;
 
+    {
+        let camera = camera.clone();
+        let pipeline = pipeline.clone();
+        let trigger = trigger.clone();
+        let last_event = last_event.clone();
+        let lock = camera_server.snapshot_lock();
+        std::thread::Builder::new()
+            .stack_size(16 * 1024)
+            .spawn(move || event_recorder(&camera, &lock, &pipeline, &trigger, &last_event))?;
+    }
+
+    {
+        let camera = camera.clone();
+        let lock = camera_server.snapshot_lock();
+        let orientation = pipeline.get_orientation();
+        let counter = counter.clone();
+        let heatmap = heatmap.clone();
+        let ws = camera_server.ws().clone();
+        std::thread::Builder::new()
+            .stack_size(16 * 1024)
+            .spawn(move || {
+                activity_monitor(
+                    &camera,
+                    &lock,
+                    orientation,
+                    &counter,
+                    &heatmap,
+                    &ws,
+                    &mut storage,
+                )
+            })?;
+    }
+
+    camera_server.route("/stats", Method::Get, Role::Viewer, |request| {
+        let reply = capture(&camera).and_then(|framebuffer| {
+            let frame = Frame::decode_jpeg(framebuffer.data())?;
+            drop(framebuffer);
+            Reply::json(&FrameStats::compute(&frame, 160)?)
+        });
+        respond(request, reply)?;
         Ok(())
     })?;
 
-    server.fn_handler("/", Method::Get, |request| {
-        let mut response = request.into_ok_response()?;
-        response.write_all("ok".as_bytes())?;
+    camera_server.route("/focus", Method::Get, Role::Viewer, |request| {
+        let reply = capture(&camera).and_then(|framebuffer| {
+            let frame = Frame::decode_jpeg(framebuffer.data())?;
+            drop(framebuffer);
+            Reply::json(&Sharpness::from_frame(&frame, 320))
+        });
+        respond(request, reply)?;
+        Ok(())
+    })?;
+
+    camera_server.route("/scan", Method::Get, Role::Viewer, |request| {
+        let reply = capture(&camera).and_then(|framebuffer| {
+            let frame = Frame::decode_jpeg(framebuffer.data())?;
+            drop(framebuffer);
+            Reply::json(&scan(&frame)?)
+        });
+        respond(request, reply)?;
+        Ok(())
+    })?;
+
+    camera_server.route("/counts", Method::Get, Role::Viewer, |request| {
+        respond(request, Reply::json(&counter.lock().unwrap().counts()))?;
+        Ok(())
+    })?;
+
+    camera_server.route("/counts/reset", Method::Get, Role::Admin, |request| {
+        counter.lock().unwrap().reset();
+        respond(request, Ok(Reply::text("counts reset")))?;
+        Ok(())
+    })?;
+
+    camera_server.route("/heatmap.png", Method::Get, Role::Viewer, |request| {
+        let current = heatmap.lock().unwrap().current().cloned();
+        respond(request, heatmap_png(&camera, &pipeline, current))?;
+        Ok(())
+    })?;
+
+    camera_server.route(
+        "/heatmap/previous.png",
+        Method::Get,
+        Role::Viewer,
+        |request| {
+            let previous = heatmap.lock().unwrap().previous().cloned();
+            respond(request, heatmap_png(&camera, &pipeline, previous))?;
+            Ok(())
+        },
+    )?;
+
+    camera_server.route("/clip.avi", Method::Get, Role::Viewer, |request| {
+        respond(request, record_clip(&camera, &pipeline))?;
+        Ok(())
+    })?;
+
+    camera_server.route("/burst.gif", Method::Get, Role::Viewer, |request| {
+        respond(request, record_gif(&camera, &pipeline))?;
+        Ok(())
+    })?;
+
+    camera_server.route("/trigger", Method::Get, Role::Admin, |request| {
+        trigger.store(true, Ordering::Relaxed);
+        respond(request, Ok(Reply::text("recording")))?;
+        Ok(())
+    })?;
+
+    camera_server.route("/event.avi", Method::Get, Role::Viewer, |request| {
+        let reply = match last_event.lock().unwrap().as_ref() {
+            Some(data) => Ok(Reply::new("video/x-msvideo", data.clone())),
+            None => Err(HttpError::NotFound("no event recorded")),
+        };
+        respond(request, reply)?;
         Ok(())
     })?;
 
@@ -83,3 +277,233 @@ fn main() -> Result<()> {
         std::thread::sleep(std::time::Duration::from_millis(1000));
     }
 }
+
+/// Keeps a pre-roll of the sensor's JPEGs, when triggered the clip goes through the pipeline
+/// and is kept in memory as an AVI. Only the frames of a saved clip are processed, so the
+/// recorder costs little while nothing happens
+fn event_recorder(
+    camera: &Camera,
+    snapshot_lock: &Mutex<()>,
+    pipeline: &FramePipeline,
+    trigger: &AtomicBool,
+    last_event: &Mutex<Option<Vec<u8>>>,
+) {
+    let sink = |clip: Clip| {
+        log::info!(
+            "event clip: {} frames, {} before the trigger",
+            clip.frames.len(),
+            clip.pre_roll().len()
+        );
+        let exif = camera.sensor().exif();
+        let (now, wall_now) = (Instant::now(), SystemTime::now());
+        let frames = clip
+            .frames
+            .into_iter()
+            .map(|frame| {
+                // stamped with the time the frame was taken, not the time it is processed
+                let time = wall_now - now.duration_since(frame.captured);
+                Ok(ClipFrame {
+                    jpeg: pipeline.process_capture(&frame.jpeg, time, &exif)?,
+                    captured: frame.captured,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let clip = Clip { frames, ..clip };
+
+        let avi = clip.write_avi(Cursor::new(Vec::new()), CLIP_FPS)?;
+        *last_event.lock().unwrap() = Some(avi.into_inner());
+        Ok(())
+    };
+    let mut recorder = EventRecorder::new(
+        sink,
+        Duration::from_secs(5),
+        Duration::from_secs(5),
+        1024 * 1024,
+    );
+
+    loop {
+        std::thread::sleep(Duration::from_millis(1000 / CLIP_FPS as u64));
+
+        // a snapshot may have the sensor at another size, the clip must keep its own
+        let framebuffer = {
+            let _guard = snapshot_lock.lock().unwrap();
+            camera.get_framebuffer()
+        };
+        let Some(framebuffer) = framebuffer else {
+            continue;
+        };
+        let data = framebuffer.data().to_vec();
+        drop(framebuffer);
+
+        let now = Instant::now();
+        if trigger.swap(false, Ordering::Relaxed) {
+            recorder.trigger(now);
+        }
+        if let Err(err) = recorder.push(data, now) {
+            log::error!("event clip error: {:?}", err);
+        }
+    }
+}
+
+/// Finds what moves in the picture, to follow it across the counting lines and zones and to
+/// build the activity heatmap. What has to survive a reboot is saved at most every minute,
+/// to spare the flash
+fn activity_monitor(
+    camera: &Camera,
+    snapshot_lock: &Mutex<()>,
+    orientation: Orientation,
+    counter: &Mutex<Counter>,
+    heatmap: &Mutex<HeatmapAccumulator>,
+    ws: &WsHub,
+    storage: &mut Storage,
+) {
+    let mut motion = MotionDetector::new(MotionConfig::default());
+    let mut saved_totals = counter.lock().unwrap().get_totals().clone();
+    let mut last_save = Instant::now();
+    let mut last_heatmap_save = Instant::now();
+
+    loop {
+        std::thread::sleep(Duration::from_millis(200));
+
+        // a frame of a snapshot's size would reset the background
+        let framebuffer = {
+            let _guard = snapshot_lock.lock().unwrap();
+            camera.get_framebuffer()
+        };
+        let Some(framebuffer) = framebuffer else {
+            continue;
+        };
+        let frame = Frame::decode_jpeg(framebuffer.data());
+        drop(framebuffer);
+
+        // lines, zones and the heatmap are placed on the upright picture
+        let frame = match frame.and_then(|frame| orientation.apply(&frame)) {
+            Ok(frame) => frame,
+            Err(err) => {
+                log::error!("activity monitor decode error: {:?}", err);
+                continue;
+            }
+        };
+        let Some(mask) = motion.update(&frame) else {
+            continue;
+        };
+
+        // the flash wears out, the running window is only saved every ten minutes
+        // and when it is completed
+        let mut heatmap = heatmap.lock().unwrap();
+        let completed = heatmap.add(&mask, SystemTime::now());
+        let history = (completed || last_heatmap_save.elapsed() > Duration::from_secs(600))
+            .then(|| heatmap.get_history().clone());
+        drop(heatmap);
+        if completed {
+            log::info!("heatmap window completed");
+        }
+        if let Some(history) = history {
+            if let Err(err) = storage.store(HEATMAP_STORAGE_KEY, &history) {
+                log::error!("could not save the heatmap: {:?}", err);
+            }
+            last_heatmap_save = Instant::now();
+        }
+
+        let blobs = motion.blobs(&mask);
+        if !blobs.is_empty() {
+            ws.publish(&WsEvent::Motion {
+                blobs: blobs.clone(),
+            });
+        }
+
+        let mut counter = counter.lock().unwrap();
+        for crossing in counter.update(&blobs) {
+            log::info!(
+                "{} crossed {} {:?}",
+                crossing.track,
+                crossing.line,
+                crossing.direction
+            );
+            ws.publish(&WsEvent::Crossing(crossing));
+        }
+        let totals = counter.get_totals().clone();
+        drop(counter);
+
+        if last_save.elapsed() < Duration::from_secs(60) {
+            continue;
+        }
+        if totals != saved_totals {
+            if let Err(err) = storage.store(COUNTS_STORAGE_KEY, &totals) {
+                log::error!("could not save the counts: {:?}", err);
+            }
+            saved_totals = totals;
+        }
+        last_save = Instant::now();
+    }
+}
+
+/// Draws the heatmap over a fresh picture, taken through the pipeline like any other
+fn heatmap_png(
+    camera: &Camera,
+    pipeline: &FramePipeline,
+    heatmap: Option<Heatmap>,
+) -> Result<Reply, HttpError> {
+    let Some(heatmap) = heatmap else {
+        return Err(HttpError::NotFound("no activity recorded yet"));
+    };
+    let framebuffer = capture(camera)?;
+    let frame = Frame::decode_jpeg(framebuffer.data())?;
+    drop(framebuffer);
+
+    let data = heatmap.render_png(&pipeline.apply(&frame, SystemTime::now())?)?;
+    Ok(Reply::new("image/png", data))
+}
+
+/// A few seconds of video as an MJPEG AVI
+fn record_clip(camera: &Camera, pipeline: &FramePipeline) -> Result<Reply, HttpError> {
+    let mut recorder: Option<Recorder<Cursor<Vec<u8>>>> = None;
+    let end = Instant::now() + CLIP_LENGTH;
+
+    while Instant::now() < end {
+        let Some(framebuffer) = camera.get_framebuffer() else {
+            continue;
+        };
+        let now = Instant::now();
+        if recorder.as_ref().is_some_and(|r| !r.wants_frame(now)) {
+            continue;
+        }
+        let data = pipeline.process_capture(
+            framebuffer.data(),
+            SystemTime::now(),
+            &camera.sensor().exif(),
+        )?;
+        drop(framebuffer);
+
+        if recorder.is_none() {
+            // the pipeline may rotate or crop, so take the size from the output
+            let (width, height) = Frame::jpeg_dimensions(&data)?;
+            recorder = Some(Recorder::clip(
+                Cursor::new(Vec::new()),
+                width as u32,
+                height as u32,
+                CLIP_FPS,
+            )?);
+        }
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.offer(&data, now)?;
+        }
+    }
+
+    let recorder = recorder.ok_or(HttpError::NoFrame)?;
+    Ok(Reply::new(
+        "video/x-msvideo",
+        recorder.finish()?.into_inner(),
+    ))
+}
+
+/// Two second animated burst
+fn record_gif(camera: &Camera, pipeline: &FramePipeline) -> Result<Reply, HttpError> {
+    let mut gif = GifBuilder::new().delay(Duration::from_millis(200));
+    gif.capture_burst(camera, pipeline, 10)?;
+
+    if gif.is_empty() {
+        return Err(HttpError::NoFrame);
+    }
+    Ok(Reply::new("image/gif", gif.encode()?))
+}
@@ -0,0 +1,149 @@
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use image::{
+    codecs::gif::{GifEncoder, Repeat},
+    imageops::{self, FilterType},
+    Delay, DynamicImage, RgbaImage,
+};
+
+use crate::{espcam::Camera, frame::Frame, pipeline::FramePipeline};
+
+/// Builds an animated GIF out of a burst or a time-lapse.
+///
+/// Frames are downsized as soon as they are pushed, so only the small
+/// copies are kept around until [`GifBuilder::encode`].
+pub struct GifBuilder {
+    max_side: u32,
+    delay: Duration,
+    repeat: Option<u16>,
+    speed: i32,
+    frames: Vec<RgbaImage>,
+}
+
+impl Default for GifBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GifBuilder {
+    pub fn new() -> Self {
+        Self {
+            max_side: 320,
+            delay: Duration::from_millis(200),
+            repeat: None,
+            speed: 10,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Longest side of the output, larger frames are scaled down
+    pub fn max_side(mut self, max_side: u32) -> Self {
+        self.max_side = max_side.max(1);
+        self
+    }
+
+    /// Time each frame is shown for
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// How many times the animation is repeated, `None` loops forever
+    pub fn repeat(mut self, repeat: Option<u16>) -> Self {
+        self.repeat = repeat;
+        self
+    }
+
+    /// Palette quantisation speed from 1 to 30, lower is slower but gives better colors
+    pub fn speed(mut self, speed: i32) -> Self {
+        self.speed = speed.clamp(1, 30);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Adds a frame, it is converted and downsized right away
+    pub fn push(&mut self, frame: Frame) -> Result<()> {
+        let rgb = frame.to_rgb_image()?;
+        drop(frame);
+
+        let (width, height) = fit(rgb.width(), rgb.height(), self.max_side);
+        let rgba = DynamicImage::ImageRgb8(rgb).into_rgba8();
+        let rgba = if (width, height) == rgba.dimensions() {
+            rgba
+        } else {
+            imageops::resize(&rgba, width, height, FilterType::Triangle)
+        };
+
+        self.frames.push(rgba);
+        Ok(())
+    }
+
+    /// Adds every frame of a burst
+    pub fn extend(&mut self, frames: impl IntoIterator<Item = Frame>) -> Result<()> {
+        for frame in frames {
+            self.push(frame)?;
+        }
+        Ok(())
+    }
+
+    /// Takes `count` frames, one every [`GifBuilder::delay`], through the pipeline like
+    /// photos. The frame waiting in the driver is dropped first as it may be old, frames
+    /// the driver does not give are skipped.
+    pub fn capture_burst(
+        &mut self,
+        camera: &Camera,
+        pipeline: &FramePipeline,
+        count: usize,
+    ) -> Result<()> {
+        camera.get_framebuffer();
+        for _ in 0..count {
+            let Some(framebuffer) = camera.get_framebuffer() else {
+                continue;
+            };
+            let frame = Frame::decode_jpeg(framebuffer.data())?;
+            drop(framebuffer);
+
+            self.push(pipeline.apply(&frame, SystemTime::now())?)?;
+            std::thread::sleep(self.delay);
+        }
+        Ok(())
+    }
+
+    /// Quantises every frame to its own palette and encodes the animation
+    pub fn encode(self) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        {
+            let mut encoder = GifEncoder::new_with_speed(&mut out, self.speed);
+            encoder.set_repeat(match self.repeat {
+                Some(count) => Repeat::Finite(count),
+                None => Repeat::Infinite,
+            })?;
+
+            let delay = Delay::from_saturating_duration(self.delay);
+            for frame in self.frames {
+                encoder.encode_frame(image::Frame::from_parts(frame, 0, 0, delay))?;
+            }
+        }
+        Ok(out)
+    }
+}
+
+fn fit(width: u32, height: u32, max_side: u32) -> (u32, u32) {
+    let longest = width.max(height);
+    if longest <= max_side {
+        return (width, height);
+    }
+    (
+        (width * max_side / longest).max(1),
+        (height * max_side / longest).max(1),
+    )
+}
@@ -0,0 +1,950 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::overlay::days_from_civil;
+
+pub const AUTH_STORAGE_KEY: &str = "auth";
+
+/// Clients whose failed logins are remembered at most. The oldest are forgotten first, then
+/// the lockouts that end first.
+const MAX_TRACKED_CLIENTS: usize = 32;
+/// How long a ticket from [`Authenticator::issue_ticket`] can be used to connect
+const TICKET_LIFETIME: Duration = Duration::from_secs(60);
+/// Unused tickets kept at most, the oldest are dropped first
+const MAX_TICKETS: usize = 8;
+/// Authorization headers that passed, so that PBKDF2 does not run on every request
+const MAX_CACHED_LOGINS: usize = 8;
+/// WS-Security nonces remembered, a digest that comes again is refused
+const MAX_DIGEST_NONCES: usize = 64;
+/// How far the `Created` time of a digest may be from the clock of the NVR, as the camera
+/// learnt it from the first digest after boot
+const MAX_CREATED_SKEW: Duration = Duration::from_secs(300);
+
+/// What a client may do, admins can do everything viewers can
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Viewer,
+    Admin,
+}
+
+/// Salted password hash, written as `pbkdf2-sha256$<rounds>$<salt hex>$<hash hex>`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PasswordHash {
+    rounds: u32,
+    salt: Vec<u8>,
+    hash: [u8; 32],
+}
+
+impl PasswordHash {
+    pub fn new(password: &str, salt: &[u8], rounds: u32) -> Self {
+        Self {
+            rounds,
+            salt: salt.to_vec(),
+            hash: pbkdf2(password, salt, rounds),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let parts: Vec<&str> = text.split('$').collect();
+        let ["pbkdf2-sha256", rounds, salt, hash] = parts[..] else {
+            bail!("password hashes look like pbkdf2-sha256$<rounds>$<salt hex>$<hash hex>");
+        };
+        let rounds: u32 = rounds.parse()?;
+        if rounds == 0 {
+            bail!("the password hash needs at least one round");
+        }
+        Ok(Self {
+            rounds,
+            salt: from_hex(salt)?,
+            hash: from_hex(hash)?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("the password hash must be 32 bytes"))?,
+        })
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        constant_time_eq(&pbkdf2(password, &self.salt, self.rounds), &self.hash)
+    }
+}
+
+impl fmt::Display for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "pbkdf2-sha256${}${}${}",
+            self.rounds,
+            to_hex(&self.salt),
+            to_hex(&self.hash)
+        )
+    }
+}
+
+impl TryFrom<String> for PasswordHash {
+    type Error = anyhow::Error;
+
+    fn try_from(text: String) -> Result<Self> {
+        Self::parse(&text)
+    }
+}
+
+impl From<PasswordHash> for String {
+    fn from(hash: PasswordHash) -> Self {
+        hash.to_string()
+    }
+}
+
+/// Bearer tokens are long and random, a plain SHA-256 is enough: `sha256$<hash hex>`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TokenHash([u8; 32]);
+
+impl TokenHash {
+    pub fn new(token: &str) -> Self {
+        Self(Sha256::digest(token.as_bytes()).into())
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let Some(hash) = text.strip_prefix("sha256$") else {
+            bail!("token hashes look like sha256$<hash hex>");
+        };
+        Ok(Self(from_hex(hash)?.try_into().map_err(|_| {
+            anyhow::anyhow!("the token hash must be 32 bytes")
+        })?))
+    }
+
+    pub fn verify(&self, token: &str) -> bool {
+        constant_time_eq(&Self::new(token).0, &self.0)
+    }
+}
+
+impl fmt::Display for TokenHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sha256${}", to_hex(&self.0))
+    }
+}
+
+impl TryFrom<String> for TokenHash {
+    type Error = anyhow::Error;
+
+    fn try_from(text: String) -> Result<Self> {
+        Self::parse(&text)
+    }
+}
+
+impl From<TokenHash> for String {
+    fn from(hash: TokenHash) -> Self {
+        hash.to_string()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct User {
+    pub name: String,
+    pub password: PasswordHash,
+    pub role: Role,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub name: String,
+    pub token: TokenHash,
+    pub role: Role,
+}
+
+/// The user of NVRs, its password is kept in plain text: ONVIF clients send a digest of it
+/// that can't be checked against a hash. It logs in with HTTP Basic too, for RTSP.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OnvifUser {
+    pub name: String,
+    pub password: String,
+    pub role: Role,
+}
+
+/// The users and tokens allowed in, only hashes are kept but for the ONVIF user
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub users: Vec<User>,
+    #[serde(default)]
+    pub tokens: Vec<ApiToken>,
+    #[serde(default)]
+    pub onvif: Option<OnvifUser>,
+}
+
+impl AuthConfig {
+    /// Parses a json object such as
+    /// `{"users": [{"name": "admin", "password": "pbkdf2-sha256$1000$<salt>$<hash>", "role": "admin"}], "tokens": [{"name": "dashboard", "token": "sha256$<hash>", "role": "viewer"}]}`
+    pub fn from_json(json: &str) -> Result<Self> {
+        if json.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.tokens.is_empty() && self.onvif.is_none()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LockoutPolicy {
+    /// Wrong credentials in a row before a client is locked out
+    pub max_failures: u32,
+    pub duration: Duration,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            duration: Duration::from_secs(300),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum AuthError {
+    #[error("credentials required")]
+    Missing,
+    #[error("invalid credentials")]
+    Invalid,
+    #[error("not allowed")]
+    Forbidden,
+    #[error("too many failed logins, retry in {} s", .0.as_secs())]
+    LockedOut(Duration),
+}
+
+pub type SharedAuthenticator = Arc<Mutex<Authenticator>>;
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// HTTP Basic and bearer token authentication with a lockout after repeated failures.
+///
+/// Failures are counted per client address, so that guessing passwords locks out the one
+/// guessing and not the user. The time is passed in by the caller.
+#[derive(Debug)]
+pub struct Authenticator {
+    config: AuthConfig,
+    lockout: LockoutPolicy,
+    failures: HashMap<IpAddr, Failures>,
+    logins: Vec<([u8; 32], Role)>,
+    /// Short lived bearer tokens and when they expire
+    tickets: Vec<(TokenHash, Role, Instant)>,
+    /// Checked for unknown user names, so that they take as long as the known ones
+    dummy: PasswordHash,
+    /// Nonce and `Created` of the last digests that passed
+    nonces: VecDeque<(Vec<u8>, String)>,
+    /// `Created` of the first digest that passed, in seconds since the epoch, and when
+    created_origin: Option<(i64, Instant)>,
+}
+
+impl Authenticator {
+    pub fn new(config: AuthConfig) -> Self {
+        let rounds = config
+            .users
+            .iter()
+            .map(|u| u.password.rounds)
+            .max()
+            .unwrap_or(1);
+        Self {
+            config,
+            lockout: LockoutPolicy::default(),
+            failures: HashMap::new(),
+            logins: Vec::new(),
+            tickets: Vec::new(),
+            dummy: PasswordHash {
+                rounds,
+                salt: vec![0; 16],
+                hash: [0; 32],
+            },
+            nonces: VecDeque::new(),
+            created_origin: None,
+        }
+    }
+
+    pub fn lockout(mut self, lockout: LockoutPolicy) -> Self {
+        self.lockout = lockout;
+        self
+    }
+
+    /// Role of the client at `peer` sending this `Authorization` header, if it is at least
+    /// `required`
+    pub fn authorize(
+        &mut self,
+        peer: IpAddr,
+        authorization: Option<&str>,
+        required: Role,
+        now: Instant,
+    ) -> Result<Role, AuthError> {
+        let role = self.authenticate(peer, authorization, now)?;
+        if role < required {
+            return Err(AuthError::Forbidden);
+        }
+        Ok(role)
+    }
+
+    /// A random bearer token with `role`, valid for a minute. The control panel passes one to
+    /// the stream on its own port, where the browser does not send the credentials again.
+    pub fn issue_ticket(&mut self, role: Role, now: Instant) -> String {
+        let ticket = to_hex(&random_bytes::<16>());
+
+        self.tickets.retain(|(_, _, expiry)| *expiry > now);
+        if self.tickets.len() >= MAX_TICKETS {
+            self.tickets.remove(0);
+        }
+        self.tickets
+            .push((TokenHash::new(&ticket), role, now + TICKET_LIFETIME));
+        ticket
+    }
+
+    /// Role of the client at `peer` sending this `Authorization` header
+    pub fn authenticate(
+        &mut self,
+        peer: IpAddr,
+        authorization: Option<&str>,
+        now: Instant,
+    ) -> Result<Role, AuthError> {
+        let authorization = authorization.ok_or(AuthError::Missing)?.trim();
+
+        let digest: [u8; 32] = Sha256::digest(authorization.as_bytes()).into();
+        if let Some((_, role)) = self.logins.iter().find(|(d, _)| *d == digest) {
+            return Ok(*role);
+        }
+        self.check_lockout(peer, now)?;
+
+        let role = if let Some(token) = strip_scheme(authorization, "Bearer") {
+            // tickets expire, they never go in the cache
+            if let Some((_, role, _)) = self
+                .tickets
+                .iter()
+                .find(|(ticket, _, expiry)| *expiry > now && ticket.verify(token))
+            {
+                return Ok(*role);
+            }
+
+            self.config
+                .tokens
+                .iter()
+                .find(|t| t.token.verify(token))
+                .map(|t| t.role)
+        } else if let Some(credentials) = strip_scheme(authorization, "Basic") {
+            let (name, password) = decode_basic(credentials).ok_or(AuthError::Invalid)?;
+            let onvif = self.config.onvif.as_ref().filter(|u| u.name == name);
+            match self.config.users.iter().find(|u| u.name == name) {
+                Some(user) => user.password.verify(&password).then_some(user.role),
+                None => {
+                    std::hint::black_box(self.dummy.verify(&password));
+                    onvif
+                        .filter(|u| constant_time_eq(u.password.as_bytes(), password.as_bytes()))
+                        .map(|u| u.role)
+                }
+            }
+        } else {
+            return Err(AuthError::Invalid);
+        };
+
+        match role {
+            Some(role) => {
+                self.failures.remove(&peer);
+                if self.logins.len() >= MAX_CACHED_LOGINS {
+                    self.logins.remove(0);
+                }
+                self.logins.push((digest, role));
+                Ok(role)
+            }
+            None => {
+                self.record_failure(peer, now);
+                Err(AuthError::Invalid)
+            }
+        }
+    }
+
+    /// Role of a WS-Security `UsernameToken` with a password digest, only the ONVIF user can
+    /// send one. The camera may not know the time, so `Created` is compared with the first
+    /// one that passed since boot, and a nonce seen recently is refused to stop replays.
+    pub fn authenticate_digest(
+        &mut self,
+        peer: IpAddr,
+        name: &str,
+        digest: &[u8],
+        nonce: &[u8],
+        created: &str,
+        now: Instant,
+    ) -> Result<Role, AuthError> {
+        self.check_lockout(peer, now)?;
+        let role = self
+            .config
+            .onvif
+            .as_ref()
+            .filter(|u| u.name == name)
+            .filter(|u| constant_time_eq(&password_digest(nonce, created, &u.password), digest))
+            .map(|u| u.role)
+            .filter(|_| self.is_fresh(nonce, created, now));
+        match role {
+            Some(role) => {
+                self.failures.remove(&peer);
+                Ok(role)
+            }
+            None => {
+                self.record_failure(peer, now);
+                Err(AuthError::Invalid)
+            }
+        }
+    }
+
+    /// Whether a digest with this nonce and creation time may pass, remembers it if so
+    fn is_fresh(&mut self, nonce: &[u8], created: &str, now: Instant) -> bool {
+        let Some(secs) = created_secs(created) else {
+            return false;
+        };
+        if self.nonces.iter().any(|(n, c)| n == nonce && c == created) {
+            return false;
+        }
+        match self.created_origin {
+            Some((origin, at)) => {
+                let expected = origin + now.duration_since(at).as_secs() as i64;
+                if (secs - expected).unsigned_abs() > MAX_CREATED_SKEW.as_secs() {
+                    return false;
+                }
+            }
+            None => self.created_origin = Some((secs, now)),
+        }
+
+        if self.nonces.len() >= MAX_DIGEST_NONCES {
+            self.nonces.pop_front();
+        }
+        self.nonces.push_back((nonce.to_vec(), created.to_string()));
+        true
+    }
+
+    fn check_lockout(&self, peer: IpAddr, now: Instant) -> Result<(), AuthError> {
+        match self.failures.get(&peer).and_then(|f| f.locked_until) {
+            Some(until) if until > now => Err(AuthError::LockedOut(until - now)),
+            _ => Ok(()),
+        }
+    }
+
+    fn record_failure(&mut self, peer: IpAddr, now: Instant) {
+        if !self.failures.contains_key(&peer) && self.failures.len() >= MAX_TRACKED_CLIENTS {
+            let forgotten = self
+                .failures
+                .iter()
+                .min_by_key(|(_, f)| match f.locked_until {
+                    Some(until) if until > now => (true, until),
+                    _ => (false, f.last),
+                })
+                .map(|(peer, _)| *peer);
+            if let Some(forgotten) = forgotten {
+                self.failures.remove(&forgotten);
+            }
+        }
+
+        let failures = self.failures.entry(peer).or_insert(Failures {
+            count: 0,
+            last: now,
+            locked_until: None,
+        });
+        // a lockout that ran out starts the count again
+        if failures.locked_until.is_some_and(|until| until <= now) {
+            failures.count = 0;
+            failures.locked_until = None;
+        }
+        failures.count += 1;
+        failures.last = now;
+        if failures.count >= self.lockout.max_failures {
+            failures.locked_until = Some(now + self.lockout.duration);
+        }
+    }
+}
+
+/// The credentials after `scheme`, which is matched ignoring case
+fn strip_scheme<'a>(authorization: &'a str, scheme: &str) -> Option<&'a str> {
+    let (name, rest) = authorization.split_once(' ')?;
+    name.eq_ignore_ascii_case(scheme).then(|| rest.trim())
+}
+
+fn decode_basic(credentials: &str) -> Option<(String, String)> {
+    let decoded = String::from_utf8(BASE64.decode(credentials).ok()?).ok()?;
+    let (name, password) = decoded.split_once(':')?;
+    Some((name.to_string(), password.to_string()))
+}
+
+/// Seconds since the epoch of an xsd:dateTime such as `2024-05-01T12:00:00.5Z`, the
+/// fraction is dropped
+fn created_secs(created: &str) -> Option<i64> {
+    let field = |range: std::ops::Range<usize>| created.get(range)?.parse::<i64>().ok();
+    let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+    let (hour, minute, second) = (field(11..13)?, field(14..16)?, field(17..19)?);
+    if created.get(4..5) != Some("-") || created.get(10..11) != Some("T") {
+        return None;
+    }
+
+    let zone = created[19..].trim_start_matches(|c: char| c == '.' || c.is_ascii_digit());
+    let offset = match zone {
+        "" | "Z" => 0,
+        _ => {
+            let sign = match zone.get(..1)? {
+                "+" => 1,
+                "-" => -1,
+                _ => return None,
+            };
+            let hours = zone.get(1..3)?.parse::<i64>().ok()?;
+            let minutes = zone.get(4..6)?.parse::<i64>().ok()?;
+            sign * (hours * 3600 + minutes * 60)
+        }
+    };
+
+    let days = days_from_civil(year, month as u32, day as u32);
+    Some(days * 86400 + hour * 3600 + minute * 60 + second - offset)
+}
+
+/// WS-Security password digest: SHA-1 of the nonce, the creation time and the password
+pub fn password_digest(nonce: &[u8], created: &str, password: &str) -> [u8; 20] {
+    Sha1::new()
+        .chain_update(nonce)
+        .chain_update(created)
+        .chain_update(password)
+        .finalize()
+        .into()
+}
+
+/// PBKDF2-HMAC-SHA256 with a single output block
+fn pbkdf2(password: &str, salt: &[u8], rounds: u32) -> [u8; 32] {
+    let mut block = salt.to_vec();
+    block.extend_from_slice(&1u32.to_be_bytes());
+    let mut u = hmac_sha256(password.as_bytes(), &block);
+    let mut out = u;
+    for _ in 1..rounds {
+        u = hmac_sha256(password.as_bytes(), &u);
+        for (o, v) in out.iter_mut().zip(u) {
+            *o ^= v;
+        }
+    }
+    out
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut padded = [0u8; 64];
+    if key.len() > 64 {
+        padded[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        padded[..key.len()].copy_from_slice(key);
+    }
+
+    let inner = Sha256::new()
+        .chain_update(padded.map(|b| b ^ 0x36))
+        .chain_update(message)
+        .finalize();
+    Sha256::new()
+        .chain_update(padded.map(|b| b ^ 0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Bytes from the hardware random number generator, for what a client must not guess
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    unsafe { esp_idf_sys::esp_fill_random(bytes.as_mut_ptr().cast(), N as _) };
+    bytes
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Result<Vec<u8>> {
+    if !text.is_ascii() || text.len() % 2 != 0 {
+        bail!("invalid hex");
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&text[i..i + 2], 16)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const PEER: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+    const OTHER: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 21));
+
+    fn config() -> AuthConfig {
+        AuthConfig {
+            users: vec![
+                User {
+                    name: "admin".to_string(),
+                    password: PasswordHash::new("secret", b"salt", 10),
+                    role: Role::Admin,
+                },
+                User {
+                    name: "guest".to_string(),
+                    password: PasswordHash::new("guest", b"pepper", 10),
+                    role: Role::Viewer,
+                },
+            ],
+            tokens: vec![ApiToken {
+                name: "dashboard".to_string(),
+                token: TokenHash::new("t0ken"),
+                role: Role::Viewer,
+            }],
+            onvif: Some(OnvifUser {
+                name: "nvr".to_string(),
+                password: "plain".to_string(),
+                role: Role::Viewer,
+            }),
+        }
+    }
+
+    fn basic(name: &str, password: &str) -> String {
+        format!("Basic {}", BASE64.encode(format!("{}:{}", name, password)))
+    }
+
+    #[test]
+    fn basic_and_bearer() {
+        let mut auth = Authenticator::new(config());
+        let now = Instant::now();
+
+        let admin = basic("admin", "secret");
+        assert_eq!(
+            auth.authorize(PEER, Some(&admin), Role::Admin, now),
+            Ok(Role::Admin)
+        );
+        // the second time comes from the cache
+        assert_eq!(
+            auth.authorize(PEER, Some(&admin), Role::Viewer, now),
+            Ok(Role::Admin)
+        );
+        assert_eq!(
+            auth.authorize(PEER, Some("Bearer t0ken"), Role::Viewer, now),
+            Ok(Role::Viewer)
+        );
+        assert_eq!(
+            auth.authorize(PEER, Some("bearer  t0ken "), Role::Viewer, now),
+            Ok(Role::Viewer)
+        );
+    }
+
+    #[test]
+    fn wrong_credentials() {
+        let mut auth = Authenticator::new(config());
+        let now = Instant::now();
+
+        assert_eq!(
+            auth.authenticate(PEER, Some(&basic("admin", "guess")), now),
+            Err(AuthError::Invalid)
+        );
+        assert_eq!(
+            auth.authenticate(PEER, Some(&basic("nobody", "secret")), now),
+            Err(AuthError::Invalid)
+        );
+        assert_eq!(
+            auth.authenticate(PEER, Some("Bearer guess"), now),
+            Err(AuthError::Invalid)
+        );
+        assert_eq!(
+            auth.authenticate(PEER, Some("Digest abc"), now),
+            Err(AuthError::Invalid)
+        );
+        assert_eq!(auth.authenticate(PEER, None, now), Err(AuthError::Missing));
+    }
+
+    #[test]
+    fn viewers_are_forbidden_admin_routes() {
+        let mut auth = Authenticator::new(config());
+        let now = Instant::now();
+
+        let guest = basic("guest", "guest");
+        assert_eq!(
+            auth.authorize(PEER, Some(&guest), Role::Admin, now),
+            Err(AuthError::Forbidden)
+        );
+        assert_eq!(
+            auth.authorize(PEER, Some("Bearer t0ken"), Role::Admin, now),
+            Err(AuthError::Forbidden)
+        );
+        assert_eq!(
+            auth.authorize(PEER, Some(&guest), Role::Viewer, now),
+            Ok(Role::Viewer)
+        );
+    }
+
+    #[test]
+    fn lockout_and_expiry() {
+        let mut auth = Authenticator::new(config());
+        let now = Instant::now();
+        let wrong = basic("admin", "guess");
+        let right = basic("admin", "secret");
+
+        for _ in 0..5 {
+            assert_eq!(
+                auth.authenticate(PEER, Some(&wrong), now),
+                Err(AuthError::Invalid)
+            );
+        }
+        assert_eq!(
+            auth.authenticate(PEER, Some(&right), now + Duration::from_secs(60)),
+            Err(AuthError::LockedOut(Duration::from_secs(240)))
+        );
+        // the user is not locked out, only the client guessing
+        assert_eq!(auth.authenticate(OTHER, Some(&right), now), Ok(Role::Admin));
+
+        let later = now + Duration::from_secs(300);
+        assert_eq!(
+            auth.authenticate(PEER, Some(&right), later),
+            Ok(Role::Admin)
+        );
+    }
+
+    #[test]
+    fn failures_start_again_after_a_lockout() {
+        let mut auth = Authenticator::new(config());
+        let now = Instant::now();
+        let wrong = basic("admin", "guess");
+
+        for _ in 0..5 {
+            let _ = auth.authenticate(PEER, Some(&wrong), now);
+        }
+        let later = now + Duration::from_secs(300);
+        assert_eq!(
+            auth.authenticate(PEER, Some(&wrong), later),
+            Err(AuthError::Invalid)
+        );
+        assert_eq!(
+            auth.authenticate(PEER, Some(&basic("admin", "secret")), later),
+            Ok(Role::Admin)
+        );
+    }
+
+    #[test]
+    fn guessing_locks_out_no_one_else() {
+        let mut auth = Authenticator::new(config());
+        let now = Instant::now();
+
+        for _ in 0..5 {
+            let _ = auth.authenticate(PEER, Some(&basic("admin", "guess")), now);
+            let _ = auth.authenticate(PEER, Some("Bearer guess"), now);
+        }
+        assert!(matches!(
+            auth.authenticate(PEER, Some("Bearer t0ken"), now),
+            Err(AuthError::LockedOut(_))
+        ));
+        assert_eq!(
+            auth.authenticate(OTHER, Some("Bearer t0ken"), now),
+            Ok(Role::Viewer)
+        );
+        assert_eq!(
+            auth.authenticate(OTHER, Some(&basic("admin", "secret")), now),
+            Ok(Role::Admin)
+        );
+    }
+
+    #[test]
+    fn locked_clients_are_kept() {
+        let mut auth = Authenticator::new(config());
+        let now = Instant::now();
+
+        for _ in 0..5 {
+            let _ = auth.authenticate(PEER, Some(&basic("admin", "guess")), now);
+        }
+        // enough other clients to fill the table twice
+        for i in 0..2 * MAX_TRACKED_CLIENTS {
+            let later = now + Duration::from_secs(1 + i as u64);
+            let peer = IpAddr::V4(Ipv4Addr::new(10, 0, 0, i as u8));
+            let _ = auth.authenticate(peer, Some(&basic("admin", "guess")), later);
+        }
+        assert!(auth.failures.len() <= MAX_TRACKED_CLIENTS);
+
+        let later = now + Duration::from_secs(200);
+        assert!(matches!(
+            auth.authenticate(PEER, Some(&basic("admin", "secret")), later),
+            Err(AuthError::LockedOut(_))
+        ));
+    }
+
+    #[test]
+    fn tickets_expire() {
+        let mut auth = Authenticator::new(config());
+        let now = Instant::now();
+
+        let ticket = auth.issue_ticket(Role::Viewer, now);
+        let bearer = format!("Bearer {}", ticket);
+        assert_eq!(ticket.len(), 32);
+        assert_ne!(ticket, auth.issue_ticket(Role::Viewer, now));
+        assert_eq!(
+            auth.authorize(
+                PEER,
+                Some(&bearer),
+                Role::Viewer,
+                now + Duration::from_secs(30)
+            ),
+            Ok(Role::Viewer)
+        );
+        assert_eq!(
+            auth.authorize(PEER, Some(&bearer), Role::Admin, now),
+            Err(AuthError::Forbidden)
+        );
+        assert_eq!(
+            auth.authenticate(PEER, Some(&bearer), now + TICKET_LIFETIME),
+            Err(AuthError::Invalid)
+        );
+    }
+
+    #[test]
+    fn onvif_user_digest_and_basic() {
+        let mut auth = Authenticator::new(config());
+        let now = Instant::now();
+        let created = "2024-05-01T12:00:00Z";
+        let digest = password_digest(b"nonce", created, "plain");
+
+        assert_eq!(
+            auth.authenticate_digest(PEER, "nvr", &digest, b"nonce", created, now),
+            Ok(Role::Viewer)
+        );
+        assert_eq!(
+            auth.authenticate_digest(PEER, "nvr", &digest, b"other", created, now),
+            Err(AuthError::Invalid)
+        );
+        // the hashed users can't send digests
+        let digest = password_digest(b"nonce", created, "secret");
+        assert_eq!(
+            auth.authenticate_digest(PEER, "admin", &digest, b"nonce", created, now),
+            Err(AuthError::Invalid)
+        );
+        assert_eq!(
+            auth.authenticate(PEER, Some(&basic("nvr", "plain")), now),
+            Ok(Role::Viewer)
+        );
+        assert_eq!(
+            auth.authenticate(PEER, Some(&basic("nvr", "Plain")), now),
+            Err(AuthError::Invalid)
+        );
+
+        for _ in 0..4 {
+            let _ = auth.authenticate_digest(PEER, "nvr", &[0; 20], b"nonce", created, now);
+        }
+        let digest = password_digest(b"nonce", created, "plain");
+        assert!(matches!(
+            auth.authenticate_digest(PEER, "nvr", &digest, b"nonce", created, now),
+            Err(AuthError::LockedOut(_))
+        ));
+    }
+
+    #[test]
+    fn digests_are_not_replayed() {
+        let mut auth = Authenticator::new(config());
+        let now = Instant::now();
+        let login = |auth: &mut Authenticator, nonce: &[u8], created: &str, now| {
+            let digest = password_digest(nonce, created, "plain");
+            auth.authenticate_digest(PEER, "nvr", &digest, nonce, created, now)
+        };
+
+        assert_eq!(
+            login(&mut auth, b"one", "2024-05-01T12:00:00Z", now),
+            Ok(Role::Viewer)
+        );
+        assert_eq!(
+            login(&mut auth, b"one", "2024-05-01T12:00:00Z", now),
+            Err(AuthError::Invalid)
+        );
+        // the clock of the NVR goes on with the camera's
+        let later = now + Duration::from_secs(3600);
+        assert_eq!(
+            login(&mut auth, b"two", "2024-05-01T13:00:02.25Z", later),
+            Ok(Role::Viewer)
+        );
+        assert_eq!(
+            login(&mut auth, b"three", "2024-05-01T12:00:01Z", later),
+            Err(AuthError::Invalid)
+        );
+        assert_eq!(
+            login(&mut auth, b"four", "2024-05-01T15:01:00+02:00", later),
+            Ok(Role::Viewer)
+        );
+        assert_eq!(
+            login(&mut auth, b"five", "yesterday", later),
+            Err(AuthError::Invalid)
+        );
+    }
+
+    #[test]
+    fn created_times() {
+        assert_eq!(created_secs("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(created_secs("2010-09-16T07:50:45Z"), Some(1284623445));
+        assert_eq!(created_secs("2010-09-16T07:50:45.123Z"), Some(1284623445));
+        assert_eq!(created_secs("2010-09-16T09:50:45+02:00"), Some(1284623445));
+        assert_eq!(created_secs("2010-09-16T07:50:45"), Some(1284623445));
+        assert_eq!(created_secs("2010-09-16 07:50:45Z"), None);
+        assert_eq!(created_secs("2010-09-16T07:50:45 UTC"), None);
+    }
+
+    #[test]
+    fn password_digest_vector() {
+        // from Python: base64(sha1(nonce + created + password))
+        let nonce = BASE64.decode("LKqI6G/AikKCQrN0zqZFlg==").unwrap();
+        let digest = password_digest(&nonce, "2010-09-16T07:50:45Z", "userpassword");
+        assert_eq!(BASE64.encode(digest), "tuOSpGlFlIXsozq4HFNeeGeFLEI=");
+    }
+
+    #[test]
+    fn password_hash_round_trip() {
+        let hash = PasswordHash::new("secret", b"salt", 1000);
+        let text = hash.to_string();
+        assert!(text.starts_with("pbkdf2-sha256$1000$73616c74$"));
+        assert_eq!(PasswordHash::parse(&text).unwrap(), hash);
+        assert!(hash.verify("secret"));
+        assert!(!hash.verify("Secret"));
+
+        assert!(PasswordHash::parse("pbkdf2-sha256$0$73616c74$00").is_err());
+        assert!(PasswordHash::parse("pbkdf2-sha256$10$73616c74$0011").is_err());
+        assert!(PasswordHash::parse("sha256$10$73616c74$0011").is_err());
+
+        let token = TokenHash::new("t0ken");
+        assert_eq!(TokenHash::parse(&token.to_string()).unwrap(), token);
+    }
+
+    #[test]
+    fn pbkdf2_vectors() {
+        // RFC 7914 section 11, the others from Python's hashlib.pbkdf2_hmac
+        assert_eq!(
+            to_hex(&pbkdf2("passwd", b"salt", 1)),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc"
+        );
+        assert_eq!(
+            to_hex(&pbkdf2("password", b"salt", 4096)),
+            "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a"
+        );
+        // keys longer than a block are hashed first
+        let long = "k".repeat(100);
+        assert_eq!(
+            to_hex(&pbkdf2(&long, b"salt", 2)),
+            "2c1357648009149f57e4d5544c3435bbca87a6b231300fa3abb2a89b50f56ec3"
+        );
+    }
+}
@@ -0,0 +1,350 @@
+use std::{
+    io::{self, Seek, SeekFrom, Write},
+    time::{Duration, Instant},
+};
+
+// offsets of the header fields that are only known once the recording is over,
+// relative to the start of the file
+const RIFF_SIZE: u64 = 4;
+const AVIH_TOTAL_FRAMES: u64 = 48;
+const AVIH_SUGGESTED_BUFFER: u64 = 60;
+const STRH_LENGTH: u64 = 140;
+const STRH_SUGGESTED_BUFFER: u64 = 144;
+const MOVI_SIZE: u64 = 216;
+const MOVI_FOURCC: u64 = 220;
+const HEADER_LEN: u64 = 224;
+
+const AVIF_HASINDEX: u32 = 0x10;
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+/// Writes JPEG frames into an AVI (RIFF MJPEG) file that standard players understand.
+///
+/// The headers are written with placeholders and fixed up by [`AviWriter::finish`],
+/// which also appends the `idx1` index, so the output needs to be seekable.
+pub struct AviWriter<W: Write + Seek> {
+    out: W,
+    start: u64,
+    /// offset from the `movi` fourcc and size of each frame
+    index: Vec<(u32, u32)>,
+    max_frame_len: u32,
+    movi_len: u64,
+}
+
+impl<W: Write + Seek> AviWriter<W> {
+    /// `fps` is the playback rate, for a time-lapse it is higher than the capture rate
+    pub fn new(mut out: W, width: u32, height: u32, fps: u32) -> io::Result<Self> {
+        let start = out.stream_position()?;
+        let fps = fps.max(1);
+
+        let mut header = Vec::with_capacity(HEADER_LEN as usize);
+        header.extend_from_slice(b"RIFF");
+        put_u32(&mut header, 0);
+        header.extend_from_slice(b"AVI ");
+
+        header.extend_from_slice(b"LIST");
+        put_u32(&mut header, 192);
+        header.extend_from_slice(b"hdrl");
+
+        header.extend_from_slice(b"avih");
+        put_u32(&mut header, 56);
+        put_u32(&mut header, 1_000_000 / fps);
+        put_u32(&mut header, 0); // max bytes per second
+        put_u32(&mut header, 0); // padding granularity
+        put_u32(&mut header, AVIF_HASINDEX);
+        put_u32(&mut header, 0); // total frames
+        put_u32(&mut header, 0); // initial frames
+        put_u32(&mut header, 1); // streams
+        put_u32(&mut header, 0); // suggested buffer size
+        put_u32(&mut header, width);
+        put_u32(&mut header, height);
+        header.extend_from_slice(&[0; 16]);
+
+        header.extend_from_slice(b"LIST");
+        put_u32(&mut header, 116);
+        header.extend_from_slice(b"strl");
+
+        header.extend_from_slice(b"strh");
+        put_u32(&mut header, 56);
+        header.extend_from_slice(b"vids");
+        header.extend_from_slice(b"MJPG");
+        put_u32(&mut header, 0); // flags
+        put_u32(&mut header, 0); // priority and language
+        put_u32(&mut header, 0); // initial frames
+        put_u32(&mut header, 1); // scale
+        put_u32(&mut header, fps); // rate, frames per second is rate / scale
+        put_u32(&mut header, 0); // start
+        put_u32(&mut header, 0); // length
+        put_u32(&mut header, 0); // suggested buffer size
+        put_u32(&mut header, u32::MAX); // default quality
+        put_u32(&mut header, 0); // sample size
+        header.extend_from_slice(&[0; 4]);
+        header.extend_from_slice(&(width as u16).to_le_bytes());
+        header.extend_from_slice(&(height as u16).to_le_bytes());
+
+        header.extend_from_slice(b"strf");
+        put_u32(&mut header, 40);
+        put_u32(&mut header, 40);
+        put_u32(&mut header, width);
+        put_u32(&mut header, height);
+        header.extend_from_slice(&1u16.to_le_bytes()); // planes
+        header.extend_from_slice(&24u16.to_le_bytes()); // bits per pixel
+        header.extend_from_slice(b"MJPG");
+        put_u32(&mut header, width * height * 3);
+        header.extend_from_slice(&[0; 16]);
+
+        header.extend_from_slice(b"LIST");
+        put_u32(&mut header, 0);
+        header.extend_from_slice(b"movi");
+
+        debug_assert_eq!(header.len() as u64, HEADER_LEN);
+        out.write_all(&header)?;
+
+        Ok(Self {
+            out,
+            start,
+            index: Vec::new(),
+            max_frame_len: 0,
+            // the index counts from the movi fourcc
+            movi_len: HEADER_LEN - MOVI_FOURCC,
+        })
+    }
+
+    pub fn frames(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Appends a JPEG frame, it is stored as it is
+    pub fn add_frame(&mut self, jpeg: &[u8]) -> io::Result<()> {
+        let len = jpeg.len() as u32;
+        self.index.push((self.movi_len as u32, len));
+        self.max_frame_len = self.max_frame_len.max(len);
+
+        self.out.write_all(b"00dc")?;
+        self.out.write_all(&len.to_le_bytes())?;
+        self.out.write_all(jpeg)?;
+        // chunks are word aligned
+        if len % 2 == 1 {
+            self.out.write_all(&[0])?;
+        }
+
+        self.movi_len += 8 + len as u64 + (len % 2) as u64;
+        Ok(())
+    }
+
+    /// Writes the index and the final sizes, returns the inner writer positioned at the end
+    pub fn finish(mut self) -> io::Result<W> {
+        let mut idx1 = Vec::with_capacity(8 + self.index.len() * 16);
+        idx1.extend_from_slice(b"idx1");
+        put_u32(&mut idx1, self.index.len() as u32 * 16);
+        for (offset, len) in &self.index {
+            idx1.extend_from_slice(b"00dc");
+            put_u32(&mut idx1, AVIIF_KEYFRAME);
+            put_u32(&mut idx1, *offset);
+            put_u32(&mut idx1, *len);
+        }
+        self.out.write_all(&idx1)?;
+
+        let end = self.out.stream_position()?;
+        let frames = self.index.len() as u32;
+        let buffer = self.max_frame_len + 8;
+
+        self.patch(RIFF_SIZE, (end - self.start - 8) as u32)?;
+        self.patch(AVIH_TOTAL_FRAMES, frames)?;
+        self.patch(AVIH_SUGGESTED_BUFFER, buffer)?;
+        self.patch(STRH_LENGTH, frames)?;
+        self.patch(STRH_SUGGESTED_BUFFER, buffer)?;
+        self.patch(MOVI_SIZE, self.movi_len as u32)?;
+
+        self.out.seek(SeekFrom::Start(end))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn patch(&mut self, offset: u64, value: u32) -> io::Result<()> {
+        self.out.seek(SeekFrom::Start(self.start + offset))?;
+        self.out.write_all(&value.to_le_bytes())
+    }
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Feeds an [`AviWriter`] from a capture loop, keeping one frame every `interval`.
+///
+/// With an interval matching the playback rate the clip plays in real time,
+/// a longer interval makes a time-lapse.
+pub struct Recorder<W: Write + Seek> {
+    writer: AviWriter<W>,
+    interval: Duration,
+    last: Option<Instant>,
+}
+
+impl<W: Write + Seek> Recorder<W> {
+    pub fn new(writer: AviWriter<W>, interval: Duration) -> Self {
+        Self {
+            writer,
+            interval,
+            last: None,
+        }
+    }
+
+    /// Real time clip, every frame offered at most `fps` times a second is kept
+    pub fn clip(out: W, width: u32, height: u32, fps: u32) -> io::Result<Self> {
+        let writer = AviWriter::new(out, width, height, fps)?;
+        Ok(Self::new(
+            writer,
+            Duration::from_micros(1_000_000 / fps.max(1) as u64),
+        ))
+    }
+
+    /// Time-lapse capturing one frame every `interval`, played back at `fps`
+    pub fn timelapse(
+        out: W,
+        width: u32,
+        height: u32,
+        interval: Duration,
+        fps: u32,
+    ) -> io::Result<Self> {
+        Ok(Self::new(
+            AviWriter::new(out, width, height, fps)?,
+            interval,
+        ))
+    }
+
+    /// Whether a frame offered now would be kept, so callers can skip processing it
+    pub fn wants_frame(&self, now: Instant) -> bool {
+        self.last
+            .map_or(true, |last| now.duration_since(last) >= self.interval)
+    }
+
+    /// Returns true if the frame was recorded
+    pub fn offer(&mut self, jpeg: &[u8], now: Instant) -> io::Result<bool> {
+        if !self.wants_frame(now) {
+            return Ok(false);
+        }
+        self.writer.add_frame(jpeg)?;
+        // keep the cadence even if frames arrive late
+        self.last = Some(match self.last {
+            Some(last) if now.duration_since(last) < self.interval * 2 => last + self.interval,
+            _ => now,
+        });
+        Ok(true)
+    }
+
+    pub fn frames(&self) -> usize {
+        self.writer.frames()
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        self.writer.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Three frames, the second one of odd length so it gets padded
+    fn record() -> (Vec<u8>, Vec<Vec<u8>>) {
+        let frames = vec![vec![0xAA; 100], vec![0xBB; 251], vec![0xCC; 64]];
+        let mut recorder = Recorder::clip(Cursor::new(Vec::new()), 320, 240, 5).unwrap();
+        let start = Instant::now();
+        for (n, frame) in frames.iter().enumerate() {
+            let now = start + Duration::from_millis(200 * n as u64);
+            assert!(recorder.offer(frame, now).unwrap());
+            // too early for the next one
+            assert!(!recorder
+                .offer(frame, now + Duration::from_millis(100))
+                .unwrap());
+        }
+        assert_eq!(recorder.frames(), 3);
+        (recorder.finish().unwrap().into_inner(), frames)
+    }
+
+    #[test]
+    fn headers() {
+        let (avi, _) = record();
+        assert_eq!(&avi[0..4], b"RIFF");
+        assert_eq!(u32_at(&avi, RIFF_SIZE as usize), avi.len() as u32 - 8);
+        assert_eq!(&avi[8..12], b"AVI ");
+        assert_eq!(&avi[24..28], b"avih");
+        assert_eq!(u32_at(&avi, 32), 200_000);
+        assert_eq!(u32_at(&avi, AVIH_TOTAL_FRAMES as usize), 3);
+        assert_eq!(u32_at(&avi, AVIH_SUGGESTED_BUFFER as usize), 251 + 8);
+        assert_eq!((u32_at(&avi, 64), u32_at(&avi, 68)), (320, 240));
+        assert_eq!(&avi[100..104], b"strh");
+        assert_eq!(&avi[108..116], b"vidsMJPG");
+        assert_eq!(u32_at(&avi, STRH_LENGTH as usize), 3);
+        assert_eq!(u32_at(&avi, STRH_SUGGESTED_BUFFER as usize), 251 + 8);
+        assert_eq!(&avi[164..168], b"strf");
+    }
+
+    #[test]
+    fn movi_chunks() {
+        let (avi, frames) = record();
+        assert_eq!(&avi[212..216], b"LIST");
+        assert_eq!(&avi[MOVI_FOURCC as usize..HEADER_LEN as usize], b"movi");
+
+        let mut pos = HEADER_LEN as usize;
+        for frame in &frames {
+            assert_eq!(&avi[pos..pos + 4], b"00dc");
+            assert_eq!(u32_at(&avi, pos + 4), frame.len() as u32);
+            assert_eq!(&avi[pos + 8..pos + 8 + frame.len()], frame.as_slice());
+            pos += 8 + frame.len() + frame.len() % 2;
+        }
+        assert_eq!(
+            u32_at(&avi, MOVI_SIZE as usize) as usize,
+            pos - MOVI_FOURCC as usize
+        );
+        assert_eq!(&avi[pos..pos + 4], b"idx1");
+    }
+
+    #[test]
+    fn index_points_at_the_frames() {
+        let (avi, frames) = record();
+        let idx1 = MOVI_FOURCC as usize + u32_at(&avi, MOVI_SIZE as usize) as usize;
+        assert_eq!(&avi[idx1..idx1 + 4], b"idx1");
+        assert_eq!(u32_at(&avi, idx1 + 4), 3 * 16);
+        assert_eq!(idx1 + 8 + 3 * 16, avi.len());
+
+        for (n, frame) in frames.iter().enumerate() {
+            let entry = idx1 + 8 + n * 16;
+            assert_eq!(&avi[entry..entry + 4], b"00dc");
+            assert_eq!(u32_at(&avi, entry + 4), AVIIF_KEYFRAME);
+            // offsets count from the movi fourcc and point at the chunk header
+            let chunk = MOVI_FOURCC as usize + u32_at(&avi, entry + 8) as usize;
+            assert_eq!(&avi[chunk..chunk + 4], b"00dc");
+            assert_eq!(u32_at(&avi, entry + 12), frame.len() as u32);
+            assert_eq!(&avi[chunk + 8..chunk + 8 + frame.len()], frame.as_slice());
+        }
+    }
+
+    #[test]
+    fn timelapse_keeps_one_frame_per_interval() {
+        let mut recorder = Recorder::timelapse(
+            Cursor::new(Vec::new()),
+            320,
+            240,
+            Duration::from_secs(60),
+            25,
+        )
+        .unwrap();
+        let start = Instant::now();
+        for n in 0..600 {
+            recorder
+                .offer(&[0xFF, 0xD8], start + Duration::from_secs(n))
+                .unwrap();
+        }
+        assert_eq!(recorder.frames(), 10);
+        let avi = recorder.finish().unwrap().into_inner();
+        assert_eq!(u32_at(&avi, 32), 40_000);
+        assert_eq!(u32_at(&avi, AVIH_TOTAL_FRAMES as usize), 10);
+    }
+}
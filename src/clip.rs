@@ -0,0 +1,281 @@
+use std::{
+    collections::VecDeque,
+    io::{Seek, Write},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use crate::{avi::AviWriter, frame::Frame};
+
+#[derive(Debug, Clone)]
+pub struct ClipFrame {
+    pub jpeg: Vec<u8>,
+    pub captured: Instant,
+}
+
+/// Frames around a trigger, the ones captured before it are the pre-roll
+#[derive(Debug, Clone)]
+pub struct Clip {
+    pub trigger: Instant,
+    pub frames: Vec<ClipFrame>,
+}
+
+impl Clip {
+    pub fn pre_roll(&self) -> &[ClipFrame] {
+        let split = self.frames.partition_point(|f| f.captured < self.trigger);
+        &self.frames[..split]
+    }
+
+    pub fn len_bytes(&self) -> usize {
+        self.frames.iter().map(|f| f.jpeg.len()).sum()
+    }
+
+    /// Writes the clip as an MJPEG AVI, the size is taken from the first frame
+    pub fn write_avi<W: Write + Seek>(&self, out: W, fps: u32) -> Result<W> {
+        let (width, height) = match self.frames.first() {
+            Some(frame) => Frame::jpeg_dimensions(&frame.jpeg)?,
+            None => (0, 0),
+        };
+
+        let mut writer = AviWriter::new(out, width as u32, height as u32, fps)?;
+        for frame in &self.frames {
+            writer.add_frame(&frame.jpeg)?;
+        }
+        Ok(writer.finish()?)
+    }
+}
+
+/// Where finished clips go: an AVI file, the SD card, an uploader...
+pub trait ClipSink {
+    fn write_clip(&mut self, clip: Clip) -> Result<()>;
+}
+
+impl<F: FnMut(Clip) -> Result<()>> ClipSink for F {
+    fn write_clip(&mut self, clip: Clip) -> Result<()> {
+        self(clip)
+    }
+}
+
+enum State {
+    Buffering,
+    Recording {
+        clip: Clip,
+        until: Instant,
+        /// Bytes captured since the trigger, the pre-roll has its own budget
+        post_roll_bytes: usize,
+    },
+}
+
+/// Keeps the last seconds of JPEG frames so that a clip can start before its trigger.
+///
+/// The buffer is bounded both in time and in bytes. Frame sized allocations are served from
+/// PSRAM when it is enabled, so the budget can be a few MiB on boards that have it.
+pub struct EventRecorder<S: ClipSink> {
+    sink: S,
+    pre_roll: Duration,
+    post_roll: Duration,
+    max_bytes: usize,
+    ring: VecDeque<ClipFrame>,
+    ring_bytes: usize,
+    state: State,
+}
+
+impl<S: ClipSink> EventRecorder<S> {
+    /// `max_bytes` bounds the pre-roll buffer and, apart from it, the frames after the
+    /// trigger, so a clip takes up to twice as much
+    pub fn new(sink: S, pre_roll: Duration, post_roll: Duration, max_bytes: usize) -> Self {
+        Self {
+            sink,
+            pre_roll,
+            post_roll,
+            max_bytes,
+            ring: VecDeque::new(),
+            ring_bytes: 0,
+            state: State::Buffering,
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        matches!(self.state, State::Recording { .. })
+    }
+
+    pub fn buffered_bytes(&self) -> usize {
+        self.ring_bytes
+    }
+
+    /// Freezes the pre-roll and starts the post-roll, triggering again while
+    /// recording extends the post-roll
+    pub fn trigger(&mut self, now: Instant) {
+        match &mut self.state {
+            State::Recording { until, .. } => *until = now + self.post_roll,
+            State::Buffering => {
+                self.ring_bytes = 0;
+                self.state = State::Recording {
+                    clip: Clip {
+                        trigger: now,
+                        frames: self.ring.drain(..).collect(),
+                    },
+                    until: now + self.post_roll,
+                    post_roll_bytes: 0,
+                };
+            }
+        }
+    }
+
+    /// Feeds a frame from the capture loop, returns true when a clip was handed to the sink
+    pub fn push(&mut self, jpeg: Vec<u8>, now: Instant) -> Result<bool> {
+        let frame = ClipFrame { jpeg, captured: now };
+
+        match &mut self.state {
+            State::Buffering => {
+                self.ring_bytes += frame.jpeg.len();
+                self.ring.push_back(frame);
+                self.trim(now);
+                Ok(false)
+            }
+            State::Recording {
+                clip,
+                until,
+                post_roll_bytes,
+            } => {
+                let full = *post_roll_bytes + frame.jpeg.len() > self.max_bytes;
+                if !full {
+                    *post_roll_bytes += frame.jpeg.len();
+                    clip.frames.push(frame);
+                }
+                if full || now >= *until {
+                    if full {
+                        log::warn!("post-roll reached {} bytes, ending early", self.max_bytes);
+                    }
+                    self.finish()?;
+                    return Ok(true);
+                }
+                Ok(false)
+            }
+        }
+    }
+
+    /// Hands the clip being recorded to the sink right away
+    pub fn finish(&mut self) -> Result<()> {
+        match std::mem::replace(&mut self.state, State::Buffering) {
+            State::Recording { clip, .. } => self.sink.write_clip(clip),
+            State::Buffering => Ok(()),
+        }
+    }
+
+    fn trim(&mut self, now: Instant) {
+        while let Some(oldest) = self.ring.front() {
+            let expired = now.duration_since(oldest.captured) > self.pre_roll;
+            if !expired && self.ring_bytes <= self.max_bytes {
+                break;
+            }
+            self.ring_bytes -= oldest.jpeg.len();
+            self.ring.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn recorder(max_bytes: usize) -> (EventRecorder<impl ClipSink>, Rc<RefCell<Vec<Clip>>>) {
+        let clips = Rc::new(RefCell::new(Vec::new()));
+        let sink = {
+            let clips = clips.clone();
+            move |clip| {
+                clips.borrow_mut().push(clip);
+                Ok(())
+            }
+        };
+        let recorder = EventRecorder::new(sink, 3 * SECOND, 2 * SECOND, max_bytes);
+        (recorder, clips)
+    }
+
+    #[test]
+    fn pre_roll_is_trimmed() {
+        let (mut recorder, _) = recorder(1000);
+        let start = Instant::now();
+
+        for i in 0..10 {
+            recorder.push(vec![0; 10], start + i * SECOND).unwrap();
+        }
+        // frames from 6 s to 9 s are within the pre-roll
+        assert_eq!(recorder.buffered_bytes(), 40);
+
+        // and the oldest go first when the bytes run out
+        recorder.push(vec![0; 995], start + 10 * SECOND).unwrap();
+        assert_eq!(recorder.buffered_bytes(), 995);
+    }
+
+    #[test]
+    fn trigger_records_the_post_roll() {
+        let (mut recorder, clips) = recorder(1000);
+        let start = Instant::now();
+
+        for i in 0..4 {
+            recorder.push(vec![0; 10], start + i * SECOND).unwrap();
+        }
+        let trigger = start + 3 * SECOND + SECOND / 2;
+        recorder.trigger(trigger);
+        assert!(recorder.is_recording());
+        assert_eq!(recorder.buffered_bytes(), 0);
+
+        assert!(!recorder.push(vec![1; 10], start + 4 * SECOND).unwrap());
+        assert!(recorder.push(vec![1; 10], start + 6 * SECOND).unwrap());
+        assert!(!recorder.is_recording());
+
+        let clips = clips.borrow();
+        assert_eq!(clips.len(), 1);
+        assert_eq!(clips[0].trigger, trigger);
+        assert_eq!(clips[0].frames.len(), 6);
+        assert_eq!(clips[0].pre_roll().len(), 4);
+    }
+
+    #[test]
+    fn triggering_again_extends_the_post_roll() {
+        let (mut recorder, clips) = recorder(1000);
+        let start = Instant::now();
+
+        recorder.trigger(start);
+        recorder.push(vec![0; 10], start + SECOND).unwrap();
+        recorder.trigger(start + SECOND);
+        assert!(!recorder.push(vec![0; 10], start + 2 * SECOND).unwrap());
+        assert!(recorder.push(vec![0; 10], start + 3 * SECOND).unwrap());
+
+        assert_eq!(clips.borrow()[0].frames.len(), 3);
+    }
+
+    #[test]
+    fn full_pre_roll_leaves_room_for_the_post_roll() {
+        let (mut recorder, clips) = recorder(100);
+        let start = Instant::now();
+
+        for i in 0..3 {
+            recorder.push(vec![0; 50], start + i * SECOND / 2).unwrap();
+        }
+        assert_eq!(recorder.buffered_bytes(), 100);
+        recorder.trigger(start + SECOND);
+
+        assert!(!recorder.push(vec![1; 50], start + 2 * SECOND).unwrap());
+        assert!(recorder.push(vec![1; 40], start + 3 * SECOND).unwrap());
+        assert_eq!(clips.borrow()[0].frames.len(), 4);
+        assert_eq!(clips.borrow()[0].len_bytes(), 190);
+
+        // past its budget the post-roll ends early, without the frame
+        for i in 0..3 {
+            recorder
+                .push(vec![0; 50], start + (4 + i) * SECOND)
+                .unwrap();
+        }
+        recorder.trigger(start + 7 * SECOND);
+        assert!(!recorder.push(vec![1; 60], start + 7 * SECOND).unwrap());
+        assert!(recorder.push(vec![1; 60], start + 8 * SECOND).unwrap());
+        assert_eq!(clips.borrow()[1].frames.len(), 3);
+    }
+}
@@ -0,0 +1,265 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    espcam::CameraSensor,
+    frame::Frame,
+    transform::{crop, Crop},
+};
+
+pub const COLOR_STORAGE_KEY: &str = "color";
+
+/// The 24 patches of a ColorChecker Classic in sRGB, row by row starting from dark skin
+pub const COLOR_CHECKER: [[u8; 3]; 24] = [
+    [115, 82, 68],
+    [194, 150, 130],
+    [98, 122, 157],
+    [87, 108, 67],
+    [133, 128, 177],
+    [103, 189, 170],
+    [214, 126, 44],
+    [80, 91, 166],
+    [193, 90, 99],
+    [94, 60, 108],
+    [157, 188, 64],
+    [224, 163, 46],
+    [56, 61, 150],
+    [70, 148, 73],
+    [175, 54, 60],
+    [231, 199, 31],
+    [187, 86, 149],
+    [8, 133, 161],
+    [243, 243, 242],
+    [200, 200, 200],
+    [160, 160, 160],
+    [122, 122, 121],
+    [85, 85, 85],
+    [52, 52, 52],
+];
+
+const IDENTITY: [[f32; 3]; 3] = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+/// Per channel gains followed by a colour correction matrix, measured once per board.
+///
+/// Both work on the encoded sRGB values rather than on linear light, which is close enough
+/// for the small differences between boards and keeps the per pixel cost low.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ColorCalibration {
+    pub gains: [f32; 3],
+    pub matrix: [[f32; 3]; 3],
+}
+
+impl Default for ColorCalibration {
+    fn default() -> Self {
+        Self {
+            gains: [1.0; 3],
+            matrix: IDENTITY,
+        }
+    }
+}
+
+impl ColorCalibration {
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// White balance from a neutral gray card, `region` is where the card is in the frame
+    pub fn from_gray_card(frame: &Frame, region: Crop) -> Result<Self> {
+        let [r, g, b] = average(frame, region)?;
+        if r < 1.0 || g < 1.0 || b < 1.0 {
+            bail!("the gray card is too dark");
+        }
+
+        Ok(Self {
+            gains: [g / r, 1.0, g / b],
+            matrix: IDENTITY,
+        })
+    }
+
+    /// Gains from the neutral patches and a least squares matrix from all of them,
+    /// `chart` is the region of the frame covered by the chart, borders excluded
+    pub fn from_color_checker(frame: &Frame, chart: Crop) -> Result<Self> {
+        let mut measured = Vec::with_capacity(COLOR_CHECKER.len());
+        for row in 0..4 {
+            for column in 0..6 {
+                // the middle half of each patch, away from the black gaps
+                let patch = Crop {
+                    x: chart.x + chart.w * (column as f32 + 0.25) / 6.0,
+                    y: chart.y + chart.h * (row as f32 + 0.25) / 4.0,
+                    w: chart.w * 0.5 / 6.0,
+                    h: chart.h * 0.5 / 4.0,
+                };
+                measured.push(average(frame, patch)?);
+            }
+        }
+
+        let neutral = measured[18..].iter().fold([0.0; 3], |acc, m| {
+            [acc[0] + m[0], acc[1] + m[1], acc[2] + m[2]]
+        });
+        if neutral.iter().any(|c| *c < 1.0) {
+            bail!("the chart is too dark");
+        }
+        let gains = [neutral[1] / neutral[0], 1.0, neutral[1] / neutral[2]];
+
+        let balanced: Vec<[f32; 3]> = measured
+            .iter()
+            .map(|m| [m[0] * gains[0], m[1] * gains[1], m[2] * gains[2]])
+            .collect();
+        let reference: Vec<[f32; 3]> = COLOR_CHECKER
+            .iter()
+            .map(|c| [c[0] as f32, c[1] as f32, c[2] as f32])
+            .collect();
+
+        Ok(Self {
+            gains,
+            matrix: least_squares_matrix(&balanced, &reference)?,
+        })
+    }
+
+    /// Gains and matrix folded together in 10 bit fixed point
+    fn combined(&self) -> [[i32; 3]; 3] {
+        let mut out = [[0; 3]; 3];
+        for (row, out) in self.matrix.iter().zip(&mut out) {
+            for c in 0..3 {
+                out[c] = (row[c] * self.gains[c] * 1024.0).round() as i32;
+            }
+        }
+        out
+    }
+
+    /// Corrects a raw frame in place
+    pub fn apply(&self, frame: &mut Frame) {
+        if self.is_identity() {
+            return;
+        }
+
+        let m = self.combined();
+        for y in 0..frame.height() {
+            for x in 0..frame.width() {
+                let [r, g, b] = frame.pixel(x, y).map(|v| v as i32);
+                let rgb =
+                    m.map(|row| ((row[0] * r + row[1] * g + row[2] * b) >> 10).clamp(0, 255) as u8);
+                frame.set_pixel(x, y, rgb);
+            }
+        }
+    }
+
+    /// Register values for the OV2640 manual white balance (0x40 is unity) and what is left
+    /// to do in software, `None` when the gains are out of the register range
+    pub fn split_sensor_gains(&self) -> Option<([u8; 3], ColorCalibration)> {
+        let mut registers = [0; 3];
+        for (register, gain) in registers.iter_mut().zip(self.gains) {
+            let value = (gain * 64.0).round();
+            if !(1.0..=255.0).contains(&value) {
+                return None;
+            }
+            *register = value as u8;
+        }
+
+        Some((
+            registers,
+            Self {
+                gains: [1.0; 3],
+                matrix: self.matrix,
+            },
+        ))
+    }
+
+    /// Moves the gains to the sensor when they fit its registers and returns what is left
+    /// for the pipeline, when they don't fit the sensor is held at unity gains. Without a
+    /// calibration the automatic white balance is turned back on.
+    pub fn apply_to_sensor(&self, sensor: &CameraSensor) -> ColorCalibration {
+        if self.is_identity() {
+            if let Err(err) = sensor.set_wb_mode(0) {
+                log::warn!("could not enable automatic white balance: {:?}", err);
+            }
+            return *self;
+        }
+
+        let Some((registers, rest)) = self.split_sensor_gains() else {
+            // the pipeline applies the whole calibration, the sensor must not correct on top
+            if let Err(err) = sensor.set_manual_wb_gains([0x40; 3]) {
+                log::warn!("could not set unity white balance gains: {:?}", err);
+            }
+            return *self;
+        };
+        match sensor.set_manual_wb_gains(registers) {
+            Ok(()) => rest,
+            Err(err) => {
+                log::warn!("could not set the white balance gains: {:?}", err);
+                *self
+            }
+        }
+    }
+}
+
+fn average(frame: &Frame, region: Crop) -> Result<[f32; 3]> {
+    let patch = crop(&frame.to_raw()?, region)?;
+    let mut sum = [0u64; 3];
+    for y in 0..patch.height() {
+        for x in 0..patch.width() {
+            for (s, v) in sum.iter_mut().zip(patch.pixel(x, y)) {
+                *s += v as u64;
+            }
+        }
+    }
+
+    let n = (patch.width() * patch.height()) as f32;
+    Ok(sum.map(|s| s as f32 / n))
+}
+
+/// Matrix `M` minimising the distance between `M * measured` and `reference`
+fn least_squares_matrix(measured: &[[f32; 3]], reference: &[[f32; 3]]) -> Result<[[f32; 3]; 3]> {
+    // M = (sum reference * measured^T) * (sum measured * measured^T)^-1
+    let mut a = [[0f32; 3]; 3];
+    let mut b = [[0f32; 3]; 3];
+    for (m, r) in measured.iter().zip(reference) {
+        for i in 0..3 {
+            for j in 0..3 {
+                a[i][j] += m[i] * m[j];
+                b[i][j] += r[i] * m[j];
+            }
+        }
+    }
+
+    let Some(a_inv) = invert(a) else {
+        bail!("the patches do not have enough colour to fit a matrix");
+    };
+
+    let mut out = [[0f32; 3]; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            out[i][j] = (0..3).map(|k| b[i][k] * a_inv[k][j]).sum();
+        }
+    }
+    Ok(out)
+}
+
+fn invert(m: [[f32; 3]; 3]) -> Option<[[f32; 3]; 3]> {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    if det.abs() < f32::EPSILON {
+        return None;
+    }
+
+    let cofactor =
+        |r0: usize, r1: usize, c0: usize, c1: usize| m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0];
+    Some([
+        [
+            cofactor(1, 2, 1, 2) / det,
+            -cofactor(0, 2, 1, 2) / det,
+            cofactor(0, 1, 1, 2) / det,
+        ],
+        [
+            -cofactor(1, 2, 0, 2) / det,
+            cofactor(0, 2, 0, 2) / det,
+            -cofactor(0, 1, 0, 2) / det,
+        ],
+        [
+            cofactor(1, 2, 0, 1) / det,
+            -cofactor(0, 2, 0, 1) / det,
+            cofactor(0, 1, 0, 1) / det,
+        ],
+    ])
+}
@@ -0,0 +1,61 @@
+use serde::Serialize;
+
+use crate::espcam::{CameraSensor, SensorSettings, SensorStatus};
+
+/// The `/status` json of the Arduino CameraWebServer example, which many dashboards and
+/// home automation integrations read: the sensor settings with the clock and pixel format
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct CompatStatus {
+    /// Clock in MHz
+    pub xclk: i32,
+    pub pixformat: i32,
+    #[serde(flatten)]
+    pub sensor: SensorStatus,
+}
+
+impl CompatStatus {
+    pub fn from_sensor(sensor: &CameraSensor) -> Self {
+        Self {
+            xclk: sensor.xclk_freq_hz() / 1_000_000,
+            pixformat: sensor.pixformat() as i32,
+            sensor: sensor.status(),
+        }
+    }
+}
+
+/// The setting a `/control?var=..&val=..` request changes, `None` for unknown variables
+/// and negative frame sizes or gain ceilings
+pub fn control_settings(var: &str, val: i32) -> Option<SensorSettings> {
+    let mut settings = SensorSettings::default();
+    let on = val != 0;
+    match var {
+        "framesize" => settings.framesize = Some(u32::try_from(val).ok()?),
+        "quality" => settings.quality = Some(val),
+        "brightness" => settings.brightness = Some(val),
+        "contrast" => settings.contrast = Some(val),
+        "saturation" => settings.saturation = Some(val),
+        "sharpness" => settings.sharpness = Some(val),
+        "denoise" => settings.denoise = Some(val),
+        "special_effect" => settings.special_effect = Some(val),
+        "wb_mode" => settings.wb_mode = Some(val),
+        "awb" => settings.awb = Some(on),
+        "awb_gain" => settings.awb_gain = Some(on),
+        "aec" => settings.aec = Some(on),
+        "aec2" => settings.aec2 = Some(on),
+        "ae_level" => settings.ae_level = Some(val),
+        "aec_value" => settings.aec_value = Some(val),
+        "agc" => settings.agc = Some(on),
+        "agc_gain" => settings.agc_gain = Some(val),
+        "gainceiling" => settings.gainceiling = Some(u32::try_from(val).ok()?),
+        "bpc" => settings.bpc = Some(on),
+        "wpc" => settings.wpc = Some(on),
+        "raw_gma" => settings.raw_gma = Some(on),
+        "lenc" => settings.lenc = Some(on),
+        "hmirror" => settings.hmirror = Some(on),
+        "dcw" => settings.dcw = Some(on),
+        "colorbar" => settings.colorbar = Some(on),
+        "vflip" => settings.vflip = Some(on),
+        _ => return None,
+    }
+    Some(settings)
+}
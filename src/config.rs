@@ -10,6 +10,22 @@ pub struct Config {
     bot_token: &'static str,
     #[default(0)]
     bot_owner_id: i64,
+    #[default("espcam")]
+    device_name: &'static str,
+    #[default("espcam")]
+    hostname: &'static str,
+    #[default(0)]
+    utc_offset_secs: i64,
+    #[default("")]
+    privacy_masks: &'static str,
+    #[default("")]
+    gps_position: &'static str,
+    #[default("")]
+    counting_areas: &'static str,
+    #[default(86400)]
+    heatmap_window_secs: u64,
+    #[default("")]
+    http_auth: &'static str,
 }
 
 pub fn get_config() -> Config {
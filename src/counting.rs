@@ -0,0 +1,435 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::motion::Blob;
+
+pub const COUNTS_STORAGE_KEY: &str = "counts";
+
+/// Virtual line, every coordinate is relative to the frame size (0.0 - 1.0).
+///
+/// Walking from `from` to `to`, crossing from the left side to the right side is
+/// counted as forward, the other way as backward.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CountingLine {
+    pub name: String,
+    pub from: (f32, f32),
+    pub to: (f32, f32),
+}
+
+/// Area whose occupancy is tracked, coordinates are relative to the frame size
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CountingZone {
+    pub name: String,
+    pub polygon: Vec<(f32, f32)>,
+}
+
+/// The lines and zones watched by a [`Counter`]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CountingAreas {
+    #[serde(default)]
+    pub lines: Vec<CountingLine>,
+    #[serde(default)]
+    pub zones: Vec<CountingZone>,
+}
+
+impl CountingAreas {
+    /// Parses a json object such as
+    /// `{"lines": [{"name": "door", "from": [0.5, 0.0], "to": [0.5, 1.0]}], "zones": [{"name": "desk", "polygon": [[0.0, 0.5], [0.5, 0.5], [0.5, 1.0]]}]}`
+    pub fn from_json(json: &str) -> Result<Self> {
+        if json.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty() && self.zones.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CountingConfig {
+    /// Farthest a centroid can move between two frames and still be the same object,
+    /// relative to the frame size
+    pub max_distance: f32,
+    /// Frames a track survives without a matching blob
+    pub max_missed: u32,
+}
+
+impl Default for CountingConfig {
+    fn default() -> Self {
+        Self {
+            max_distance: 0.2,
+            max_missed: 3,
+        }
+    }
+}
+
+/// Object followed from frame to frame
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Track {
+    pub id: u32,
+    pub centroid: (f32, f32),
+    /// Centroid in the previous frame it was seen, `None` for new tracks
+    #[serde(skip)]
+    pub previous: Option<(f32, f32)>,
+    /// Frames since it was last matched to a blob
+    pub missed: u32,
+}
+
+/// Nearest centroid tracking, good enough for a few objects moving across the view
+pub struct Tracker {
+    tracks: Vec<Track>,
+    next_id: u32,
+    max_distance: f32,
+    max_missed: u32,
+}
+
+impl Tracker {
+    pub fn new(max_distance: f32, max_missed: u32) -> Self {
+        Self {
+            tracks: Vec::new(),
+            next_id: 0,
+            max_distance,
+            max_missed,
+        }
+    }
+
+    pub fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    /// Matches the blobs to the existing tracks, closest pairs first
+    pub fn update(&mut self, blobs: &[Blob]) -> &[Track] {
+        let mut pairs = Vec::new();
+        for (t, track) in self.tracks.iter().enumerate() {
+            for (b, blob) in blobs.iter().enumerate() {
+                let d = distance(track.centroid, blob.centroid);
+                if d <= self.max_distance {
+                    pairs.push((d, t, b));
+                }
+            }
+        }
+        pairs.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut track_matched = vec![false; self.tracks.len()];
+        let mut blob_matched = vec![false; blobs.len()];
+        for (_, t, b) in pairs {
+            if track_matched[t] || blob_matched[b] {
+                continue;
+            }
+            track_matched[t] = true;
+            blob_matched[b] = true;
+            let track = &mut self.tracks[t];
+            track.previous = Some(track.centroid);
+            track.centroid = blobs[b].centroid;
+            track.missed = 0;
+        }
+
+        for (track, matched) in self.tracks.iter_mut().zip(track_matched) {
+            if !matched {
+                // a lost track must not cross a line when it is found again far away
+                track.previous = None;
+                track.missed += 1;
+            }
+        }
+        let max_missed = self.max_missed;
+        self.tracks.retain(|t| t.missed <= max_missed);
+
+        for (blob, matched) in blobs.iter().zip(blob_matched) {
+            if !matched {
+                self.tracks.push(Track {
+                    id: self.next_id,
+                    centroid: blob.centroid,
+                    previous: None,
+                    missed: 0,
+                });
+                self.next_id = self.next_id.wrapping_add(1);
+            }
+        }
+
+        &self.tracks
+    }
+}
+
+fn distance(a: (f32, f32), b: (f32, f32)) -> f32 {
+    ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Crossing {
+    pub line: String,
+    pub direction: Direction,
+    pub track: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LineCount {
+    pub forward: u64,
+    pub backward: u64,
+}
+
+/// Counts that survive a reboot
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CountTotals {
+    #[serde(default)]
+    pub lines: BTreeMap<String, LineCount>,
+    /// Times something entered each zone
+    #[serde(default)]
+    pub zone_entries: BTreeMap<String, u64>,
+}
+
+/// Snapshot returned by the counting endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct Counts {
+    pub totals: CountTotals,
+    /// Objects inside each zone right now
+    pub occupancy: BTreeMap<String, usize>,
+    pub tracks: Vec<Track>,
+}
+
+/// Counts the objects crossing the lines and entering the zones of [`CountingAreas`]
+pub struct Counter {
+    areas: CountingAreas,
+    tracker: Tracker,
+    totals: CountTotals,
+    occupancy: BTreeMap<String, usize>,
+}
+
+impl Counter {
+    pub fn new(areas: CountingAreas, config: CountingConfig) -> Self {
+        let tracker = Tracker::new(config.max_distance, config.max_missed);
+        let mut counter = Self {
+            areas,
+            tracker,
+            totals: CountTotals::default(),
+            occupancy: BTreeMap::new(),
+        };
+        counter.reset();
+        counter
+    }
+
+    /// Continues from totals loaded from storage, lines and zones no longer configured are dropped
+    pub fn totals(mut self, totals: CountTotals) -> Self {
+        for (name, count) in totals.lines {
+            if let Some(current) = self.totals.lines.get_mut(&name) {
+                *current = count;
+            }
+        }
+        for (name, entries) in totals.zone_entries {
+            if let Some(current) = self.totals.zone_entries.get_mut(&name) {
+                *current = entries;
+            }
+        }
+        self
+    }
+
+    pub fn get_totals(&self) -> &CountTotals {
+        &self.totals
+    }
+
+    pub fn counts(&self) -> Counts {
+        Counts {
+            totals: self.totals.clone(),
+            occupancy: self.occupancy.clone(),
+            tracks: self.tracker.tracks().to_vec(),
+        }
+    }
+
+    /// Sets every total back to zero, the tracks and the background are kept
+    pub fn reset(&mut self) {
+        self.totals = CountTotals {
+            lines: self
+                .areas
+                .lines
+                .iter()
+                .map(|l| (l.name.clone(), LineCount::default()))
+                .collect(),
+            zone_entries: self
+                .areas
+                .zones
+                .iter()
+                .map(|z| (z.name.clone(), 0))
+                .collect(),
+        };
+    }
+
+    /// Feeds the blobs of a frame, returns the line crossings they completed
+    pub fn update(&mut self, blobs: &[Blob]) -> Vec<Crossing> {
+        let tracks = self.tracker.update(blobs);
+
+        let mut crossings = Vec::new();
+        for track in tracks.iter().filter(|t| t.missed == 0) {
+            let Some(previous) = track.previous else {
+                continue;
+            };
+            for line in &self.areas.lines {
+                let Some(direction) = crossing(line, previous, track.centroid) else {
+                    continue;
+                };
+                let count = self.totals.lines.entry(line.name.clone()).or_default();
+                match direction {
+                    Direction::Forward => count.forward += 1,
+                    Direction::Backward => count.backward += 1,
+                }
+                crossings.push(Crossing {
+                    line: line.name.clone(),
+                    direction,
+                    track: track.id,
+                });
+            }
+            for zone in &self.areas.zones {
+                // objects appearing inside are not counted, they could be a lost track found again
+                if !contains(&zone.polygon, previous) && contains(&zone.polygon, track.centroid) {
+                    *self
+                        .totals
+                        .zone_entries
+                        .entry(zone.name.clone())
+                        .or_default() += 1;
+                }
+            }
+        }
+
+        self.occupancy = self
+            .areas
+            .zones
+            .iter()
+            .map(|zone| {
+                let inside = tracks
+                    .iter()
+                    .filter(|t| contains(&zone.polygon, t.centroid))
+                    .count();
+                (zone.name.clone(), inside)
+            })
+            .collect();
+
+        crossings
+    }
+}
+
+/// Side of the line `p` is on, positive on the right walking from `from` to `to`
+/// (y grows downwards)
+fn side(line: &CountingLine, p: (f32, f32)) -> f32 {
+    let (ax, ay) = line.from;
+    let (bx, by) = line.to;
+    (bx - ax) * (p.1 - ay) - (by - ay) * (p.0 - ax)
+}
+
+/// Direction of the move from `a` to `b`, when it goes through the line segment
+fn crossing(line: &CountingLine, a: (f32, f32), b: (f32, f32)) -> Option<Direction> {
+    let (sa, sb) = (side(line, a), side(line, b));
+    if (sa < 0.0) == (sb < 0.0) {
+        return None;
+    }
+
+    // the ends of the line must be on opposite sides of the move, or it went around it
+    let movement = CountingLine {
+        name: String::new(),
+        from: a,
+        to: b,
+    };
+    if (side(&movement, line.from) < 0.0) == (side(&movement, line.to) < 0.0) {
+        return None;
+    }
+
+    Some(if sa < 0.0 {
+        Direction::Forward
+    } else {
+        Direction::Backward
+    })
+}
+
+/// Even-odd rule
+fn contains(polygon: &[(f32, f32)], p: (f32, f32)) -> bool {
+    let mut inside = false;
+    for (i, a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+        if (a.1 <= p.1) != (b.1 <= p.1) && p.0 < a.0 + (p.1 - a.1) / (b.1 - a.1) * (b.0 - a.0) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blob(x: f32, y: f32) -> Blob {
+        Blob {
+            x: x - 0.05,
+            y: y - 0.05,
+            w: 0.1,
+            h: 0.1,
+            centroid: (x, y),
+            area: 0.01,
+        }
+    }
+
+    fn door() -> Counter {
+        let areas = CountingAreas::from_json(
+            r#"{"lines": [{"name": "door", "from": [0.5, 0.0], "to": [0.5, 1.0]}]}"#,
+        )
+        .unwrap();
+        Counter::new(areas, CountingConfig::default())
+    }
+
+    #[test]
+    fn crossing_the_line_both_ways() {
+        let mut counter = door();
+        assert!(counter.update(&[blob(0.42, 0.5)]).is_empty());
+        let crossings = counter.update(&[blob(0.58, 0.5)]);
+        assert_eq!(crossings.len(), 1);
+        assert_eq!(crossings[0].line, "door");
+        assert_eq!(crossings[0].direction, Direction::Backward);
+
+        let crossings = counter.update(&[blob(0.45, 0.5)]);
+        assert_eq!(crossings.len(), 1);
+        assert_eq!(crossings[0].direction, Direction::Forward);
+        assert_eq!(crossings[0].track, 0);
+        assert_eq!(
+            counter.get_totals().lines["door"],
+            LineCount {
+                forward: 1,
+                backward: 1
+            }
+        );
+    }
+
+    #[test]
+    fn staying_on_the_line_is_not_a_crossing() {
+        let mut counter = door();
+        for y in [0.3, 0.3, 0.4, 0.5, 0.5] {
+            assert!(counter.update(&[blob(0.5, y)]).is_empty());
+        }
+        assert_eq!(counter.get_totals().lines["door"], LineCount::default());
+    }
+
+    #[test]
+    fn entering_and_leaving_a_zone() {
+        let areas = CountingAreas::from_json(
+            r#"{"zones": [{"name": "desk", "polygon": [[0.2, 0.2], [0.4, 0.2], [0.4, 0.4], [0.2, 0.4]]}]}"#,
+        )
+        .unwrap();
+        let mut counter = Counter::new(areas, CountingConfig::default());
+
+        counter.update(&[blob(0.15, 0.3)]);
+        assert_eq!(counter.counts().occupancy["desk"], 0);
+        counter.update(&[blob(0.3, 0.3)]);
+        assert_eq!(counter.counts().occupancy["desk"], 1);
+        counter.update(&[blob(0.35, 0.3)]);
+        counter.update(&[blob(0.45, 0.3)]);
+        let counts = counter.counts();
+        assert_eq!(counts.occupancy["desk"], 0);
+        assert_eq!(counts.totals.zone_entries["desk"], 1);
+    }
+}
@@ -3,6 +3,12 @@ use std::marker::PhantomData;
 use esp_idf_hal::gpio::*;
 use esp_idf_hal::peripheral::Peripheral;
 use esp_idf_sys::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    exif::ExifMetadata,
+    frame::{Frame, PixelFormat},
+};
 
 pub struct FrameBuffer<'a> {
     fb: *mut camera::camera_fb_t,
@@ -30,6 +36,26 @@ impl<'a> FrameBuffer<'a> {
         unsafe { (*self.fb).timestamp }
     }
 
+    pub fn pixel_format(&self) -> Option<PixelFormat> {
+        match self.format() {
+            camera::pixformat_t_PIXFORMAT_RGB565 => Some(PixelFormat::Rgb565),
+            camera::pixformat_t_PIXFORMAT_GRAYSCALE => Some(PixelFormat::Grayscale),
+            camera::pixformat_t_PIXFORMAT_RGB888 => Some(PixelFormat::Rgb888),
+            camera::pixformat_t_PIXFORMAT_JPEG => Some(PixelFormat::Jpeg),
+            _ => None,
+        }
+    }
+
+    /// Copies the framebuffer so it can be processed after being returned to the driver
+    pub fn to_frame(&self) -> Option<Frame> {
+        Some(Frame::new(
+            self.data().to_vec(),
+            self.width(),
+            self.height(),
+            self.pixel_format()?,
+        ))
+    }
+
     pub fn fb_return(&self) {
         unsafe { camera::esp_camera_fb_return(self.fb) }
     }
@@ -154,6 +180,14 @@ impl<'a> CameraSensor<'a> {
     pub fn set_reg(&self, reg: i32, mask: i32, value: i32) -> Result<(), EspError> {
         esp!(unsafe { (*self.sensor).set_reg.unwrap()(self.sensor, reg, mask, value) })
     }
+    /// OV2640 only: turns automatic white balance off and sets the red, green and blue
+    /// gains of the DSP, 0x40 is unity
+    pub fn set_manual_wb_gains(&self, gains: [u8; 3]) -> Result<(), EspError> {
+        self.set_reg(0xC7, 0xFF, 0x40)?;
+        self.set_reg(0xCC, 0xFF, gains[0] as i32)?;
+        self.set_reg(0xCD, 0xFF, gains[1] as i32)?;
+        self.set_reg(0xCE, 0xFF, gains[2] as i32)
+    }
     pub fn set_res_raw(
         &self,
         start_x: i32,
@@ -215,9 +249,296 @@ impl<'a> CameraSensor<'a> {
     pub fn set_xclk(&self, timer: i32, xclk: i32) -> Result<(), EspError> {
         esp!(unsafe { (*self.sensor).set_xclk.unwrap()(self.sensor, timer, xclk) })
     }
+
+    /// Name of the sensor as known by the driver, like "OV2640"
+    pub fn model(&self) -> Option<&'static str> {
+        unsafe {
+            let info = camera::esp_camera_sensor_get_info(&mut (*self.sensor).id);
+            if info.is_null() || (*info).name.is_null() {
+                return None;
+            }
+            std::ffi::CStr::from_ptr((*info).name).to_str().ok()
+        }
+    }
+
+    /// Sensor model, exposure and gain of the current frames, for the EXIF block.
+    ///
+    /// The driver only exposes register values: the exposure counts lines, about 30 µs each
+    /// with the default 20 MHz clock, and the gain goes from 1x to 32x in 30 steps,
+    /// turned into an ISO value with 1x as ISO 100.
+    pub fn exif(&self) -> ExifMetadata {
+        let status = unsafe { (*self.sensor).status };
+        let mut exif = ExifMetadata::new()
+            .exposure_time(status.aec_value as u32 * 30, 1_000_000)
+            .iso((100.0 * 2f32.powf(status.agc_gain as f32 / 6.0)) as u16);
+        if let Some(model) = self.model() {
+            exif = exif.model(model);
+        }
+        exif
+    }
+
+    pub fn pixformat(&self) -> camera::pixformat_t {
+        unsafe { (*self.sensor).pixformat }
+    }
+
+    pub fn xclk_freq_hz(&self) -> i32 {
+        unsafe { (*self.sensor).xclk_freq_hz }
+    }
+
+    /// Settings the driver last applied to the sensor
+    pub fn status(&self) -> SensorStatus {
+        SensorStatus::from(unsafe { (*self.sensor).status })
+    }
+}
+
+/// Copy of the driver's `camera_status_t`, the on/off settings as booleans.
+///
+/// Serialized like the `/status` of the Arduino CameraWebServer example, with 0 and 1
+/// for the booleans.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct SensorStatus {
+    pub framesize: camera::framesize_t,
+    pub quality: u8,
+    pub brightness: i8,
+    pub contrast: i8,
+    pub saturation: i8,
+    pub sharpness: i8,
+    pub denoise: u8,
+    pub special_effect: u8,
+    pub wb_mode: u8,
+    #[serde(serialize_with = "bool_as_int")]
+    pub awb: bool,
+    #[serde(serialize_with = "bool_as_int")]
+    pub awb_gain: bool,
+    #[serde(serialize_with = "bool_as_int")]
+    pub aec: bool,
+    #[serde(serialize_with = "bool_as_int")]
+    pub aec2: bool,
+    pub ae_level: i8,
+    pub aec_value: u16,
+    #[serde(serialize_with = "bool_as_int")]
+    pub agc: bool,
+    pub agc_gain: u8,
+    pub gainceiling: u8,
+    #[serde(serialize_with = "bool_as_int")]
+    pub bpc: bool,
+    #[serde(serialize_with = "bool_as_int")]
+    pub wpc: bool,
+    #[serde(serialize_with = "bool_as_int")]
+    pub raw_gma: bool,
+    #[serde(serialize_with = "bool_as_int")]
+    pub lenc: bool,
+    #[serde(serialize_with = "bool_as_int")]
+    pub hmirror: bool,
+    #[serde(serialize_with = "bool_as_int")]
+    pub vflip: bool,
+    #[serde(serialize_with = "bool_as_int")]
+    pub dcw: bool,
+    #[serde(serialize_with = "bool_as_int")]
+    pub colorbar: bool,
+}
+
+impl From<camera::camera_status_t> for SensorStatus {
+    fn from(status: camera::camera_status_t) -> Self {
+        Self {
+            framesize: status.framesize,
+            quality: status.quality,
+            brightness: status.brightness,
+            contrast: status.contrast,
+            saturation: status.saturation,
+            sharpness: status.sharpness,
+            denoise: status.denoise,
+            special_effect: status.special_effect,
+            wb_mode: status.wb_mode,
+            awb: status.awb != 0,
+            awb_gain: status.awb_gain != 0,
+            aec: status.aec != 0,
+            aec2: status.aec2 != 0,
+            ae_level: status.ae_level,
+            aec_value: status.aec_value,
+            agc: status.agc != 0,
+            agc_gain: status.agc_gain,
+            gainceiling: status.gainceiling,
+            bpc: status.bpc != 0,
+            wpc: status.wpc != 0,
+            raw_gma: status.raw_gma != 0,
+            lenc: status.lenc != 0,
+            hmirror: status.hmirror != 0,
+            vflip: status.vflip != 0,
+            dcw: status.dcw != 0,
+            colorbar: status.colorbar != 0,
+        }
+    }
+}
+
+/// Sensor settings to change, the ones left empty are not touched.
+///
+/// The names match [`SensorStatus`] and the keys of the Arduino CameraWebServer `/status`,
+/// on/off settings take booleans or numbers like the ones `/status` returns.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SensorSettings {
+    pub framesize: Option<camera::framesize_t>,
+    pub quality: Option<i32>,
+    pub brightness: Option<i32>,
+    pub contrast: Option<i32>,
+    pub saturation: Option<i32>,
+    pub sharpness: Option<i32>,
+    pub denoise: Option<i32>,
+    pub special_effect: Option<i32>,
+    pub wb_mode: Option<i32>,
+    #[serde(deserialize_with = "bool_or_int")]
+    pub awb: Option<bool>,
+    #[serde(deserialize_with = "bool_or_int")]
+    pub awb_gain: Option<bool>,
+    #[serde(deserialize_with = "bool_or_int")]
+    pub aec: Option<bool>,
+    #[serde(deserialize_with = "bool_or_int")]
+    pub aec2: Option<bool>,
+    pub ae_level: Option<i32>,
+    pub aec_value: Option<i32>,
+    #[serde(deserialize_with = "bool_or_int")]
+    pub agc: Option<bool>,
+    pub agc_gain: Option<i32>,
+    pub gainceiling: Option<camera::gainceiling_t>,
+    #[serde(deserialize_with = "bool_or_int")]
+    pub bpc: Option<bool>,
+    #[serde(deserialize_with = "bool_or_int")]
+    pub wpc: Option<bool>,
+    #[serde(deserialize_with = "bool_or_int")]
+    pub raw_gma: Option<bool>,
+    #[serde(deserialize_with = "bool_or_int")]
+    pub lenc: Option<bool>,
+    #[serde(deserialize_with = "bool_or_int")]
+    pub hmirror: Option<bool>,
+    #[serde(deserialize_with = "bool_or_int")]
+    pub vflip: Option<bool>,
+    #[serde(deserialize_with = "bool_or_int")]
+    pub dcw: Option<bool>,
+    #[serde(deserialize_with = "bool_or_int")]
+    pub colorbar: Option<bool>,
+}
+
+impl SensorSettings {
+    /// Applies every setting that is set, stopping at the first one the driver rejects
+    pub fn apply(&self, sensor: &CameraSensor) -> Result<(), EspError> {
+        macro_rules! set {
+            ($($field:ident => $setter:ident),* $(,)?) => {
+                $(if let Some(value) = self.$field {
+                    sensor.$setter(value)?;
+                })*
+            };
+        }
+
+        set! {
+            framesize => set_framesize,
+            quality => set_quality,
+            brightness => set_brightness,
+            contrast => set_contrast,
+            saturation => set_saturation,
+            sharpness => set_sharpness,
+            denoise => set_denoise,
+            special_effect => set_special_effect,
+            wb_mode => set_wb_mode,
+            awb => set_whitebal,
+            awb_gain => set_awb_gain,
+            aec => set_exposure_ctrl,
+            aec2 => set_aec2,
+            ae_level => set_ae_level,
+            aec_value => set_aec_value,
+            agc => set_gain_ctrl,
+            agc_gain => set_agc_gain,
+            gainceiling => set_gainceiling,
+            bpc => set_bpc,
+            wpc => set_wpc,
+            raw_gma => set_raw_gma,
+            lenc => set_lenc,
+            hmirror => set_hmirror,
+            vflip => set_vflip,
+            dcw => set_dcw,
+            colorbar => set_colorbar,
+        }
+        Ok(())
+    }
+}
+
+fn bool_as_int<S: Serializer>(value: &bool, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u8(*value as u8)
+}
+
+/// `true`/`false` or a number, anything but 0 is on like in `/control`
+fn bool_or_int<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<bool>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BoolOrInt {
+        Bool(bool),
+        Int(i64),
+    }
+
+    Ok(
+        Option::<BoolOrInt>::deserialize(deserializer)?.map(|value| match value {
+            BoolOrInt::Bool(on) => on,
+            BoolOrInt::Int(value) => value != 0,
+        }),
+    )
+}
+
+/// Frame size from its name, like "vga" or "uxga", or from its number in `framesize_t`
+pub fn framesize_from_name(name: &str) -> Option<camera::framesize_t> {
+    let framesize = match name.to_ascii_lowercase().as_str() {
+        "96x96" => camera::framesize_t_FRAMESIZE_96X96,
+        "qqvga" => camera::framesize_t_FRAMESIZE_QQVGA,
+        "qcif" => camera::framesize_t_FRAMESIZE_QCIF,
+        "hqvga" => camera::framesize_t_FRAMESIZE_HQVGA,
+        "240x240" => camera::framesize_t_FRAMESIZE_240X240,
+        "qvga" => camera::framesize_t_FRAMESIZE_QVGA,
+        "cif" => camera::framesize_t_FRAMESIZE_CIF,
+        "hvga" => camera::framesize_t_FRAMESIZE_HVGA,
+        "vga" => camera::framesize_t_FRAMESIZE_VGA,
+        "svga" => camera::framesize_t_FRAMESIZE_SVGA,
+        "xga" => camera::framesize_t_FRAMESIZE_XGA,
+        "hd" => camera::framesize_t_FRAMESIZE_HD,
+        "sxga" => camera::framesize_t_FRAMESIZE_SXGA,
+        "uxga" => camera::framesize_t_FRAMESIZE_UXGA,
+        number => number
+            .parse()
+            .ok()
+            .filter(|&n| n < camera::framesize_t_FRAMESIZE_INVALID)?,
+    };
+    Some(framesize)
+}
+
+/// Width and height of a frame size, for the ones up to UXGA
+pub fn framesize_dimensions(framesize: camera::framesize_t) -> Option<(u16, u16)> {
+    let dimensions = match framesize {
+        camera::framesize_t_FRAMESIZE_96X96 => (96, 96),
+        camera::framesize_t_FRAMESIZE_QQVGA => (160, 120),
+        camera::framesize_t_FRAMESIZE_QCIF => (176, 144),
+        camera::framesize_t_FRAMESIZE_HQVGA => (240, 176),
+        camera::framesize_t_FRAMESIZE_240X240 => (240, 240),
+        camera::framesize_t_FRAMESIZE_QVGA => (320, 240),
+        camera::framesize_t_FRAMESIZE_CIF => (400, 296),
+        camera::framesize_t_FRAMESIZE_HVGA => (480, 320),
+        camera::framesize_t_FRAMESIZE_VGA => (640, 480),
+        camera::framesize_t_FRAMESIZE_SVGA => (800, 600),
+        camera::framesize_t_FRAMESIZE_XGA => (1024, 768),
+        camera::framesize_t_FRAMESIZE_HD => (1280, 720),
+        camera::framesize_t_FRAMESIZE_SXGA => (1280, 1024),
+        camera::framesize_t_FRAMESIZE_UXGA => (1600, 1200),
+        _ => return None,
+    };
+    Some(dimensions)
+}
+
+/// The factory MAC address of the board, used as its serial number
+pub fn device_serial() -> String {
+    let mut mac = [0u8; 6];
+    unsafe { esp_efuse_mac_get_default(mac.as_mut_ptr()) };
+    mac.iter().map(|b| format!("{:02X}", b)).collect()
 }
 
 pub struct Camera<'a> {
+    frame_size: camera::framesize_t,
     _p: PhantomData<&'a ()>,
 }
 
@@ -286,7 +607,24 @@ impl<'a> Camera<'a> {
         };
 
         esp_idf_sys::esp!(unsafe { camera::esp_camera_init(&config) })?;
-        Ok(Self { _p: PhantomData })
+        Ok(Self {
+            frame_size,
+            _p: PhantomData,
+        })
+    }
+
+    /// The frame size of the init, the driver sizes its frame buffers for it
+    pub fn max_framesize(&self) -> camera::framesize_t {
+        self.frame_size
+    }
+
+    /// Whether frames of `framesize` fit the frame buffers, larger ones come out cut
+    pub fn fits(&self, framesize: camera::framesize_t) -> bool {
+        let area = |framesize| framesize_dimensions(framesize).map(|(w, h)| w as u32 * h as u32);
+        match (area(framesize), area(self.frame_size)) {
+            (Some(area), Some(max)) => area <= max,
+            _ => framesize <= self.frame_size,
+        }
     }
 
     pub fn get_framebuffer(&self) -> Option<FrameBuffer> {
@@ -0,0 +1,91 @@
+use std::sync::{
+    mpsc::{channel, Receiver, Sender},
+    Arc, Mutex,
+};
+
+use serde::Serialize;
+
+use crate::{scan::Symbology, tamper::TamperKind};
+
+/// Something noteworthy seen by the camera, delivered to every subscriber
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Tamper {
+        kind: TamperKind,
+    },
+    TamperCleared,
+    CodeScanned {
+        symbology: Symbology,
+        content: String,
+    },
+}
+
+impl Event {
+    /// Short human readable description, used for chat notifications
+    pub fn describe(&self) -> String {
+        match self {
+            Event::Tamper { kind } => format!("Tamper detected: {}", kind.describe()),
+            Event::TamperCleared => "Camera view restored".to_string(),
+            Event::CodeScanned { symbology, content } => {
+                format!("Scanned {:?}: {}", symbology, content)
+            }
+        }
+    }
+}
+
+/// Fan-out of events from the detectors to the notification paths.
+///
+/// Cloning the bus gives another handle to the same set of subscribers.
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (tx, rx) = channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Sends the event to every subscriber, dropping the ones that went away
+    pub fn publish(&self, event: Event) {
+        log::info!("event: {:?}", event);
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_subscriber_gets_the_event() {
+        let bus = EventBus::new();
+        let first = bus.subscribe();
+        let second = bus.clone().subscribe();
+
+        bus.publish(Event::TamperCleared);
+        assert_eq!(first.try_recv(), Ok(Event::TamperCleared));
+        assert_eq!(second.try_recv(), Ok(Event::TamperCleared));
+    }
+
+    #[test]
+    fn dropped_subscribers_are_forgotten() {
+        let bus = EventBus::new();
+        let kept = bus.subscribe();
+        drop(bus.subscribe());
+
+        bus.publish(Event::TamperCleared);
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+        assert_eq!(kept.try_recv(), Ok(Event::TamperCleared));
+    }
+}
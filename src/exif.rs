@@ -0,0 +1,501 @@
+use std::time::SystemTime;
+
+use anyhow::{bail, Result};
+
+use crate::overlay::format_timestamp;
+
+const BYTE: u16 = 1;
+const ASCII: u16 = 2;
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const RATIONAL: u16 = 5;
+const UNDEFINED: u16 = 7;
+
+/// Position written to the GPS tags, in degrees and meters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GpsPosition {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: Option<f64>,
+}
+
+impl GpsPosition {
+    /// Parses `"latitude,longitude"` or `"latitude,longitude,altitude"`, empty means no position
+    pub fn parse(text: &str) -> Result<Option<Self>> {
+        if text.trim().is_empty() {
+            return Ok(None);
+        }
+
+        let values = text
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()?;
+        match values[..] {
+            [latitude, longitude] => Ok(Some(Self {
+                latitude,
+                longitude,
+                altitude: None,
+            })),
+            [latitude, longitude, altitude] => Ok(Some(Self {
+                latitude,
+                longitude,
+                altitude: Some(altitude),
+            })),
+            _ => bail!("expected latitude,longitude[,altitude]"),
+        }
+    }
+}
+
+/// What is written in the EXIF block of the pictures, every field is optional
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExifMetadata {
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub device_name: Option<String>,
+    pub serial: Option<String>,
+    pub time: Option<SystemTime>,
+    pub utc_offset_secs: i64,
+    /// Exposure time in seconds, as a fraction
+    pub exposure_time: Option<(u32, u32)>,
+    pub iso: Option<u16>,
+    /// EXIF orientation, 1 is upright
+    pub orientation: Option<u16>,
+    pub gps: Option<GpsPosition>,
+}
+
+impl ExifMetadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn make(mut self, make: impl Into<String>) -> Self {
+        self.make = Some(make.into());
+        self
+    }
+
+    pub fn model(mut self, model: impl Into<String>) -> Self {
+        self.model = Some(model.into());
+        self
+    }
+
+    pub fn device_name(mut self, device_name: impl Into<String>) -> Self {
+        self.device_name = Some(device_name.into());
+        self
+    }
+
+    pub fn serial(mut self, serial: impl Into<String>) -> Self {
+        self.serial = Some(serial.into());
+        self
+    }
+
+    pub fn time(mut self, time: SystemTime) -> Self {
+        self.time = Some(time);
+        self
+    }
+
+    pub fn utc_offset(mut self, utc_offset_secs: i64) -> Self {
+        self.utc_offset_secs = utc_offset_secs;
+        self
+    }
+
+    pub fn exposure_time(mut self, numerator: u32, denominator: u32) -> Self {
+        self.exposure_time = Some((numerator, denominator));
+        self
+    }
+
+    pub fn iso(mut self, iso: u16) -> Self {
+        self.iso = Some(iso);
+        self
+    }
+
+    pub fn orientation(mut self, orientation: u16) -> Self {
+        self.orientation = Some(orientation);
+        self
+    }
+
+    pub fn gps(mut self, gps: Option<GpsPosition>) -> Self {
+        self.gps = gps;
+        self
+    }
+
+    /// Fields set in `other` replace the ones of `self`
+    pub fn merge(mut self, other: &ExifMetadata) -> Self {
+        fn pick<T: Clone>(field: &mut Option<T>, other: &Option<T>) {
+            if other.is_some() {
+                field.clone_from(other);
+            }
+        }
+        pick(&mut self.make, &other.make);
+        pick(&mut self.model, &other.model);
+        pick(&mut self.device_name, &other.device_name);
+        pick(&mut self.serial, &other.serial);
+        pick(&mut self.time, &other.time);
+        pick(&mut self.exposure_time, &other.exposure_time);
+        pick(&mut self.iso, &other.iso);
+        pick(&mut self.orientation, &other.orientation);
+        pick(&mut self.gps, &other.gps);
+        if other.utc_offset_secs != 0 {
+            self.utc_offset_secs = other.utc_offset_secs;
+        }
+        self
+    }
+
+    /// The complete APP1 segment, marker and length included
+    pub fn to_app1(&self) -> Vec<u8> {
+        let mut ifd0 = Vec::new();
+        let mut exif = Vec::new();
+        let mut gps = Vec::new();
+
+        if let Some(name) = &self.device_name {
+            ifd0.push(Entry::ascii(0x010E, name));
+        }
+        if let Some(make) = &self.make {
+            ifd0.push(Entry::ascii(0x010F, make));
+        }
+        if let Some(model) = &self.model {
+            ifd0.push(Entry::ascii(0x0110, model));
+        }
+        if let Some(orientation) = self.orientation {
+            ifd0.push(Entry::short(0x0112, orientation));
+        }
+        ifd0.push(Entry::ascii(
+            0x0131,
+            concat!("espcam ", env!("CARGO_PKG_VERSION")),
+        ));
+
+        exif.push(Entry::new(0x9000, UNDEFINED, 4, b"0232".to_vec()));
+        if let Some(time) = self.time {
+            // EXIF wants colons in the date too
+            let stamp = format_timestamp(time, self.utc_offset_secs).replacen('-', ":", 2);
+            ifd0.push(Entry::ascii(0x0132, &stamp));
+            exif.push(Entry::ascii(0x9003, &stamp));
+            exif.push(Entry::ascii(0x9004, &stamp));
+
+            let offset = self.utc_offset_secs;
+            let sign = if offset < 0 { '-' } else { '+' };
+            let offset = format!(
+                "{}{:02}:{:02}",
+                sign,
+                offset.abs() / 3600,
+                (offset.abs() % 3600) / 60
+            );
+            exif.push(Entry::ascii(0x9010, &offset));
+            exif.push(Entry::ascii(0x9011, &offset));
+        }
+        if let Some((numerator, denominator)) = self.exposure_time {
+            exif.push(Entry::rationals(0x829A, &[(numerator, denominator)]));
+        }
+        if let Some(iso) = self.iso {
+            exif.push(Entry::short(0x8827, iso));
+        }
+        if let Some(serial) = &self.serial {
+            exif.push(Entry::ascii(0xA431, serial));
+        }
+
+        if let Some(position) = &self.gps {
+            gps.push(Entry::new(0x0000, BYTE, 4, vec![2, 3, 0, 0]));
+            let lat_ref = if position.latitude < 0.0 { "S" } else { "N" };
+            gps.push(Entry::ascii(0x0001, lat_ref));
+            gps.push(Entry::rationals(0x0002, &degrees(position.latitude)));
+            let lon_ref = if position.longitude < 0.0 { "W" } else { "E" };
+            gps.push(Entry::ascii(0x0003, lon_ref));
+            gps.push(Entry::rationals(0x0004, &degrees(position.longitude)));
+            if let Some(altitude) = position.altitude {
+                gps.push(Entry::new(
+                    0x0005,
+                    BYTE,
+                    1,
+                    vec![if altitude < 0.0 { 1 } else { 0 }],
+                ));
+                let centimeters = (altitude.abs() * 100.0).round() as u32;
+                gps.push(Entry::rationals(0x0006, &[(centimeters, 100)]));
+            }
+        }
+
+        // the pointers are plain longs, so the sizes are known before their values
+        let exif_offset = 8 + ifd_size(&ifd0) + 12 + if gps.is_empty() { 0 } else { 12 };
+        let gps_offset = exif_offset + ifd_size(&exif);
+        ifd0.push(Entry::long(0x8769, exif_offset as u32));
+        if !gps.is_empty() {
+            ifd0.push(Entry::long(0x8825, gps_offset as u32));
+        }
+
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend_from_slice(&8u32.to_le_bytes());
+        write_ifd(&mut tiff, ifd0);
+        write_ifd(&mut tiff, exif);
+        if !gps.is_empty() {
+            write_ifd(&mut tiff, gps);
+        }
+
+        let mut app1 = vec![0xFF, 0xE1];
+        app1.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+        app1.extend_from_slice(b"Exif\0\0");
+        app1.extend_from_slice(&tiff);
+        app1
+    }
+
+    /// Adds the metadata to a JPEG without decoding it, an EXIF block already there is replaced
+    pub fn insert(&self, jpeg: &[u8]) -> Result<Vec<u8>> {
+        insert_app1(jpeg, &self.to_app1())
+    }
+}
+
+/// Places an APP1 segment after the JFIF header, if any, dropping the EXIF segments
+/// the picture already has
+pub fn insert_app1(jpeg: &[u8], app1: &[u8]) -> Result<Vec<u8>> {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        bail!("not a jpeg");
+    }
+
+    let mut jfif = Vec::new();
+    let mut others = Vec::new();
+    let mut pos = 2;
+    while pos + 4 <= jpeg.len() && jpeg[pos] == 0xFF && (0xE0..=0xEF).contains(&jpeg[pos + 1]) {
+        let len = u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]) as usize;
+        // the length counts its own two bytes
+        if len < 2 {
+            bail!("invalid jpeg segment length");
+        }
+        let Some(segment) = jpeg.get(pos..pos + 2 + len) else {
+            bail!("truncated jpeg segment");
+        };
+        match segment[1] {
+            0xE0 => jfif.push(segment),
+            0xE1 if segment.get(4..).is_some_and(|d| d.starts_with(b"Exif\0\0")) => {}
+            _ => others.push(segment),
+        }
+        pos += 2 + len;
+    }
+
+    let mut out = Vec::with_capacity(jpeg.len() + app1.len());
+    out.extend_from_slice(&jpeg[..2]);
+    jfif.iter().for_each(|s| out.extend_from_slice(s));
+    out.extend_from_slice(app1);
+    others.iter().for_each(|s| out.extend_from_slice(s));
+    out.extend_from_slice(&jpeg[pos..]);
+    Ok(out)
+}
+
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    data: Vec<u8>,
+}
+
+impl Entry {
+    fn new(tag: u16, kind: u16, count: u32, data: Vec<u8>) -> Self {
+        Self {
+            tag,
+            kind,
+            count,
+            data,
+        }
+    }
+
+    fn ascii(tag: u16, text: &str) -> Self {
+        let mut data = text.as_bytes().to_vec();
+        data.push(0);
+        Self::new(tag, ASCII, data.len() as u32, data)
+    }
+
+    fn short(tag: u16, value: u16) -> Self {
+        Self::new(tag, SHORT, 1, value.to_le_bytes().to_vec())
+    }
+
+    fn long(tag: u16, value: u32) -> Self {
+        Self::new(tag, LONG, 1, value.to_le_bytes().to_vec())
+    }
+
+    fn rationals(tag: u16, values: &[(u32, u32)]) -> Self {
+        let mut data = Vec::with_capacity(values.len() * 8);
+        for (numerator, denominator) in values {
+            data.extend_from_slice(&numerator.to_le_bytes());
+            data.extend_from_slice(&denominator.to_le_bytes());
+        }
+        Self::new(tag, RATIONAL, values.len() as u32, data)
+    }
+
+    /// Bytes stored after the IFD, values up to 4 bytes live in the entry itself
+    fn external_len(&self) -> usize {
+        if self.data.len() > 4 {
+            self.data.len() + self.data.len() % 2
+        } else {
+            0
+        }
+    }
+}
+
+fn ifd_size(entries: &[Entry]) -> usize {
+    2 + entries.len() * 12 + 4 + entries.iter().map(Entry::external_len).sum::<usize>()
+}
+
+/// Appends an IFD and its values at the end of `tiff`, offsets count from the TIFF header
+fn write_ifd(tiff: &mut Vec<u8>, mut entries: Vec<Entry>) {
+    entries.sort_by_key(|e| e.tag);
+
+    let start = tiff.len();
+    let mut data_offset = start + 2 + entries.len() * 12 + 4;
+    let mut data = Vec::new();
+
+    tiff.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    for entry in &entries {
+        tiff.extend_from_slice(&entry.tag.to_le_bytes());
+        tiff.extend_from_slice(&entry.kind.to_le_bytes());
+        tiff.extend_from_slice(&entry.count.to_le_bytes());
+        if entry.data.len() <= 4 {
+            let mut value = [0; 4];
+            value[..entry.data.len()].copy_from_slice(&entry.data);
+            tiff.extend_from_slice(&value);
+        } else {
+            tiff.extend_from_slice(&(data_offset as u32).to_le_bytes());
+            data.extend_from_slice(&entry.data);
+            if entry.data.len() % 2 == 1 {
+                data.push(0);
+            }
+            data_offset += entry.external_len();
+        }
+    }
+    // no next IFD, the thumbnail IFD is not written
+    tiff.extend_from_slice(&0u32.to_le_bytes());
+    tiff.extend_from_slice(&data);
+}
+
+/// Degrees, minutes and seconds with a hundredth of a second precision
+fn degrees(value: f64) -> [(u32, u32); 3] {
+    let value = value.abs();
+    let degrees = value.trunc();
+    let minutes = ((value - degrees) * 60.0).trunc();
+    let seconds = (value - degrees - minutes / 60.0) * 3600.0;
+    [
+        (degrees as u32, 1),
+        (minutes as u32, 1),
+        ((seconds * 100.0).round() as u32, 100),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use exif::{In, Reader, Tag, Value};
+
+    use super::*;
+
+    /// Start of image, a JFIF header and the end of image, enough for an EXIF reader
+    const JPEG: &[u8] = &[
+        0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10, b'J', b'F', b'I', b'F', 0x00, 0x01, 0x01, 0x00, 0x00,
+        0x01, 0x00, 0x01, 0x00, 0x00, 0xFF, 0xD9,
+    ];
+
+    fn read(jpeg: &[u8]) -> exif::Exif {
+        Reader::new()
+            .read_from_container(&mut Cursor::new(jpeg))
+            .unwrap()
+    }
+
+    fn ascii(exif: &exif::Exif, tag: Tag) -> String {
+        match &exif.get_field(tag, In::PRIMARY).unwrap().value {
+            Value::Ascii(values) => String::from_utf8(values[0].clone()).unwrap(),
+            value => panic!("{:?} is not ascii: {:?}", tag, value),
+        }
+    }
+
+    fn rationals(exif: &exif::Exif, tag: Tag) -> Vec<(u32, u32)> {
+        match &exif.get_field(tag, In::PRIMARY).unwrap().value {
+            Value::Rational(values) => values.iter().map(|r| (r.num, r.denom)).collect(),
+            value => panic!("{:?} is not rational: {:?}", tag, value),
+        }
+    }
+
+    fn uint(exif: &exif::Exif, tag: Tag) -> u32 {
+        exif.get_field(tag, In::PRIMARY)
+            .unwrap()
+            .value
+            .get_uint(0)
+            .unwrap()
+    }
+
+    #[test]
+    fn tags_read_back() {
+        let metadata = ExifMetadata::new()
+            .make("Ai-Thinker")
+            .model("ESP32-CAM")
+            .device_name("front door")
+            .serial("0123456789ab")
+            .time(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+            .utc_offset(3600)
+            .exposure_time(1, 50)
+            .iso(400)
+            .orientation(6)
+            .gps(Some(GpsPosition {
+                latitude: 45.5,
+                longitude: -9.25,
+                altitude: Some(-12.5),
+            }));
+        let exif = read(&metadata.insert(JPEG).unwrap());
+
+        assert_eq!(ascii(&exif, Tag::Make), "Ai-Thinker");
+        assert_eq!(ascii(&exif, Tag::Model), "ESP32-CAM");
+        assert_eq!(ascii(&exif, Tag::ImageDescription), "front door");
+        assert_eq!(ascii(&exif, Tag::BodySerialNumber), "0123456789ab");
+        assert!(ascii(&exif, Tag::Software).starts_with("espcam "));
+        assert_eq!(ascii(&exif, Tag::DateTime), "2023:11:14 23:13:20");
+        assert_eq!(ascii(&exif, Tag::DateTimeOriginal), "2023:11:14 23:13:20");
+        assert_eq!(ascii(&exif, Tag::OffsetTimeOriginal), "+01:00");
+        assert_eq!(rationals(&exif, Tag::ExposureTime), [(1, 50)]);
+        assert_eq!(uint(&exif, Tag::PhotographicSensitivity), 400);
+        assert_eq!(uint(&exif, Tag::Orientation), 6);
+
+        assert_eq!(ascii(&exif, Tag::GPSLatitudeRef), "N");
+        assert_eq!(
+            rationals(&exif, Tag::GPSLatitude),
+            [(45, 1), (30, 1), (0, 100)]
+        );
+        assert_eq!(ascii(&exif, Tag::GPSLongitudeRef), "W");
+        assert_eq!(
+            rationals(&exif, Tag::GPSLongitude),
+            [(9, 1), (15, 1), (0, 100)]
+        );
+        assert_eq!(uint(&exif, Tag::GPSAltitudeRef), 1);
+        assert_eq!(rationals(&exif, Tag::GPSAltitude), [(1250, 100)]);
+    }
+
+    #[test]
+    fn optional_tags_are_left_out() {
+        let exif = read(&ExifMetadata::new().insert(JPEG).unwrap());
+        assert!(exif.get_field(Tag::Make, In::PRIMARY).is_none());
+        assert!(exif.get_field(Tag::DateTimeOriginal, In::PRIMARY).is_none());
+        assert!(exif.get_field(Tag::GPSLatitude, In::PRIMARY).is_none());
+        assert!(exif.get_field(Tag::Software, In::PRIMARY).is_some());
+    }
+
+    #[test]
+    fn existing_exif_is_replaced_after_jfif() {
+        let first = ExifMetadata::new().make("first").insert(JPEG).unwrap();
+        let second = ExifMetadata::new().make("second").insert(&first).unwrap();
+
+        assert!(second.starts_with(&JPEG[..20]));
+        assert_eq!(second[20..22], [0xFF, 0xE1]);
+        assert_eq!(second.windows(6).filter(|w| w == b"Exif\0\0").count(), 1);
+        assert_eq!(ascii(&read(&second), Tag::Make), "second");
+    }
+
+    #[test]
+    fn malformed_segments_are_refused() {
+        assert!(insert_app1(b"GIF89a", &[]).is_err());
+        // a length below the two bytes of the length itself
+        assert!(insert_app1(&[0xFF, 0xD8, 0xFF, 0xE1, 0x00, 0x01, 0xFF, 0xD9], &[]).is_err());
+        // an APP1 too short to hold an identifier
+        let jpeg = [0xFF, 0xD8, 0xFF, 0xE1, 0x00, 0x02, 0xFF, 0xD9];
+        assert_eq!(
+            insert_app1(&jpeg, &[0xFF, 0xE1, 0x00, 0x02]).unwrap(),
+            [0xFF, 0xD8, 0xFF, 0xE1, 0x00, 0x02, 0xFF, 0xE1, 0x00, 0x02, 0xFF, 0xD9]
+        );
+        assert!(insert_app1(&[0xFF, 0xD8, 0xFF, 0xE1, 0x00, 0x40, 0x00], &[]).is_err());
+    }
+}
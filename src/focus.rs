@@ -0,0 +1,161 @@
+use serde::Serialize;
+
+use crate::frame::Frame;
+
+/// Luma of a raw frame, subsampled to at most `max_side` pixels along its longest side
+#[derive(Debug, Clone)]
+pub struct LumaPlane {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl LumaPlane {
+    pub fn from_frame(frame: &Frame, max_side: usize) -> Self {
+        let step = (frame.width().max(frame.height()) / max_side.max(1)).max(1);
+        let width = frame.width() / step;
+        let height = frame.height() / step;
+
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                data.push(frame.luma(x * step, y * step));
+            }
+        }
+
+        Self {
+            width,
+            height,
+            data,
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> i32 {
+        self.data[x + y * self.width] as i32
+    }
+}
+
+/// Focus scores, higher means sharper. They only make sense compared to
+/// other scores of the same scene at the same resolution.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Sharpness {
+    pub variance_of_laplacian: f32,
+    pub tenengrad: f32,
+}
+
+impl Sharpness {
+    pub fn compute(plane: &LumaPlane) -> Self {
+        Self {
+            variance_of_laplacian: variance_of_laplacian(plane),
+            tenengrad: tenengrad(plane),
+        }
+    }
+
+    pub fn from_frame(frame: &Frame, max_side: usize) -> Self {
+        Self::compute(&LumaPlane::from_frame(frame, max_side))
+    }
+}
+
+/// Variance of the 4-neighbour Laplacian, blurry pictures have few strong edges
+pub fn variance_of_laplacian(plane: &LumaPlane) -> f32 {
+    if plane.width < 3 || plane.height < 3 {
+        return 0.0;
+    }
+
+    let mut sum = 0f64;
+    let mut sum_sq = 0f64;
+    let mut n = 0f64;
+    for y in 1..plane.height - 1 {
+        for x in 1..plane.width - 1 {
+            let lap = plane.get(x - 1, y)
+                + plane.get(x + 1, y)
+                + plane.get(x, y - 1)
+                + plane.get(x, y + 1)
+                - 4 * plane.get(x, y);
+            sum += lap as f64;
+            sum_sq += (lap * lap) as f64;
+            n += 1.0;
+        }
+    }
+
+    let mean = sum / n;
+    (sum_sq / n - mean * mean) as f32
+}
+
+/// Mean squared Sobel gradient magnitude
+pub fn tenengrad(plane: &LumaPlane) -> f32 {
+    if plane.width < 3 || plane.height < 3 {
+        return 0.0;
+    }
+
+    let mut sum = 0f64;
+    let mut n = 0f64;
+    for y in 1..plane.height - 1 {
+        for x in 1..plane.width - 1 {
+            let p = |dx: usize, dy: usize| plane.get(x + dx - 1, y + dy - 1);
+            let gx = p(2, 0) + 2 * p(2, 1) + p(2, 2) - p(0, 0) - 2 * p(0, 1) - p(0, 2);
+            let gy = p(0, 2) + 2 * p(1, 2) + p(2, 2) - p(0, 0) - 2 * p(1, 0) - p(2, 0);
+            sum += (gx * gx + gy * gy) as f64;
+            n += 1.0;
+        }
+    }
+
+    (sum / n) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::PixelFormat;
+
+    fn checkerboard(w: usize, h: usize, square: usize) -> Frame {
+        let data = (0..w * h)
+            .map(|i| {
+                if (i % w / square + i / w / square) % 2 == 0 {
+                    40
+                } else {
+                    220
+                }
+            })
+            .collect();
+        Frame::new(data, w, h, PixelFormat::Grayscale)
+    }
+
+    /// Box blur over a `(2 * radius + 1)` square
+    fn blur(frame: &Frame, radius: usize) -> Frame {
+        let (w, h) = (frame.width(), frame.height());
+        let mut data = Vec::with_capacity(w * h);
+        for y in 0..h {
+            for x in 0..w {
+                let (mut sum, mut n) = (0u32, 0u32);
+                for sy in y.saturating_sub(radius)..(y + radius + 1).min(h) {
+                    for sx in x.saturating_sub(radius)..(x + radius + 1).min(w) {
+                        sum += frame.luma(sx, sy) as u32;
+                        n += 1;
+                    }
+                }
+                data.push((sum / n) as u8);
+            }
+        }
+        Frame::new(data, w, h, PixelFormat::Grayscale)
+    }
+
+    #[test]
+    fn blurred_scores_lower() {
+        let sharp = checkerboard(160, 120, 10);
+        let blurred = blur(&sharp, 4);
+
+        let sharp = Sharpness::from_frame(&sharp, 160);
+        let blurred = Sharpness::from_frame(&blurred, 160);
+        assert!(blurred.tenengrad < sharp.tenengrad / 2.0);
+        assert!(blurred.variance_of_laplacian < sharp.variance_of_laplacian / 2.0);
+    }
+
+    #[test]
+    fn flat_frame_scores_zero() {
+        let flat = Frame::new(vec![128; 160 * 120], 160, 120, PixelFormat::Grayscale);
+        let score = Sharpness::from_frame(&flat, 160);
+        assert_eq!(score.tenengrad, 0.0);
+        assert_eq!(score.variance_of_laplacian, 0.0);
+    }
+}
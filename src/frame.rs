@@ -0,0 +1,287 @@
+use std::io::Cursor;
+
+use anyhow::{bail, Result};
+use image::{
+    codecs::{jpeg::JpegDecoder, png::PngEncoder},
+    ExtendedColorType, ImageDecoder, ImageEncoder, RgbImage,
+};
+use jpeg_encoder::{ColorType, Encoder, SamplingFactor};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb565,
+    Grayscale,
+    Rgb888,
+    Jpeg,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> Option<usize> {
+        match self {
+            PixelFormat::Rgb565 => Some(2),
+            PixelFormat::Grayscale => Some(1),
+            PixelFormat::Rgb888 => Some(3),
+            PixelFormat::Jpeg => None,
+        }
+    }
+}
+
+/// An owned copy of a camera frame, detached from the driver's framebuffer pool.
+///
+/// RGB565 pixels are stored big endian, the same way the sensor delivers them.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    data: Vec<u8>,
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+}
+
+impl Frame {
+    pub fn new(data: Vec<u8>, width: usize, height: usize, format: PixelFormat) -> Self {
+        Self {
+            data,
+            width,
+            height,
+            format,
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    pub fn is_raw(&self) -> bool {
+        self.format != PixelFormat::Jpeg
+    }
+
+    /// Reads a pixel as RGB
+    ///
+    /// # Panics
+    ///
+    /// On JPEG frames, decode them with [`Frame::to_raw`] first, and on coordinates
+    /// outside the frame.
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 3] {
+        let idx = x + y * self.width;
+        match self.format {
+            PixelFormat::Rgb565 => {
+                let raw = u16::from_be_bytes([self.data[idx * 2], self.data[idx * 2 + 1]]);
+                let decoded = rgb565::Rgb565::unpack_565(raw);
+                [decoded.0, decoded.1, decoded.2]
+            }
+            PixelFormat::Grayscale => {
+                let v = self.data[idx];
+                [v, v, v]
+            }
+            PixelFormat::Rgb888 => [
+                self.data[idx * 3],
+                self.data[idx * 3 + 1],
+                self.data[idx * 3 + 2],
+            ],
+            PixelFormat::Jpeg => panic!("pixel access on a jpeg frame"),
+        }
+    }
+
+    /// Writes an RGB pixel, grayscale frames keep its luma
+    ///
+    /// # Panics
+    ///
+    /// On JPEG frames and on coordinates outside the frame, like [`Frame::pixel`].
+    pub fn set_pixel(&mut self, x: usize, y: usize, rgb: [u8; 3]) {
+        let idx = x + y * self.width;
+        match self.format {
+            PixelFormat::Rgb565 => {
+                let raw = pack_565(rgb).to_be_bytes();
+                self.data[idx * 2] = raw[0];
+                self.data[idx * 2 + 1] = raw[1];
+            }
+            PixelFormat::Grayscale => self.data[idx] = luma(rgb),
+            PixelFormat::Rgb888 => self.data[idx * 3..idx * 3 + 3].copy_from_slice(&rgb),
+            PixelFormat::Jpeg => panic!("pixel access on a jpeg frame"),
+        }
+    }
+
+    /// Brightness of a pixel, panics like [`Frame::pixel`]
+    pub fn luma(&self, x: usize, y: usize) -> u8 {
+        match self.format {
+            PixelFormat::Grayscale => self.data[x + y * self.width],
+            _ => luma(self.pixel(x, y)),
+        }
+    }
+
+    /// Returns a frame that can be accessed pixel by pixel, decoding JPEG into RGB888.
+    pub fn to_raw(&self) -> Result<Frame> {
+        match self.format {
+            PixelFormat::Jpeg => Frame::decode_jpeg(&self.data),
+            _ => Ok(self.clone()),
+        }
+    }
+
+    pub fn decode_jpeg(jpeg: &[u8]) -> Result<Frame> {
+        let img = image::load_from_memory_with_format(jpeg, image::ImageFormat::Jpeg)?;
+        Ok(Frame::from_rgb_image(img.into_rgb8()))
+    }
+
+    /// Reads the size from the JPEG header without decoding the picture
+    pub fn jpeg_dimensions(jpeg: &[u8]) -> Result<(usize, usize)> {
+        let (width, height) = JpegDecoder::new(Cursor::new(jpeg))?.dimensions();
+        Ok((width as usize, height as usize))
+    }
+
+    pub fn from_rgb_image(img: RgbImage) -> Frame {
+        let (width, height) = img.dimensions();
+        Frame::new(
+            img.into_raw(),
+            width as usize,
+            height as usize,
+            PixelFormat::Rgb888,
+        )
+    }
+
+    pub fn to_rgb_image(&self) -> Result<RgbImage> {
+        let raw = self.to_raw()?;
+        if raw.format == PixelFormat::Rgb888 {
+            return RgbImage::from_raw(raw.width as u32, raw.height as u32, raw.data)
+                .ok_or_else(|| anyhow::anyhow!("frame buffer too small"));
+        }
+
+        Ok(RgbImage::from_fn(
+            raw.width as u32,
+            raw.height as u32,
+            |x, y| image::Rgb(raw.pixel(x as usize, y as usize)),
+        ))
+    }
+
+    /// Encodes the frame as JPEG, `quality` goes from 1 (worst) to 100 (best).
+    ///
+    /// Colour is subsampled 4:2:0 like the sensor does, RTP/JPEG can't carry anything else.
+    /// JPEG frames are returned as they are.
+    pub fn to_jpeg(&self, quality: u8) -> Result<Vec<u8>> {
+        let (Ok(width), Ok(height)) = (u16::try_from(self.width), u16::try_from(self.height))
+        else {
+            bail!("frame too large for a jpeg");
+        };
+        let mut out = Vec::new();
+        let mut encoder = Encoder::new(&mut out, quality);
+        encoder.set_sampling_factor(SamplingFactor::R_4_2_0);
+
+        match self.format {
+            PixelFormat::Jpeg => return Ok(self.data.clone()),
+            PixelFormat::Grayscale => encoder.encode(&self.data, width, height, ColorType::Luma)?,
+            PixelFormat::Rgb888 => encoder.encode(&self.data, width, height, ColorType::Rgb)?,
+            PixelFormat::Rgb565 => {
+                let img = self.to_rgb_image()?;
+                encoder.encode(img.as_raw(), width, height, ColorType::Rgb)?
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Encodes the frame as PNG, lossless so thin lines and flat colours stay crisp
+    pub fn to_png(&self) -> Result<Vec<u8>> {
+        let img = self.to_rgb_image()?;
+        let mut out = Vec::new();
+        PngEncoder::new(&mut out).write_image(
+            img.as_raw(),
+            img.width(),
+            img.height(),
+            ExtendedColorType::Rgb8,
+        )?;
+        Ok(out)
+    }
+
+    /// Converts a raw frame to another raw pixel format.
+    pub fn convert(&self, format: PixelFormat) -> Result<Frame> {
+        if format == PixelFormat::Jpeg {
+            bail!("use to_jpeg to encode frames");
+        }
+        let raw = self.to_raw()?;
+        if raw.format == format {
+            return Ok(raw);
+        }
+
+        let bpp = format.bytes_per_pixel().unwrap_or(3);
+        let mut out = Frame::new(
+            vec![0; raw.width * raw.height * bpp],
+            raw.width,
+            raw.height,
+            format,
+        );
+        for y in 0..raw.height {
+            for x in 0..raw.width {
+                out.set_pixel(x, y, raw.pixel(x, y));
+            }
+        }
+        Ok(out)
+    }
+}
+
+pub fn pack_565(rgb: [u8; 3]) -> u16 {
+    ((rgb[0] as u16 & 0xf8) << 8) | ((rgb[1] as u16 & 0xfc) << 3) | (rgb[2] as u16 >> 3)
+}
+
+pub fn luma(rgb: [u8; 3]) -> u8 {
+    ((rgb[0] as u32 * 77 + rgb[1] as u32 * 150 + rgb[2] as u32 * 29) >> 8) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sampling factors of the components in the baseline frame header
+    fn sampling(jpeg: &[u8]) -> Vec<u8> {
+        let sof = jpeg.windows(2).position(|w| w == [0xFF, 0xC0]).unwrap();
+        let components = jpeg[sof + 9] as usize;
+        (0..components).map(|i| jpeg[sof + 11 + i * 3]).collect()
+    }
+
+    #[test]
+    fn jpeg_is_subsampled() {
+        let mut frame = Frame::new(vec![0; 64 * 48 * 3], 64, 48, PixelFormat::Rgb888);
+        for y in 0..48 {
+            for x in 0..64 {
+                frame.set_pixel(x, y, [(x * 4) as u8, (y * 5) as u8, 128]);
+            }
+        }
+
+        let jpeg = frame.to_jpeg(90).unwrap();
+        assert_eq!(sampling(&jpeg), [0x22, 0x11, 0x11]);
+
+        let decoded = Frame::decode_jpeg(&jpeg).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (64, 48));
+        let [r, g, b] = decoded.pixel(40, 20);
+        assert!(r.abs_diff(160) < 12 && g.abs_diff(100) < 12 && b.abs_diff(128) < 12);
+    }
+
+    #[test]
+    fn jpeg_frames_are_kept() {
+        let jpeg = Frame::new(vec![0; 8 * 8], 8, 8, PixelFormat::Grayscale)
+            .to_jpeg(80)
+            .unwrap();
+        assert_eq!(sampling(&jpeg).len(), 1);
+
+        let frame = Frame::new(jpeg.clone(), 8, 8, PixelFormat::Jpeg);
+        assert_eq!(frame.to_jpeg(10).unwrap(), jpeg);
+    }
+}
@@ -0,0 +1,212 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    frame::{Frame, PixelFormat},
+    motion::MotionMask,
+};
+
+pub const HEATMAP_STORAGE_KEY: &str = "heatmap";
+
+/// 4:3 like the sensor, small enough that two windows fit in one NVS value
+pub const HEATMAP_COLUMNS: usize = 24;
+pub const HEATMAP_ROWS: usize = 18;
+
+/// Strongest tint, the snapshot always shows through
+const MAX_ALPHA: f32 = 0.6;
+
+/// Motion summed over a time window on a coarse grid
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Heatmap {
+    pub columns: usize,
+    pub rows: usize,
+    /// Start of the window, in seconds since the unix epoch
+    pub start: u64,
+    pub frames: u32,
+    /// Moving pixels seen in each cell, row by row
+    pub cells: Vec<u32>,
+}
+
+impl Heatmap {
+    pub fn new(columns: usize, rows: usize, start: SystemTime) -> Self {
+        Self {
+            columns,
+            rows,
+            start: unix_secs(start),
+            frames: 0,
+            cells: vec![0; columns * rows],
+        }
+    }
+
+    pub fn add(&mut self, mask: &MotionMask) {
+        for y in 0..mask.height {
+            let row = y * self.rows / mask.height;
+            for x in 0..mask.width {
+                if mask.get(x, y) {
+                    self.cells[x * self.columns / mask.width + row * self.columns] += 1;
+                }
+            }
+        }
+        self.frames += 1;
+    }
+
+    /// Activity of each cell from 0.0 to 1.0, relative to the busiest one
+    pub fn normalized(&self) -> Vec<f32> {
+        let max = self.cells.iter().copied().max().unwrap_or(0).max(1) as f32;
+        // square root so that places with a little activity still show up
+        self.cells
+            .iter()
+            .map(|&c| (c as f32 / max).sqrt())
+            .collect()
+    }
+
+    /// Tints a snapshot with the activity, from blue for a little to red for the most
+    pub fn render(&self, snapshot: &Frame) -> Result<Frame> {
+        let values = self.normalized();
+        let (width, height) = (snapshot.width(), snapshot.height());
+        let mut out = snapshot.convert(PixelFormat::Rgb888)?;
+
+        for y in 0..height {
+            // bilinear interpolation between the cell centers
+            let gy = ((y as f32 + 0.5) * self.rows as f32 / height as f32 - 0.5)
+                .clamp(0.0, (self.rows - 1) as f32);
+            let (y0, fy) = (gy as usize, gy.fract());
+            let y1 = (y0 + 1).min(self.rows - 1);
+
+            for x in 0..width {
+                let gx = ((x as f32 + 0.5) * self.columns as f32 / width as f32 - 0.5)
+                    .clamp(0.0, (self.columns - 1) as f32);
+                let (x0, fx) = (gx as usize, gx.fract());
+                let x1 = (x0 + 1).min(self.columns - 1);
+
+                let top = values[x0 + y0 * self.columns] * (1.0 - fx)
+                    + values[x1 + y0 * self.columns] * fx;
+                let bottom = values[x0 + y1 * self.columns] * (1.0 - fx)
+                    + values[x1 + y1 * self.columns] * fx;
+                let value = top * (1.0 - fy) + bottom * fy;
+                if value <= 0.0 {
+                    continue;
+                }
+
+                let alpha = value * MAX_ALPHA;
+                let color = colormap(value);
+                let px = out.pixel(x, y);
+                out.set_pixel(
+                    x,
+                    y,
+                    [0, 1, 2].map(|c| {
+                        (px[c] as f32 * (1.0 - alpha) + color[c] as f32 * alpha).round() as u8
+                    }),
+                );
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Renders over the snapshot and encodes it as PNG
+    pub fn render_png(&self, snapshot: &Frame) -> Result<Vec<u8>> {
+        self.render(snapshot)?.to_png()
+    }
+}
+
+/// Blue, cyan, green, yellow, red
+fn colormap(value: f32) -> [u8; 3] {
+    const STOPS: [[f32; 3]; 5] = [
+        [0.0, 0.0, 255.0],
+        [0.0, 255.0, 255.0],
+        [0.0, 255.0, 0.0],
+        [255.0, 255.0, 0.0],
+        [255.0, 0.0, 0.0],
+    ];
+    let pos = value.clamp(0.0, 1.0) * (STOPS.len() - 1) as f32;
+    let i = (pos as usize).min(STOPS.len() - 2);
+    let f = pos - i as f32;
+    [0, 1, 2].map(|c| (STOPS[i][c] * (1.0 - f) + STOPS[i + 1][c] * f) as u8)
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// The window being filled and the last complete one, this is what gets saved
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HeatmapHistory {
+    pub current: Option<Heatmap>,
+    pub previous: Option<Heatmap>,
+}
+
+/// Sums motion masks into heatmaps, starting a new one every `window`
+pub struct HeatmapAccumulator {
+    window: Duration,
+    columns: usize,
+    rows: usize,
+    history: HeatmapHistory,
+}
+
+impl HeatmapAccumulator {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            columns: HEATMAP_COLUMNS,
+            rows: HEATMAP_ROWS,
+            history: HeatmapHistory::default(),
+        }
+    }
+
+    pub fn grid(mut self, columns: usize, rows: usize) -> Self {
+        self.columns = columns.max(1);
+        self.rows = rows.max(1);
+        self
+    }
+
+    /// Continues from what was saved, heatmaps with another grid size are dropped
+    pub fn history(mut self, history: HeatmapHistory) -> Self {
+        let fits = |h: &Option<Heatmap>| {
+            h.as_ref()
+                .filter(|h| h.columns == self.columns && h.rows == self.rows)
+                .cloned()
+        };
+        self.history = HeatmapHistory {
+            current: fits(&history.current),
+            previous: fits(&history.previous),
+        };
+        self
+    }
+
+    pub fn get_history(&self) -> &HeatmapHistory {
+        &self.history
+    }
+
+    pub fn current(&self) -> Option<&Heatmap> {
+        self.history.current.as_ref()
+    }
+
+    pub fn previous(&self) -> Option<&Heatmap> {
+        self.history.previous.as_ref()
+    }
+
+    /// Adds a motion mask, returns true when a window was completed
+    pub fn add(&mut self, mask: &MotionMask, time: SystemTime) -> bool {
+        let now = unix_secs(time);
+        let expired = self.history.current.as_ref().map_or(false, |h| {
+            now.saturating_sub(h.start) >= self.window.as_secs()
+        });
+        if expired {
+            self.history.previous = self.history.current.take();
+        }
+
+        self.history
+            .current
+            .get_or_insert_with(|| Heatmap::new(self.columns, self.rows, time))
+            .add(mask);
+        expired
+    }
+
+    /// Drops both windows and starts over
+    pub fn reset(&mut self) {
+        self.history = HeatmapHistory::default();
+    }
+}
@@ -0,0 +1,1037 @@
+use std::{
+    mem::ManuallyDrop,
+    net::{IpAddr, Ipv4Addr, TcpStream},
+    os::fd::FromRawFd,
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use embedded_svc::{
+    http::server::{HandlerResult, Request},
+    ws::FrameType,
+};
+use esp_idf_hal::{
+    gpio::{AnyOutputPin, Output, PinDriver},
+    io::{Read, Write},
+};
+use esp_idf_svc::{
+    handle::RawHandle,
+    http::{
+        server::{ws::EspHttpWsConnection, Configuration, EspHttpConnection, EspHttpServer},
+        Method,
+    },
+};
+use esp_idf_sys::EspError;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    auth::{AuthError, Authenticator, Role, SharedAuthenticator},
+    compat::{control_settings, CompatStatus},
+    espcam::{framesize_dimensions, Camera, FrameBuffer, SensorSettings},
+    frame::Frame,
+    mdns::Mdns,
+    onvif::{self, Endpoints, MediaProfile, OnvifDevice, TokenPassword},
+    pipeline::FramePipeline,
+    rtsp::{spawn_rtsp_server, RtspConfig},
+    snapshot::{ImageFormat, SnapshotOptions},
+    stream::{query_value, spawn_stream_server, StreamConfig, StreamHub},
+    transform::{rotate, Rotation},
+    ws::{WsClient, WsCommand, WsEvent, WsHub},
+};
+
+/// Biggest request body the JSON routes accept
+const MAX_BODY_LEN: usize = 4096;
+
+/// Time for the exposure to settle once the flash is on
+const FLASH_WARMUP: Duration = Duration::from_millis(150);
+/// Frames still in the driver's queue after the sensor settings changed
+const STALE_FRAMES: usize = 2;
+
+/// `web/index.html`, compressed by the build script
+const PANEL_HTML_GZ: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/index.html.gz"));
+
+pub type FlashLed = PinDriver<'static, AnyOutputPin, Output>;
+
+pub type HttpRequest<'r, 'c> = Request<&'r mut EspHttpConnection<'c>>;
+
+/// Why a route could not answer, each one has its own status code
+#[derive(Debug, Error)]
+pub enum HttpError {
+    #[error("no frame available")]
+    NoFrame,
+    #[error("{0}")]
+    NotFound(&'static str),
+    #[error("bad request: {0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Auth(#[from] AuthError),
+    #[error("camera driver error: {0}")]
+    Driver(#[from] EspError),
+    #[error("{0}")]
+    Internal(#[from] anyhow::Error),
+}
+
+impl HttpError {
+    pub fn status(&self) -> u16 {
+        match self {
+            HttpError::NoFrame => 503,
+            HttpError::BadRequest(_) => 400,
+            HttpError::NotFound(_) => 404,
+            HttpError::Auth(AuthError::Missing | AuthError::Invalid) => 401,
+            HttpError::Auth(AuthError::Forbidden) => 403,
+            HttpError::Auth(AuthError::LockedOut(_)) => 429,
+            HttpError::Driver(_) | HttpError::Internal(_) => 500,
+        }
+    }
+
+    /// Headers the status code calls for
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        match self {
+            HttpError::Auth(AuthError::Missing | AuthError::Invalid) => {
+                vec![("WWW-Authenticate", "Basic realm=\"espcam\"".to_string())]
+            }
+            HttpError::Auth(AuthError::LockedOut(retry)) => {
+                vec![("Retry-After", retry.as_secs().to_string())]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Successful answer of a route
+pub struct Reply {
+    pub content_type: &'static str,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    pub fn new(content_type: &'static str, body: Vec<u8>) -> Self {
+        Self {
+            content_type,
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    pub fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn jpeg(body: Vec<u8>) -> Self {
+        Self::new("image/jpeg", body)
+    }
+
+    pub fn text(text: &str) -> Self {
+        Self::new("text/plain", text.as_bytes().to_vec())
+    }
+
+    pub fn json<T: Serialize>(value: &T) -> Result<Self, HttpError> {
+        Ok(Self::new(
+            "application/json",
+            serde_json::to_vec(value).map_err(anyhow::Error::from)?,
+        ))
+    }
+}
+
+/// Sends the reply, or the error with its status code
+pub fn respond(request: HttpRequest, reply: Result<Reply, HttpError>) -> Result<()> {
+    match reply {
+        Ok(reply) => send_with_headers(
+            request,
+            200,
+            reply.content_type,
+            &reply.headers,
+            &reply.body,
+        ),
+        Err(err) => {
+            if err.status() >= 500 {
+                log::error!("{} error: {:?}", request.uri(), err);
+            }
+            send_with_headers(
+                request,
+                err.status(),
+                "text/plain",
+                &err.headers(),
+                err.to_string().as_bytes(),
+            )
+        }
+    }
+}
+
+pub fn send(request: HttpRequest, status: u16, content_type: &str, body: &[u8]) -> Result<()> {
+    send_with_headers(request, status, content_type, &[], body)
+}
+
+pub fn send_with_headers(
+    request: HttpRequest,
+    status: u16,
+    content_type: &str,
+    extra: &[(&str, String)],
+    body: &[u8],
+) -> Result<()> {
+    let len = body.len().to_string();
+    let mut headers = vec![("Content-Type", content_type), ("Content-Length", &len)];
+    headers.extend(extra.iter().map(|(name, value)| (*name, value.as_str())));
+    let mut response = request.into_response(status, Some(reason(status)), &headers)?;
+    response.write_all(body)?;
+    Ok(())
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Reads the body of the request as json
+pub fn read_json<T: DeserializeOwned>(request: &mut HttpRequest) -> Result<T, HttpError> {
+    let body = read_body(request)?;
+    serde_json::from_slice(&body).map_err(|err| HttpError::BadRequest(err.to_string()))
+}
+
+/// Reads the whole body of the request, up to `MAX_BODY_LEN`
+pub fn read_body(request: &mut HttpRequest) -> Result<Vec<u8>, HttpError> {
+    let mut body = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let len = request
+            .read(&mut buf)
+            .map_err(|err| anyhow::anyhow!("read error: {:?}", err))?;
+        if len == 0 {
+            break;
+        }
+        body.extend_from_slice(&buf[..len]);
+        if body.len() > MAX_BODY_LEN {
+            return Err(HttpError::BadRequest("body too large".to_string()));
+        }
+    }
+    Ok(body)
+}
+
+/// A fresh frame, the first one in the driver's queue can be old
+pub fn capture<'a>(camera: &'a Camera) -> Result<FrameBuffer<'a>, HttpError> {
+    camera.get_framebuffer();
+    camera.get_framebuffer().ok_or(HttpError::NoFrame)
+}
+
+/// The Arduino CameraWebServer status, with a few more keys its clients ignore
+#[derive(Debug, Clone, Serialize)]
+pub struct CameraStatus {
+    #[serde(flatten)]
+    pub sensor: CompatStatus,
+    pub model: Option<&'static str>,
+    pub stream_viewers: usize,
+    /// Largest frame size the camera takes, see [`Camera::max_framesize`]
+    pub max_framesize: i32,
+}
+
+/// Answer of [`CameraServer::stream_ticket`], no ticket when the server has no credentials
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamTicket {
+    pub ticket: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlashState {
+    pub on: bool,
+}
+
+impl CameraStatus {
+    pub fn new(camera: &Camera, hub: &StreamHub) -> Self {
+        let sensor = camera.sensor();
+        Self {
+            sensor: CompatStatus::from_sensor(&sensor),
+            model: sensor.model(),
+            stream_viewers: hub.viewers(),
+            max_framesize: camera.max_framesize() as i32,
+        }
+    }
+}
+
+/// HTTP server with the usual camera routes, every picture goes through the pipeline.
+///
+/// Applications add their own routes with [`CameraServer::route`], which checks the
+/// credentials when an [`Authenticator`] is set, the helpers of this module give them the
+/// same status codes.
+pub struct CameraServer {
+    server: EspHttpServer,
+    camera: Arc<Camera<'static>>,
+    pipeline: FramePipeline,
+    hub: StreamHub,
+    /// Threads of the capture loop, the streams and the discovery
+    streams: Vec<JoinHandle<()>>,
+    ws: WsHub,
+    auth: Option<SharedAuthenticator>,
+    flash_led: Arc<Mutex<Option<FlashLed>>>,
+    /// Held while the sensor settings change, so that a snapshot with options does not put
+    /// back what was set during it, and while frames are taken for the streams so that they
+    /// don't get the size of a snapshot
+    snapshot_lock: Arc<Mutex<()>>,
+    http_port: u16,
+    /// Frame rate of the capture loop, once started
+    capture_fps: Option<u32>,
+    /// Port and path of the RTSP server, for the ONVIF clients
+    rtsp: Option<(u16, &'static str)>,
+    mdns: Option<Mdns>,
+}
+
+impl CameraServer {
+    pub fn new(camera: Arc<Camera<'static>>, pipeline: FramePipeline) -> Result<Self> {
+        Self::with_config(camera, pipeline, &Configuration::default())
+    }
+
+    pub fn with_config(
+        camera: Arc<Camera<'static>>,
+        pipeline: FramePipeline,
+        config: &Configuration,
+    ) -> Result<Self> {
+        let hub = StreamHub::new();
+        let stream = StreamConfig::default();
+        Ok(Self {
+            server: EspHttpServer::new(config)?,
+            camera,
+            pipeline,
+            ws: WsHub::new(hub.clone(), stream.max_viewers, stream.max_fps),
+            hub,
+            streams: Vec::new(),
+            auth: None,
+            flash_led: Arc::new(Mutex::new(None)),
+            snapshot_lock: Arc::new(Mutex::new(())),
+            http_port: config.http_port,
+            capture_fps: None,
+            rtsp: None,
+            mdns: None,
+        })
+    }
+
+    /// Every route asks for credentials, set it before adding them
+    pub fn auth(mut self, auth: Authenticator) -> Self {
+        self.auth = Some(Arc::new(Mutex::new(auth)));
+        self
+    }
+
+    /// The underlying server, routes added there skip the authentication
+    pub fn server(&mut self) -> &mut EspHttpServer {
+        &mut self.server
+    }
+
+    pub fn camera(&self) -> &Arc<Camera<'static>> {
+        &self.camera
+    }
+
+    pub fn pipeline(&self) -> &FramePipeline {
+        &self.pipeline
+    }
+
+    pub fn hub(&self) -> &StreamHub {
+        &self.hub
+    }
+
+    /// WebSocket clients, [`WsHub::publish`] sends them an event
+    pub fn ws(&self) -> &WsHub {
+        &self.ws
+    }
+
+    /// Held while a snapshot has the sensor at its own size or quality. Threads that take
+    /// frames from the camera beside the server hold it for each frame, to keep the size
+    /// they expect
+    pub fn snapshot_lock(&self) -> Arc<Mutex<()>> {
+        self.snapshot_lock.clone()
+    }
+
+    /// Adds a route that only clients with at least `role` reach
+    pub fn route<F>(
+        &mut self,
+        uri: &str,
+        method: Method,
+        role: Role,
+        handler: F,
+    ) -> Result<&mut Self>
+    where
+        F: for<'r, 'c> Fn(HttpRequest<'r, 'c>) -> HandlerResult + Send,
+    {
+        let auth = self.auth.clone();
+        self.server.fn_handler(uri, method, move |request| {
+            match authorize(auth.as_ref(), role, request)? {
+                Some(request) => handler(request),
+                None => Ok(()),
+            }
+        })?;
+        Ok(self)
+    }
+
+    /// The control panel at `/`, `/camera.jpg`, `/status`, `/control`, `/ws` and the stream at
+    /// `:81/stream`, along with `/capture` these are the routes of the Arduino CameraWebServer.
+    /// `/stream/ticket` lets the panel into the stream.
+    pub fn default_routes(&mut self) -> Result<&mut Self> {
+        self.panel("/")?
+            .snapshot("/camera.jpg")?
+            .snapshot("/capture")?
+            .status("/status")?
+            .control("/control")?
+            .websocket("/ws")?
+            .stream_ticket("/stream/ticket")?
+            .stream(81, "/stream", StreamConfig::default())
+    }
+
+    /// GET returns the control panel, it expects the default routes and the stream on port 81
+    pub fn panel(&mut self, uri: &str) -> Result<&mut Self> {
+        self.route(uri, Method::Get, Role::Viewer, |request| {
+            let reply =
+                Reply::new("text/html", PANEL_HTML_GZ.to_vec()).header("Content-Encoding", "gzip");
+            respond(request, Ok(reply))?;
+            Ok(())
+        })?;
+        Ok(self)
+    }
+
+    /// GET returns the [`FlashState`] of the led, POST switches it on or off.
+    /// Snapshots can fire it too once it is set.
+    pub fn flash(&mut self, uri: &str, led: FlashLed) -> Result<&mut Self> {
+        *self.flash_led.lock().unwrap() = Some(led);
+
+        let led = self.flash_led.clone();
+        self.route(uri, Method::Get, Role::Viewer, move |request| {
+            let on = led
+                .lock()
+                .unwrap()
+                .as_ref()
+                .is_some_and(|led| led.is_set_high());
+            respond(request, Reply::json(&FlashState { on }))?;
+            Ok(())
+        })?;
+
+        let led = self.flash_led.clone();
+        self.route(uri, Method::Post, Role::Admin, move |mut request| {
+            let reply = read_json::<FlashState>(&mut request).and_then(|state| {
+                let on = set_flash(&led, state.on)?;
+                Reply::json(&FlashState { on })
+            });
+            respond(request, reply)?;
+            Ok(())
+        })?;
+        Ok(self)
+    }
+
+    /// GET returns a processed picture, the query can ask for another size, quality,
+    /// format or rotation and for the flash, see [`SnapshotOptions`]
+    pub fn snapshot(&mut self, uri: &str) -> Result<&mut Self> {
+        let camera = self.camera.clone();
+        let pipeline = self.pipeline.clone();
+        let led = self.flash_led.clone();
+        let lock = self.snapshot_lock.clone();
+        self.route(uri, Method::Get, Role::Viewer, move |request| {
+            let reply = SnapshotOptions::from_query(request.uri())
+                .map_err(|err| HttpError::BadRequest(err.to_string()))
+                .and_then(|options| {
+                    let _guard = lock.lock().unwrap();
+                    let reply = snapshot(&camera, &pipeline, &led, &options)?;
+                    let filename =
+                        format!("inline; filename=capture.{}", options.format.extension());
+                    Ok(reply
+                        .header("Content-Disposition", filename)
+                        .header("Access-Control-Allow-Origin", "*"))
+                });
+            respond(request, reply)?;
+            Ok(())
+        })?;
+        Ok(self)
+    }
+
+    /// GET returns the sensor settings as [`CameraStatus`] json
+    pub fn status(&mut self, uri: &str) -> Result<&mut Self> {
+        let camera = self.camera.clone();
+        let hub = self.hub.clone();
+        self.route(uri, Method::Get, Role::Viewer, move |request| {
+            let reply = Reply::json(&CameraStatus::new(&camera, &hub))
+                .map(|reply| reply.header("Access-Control-Allow-Origin", "*"));
+            respond(request, reply)?;
+            Ok(())
+        })?;
+        Ok(self)
+    }
+
+    /// POST takes json [`SensorSettings`], applies them and returns the new status.
+    /// GET changes one setting like the Arduino CameraWebServer, `?var=framesize&val=8`
+    pub fn control(&mut self, uri: &str) -> Result<&mut Self> {
+        let camera = self.camera.clone();
+        let hub = self.hub.clone();
+        let ws = self.ws.clone();
+        let lock = self.snapshot_lock.clone();
+        self.route(uri, Method::Post, Role::Admin, move |mut request| {
+            let reply = read_json::<SensorSettings>(&mut request).and_then(|settings| {
+                apply_settings(&camera, &lock, &settings)?;
+                let status = CameraStatus::new(&camera, &hub);
+                ws.publish(&WsEvent::Status(status.clone()));
+                Reply::json(&status)
+            });
+            respond(request, reply)?;
+            Ok(())
+        })?;
+
+        let camera = self.camera.clone();
+        let hub = self.hub.clone();
+        let ws = self.ws.clone();
+        let lock = self.snapshot_lock.clone();
+        self.route(uri, Method::Get, Role::Admin, move |request| {
+            let reply = compat_control(request.uri()).and_then(|settings| {
+                apply_settings(&camera, &lock, &settings)?;
+                ws.publish(&WsEvent::Status(CameraStatus::new(&camera, &hub)));
+                Ok(Reply::new("text/plain", Vec::new()).header("Access-Control-Allow-Origin", "*"))
+            });
+            respond(request, reply)?;
+            Ok(())
+        })?;
+        Ok(self)
+    }
+
+    /// WebSocket pushing the stream as binary JPEG messages and [`WsEvent`] json, it takes
+    /// [`WsCommand`] json. When the server asks for credentials, nothing is sent before
+    /// the client logs in with the auth command.
+    pub fn websocket(&mut self, uri: &str) -> Result<&mut Self> {
+        let context = WsContext {
+            camera: self.camera.clone(),
+            pipeline: self.pipeline.clone(),
+            hub: self.hub.clone(),
+            ws: self.ws.clone(),
+            auth: self.auth.clone(),
+            led: self.flash_led.clone(),
+            lock: self.snapshot_lock.clone(),
+        };
+
+        self.server
+            .ws_handler(uri, move |conn: &mut EspHttpWsConnection| -> Result<()> {
+                let session = conn.session();
+                if conn.is_new() {
+                    let role = context.auth.is_none().then_some(Role::Admin);
+                    let sender = conn.create_detached_sender()?;
+                    if let Err(err) = context.ws.connect(session, sender, role) {
+                        log::warn!("websocket refused: {:?}", err);
+                        conn.send(FrameType::Close, &[])?;
+                    }
+                    return Ok(());
+                }
+                if conn.is_closed() {
+                    context.ws.disconnect(session);
+                    return Ok(());
+                }
+                let Some(client) = context.ws.client(session) else {
+                    return Ok(());
+                };
+
+                let (_, len) = conn.recv(&mut [])?;
+                if len > MAX_BODY_LEN {
+                    send_ws_event(conn, &ws_error("message too large"))?;
+                    conn.send(FrameType::Close, &[])?;
+                    return Ok(());
+                }
+                let mut message = vec![0; len];
+                let (FrameType::Text(_), _) = conn.recv(&mut message)? else {
+                    return Ok(());
+                };
+
+                let result = serde_json::from_slice::<WsCommand>(&message)
+                    .map_err(|err| HttpError::BadRequest(err.to_string()))
+                    .and_then(|command| context.run(conn, &client, command));
+                match result {
+                    Ok(Some(event)) => send_ws_event(conn, &event),
+                    Ok(None) => Ok(()),
+                    Err(err) => send_ws_event(conn, &ws_error(err)),
+                }
+            })?;
+        Ok(self)
+    }
+
+    /// GET returns a [`StreamTicket`], which the stream takes as `?token=` in place of the
+    /// credentials the browser only sends to this server, see [`Authenticator::issue_ticket`]
+    pub fn stream_ticket(&mut self, uri: &str) -> Result<&mut Self> {
+        let auth = self.auth.clone();
+        self.route(uri, Method::Get, Role::Viewer, move |mut request| {
+            let peer = request_peer(&mut request);
+            let ticket = auth.as_ref().map(|auth| {
+                let mut auth = auth.lock().unwrap();
+                let now = Instant::now();
+                // the route already checked the credentials, this is for their role
+                let role = auth.authenticate(peer, request.header("Authorization"), now)?;
+                Ok::<_, HttpError>(auth.issue_ticket(role, now))
+            });
+            let reply = ticket
+                .transpose()
+                .and_then(|ticket| Reply::json(&StreamTicket { ticket }));
+            respond(request, reply)?;
+            Ok(())
+        })?;
+        Ok(self)
+    }
+
+    /// MJPEG stream on its own port, see [`spawn_stream_server`]. It asks for the
+    /// credentials of the server unless the config has its own
+    pub fn stream(
+        &mut self,
+        port: u16,
+        path: &'static str,
+        config: StreamConfig,
+    ) -> Result<&mut Self> {
+        self.start_capture(config.max_fps)?;
+        let config = StreamConfig {
+            auth: config.auth.or_else(|| self.auth.clone()),
+            ..config
+        };
+        self.streams
+            .push(spawn_stream_server(port, path, self.hub.clone(), config)?);
+        Ok(self)
+    }
+
+    /// RTSP server for NVRs and players, see [`spawn_rtsp_server`]. It shares the capture
+    /// loop of the MJPEG stream and the credentials of the server unless the config has
+    /// its own
+    pub fn rtsp(&mut self, port: u16, path: &'static str, config: RtspConfig) -> Result<&mut Self> {
+        self.start_capture(config.max_fps)?;
+        let config = RtspConfig {
+            auth: config.auth.or_else(|| self.auth.clone()),
+            ..config
+        };
+        self.streams
+            .push(spawn_rtsp_server(port, path, self.hub.clone(), config)?);
+        self.rtsp = Some((port, path));
+        Ok(self)
+    }
+
+    /// ONVIF device and media services with WS-Discovery, so that NVRs find the camera
+    /// and its snapshot and RTSP URLs on their own. Add it after the RTSP server, the
+    /// snapshots are taken from `/capture`.
+    ///
+    /// The services ask for the credentials of the server, as HTTP Basic or as a
+    /// WS-Security token. Password digests are only checked for the ONVIF user of
+    /// [`AuthConfig`](crate::auth::AuthConfig), the only one whose password is known.
+    pub fn onvif(&mut self, device: OnvifDevice) -> Result<&mut Self> {
+        let context = Arc::new(OnvifContext {
+            camera: self.camera.clone(),
+            auth: self.auth.clone(),
+            device: device.clone(),
+            rtsp: self.rtsp,
+            fps: self.capture_fps.unwrap_or(StreamConfig::default().max_fps),
+        });
+        for uri in [onvif::DEVICE_SERVICE, onvif::MEDIA_SERVICE] {
+            let context = context.clone();
+            self.server
+                .fn_handler(uri, Method::Post, move |request| context.handle(request))?;
+        }
+        self.streams
+            .push(onvif::spawn_discovery(device, self.http_port)?);
+        Ok(self)
+    }
+
+    /// Advertises the camera as `<hostname>.local` with mDNS, along with its `_http._tcp`
+    /// service and `_rtsp._tcp` when the RTSP server is on, so add it last. The TXT records
+    /// carry the sensor model and the firmware version.
+    pub fn mdns(&mut self, hostname: &str, instance: &str) -> Result<&mut Self> {
+        let model = self.camera.sensor().model().unwrap_or("unknown");
+        let firmware = env!("CARGO_PKG_VERSION");
+        let mut mdns = Mdns::new(hostname, instance)?;
+        mdns.advertise(
+            "_http",
+            "_tcp",
+            self.http_port,
+            &[("path", "/"), ("model", model), ("firmware", firmware)],
+        )?;
+        if let Some((port, path)) = self.rtsp {
+            mdns.advertise(
+                "_rtsp",
+                "_tcp",
+                port,
+                &[("path", path), ("model", model), ("firmware", firmware)],
+            )?;
+        }
+        self.mdns = Some(mdns);
+        Ok(self)
+    }
+
+    /// The mDNS advertisement, to add more services to it
+    pub fn mdns_mut(&mut self) -> Option<&mut Mdns> {
+        self.mdns.as_mut()
+    }
+
+    /// Capture loop feeding the hub, started by the first stream at its frame rate
+    fn start_capture(&mut self, max_fps: u32) -> Result<()> {
+        if self.capture_fps.is_some() {
+            return Ok(());
+        }
+        self.capture_fps = Some(max_fps);
+        let camera = self.camera.clone();
+        let pipeline = self.pipeline.clone();
+        let hub = self.hub.clone();
+        let lock = self.snapshot_lock.clone();
+        self.streams.push(
+            std::thread::Builder::new()
+                .stack_size(16 * 1024)
+                .spawn(move || {
+                    hub.run(max_fps, || {
+                        let framebuffer = {
+                            let _guard = lock.lock().unwrap();
+                            camera.get_framebuffer().ok_or(HttpError::NoFrame)?
+                        };
+                        pipeline.process_capture(
+                            framebuffer.data(),
+                            SystemTime::now(),
+                            &camera.sensor().exif(),
+                        )
+                    })
+                })?,
+        );
+        Ok(())
+    }
+}
+
+fn ws_error(err: impl std::fmt::Display) -> WsEvent {
+    WsEvent::Error {
+        message: err.to_string(),
+    }
+}
+
+fn send_ws_event(conn: &mut EspHttpWsConnection, event: &WsEvent) -> Result<()> {
+    conn.send(FrameType::Text(false), &serde_json::to_vec(event)?)?;
+    Ok(())
+}
+
+/// What the commands of the WebSocket clients work on
+struct WsContext {
+    camera: Arc<Camera<'static>>,
+    pipeline: FramePipeline,
+    hub: StreamHub,
+    ws: WsHub,
+    auth: Option<SharedAuthenticator>,
+    led: Arc<Mutex<Option<FlashLed>>>,
+    lock: Arc<Mutex<()>>,
+}
+
+impl WsContext {
+    fn status(&self) -> WsEvent {
+        WsEvent::Status(CameraStatus::new(&self.camera, &self.hub))
+    }
+
+    /// Runs a command of a client, returns the event that answers it
+    fn run(
+        &self,
+        conn: &mut EspHttpWsConnection,
+        client: &WsClient,
+        command: WsCommand,
+    ) -> Result<Option<WsEvent>, HttpError> {
+        if let WsCommand::Auth { authorization } = &command {
+            if let Some(auth) = &self.auth {
+                let role = auth.lock().unwrap().authenticate(
+                    socket_peer(conn.session()),
+                    Some(authorization),
+                    Instant::now(),
+                )?;
+                client.set_role(role);
+            }
+            return Ok(Some(self.status()));
+        }
+
+        match client.role() {
+            None => return Err(AuthError::Missing.into()),
+            Some(role) if role < command.role() => return Err(AuthError::Forbidden.into()),
+            Some(_) => {}
+        }
+
+        match command {
+            WsCommand::Auth { .. } | WsCommand::Status => Ok(Some(self.status())),
+            WsCommand::Settings(settings) => {
+                apply_settings(&self.camera, &self.lock, &settings)?;
+                // every client hears about it, this one included
+                self.ws.publish(&self.status());
+                Ok(None)
+            }
+            WsCommand::Capture { options } => {
+                let options = SnapshotOptions::from_query(&format!("?{}", options))
+                    .map_err(|err| HttpError::BadRequest(err.to_string()))?;
+                let reply = {
+                    let _guard = self.lock.lock().unwrap();
+                    snapshot(&self.camera, &self.pipeline, &self.led, &options)?
+                };
+                // through the push loop, so that no stream frame gets between the two
+                client.push_picture(reply.content_type, reply.body)?;
+                Ok(None)
+            }
+            WsCommand::Stream { fps } => {
+                client.set_fps(fps.min(self.ws.max_fps()));
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// What the ONVIF services answer from
+struct OnvifContext {
+    camera: Arc<Camera<'static>>,
+    auth: Option<SharedAuthenticator>,
+    device: OnvifDevice,
+    rtsp: Option<(u16, &'static str)>,
+    fps: u32,
+}
+
+impl OnvifContext {
+    fn handle(&self, mut request: HttpRequest) -> HandlerResult {
+        let body = match read_body(&mut request) {
+            Ok(body) => body,
+            Err(err) => {
+                respond(request, Err(err))?;
+                return Ok(());
+            }
+        };
+        let envelope = String::from_utf8_lossy(&body);
+
+        let action = onvif::soap_action(&envelope).unwrap_or("");
+        if let (Some(auth), false) = (&self.auth, onvif::is_pre_auth(action)) {
+            // NVRs send the credentials in the SOAP header, or over HTTP once refused
+            let now = Instant::now();
+            let peer = request_peer(&mut request);
+            let header = request.header("Authorization");
+            let mut auth = auth.lock().unwrap();
+            let result = match onvif::username_token(&envelope).filter(|_| header.is_none()) {
+                Some((
+                    user,
+                    TokenPassword::Digest {
+                        digest,
+                        nonce,
+                        created,
+                    },
+                )) => {
+                    // any role may use the services
+                    auth.authenticate_digest(peer, &user, &digest, &nonce, &created, now)
+                }
+                Some((user, TokenPassword::Text(password))) => {
+                    let basic =
+                        format!("Basic {}", BASE64.encode(format!("{}:{}", user, password)));
+                    auth.authorize(peer, Some(&basic), Role::Viewer, now)
+                }
+                None => auth.authorize(peer, header, Role::Viewer, now),
+            };
+            drop(auth);
+            if let Err(err) = result {
+                log::info!("onvif {} refused: {}", action, err);
+                respond(request, Err(err.into()))?;
+                return Ok(());
+            }
+        }
+
+        let Some(host) = request.header("Host").map(str::to_string) else {
+            let err = HttpError::BadRequest("no Host header".to_string());
+            respond(request, Err(err))?;
+            return Ok(());
+        };
+        let endpoints = Endpoints {
+            host,
+            snapshot_path: "/capture",
+            rtsp: self.rtsp,
+        };
+        let status = self.camera.sensor().status();
+        let (width, height) = framesize_dimensions(status.framesize).unwrap_or((0, 0));
+        let profile = MediaProfile {
+            width,
+            height,
+            fps: self.fps,
+            // the sensor goes from 0, the best, to 63
+            quality: 100.0 - status.quality.min(63) as f32 * 100.0 / 63.0,
+        };
+
+        let answer = onvif::respond(
+            &envelope,
+            &self.device,
+            &endpoints,
+            &profile,
+            SystemTime::now(),
+        );
+        let (status, xml) = match answer {
+            Ok(xml) => (200, xml),
+            Err(fault) => {
+                log::info!("onvif fault: {}", fault.reason);
+                (fault.status(), fault.to_xml())
+            }
+        };
+        send(request, status, onvif::CONTENT_TYPE, xml.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Hands the request back when its credentials are good enough for `role`,
+/// answers it otherwise
+fn authorize<'r, 'c>(
+    auth: Option<&SharedAuthenticator>,
+    role: Role,
+    mut request: HttpRequest<'r, 'c>,
+) -> Result<Option<HttpRequest<'r, 'c>>> {
+    let Some(auth) = auth else {
+        return Ok(Some(request));
+    };
+    let peer = request_peer(&mut request);
+    let result =
+        auth.lock()
+            .unwrap()
+            .authorize(peer, request.header("Authorization"), role, Instant::now());
+    match result {
+        Ok(_) => Ok(Some(request)),
+        Err(err) => {
+            log::info!("{} refused: {}", request.uri(), err);
+            respond(request, Err(err.into()))?;
+            Ok(None)
+        }
+    }
+}
+
+/// Address of the client on this socket, failed logins are counted per address
+fn socket_peer(fd: i32) -> IpAddr {
+    // the server owns the socket, it must stay open
+    let stream = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(fd) });
+    stream
+        .peer_addr()
+        .map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |addr| addr.ip())
+}
+
+fn request_peer(request: &mut HttpRequest) -> IpAddr {
+    socket_peer(unsafe { esp_idf_sys::httpd_req_to_sockfd(request.connection().handle()) })
+}
+
+fn compat_control(uri: &str) -> Result<SensorSettings, HttpError> {
+    let (Some(var), Some(val)) = (query_value(uri, "var"), query_value(uri, "val")) else {
+        return Err(HttpError::BadRequest("var and val are needed".to_string()));
+    };
+    let val = val
+        .parse()
+        .map_err(|_| HttpError::BadRequest(format!("invalid value {}", val)))?;
+    control_settings(var, val)
+        .ok_or_else(|| HttpError::BadRequest(format!("unknown variable {}", var)))
+}
+
+/// Refuses frame sizes that would not fit the frame buffers of the camera
+fn check_settings(camera: &Camera, settings: &SensorSettings) -> Result<(), HttpError> {
+    match settings.framesize {
+        Some(framesize) if !camera.fits(framesize) => Err(HttpError::BadRequest(format!(
+            "frame size {} is larger than the {} the camera was set up with",
+            framesize,
+            camera.max_framesize()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Changes the sensor settings, waiting for a snapshot with options to put its own back
+fn apply_settings(
+    camera: &Camera,
+    lock: &Mutex<()>,
+    settings: &SensorSettings,
+) -> Result<(), HttpError> {
+    check_settings(camera, settings)?;
+    let _guard = lock.lock().unwrap();
+    settings.apply(&camera.sensor())?;
+    Ok(())
+}
+
+/// Switches the led, returns whether it is on
+fn set_flash(led: &Mutex<Option<FlashLed>>, on: bool) -> Result<bool, HttpError> {
+    let mut led = led.lock().unwrap();
+    let Some(led) = led.as_mut() else {
+        return Err(HttpError::NotFound("no flash led"));
+    };
+    if on {
+        led.set_high()?;
+    } else {
+        led.set_low()?;
+    }
+    Ok(led.is_set_high())
+}
+
+/// Takes a picture with `options`, the sensor settings and the flash are put back after.
+/// The caller holds the snapshot lock.
+fn snapshot(
+    camera: &Camera,
+    pipeline: &FramePipeline,
+    led: &Mutex<Option<FlashLed>>,
+    options: &SnapshotOptions,
+) -> Result<Reply, HttpError> {
+    if options.is_plain() {
+        return encode(camera, pipeline, options, capture(camera)?);
+    }
+    check_settings(camera, &options.sensor_settings())?;
+
+    let sensor = camera.sensor();
+    let previous = sensor.status();
+    let restore = SensorSettings {
+        framesize: options.size.map(|_| previous.framesize),
+        quality: options.quality.map(|_| previous.quality as i32),
+        ..Default::default()
+    };
+    let flash_was_on = led
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|l| l.is_set_high());
+
+    let result = (|| -> Result<Reply, HttpError> {
+        options.sensor_settings().apply(&sensor)?;
+        if options.flash {
+            set_flash(led, true)?;
+            std::thread::sleep(FLASH_WARMUP);
+        }
+        for _ in 0..STALE_FRAMES {
+            camera.get_framebuffer();
+        }
+        encode(camera, pipeline, options, capture(camera)?)
+    })();
+
+    if options.flash && !flash_was_on {
+        if let Err(err) = set_flash(led, false) {
+            log::error!("could not switch the flash off: {:?}", err);
+        }
+    }
+    if let Err(err) = restore.apply(&sensor) {
+        log::error!("could not restore the sensor settings: {:?}", err);
+    }
+    // frames of the snapshot's size are still queued, they must not reach the streams
+    if restore.framesize.is_some() {
+        for _ in 0..STALE_FRAMES {
+            camera.get_framebuffer();
+        }
+    }
+    result
+}
+
+fn encode(
+    camera: &Camera,
+    pipeline: &FramePipeline,
+    options: &SnapshotOptions,
+    framebuffer: FrameBuffer,
+) -> Result<Reply, HttpError> {
+    let time = SystemTime::now();
+    let exif = camera.sensor().exif();
+    if !options.needs_decoding() {
+        let data = pipeline.process_capture(framebuffer.data(), time, &exif)?;
+        return Ok(Reply::jpeg(data));
+    }
+
+    let frame = Frame::decode_jpeg(framebuffer.data())?;
+    drop(framebuffer);
+    let mut frame = pipeline.apply(&frame, time)?;
+    if options.rotate != Rotation::None {
+        frame = rotate(&frame, options.rotate)?;
+    }
+    let data = match options.format {
+        ImageFormat::Jpeg => pipeline.encode(&frame, time, &exif)?,
+        ImageFormat::Png => frame.to_png()?,
+    };
+    Ok(Reply::new(options.format.content_type(), data))
+}
@@ -1,4 +1,35 @@
+pub mod animation;
+pub mod auth;
+pub mod avi;
 pub mod ble;
+pub mod clip;
+pub mod color;
+pub mod compat;
 pub mod config;
+pub mod counting;
 pub mod espcam;
+pub mod events;
+pub mod exif;
+pub mod focus;
+pub mod frame;
+pub mod heatmap;
+pub mod http;
+pub mod mdns;
+pub mod motion;
+pub mod night;
+pub mod onvif;
+pub mod overlay;
+pub mod phash;
+pub mod pipeline;
+pub mod privacy;
+pub mod rtp;
+pub mod rtsp;
+pub mod scan;
+pub mod snapshot;
+pub mod stats;
+pub mod storage;
+pub mod stream;
+pub mod tamper;
+pub mod transform;
 pub mod wifi_handler;
+pub mod ws;
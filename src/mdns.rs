@@ -0,0 +1,53 @@
+use anyhow::{bail, Result};
+use esp_idf_svc::mdns::EspMdns;
+
+/// Advertises the camera as `<hostname>.local` along with its services, they are
+/// withdrawn when dropped
+pub struct Mdns {
+    mdns: EspMdns,
+    instance: String,
+}
+
+impl Mdns {
+    /// `hostname` is a single DNS label, like `espcam-kitchen`, a `.local` suffix is dropped.
+    /// `instance` is the name browsers and NVRs show for the services.
+    pub fn new(hostname: &str, instance: &str) -> Result<Self> {
+        let hostname = hostname_label(hostname)?;
+        let mut mdns = EspMdns::take()?;
+        mdns.set_hostname(&hostname)?;
+        mdns.set_instance_name(instance)?;
+        log::info!("advertising as {}.local", hostname);
+        Ok(Self {
+            mdns,
+            instance: instance.to_string(),
+        })
+    }
+
+    /// Adds a DNS-SD service like `_http._tcp`, with its TXT records
+    pub fn advertise(
+        &mut self,
+        service: &str,
+        protocol: &str,
+        port: u16,
+        txt: &[(&str, &str)],
+    ) -> Result<()> {
+        self.mdns
+            .add_service(Some(&self.instance), service, protocol, port, txt)?;
+        Ok(())
+    }
+}
+
+/// The hostname as a DNS label, letters, digits and dashes only
+pub fn hostname_label(hostname: &str) -> Result<String> {
+    let label = hostname.trim().trim_end_matches('.');
+    let label = label.strip_suffix(".local").unwrap_or(label);
+    if label.is_empty()
+        || label.len() > 63
+        || label.starts_with('-')
+        || label.ends_with('-')
+        || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        bail!("{:?} is not a valid hostname", hostname);
+    }
+    Ok(label.to_ascii_lowercase())
+}
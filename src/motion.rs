@@ -0,0 +1,179 @@
+use serde::Serialize;
+
+use crate::{focus::LumaPlane, frame::Frame};
+
+#[derive(Debug, Clone)]
+pub struct MotionConfig {
+    /// Longest side of the luma plane the differencing works on
+    pub plane_side: usize,
+    /// Luma difference from the background for a pixel to be moving
+    pub threshold: u8,
+    /// How fast the background follows the scene, 0.0 - 1.0 per frame
+    pub learning_rate: f32,
+    /// Smallest blob, as a fraction of the frame area
+    pub min_blob_area: f32,
+}
+
+impl Default for MotionConfig {
+    fn default() -> Self {
+        Self {
+            plane_side: 160,
+            threshold: 25,
+            learning_rate: 0.05,
+            min_blob_area: 0.005,
+        }
+    }
+}
+
+/// Pixels of the luma plane that differ from the background, row by row
+#[derive(Debug, Clone)]
+pub struct MotionMask {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<bool>,
+}
+
+impl MotionMask {
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        self.data[x + y * self.width]
+    }
+}
+
+/// Connected area of moving pixels, relative to the frame size
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Blob {
+    pub x: f32,
+    pub y: f32,
+    pub w: f32,
+    pub h: f32,
+    pub centroid: (f32, f32),
+    /// Fraction of the frame covered by the blob
+    pub area: f32,
+}
+
+/// Frame differencing against a running average of the scene
+pub struct MotionDetector {
+    config: MotionConfig,
+    background: Vec<f32>,
+    width: usize,
+    height: usize,
+}
+
+impl MotionDetector {
+    pub fn new(config: MotionConfig) -> Self {
+        Self {
+            config,
+            background: Vec::new(),
+            width: 0,
+            height: 0,
+        }
+    }
+
+    /// Feeds a raw frame and returns what moved, the first frame only sets the background
+    pub fn update(&mut self, frame: &Frame) -> Option<MotionMask> {
+        let plane = LumaPlane::from_frame(frame, self.config.plane_side);
+        if plane.width != self.width || plane.height != self.height {
+            self.width = plane.width;
+            self.height = plane.height;
+            self.background = plane.data.iter().map(|&v| v as f32).collect();
+            return None;
+        }
+
+        let threshold = self.config.threshold as f32;
+        let rate = self.config.learning_rate;
+        let mut mask = vec![false; plane.data.len()];
+        for ((moving, bg), &v) in mask.iter_mut().zip(&mut self.background).zip(&plane.data) {
+            let v = v as f32;
+            *moving = (v - *bg).abs() > threshold;
+            // objects that stop moving fade into the background, just more slowly
+            *bg += (v - *bg) * if *moving { rate * 0.1 } else { rate };
+        }
+
+        Some(MotionMask {
+            width: self.width,
+            height: self.height,
+            data: mask,
+        })
+    }
+
+    /// 8-connected components of the mask, smaller than
+    /// [`MotionConfig::min_blob_area`] are dropped
+    pub fn blobs(&self, mask: &MotionMask) -> Vec<Blob> {
+        let (width, height) = (mask.width, mask.height);
+        let total = (width * height) as f32;
+        let min_area = (self.config.min_blob_area * total).max(1.0) as usize;
+
+        let mask = dilate(mask);
+        let mut seen = vec![false; mask.len()];
+        let mut stack = Vec::new();
+        let mut blobs = Vec::new();
+
+        for start in 0..mask.len() {
+            if !mask[start] || seen[start] {
+                continue;
+            }
+            seen[start] = true;
+            stack.push(start);
+
+            let (mut x0, mut y0, mut x1, mut y1) = (width, height, 0, 0);
+            let (mut sum_x, mut sum_y, mut area) = (0usize, 0usize, 0usize);
+            while let Some(i) = stack.pop() {
+                let (x, y) = (i % width, i / width);
+                x0 = x0.min(x);
+                y0 = y0.min(y);
+                x1 = x1.max(x);
+                y1 = y1.max(y);
+                sum_x += x;
+                sum_y += y;
+                area += 1;
+
+                for ny in y.saturating_sub(1)..(y + 2).min(height) {
+                    for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                        let n = nx + ny * width;
+                        if mask[n] && !seen[n] {
+                            seen[n] = true;
+                            stack.push(n);
+                        }
+                    }
+                }
+            }
+
+            if area < min_area {
+                continue;
+            }
+            let (w, h) = (width as f32, height as f32);
+            blobs.push(Blob {
+                x: x0 as f32 / w,
+                y: y0 as f32 / h,
+                w: (x1 + 1 - x0) as f32 / w,
+                h: (y1 + 1 - y0) as f32 / h,
+                centroid: (
+                    (sum_x as f32 / area as f32 + 0.5) / w,
+                    (sum_y as f32 / area as f32 + 0.5) / h,
+                ),
+                area: area as f32 / total,
+            });
+        }
+
+        blobs
+    }
+}
+
+/// Joins the fragments of the same object, differencing leaves holes where it has flat colours
+fn dilate(mask: &MotionMask) -> Vec<bool> {
+    let (width, height) = (mask.width, mask.height);
+    let mut out = vec![false; mask.data.len()];
+    for y in 0..height {
+        for x in 0..width {
+            if !mask.get(x, y) {
+                continue;
+            }
+            for ny in y.saturating_sub(1)..(y + 2).min(height) {
+                for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                    out[nx + ny * width] = true;
+                }
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,390 @@
+use std::borrow::Cow;
+
+use anyhow::{bail, Result};
+
+use crate::{
+    espcam::Camera,
+    focus::LumaPlane,
+    frame::{Frame, PixelFormat},
+};
+
+/// Size of the luma planes used to find the shift between frames
+const ALIGN_SIDE: usize = 160;
+/// Largest shift searched for, in plane pixels
+const MAX_SHIFT: isize = 8;
+/// Size of the weight maps of the exposure fusion, they are smooth on purpose to avoid halos
+const WEIGHT_SIDE: usize = 40;
+/// Frames thrown away after changing the exposure, the sensor needs a moment to apply it
+const SETTLE_FRAMES: usize = 2;
+/// Most frames a median stack takes, every one of them is kept decoded
+pub const MAX_MEDIAN_FRAMES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackMode {
+    /// Average of the frames, only one frame is decoded at a time
+    Mean,
+    /// Per pixel median, removes outliers like passing lights but keeps every frame decoded,
+    /// up to [`MAX_MEDIAN_FRAMES`]
+    Median,
+}
+
+/// Manual exposure, in the OV2640 ranges
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exposure {
+    /// Exposure time, 0 to 1200
+    pub aec_value: i32,
+    /// Analog gain, 0 to 30
+    pub agc_gain: i32,
+}
+
+/// Captures one frame for each exposure with automatic exposure and gain turned off,
+/// they are restored afterwards. Frames are kept as JPEG.
+pub fn capture_frames(camera: &Camera, exposures: &[Exposure]) -> Result<Vec<Frame>> {
+    let sensor = camera.sensor();
+    sensor.set_exposure_ctrl(false)?;
+    sensor.set_gain_ctrl(false)?;
+
+    let mut frames = Vec::with_capacity(exposures.len());
+    let mut current = None;
+    let result = exposures.iter().try_for_each(|exposure| {
+        if current != Some(*exposure) {
+            sensor.set_aec_value(exposure.aec_value)?;
+            sensor.set_agc_gain(exposure.agc_gain)?;
+            current = Some(*exposure);
+            for _ in 0..SETTLE_FRAMES {
+                camera.get_framebuffer();
+            }
+        }
+
+        match camera.get_framebuffer().and_then(|fb| fb.to_frame()) {
+            Some(frame) => {
+                frames.push(frame);
+                Ok(())
+            }
+            None => bail!("no framebuffer"),
+        }
+    });
+
+    sensor.set_exposure_ctrl(true)?;
+    sensor.set_gain_ctrl(true)?;
+    result?;
+    Ok(frames)
+}
+
+/// Low light still: `count` frames with the same long exposure, aligned and stacked
+pub fn night_shot(
+    camera: &Camera,
+    count: usize,
+    exposure: Exposure,
+    mode: StackMode,
+) -> Result<Frame> {
+    stack(&capture_frames(camera, &vec![exposure; count])?, mode)
+}
+
+/// High contrast still: one frame per exposure, merged with [`fuse_exposures`]
+pub fn bracket_shot(camera: &Camera, exposures: &[Exposure]) -> Result<Frame> {
+    fuse_exposures(&capture_frames(camera, exposures)?)
+}
+
+/// Global translation that best maps `plane` onto `reference`, in plane pixels:
+/// `reference(x, y)` matches `plane(x + dx, y + dy)`
+pub fn align(reference: &LumaPlane, plane: &LumaPlane, max_shift: isize) -> (isize, isize) {
+    let width = reference.width.min(plane.width) as isize;
+    let height = reference.height.min(plane.height) as isize;
+    if width <= 2 * max_shift || height <= 2 * max_shift {
+        return (0, 0);
+    }
+
+    let mut best = (0, 0);
+    let mut best_cost = u64::MAX;
+    for dy in -max_shift..=max_shift {
+        for dx in -max_shift..=max_shift {
+            let mut cost = 0u64;
+            for y in max_shift..height - max_shift {
+                for x in max_shift..width - max_shift {
+                    let a = reference.get(x as usize, y as usize);
+                    let b = plane.get((x + dx) as usize, (y + dy) as usize);
+                    cost += a.abs_diff(b) as u64;
+                }
+            }
+            // prefer no movement on ties, flat scenes should not drift
+            if cost < best_cost || (cost == best_cost && dx == 0 && dy == 0) {
+                best_cost = cost;
+                best = (dx, dy);
+            }
+        }
+    }
+    best
+}
+
+/// Searches around `guess`, comparing one pixel out of four along both axes
+/// and ignoring `margin` pixels on each side
+fn refine(
+    reference: &LumaPlane,
+    plane: &LumaPlane,
+    guess: (isize, isize),
+    radius: isize,
+    margin: isize,
+) -> (isize, isize) {
+    let width = reference.width.min(plane.width) as isize;
+    let height = reference.height.min(plane.height) as isize;
+    let margin = margin + radius;
+    if width <= 2 * margin || height <= 2 * margin {
+        return guess;
+    }
+
+    let mut best = guess;
+    let mut best_cost = u64::MAX;
+    for dy in guess.1 - radius..=guess.1 + radius {
+        for dx in guess.0 - radius..=guess.0 + radius {
+            let mut cost = 0u64;
+            for y in (margin..height - margin).step_by(4) {
+                for x in (margin..width - margin).step_by(4) {
+                    let a = reference.get(x as usize, y as usize);
+                    let b = plane.get((x + dx) as usize, (y + dy) as usize);
+                    cost += a.abs_diff(b) as u64;
+                }
+            }
+            if cost < best_cost {
+                best_cost = cost;
+                best = (dx, dy);
+            }
+        }
+    }
+    best
+}
+
+/// Aligns every frame on the first one and merges them, the result is RGB888.
+///
+/// Frames can be JPEG, they are decoded when needed.
+pub fn stack(frames: &[Frame], mode: StackMode) -> Result<Frame> {
+    let Some(first) = frames.first() else {
+        bail!("no frames to stack");
+    };
+    let first = raw(first)?;
+    let (width, height) = (first.width(), first.height());
+    let reference = LumaPlane::from_frame(&first, ALIGN_SIDE);
+    let step = (width / reference.width.max(1)).max(1) as isize;
+    let full_reference = LumaPlane::from_frame(&first, width.max(height));
+
+    // coarse search on the small planes, then refined to the pixel at full resolution
+    let shift = |frame: &Frame| {
+        let (dx, dy) = align(
+            &reference,
+            &LumaPlane::from_frame(frame, ALIGN_SIDE),
+            MAX_SHIFT,
+        );
+        refine(
+            &full_reference,
+            &LumaPlane::from_frame(frame, width.max(height)),
+            (dx * step, dy * step),
+            step - 1,
+            (MAX_SHIFT + 1) * step,
+        )
+    };
+    let check_size = |frame: &Frame| {
+        if (frame.width(), frame.height()) != (width, height) {
+            bail!("frames to stack must have the same size");
+        }
+        Ok(())
+    };
+
+    match mode {
+        StackMode::Mean => {
+            if frames.len() > 257 {
+                bail!("at most 257 frames can be averaged");
+            }
+
+            let mut sums = vec![0u16; width * height * 3];
+            accumulate(&mut sums, &first, (0, 0));
+            drop(first);
+
+            for frame in &frames[1..] {
+                let frame = raw(frame)?;
+                check_size(&frame)?;
+                accumulate(&mut sums, &frame, shift(&frame));
+            }
+
+            let n = frames.len() as u16;
+            let data = sums.iter().map(|s| ((s + n / 2) / n) as u8).collect();
+            Ok(Frame::new(data, width, height, PixelFormat::Rgb888))
+        }
+        StackMode::Median => {
+            if frames.len() > MAX_MEDIAN_FRAMES {
+                bail!("at most {} frames can be median stacked", MAX_MEDIAN_FRAMES);
+            }
+
+            let mut aligned = vec![(first.into_owned(), (0, 0))];
+            for frame in &frames[1..] {
+                let frame = raw(frame)?.into_owned();
+                check_size(&frame)?;
+                let offset = shift(&frame);
+                aligned.push((frame, offset));
+            }
+
+            let mut out = Frame::new(
+                vec![0; width * height * 3],
+                width,
+                height,
+                PixelFormat::Rgb888,
+            );
+            let mut channels = [[0u8; MAX_MEDIAN_FRAMES]; 3];
+            let n = aligned.len();
+            for y in 0..height {
+                for x in 0..width {
+                    for (i, (frame, offset)) in aligned.iter().enumerate() {
+                        let rgb = shifted_pixel(frame, x, y, *offset);
+                        for (channel, value) in channels.iter_mut().zip(rgb) {
+                            channel[i] = value;
+                        }
+                    }
+                    let mut rgb = [0; 3];
+                    for (out, channel) in rgb.iter_mut().zip(&mut channels) {
+                        let channel = &mut channel[..n];
+                        channel.sort_unstable();
+                        *out = channel[n / 2];
+                    }
+                    out.set_pixel(x, y, rgb);
+                }
+            }
+            Ok(out)
+        }
+    }
+}
+
+/// Merges differently exposed frames of a still scene, keeping from each the parts that are
+/// well exposed and detailed (a single scale take on Mertens exposure fusion).
+///
+/// Frames can be JPEG, they are decoded twice but only one at a time.
+pub fn fuse_exposures(frames: &[Frame]) -> Result<Frame> {
+    let Some(first) = frames.first() else {
+        bail!("no frames to fuse");
+    };
+    let (width, height) = (first.width(), first.height());
+
+    let mut maps = Vec::with_capacity(frames.len());
+    for frame in frames {
+        let frame = raw(frame)?;
+        if (frame.width(), frame.height()) != (width, height) {
+            bail!("frames to fuse must have the same size");
+        }
+        maps.push(weight_map(&LumaPlane::from_frame(&frame, WEIGHT_SIDE)));
+    }
+
+    // normalise the maps so that the weights of a cell add up to one
+    let cells = maps[0].data.len();
+    for i in 0..cells {
+        let total: f32 = maps.iter().map(|m| m.data[i]).sum();
+        for map in &mut maps {
+            map.data[i] = if total > 0.0 {
+                map.data[i] / total
+            } else {
+                1.0 / frames.len() as f32
+            };
+        }
+    }
+
+    let mut out = Frame::new(
+        vec![0; width * height * 3],
+        width,
+        height,
+        PixelFormat::Rgb888,
+    );
+    for (frame, map) in frames.iter().zip(&maps) {
+        let frame = raw(frame)?;
+        for y in 0..height {
+            for x in 0..width {
+                let w = map.sample(
+                    x as f32 * map.width as f32 / width as f32,
+                    y as f32 * map.height as f32 / height as f32,
+                );
+                let rgb = frame.pixel(x, y);
+                let offset = (x + y * width) * 3;
+                for (c, value) in rgb.iter().enumerate() {
+                    let add = (*value as f32 * w).round() as u8;
+                    let acc = &mut out.data_mut()[offset + c];
+                    *acc = acc.saturating_add(add);
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+struct WeightMap {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+}
+
+impl WeightMap {
+    /// Bilinear sample at cell coordinates
+    fn sample(&self, x: f32, y: f32) -> f32 {
+        let x = (x - 0.5).clamp(0.0, (self.width - 1) as f32);
+        let y = (y - 0.5).clamp(0.0, (self.height - 1) as f32);
+        let (x0, y0) = (x as usize, y as usize);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+        let get = |x: usize, y: usize| self.data[x + y * self.width];
+        let top = get(x0, y0) * (1.0 - fx) + get(x1, y0) * fx;
+        let bottom = get(x0, y1) * (1.0 - fx) + get(x1, y1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+/// Well-exposedness times local contrast, a small floor keeps flat areas defined
+fn weight_map(plane: &LumaPlane) -> WeightMap {
+    let (width, height) = (plane.width, plane.height);
+    let mut data = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let l = plane.get(x, y);
+            let exposure = (l as f32 / 255.0 - 0.5) / 0.2;
+            let well_exposed = (-0.5 * exposure * exposure).exp();
+
+            let neighbour = |dx: isize, dy: isize| {
+                let nx = (x as isize + dx).clamp(0, width as isize - 1) as usize;
+                let ny = (y as isize + dy).clamp(0, height as isize - 1) as usize;
+                plane.get(nx, ny)
+            };
+            let laplacian =
+                neighbour(-1, 0) + neighbour(1, 0) + neighbour(0, -1) + neighbour(0, 1) - 4 * l;
+            let contrast = laplacian.abs() as f32 / 255.0;
+
+            data.push(well_exposed * (contrast + 0.05));
+        }
+    }
+    WeightMap {
+        width,
+        height,
+        data,
+    }
+}
+
+fn raw(frame: &Frame) -> Result<Cow<'_, Frame>> {
+    if frame.is_raw() {
+        Ok(Cow::Borrowed(frame))
+    } else {
+        Ok(Cow::Owned(frame.to_raw()?))
+    }
+}
+
+fn shifted_pixel(frame: &Frame, x: usize, y: usize, (dx, dy): (isize, isize)) -> [u8; 3] {
+    let sx = (x as isize + dx).clamp(0, frame.width() as isize - 1) as usize;
+    let sy = (y as isize + dy).clamp(0, frame.height() as isize - 1) as usize;
+    frame.pixel(sx, sy)
+}
+
+fn accumulate(sums: &mut [u16], frame: &Frame, offset: (isize, isize)) {
+    let width = frame.width();
+    for y in 0..frame.height() {
+        for x in 0..width {
+            let rgb = shifted_pixel(frame, x, y, offset);
+            let i = (x + y * width) * 3;
+            for c in 0..3 {
+                sums[i + c] += rgb[c] as u16;
+            }
+        }
+    }
+}
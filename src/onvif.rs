@@ -0,0 +1,697 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    thread::JoinHandle,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use sha2::{Digest, Sha256};
+
+use crate::{auth::random_bytes, overlay::civil_from_days};
+
+pub const DEVICE_SERVICE: &str = "/onvif/device_service";
+pub const MEDIA_SERVICE: &str = "/onvif/media_service";
+pub const CONTENT_TYPE: &str = "application/soap+xml; charset=utf-8";
+
+const DISCOVERY_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const DISCOVERY_PORT: u16 = 3702;
+/// Probes are a few hundred bytes, the ones of some NVRs list many scopes
+const MAX_PROBE_LEN: usize = 4096;
+
+/// The only profile, the stream of the camera
+const PROFILE_TOKEN: &str = "profile_1";
+const VIDEO_SOURCE_TOKEN: &str = "video_source";
+
+const NAMESPACES: &str = "xmlns:env=\"http://www.w3.org/2003/05/soap-envelope\" \
+     xmlns:tds=\"http://www.onvif.org/ver10/device/wsdl\" \
+     xmlns:trt=\"http://www.onvif.org/ver10/media/wsdl\" \
+     xmlns:tt=\"http://www.onvif.org/ver10/schema\" \
+     xmlns:ter=\"http://www.onvif.org/ver10/error\" \
+     xmlns:wsa=\"http://schemas.xmlsoap.org/ws/2004/08/addressing\" \
+     xmlns:d=\"http://schemas.xmlsoap.org/ws/2005/04/discovery\" \
+     xmlns:dn=\"http://www.onvif.org/ver10/network/wsdl\"";
+
+/// What the camera says about itself to NVRs
+#[derive(Debug, Clone)]
+pub struct OnvifDevice {
+    /// Shown by NVRs as the camera name
+    pub name: String,
+    pub manufacturer: String,
+    pub model: String,
+    pub firmware_version: String,
+    pub serial_number: String,
+}
+
+impl OnvifDevice {
+    pub fn new(name: &str, serial_number: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            manufacturer: "Espressif".to_string(),
+            model: "ESP32-CAM".to_string(),
+            firmware_version: env!("CARGO_PKG_VERSION").to_string(),
+            serial_number: serial_number.to_string(),
+        }
+    }
+
+    pub fn model(mut self, model: &str) -> Self {
+        self.model = model.to_string();
+        self
+    }
+
+    /// Endpoint reference of WS-Discovery, the same across reboots so that NVRs
+    /// recognize the camera
+    pub fn uuid(&self) -> String {
+        let hash = Sha256::digest(format!("espcam:{}", self.serial_number));
+        let mut bytes: [u8; 16] = hash[..16].try_into().unwrap();
+        // name based version 5 layout
+        bytes[6] = (bytes[6] & 0x0F) | 0x50;
+        bytes[8] = (bytes[8] & 0x3F) | 0x80;
+        format_uuid(&bytes)
+    }
+
+    fn scopes(&self) -> String {
+        [
+            "onvif://www.onvif.org/type/video_encoder".to_string(),
+            "onvif://www.onvif.org/Profile/Streaming".to_string(),
+            format!("onvif://www.onvif.org/name/{}", scope_escape(&self.name)),
+            format!(
+                "onvif://www.onvif.org/hardware/{}",
+                scope_escape(&self.model)
+            ),
+        ]
+        .join(" ")
+    }
+}
+
+/// Where the other services of the camera are, as reached by the client
+#[derive(Debug, Clone)]
+pub struct Endpoints {
+    /// Host and port of the web server as the client wrote them, from the `Host` header.
+    /// It is escaped wherever it goes in a reply
+    pub host: String,
+    pub snapshot_path: &'static str,
+    /// Port and path of the RTSP server, if there is one
+    pub rtsp: Option<(u16, &'static str)>,
+}
+
+impl Endpoints {
+    fn service(&self, path: &str) -> String {
+        format!("http://{}{}", self.host, path)
+    }
+
+    /// The host without the port of the web server
+    fn hostname(&self) -> &str {
+        if self.host.starts_with('[') {
+            self.host
+                .find(']')
+                .map_or(&self.host, |end| &self.host[..=end])
+        } else {
+            self.host.split(':').next().unwrap_or(&self.host)
+        }
+    }
+}
+
+/// The video of the only profile, as the sensor is set right now
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MediaProfile {
+    pub width: u16,
+    pub height: u16,
+    pub fps: u32,
+    /// JPEG quality, from 0 to 100
+    pub quality: f32,
+}
+
+/// A SOAP fault, sent with a 400 when the request is at fault and a 500 otherwise
+#[derive(Debug, Clone, PartialEq)]
+pub struct SoapFault {
+    pub sender: bool,
+    pub subcode: &'static str,
+    pub reason: String,
+}
+
+impl SoapFault {
+    fn not_supported(action: &str) -> Self {
+        Self {
+            sender: false,
+            subcode: "ter:ActionNotSupported",
+            reason: format!("{} is not supported", action),
+        }
+    }
+
+    fn invalid(subcode: &'static str, reason: &str) -> Self {
+        Self {
+            sender: true,
+            subcode,
+            reason: reason.to_string(),
+        }
+    }
+
+    pub fn status(&self) -> u16 {
+        if self.sender {
+            400
+        } else {
+            500
+        }
+    }
+
+    pub fn to_xml(&self) -> String {
+        soap_envelope(&format!(
+            "<env:Fault><env:Code><env:Value>env:{}</env:Value>\
+             <env:Subcode><env:Value>{}</env:Value></env:Subcode></env:Code>\
+             <env:Reason><env:Text xml:lang=\"en\">{}</env:Text></env:Reason></env:Fault>",
+            if self.sender { "Sender" } else { "Receiver" },
+            self.subcode,
+            escape(&self.reason)
+        ))
+    }
+}
+
+/// Name of the operation, the first element in the body, like `GetProfiles`
+pub fn soap_action(envelope: &str) -> Option<&str> {
+    let (_, body) = find_element(envelope, |name| name == "Body")?;
+    let (start, _) = find_element(&envelope[body..], |_| true)?;
+    Some(local_name(tag_name(&envelope[body + start + 1..])))
+}
+
+/// Operations any client may call, NVRs use them to set their clock and find the services
+/// before logging in
+pub fn is_pre_auth(action: &str) -> bool {
+    matches!(
+        action,
+        "GetSystemDateAndTime" | "GetCapabilities" | "GetServices"
+    )
+}
+
+/// Password of a WS-Security `UsernameToken`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenPassword {
+    Text(String),
+    /// `Base64(SHA1(nonce + created + password))`, with the decoded nonce
+    Digest {
+        digest: Vec<u8>,
+        nonce: Vec<u8>,
+        created: String,
+    },
+}
+
+/// User name and password of a WS-Security `UsernameToken`
+pub fn username_token(envelope: &str) -> Option<(String, TokenPassword)> {
+    let (_, user) = element(envelope, "Username")?;
+    let (tag, password) = element(envelope, "Password")?;
+    let password = unescape(password.trim());
+    let password = if tag.contains("PasswordDigest") {
+        let (_, nonce) = element(envelope, "Nonce")?;
+        let (_, created) = element(envelope, "Created")?;
+        TokenPassword::Digest {
+            digest: BASE64.decode(password).ok()?,
+            nonce: BASE64.decode(unescape(nonce.trim())).ok()?,
+            created: unescape(created.trim()),
+        }
+    } else {
+        TokenPassword::Text(password)
+    };
+    Some((unescape(user.trim()), password))
+}
+
+/// Answers a device or media service request
+pub fn respond(
+    envelope: &str,
+    device: &OnvifDevice,
+    endpoints: &Endpoints,
+    profile: &MediaProfile,
+    now: SystemTime,
+) -> Result<String, SoapFault> {
+    let action = soap_action(envelope)
+        .ok_or_else(|| SoapFault::invalid("ter:InvalidArgVal", "no operation in the body"))?;
+    let body = match action {
+        "GetSystemDateAndTime" => system_date_and_time(now),
+        "GetDeviceInformation" => format!(
+            "<tds:GetDeviceInformationResponse>\
+             <tds:Manufacturer>{}</tds:Manufacturer><tds:Model>{}</tds:Model>\
+             <tds:FirmwareVersion>{}</tds:FirmwareVersion>\
+             <tds:SerialNumber>{}</tds:SerialNumber><tds:HardwareId>{}</tds:HardwareId>\
+             </tds:GetDeviceInformationResponse>",
+            escape(&device.manufacturer),
+            escape(&device.model),
+            escape(&device.firmware_version),
+            escape(&device.serial_number),
+            escape(&device.model)
+        ),
+        "GetCapabilities" => format!(
+            "<tds:GetCapabilitiesResponse><tds:Capabilities>\
+             <tt:Device><tt:XAddr>{}</tt:XAddr></tt:Device>\
+             <tt:Media><tt:XAddr>{}</tt:XAddr><tt:StreamingCapabilities>\
+             <tt:RTPMulticast>false</tt:RTPMulticast><tt:RTP_TCP>true</tt:RTP_TCP>\
+             <tt:RTP_RTSP_TCP>true</tt:RTP_RTSP_TCP></tt:StreamingCapabilities></tt:Media>\
+             </tds:Capabilities></tds:GetCapabilitiesResponse>",
+            escape(&endpoints.service(DEVICE_SERVICE)),
+            escape(&endpoints.service(MEDIA_SERVICE))
+        ),
+        "GetServices" => format!(
+            "<tds:GetServicesResponse>{}{}</tds:GetServicesResponse>",
+            service(
+                "http://www.onvif.org/ver10/device/wsdl",
+                &endpoints.service(DEVICE_SERVICE)
+            ),
+            service(
+                "http://www.onvif.org/ver10/media/wsdl",
+                &endpoints.service(MEDIA_SERVICE)
+            )
+        ),
+        "GetScopes" => format!(
+            "<tds:GetScopesResponse>{}</tds:GetScopesResponse>",
+            device
+                .scopes()
+                .split(' ')
+                .map(|scope| format!(
+                    "<tds:Scopes><tt:ScopeDef>Fixed</tt:ScopeDef>\
+                     <tt:ScopeItem>{}</tt:ScopeItem></tds:Scopes>",
+                    escape(scope)
+                ))
+                .collect::<String>()
+        ),
+        "GetProfiles" => format!(
+            "<trt:GetProfilesResponse>{}</trt:GetProfilesResponse>",
+            media_profile("trt:Profiles", profile)
+        ),
+        "GetProfile" => format!(
+            "<trt:GetProfileResponse>{}</trt:GetProfileResponse>",
+            media_profile("trt:Profile", profile)
+        ),
+        "GetVideoSources" => format!(
+            "<trt:GetVideoSourcesResponse><trt:VideoSources token=\"{}\">\
+             <tt:Framerate>{}</tt:Framerate><tt:Resolution><tt:Width>{}</tt:Width>\
+             <tt:Height>{}</tt:Height></tt:Resolution></trt:VideoSources>\
+             </trt:GetVideoSourcesResponse>",
+            VIDEO_SOURCE_TOKEN, profile.fps, profile.width, profile.height
+        ),
+        "GetStreamUri" => {
+            let Some((port, path)) = endpoints.rtsp else {
+                return Err(SoapFault::invalid(
+                    "ter:InvalidArgVal",
+                    "the camera has no rtsp server",
+                ));
+            };
+            media_uri(
+                "GetStreamUri",
+                &format!("rtsp://{}:{}{}", endpoints.hostname(), port, path),
+            )
+        }
+        "GetSnapshotUri" => media_uri(
+            "GetSnapshotUri",
+            &endpoints.service(endpoints.snapshot_path),
+        ),
+        action => return Err(SoapFault::not_supported(action)),
+    };
+    Ok(soap_envelope(&body))
+}
+
+fn system_date_and_time(now: SystemTime) -> String {
+    let secs = now
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let rem = secs.rem_euclid(86400);
+    format!(
+        "<tds:GetSystemDateAndTimeResponse><tds:SystemDateAndTime>\
+         <tt:DateTimeType>NTP</tt:DateTimeType><tt:DaylightSavings>false</tt:DaylightSavings>\
+         <tt:TimeZone><tt:TZ>UTC0</tt:TZ></tt:TimeZone>\
+         <tt:UTCDateTime><tt:Time><tt:Hour>{}</tt:Hour><tt:Minute>{}</tt:Minute>\
+         <tt:Second>{}</tt:Second></tt:Time><tt:Date><tt:Year>{}</tt:Year>\
+         <tt:Month>{}</tt:Month><tt:Day>{}</tt:Day></tt:Date></tt:UTCDateTime>\
+         </tds:SystemDateAndTime></tds:GetSystemDateAndTimeResponse>",
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60,
+        year,
+        month,
+        day
+    )
+}
+
+fn service(namespace: &str, xaddr: &str) -> String {
+    format!(
+        "<tds:Service><tds:Namespace>{}</tds:Namespace><tds:XAddr>{}</tds:XAddr>\
+         <tds:Version><tt:Major>2</tt:Major><tt:Minor>0</tt:Minor></tds:Version></tds:Service>",
+        namespace,
+        escape(xaddr)
+    )
+}
+
+fn media_profile(element: &str, profile: &MediaProfile) -> String {
+    format!(
+        "<{element} token=\"{PROFILE_TOKEN}\" fixed=\"true\"><tt:Name>main</tt:Name>\
+         <tt:VideoSourceConfiguration token=\"{VIDEO_SOURCE_TOKEN}\"><tt:Name>camera</tt:Name>\
+         <tt:UseCount>1</tt:UseCount><tt:SourceToken>{VIDEO_SOURCE_TOKEN}</tt:SourceToken>\
+         <tt:Bounds x=\"0\" y=\"0\" width=\"{width}\" height=\"{height}\"/>\
+         </tt:VideoSourceConfiguration>\
+         <tt:VideoEncoderConfiguration token=\"jpeg\"><tt:Name>jpeg</tt:Name>\
+         <tt:UseCount>1</tt:UseCount><tt:Encoding>JPEG</tt:Encoding>\
+         <tt:Resolution><tt:Width>{width}</tt:Width><tt:Height>{height}</tt:Height></tt:Resolution>\
+         <tt:Quality>{quality}</tt:Quality><tt:RateControl>\
+         <tt:FrameRateLimit>{fps}</tt:FrameRateLimit><tt:EncodingInterval>1</tt:EncodingInterval>\
+         <tt:BitrateLimit>0</tt:BitrateLimit></tt:RateControl>\
+         <tt:SessionTimeout>PT60S</tt:SessionTimeout></tt:VideoEncoderConfiguration></{element}>",
+        width = profile.width,
+        height = profile.height,
+        quality = profile.quality,
+        fps = profile.fps,
+    )
+}
+
+fn media_uri(action: &str, uri: &str) -> String {
+    format!(
+        "<trt:{action}Response><trt:MediaUri><tt:Uri>{}</tt:Uri>\
+         <tt:InvalidAfterConnect>false</tt:InvalidAfterConnect>\
+         <tt:InvalidAfterReboot>false</tt:InvalidAfterReboot><tt:Timeout>PT0S</tt:Timeout>\
+         </trt:MediaUri></trt:{action}Response>",
+        escape(uri)
+    )
+}
+
+fn soap_envelope(body: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+         <env:Envelope {}><env:Body>{}</env:Body></env:Envelope>",
+        NAMESPACES, body
+    )
+}
+
+fn discovery_envelope(action: &str, relates_to: Option<&str>, body: &str) -> String {
+    let relates_to = relates_to
+        .map(|id| format!("<wsa:RelatesTo>{}</wsa:RelatesTo>", escape(id)))
+        .unwrap_or_default();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?><env:Envelope {}><env:Header>\
+         <wsa:MessageID>urn:uuid:{}</wsa:MessageID>{}\
+         <wsa:To>{}</wsa:To>\
+         <wsa:Action>http://schemas.xmlsoap.org/ws/2005/04/discovery/{}</wsa:Action>\
+         </env:Header><env:Body>{}</env:Body></env:Envelope>",
+        NAMESPACES,
+        random_uuid(),
+        relates_to,
+        if relates_to.is_empty() {
+            "urn:schemas-xmlsoap-org:ws:2005:04:discovery"
+        } else {
+            "http://schemas.xmlsoap.org/ws/2004/08/addressing/role/anonymous"
+        },
+        action,
+        body
+    )
+}
+
+/// What a probe match and a hello have in common
+fn endpoint_description(device: &OnvifDevice, xaddr: &str) -> String {
+    format!(
+        "<wsa:EndpointReference><wsa:Address>urn:uuid:{}</wsa:Address></wsa:EndpointReference>\
+         <d:Types>dn:NetworkVideoTransmitter tds:Device</d:Types>\
+         <d:Scopes>{}</d:Scopes><d:XAddrs>{}</d:XAddrs>\
+         <d:MetadataVersion>1</d:MetadataVersion>",
+        device.uuid(),
+        escape(&device.scopes()),
+        escape(xaddr)
+    )
+}
+
+/// The answer to a WS-Discovery message, `None` for the ones that are not a probe for
+/// this camera
+pub fn probe_match(message: &str, device: &OnvifDevice, xaddr: &str) -> Option<String> {
+    if soap_action(message) != Some("Probe") {
+        return None;
+    }
+    // an empty type list or scope list matches everything
+    if let Some((_, types)) = element(message, "Types") {
+        let wanted = types.split_whitespace().map(local_name).collect::<Vec<_>>();
+        if !wanted.is_empty()
+            && !wanted
+                .iter()
+                .any(|t| *t == "NetworkVideoTransmitter" || *t == "Device")
+        {
+            return None;
+        }
+    }
+    if let Some((_, scopes)) = element(message, "Scopes") {
+        let ours = device.scopes();
+        if !scopes
+            .split_whitespace()
+            .all(|scope| ours.split(' ').any(|own| own.starts_with(scope)))
+        {
+            return None;
+        }
+    }
+
+    let relates_to = element(message, "MessageID").map(|(_, id)| id.trim());
+    Some(discovery_envelope(
+        "ProbeMatches",
+        relates_to,
+        &format!(
+            "<d:ProbeMatches><d:ProbeMatch>{}</d:ProbeMatch></d:ProbeMatches>",
+            endpoint_description(device, xaddr)
+        ),
+    ))
+}
+
+/// Announcement of a camera that joined the network
+pub fn hello(device: &OnvifDevice, xaddr: &str) -> String {
+    discovery_envelope(
+        "Hello",
+        None,
+        &format!("<d:Hello>{}</d:Hello>", endpoint_description(device, xaddr)),
+    )
+}
+
+/// Answers the WS-Discovery probes of NVRs looking for cameras, after announcing the
+/// camera once. `http_port` is the port of the web server with the ONVIF services.
+pub fn spawn_discovery(device: OnvifDevice, http_port: u16) -> Result<JoinHandle<()>> {
+    let socket = UdpSocket::bind(("0.0.0.0", DISCOVERY_PORT))?;
+    socket.join_multicast_v4(&DISCOVERY_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+
+    Ok(std::thread::Builder::new()
+        .stack_size(8 * 1024)
+        .spawn(move || {
+            let group = SocketAddr::from((DISCOVERY_ADDR, DISCOVERY_PORT));
+            if let Some(xaddr) = xaddr_towards(group, http_port) {
+                if let Err(err) = socket.send_to(hello(&device, &xaddr).as_bytes(), group) {
+                    log::warn!("could not send the onvif hello: {:?}", err);
+                }
+            }
+
+            let mut buf = vec![0; MAX_PROBE_LEN];
+            loop {
+                let (len, peer) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(err) => {
+                        log::error!("onvif discovery error: {:?}", err);
+                        continue;
+                    }
+                };
+                let message = String::from_utf8_lossy(&buf[..len]);
+                let Some(xaddr) = xaddr_towards(peer, http_port) else {
+                    continue;
+                };
+                if let Some(reply) = probe_match(&message, &device, &xaddr) {
+                    log::info!("onvif probe from {}", peer);
+                    if let Err(err) = socket.send_to(reply.as_bytes(), peer) {
+                        log::warn!("could not answer the onvif probe: {:?}", err);
+                    }
+                }
+            }
+        })?)
+}
+
+/// Device service address on the interface that reaches `peer`, the camera only learns
+/// its own address by asking the network stack for a route
+fn xaddr_towards(peer: SocketAddr, http_port: u16) -> Option<String> {
+    let probe = UdpSocket::bind(("0.0.0.0", 0)).ok()?;
+    probe.connect(peer).ok()?;
+    let ip = match probe.local_addr().ok()?.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() => ip,
+        _ => return None,
+    };
+    Some(if http_port == 80 {
+        format!("http://{}{}", ip, DEVICE_SERVICE)
+    } else {
+        format!("http://{}:{}{}", ip, http_port, DEVICE_SERVICE)
+    })
+}
+
+/// Start tag and text of the first element with this local name, whatever its prefix
+fn element<'a>(xml: &'a str, name: &str) -> Option<(&'a str, &'a str)> {
+    let (start, end) = find_element(xml, |n| n == name)?;
+    let tag = &xml[start..end];
+    if tag.ends_with("/>") {
+        return Some((tag, ""));
+    }
+    let text = &xml[end..];
+    Some((tag, &text[..text.find('<').unwrap_or(text.len())]))
+}
+
+/// Where the first start tag whose local name passes `matches` begins and ends
+fn find_element(xml: &str, matches: impl Fn(&str) -> bool) -> Option<(usize, usize)> {
+    let mut pos = 0;
+    while let Some(start) = xml[pos..].find('<').map(|i| pos + i) {
+        let end = start + xml[start..].find('>')? + 1;
+        let inner = &xml[start + 1..];
+        // closing tags, comments and the xml declaration
+        if !inner.starts_with(['/', '!', '?']) && matches(local_name(tag_name(inner))) {
+            return Some((start, end));
+        }
+        pos = start + 1;
+    }
+    None
+}
+
+fn tag_name(tag: &str) -> &str {
+    let end = tag
+        .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+        .unwrap_or(tag.len());
+    &tag[..end]
+}
+
+fn local_name(name: &str) -> &str {
+    name.rsplit(':').next().unwrap_or(name)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Scope items are URIs, so the name and model cannot have spaces
+fn scope_escape(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn random_uuid() -> String {
+    let mut bytes = random_bytes::<16>();
+    bytes[6] = (bytes[6] & 0x0F) | 0x40;
+    bytes[8] = (bytes[8] & 0x3F) | 0x80;
+    format_uuid(&bytes)
+}
+
+fn format_uuid(bytes: &[u8; 16]) -> String {
+    let hex = bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(action: &str) -> String {
+        format!(
+            "<s:Envelope xmlns:s=\"http://www.w3.org/2003/05/soap-envelope\"><s:Body>\
+             <tds:{} xmlns:tds=\"http://www.onvif.org/ver10/device/wsdl\"/></s:Body></s:Envelope>",
+            action
+        )
+    }
+
+    fn secured(password: &str) -> String {
+        format!(
+            "<s:Envelope xmlns:s=\"http://www.w3.org/2003/05/soap-envelope\"><s:Header>\
+             <wsse:Security><wsse:UsernameToken><wsse:Username>nvr</wsse:Username>{}\
+             </wsse:UsernameToken></wsse:Security></s:Header><s:Body>\
+             <tds:GetDeviceInformation/></s:Body></s:Envelope>",
+            password
+        )
+    }
+
+    #[test]
+    fn reads_username_tokens() {
+        let text = secured("<wsse:Password Type=\"...#PasswordText\">p&amp;ss</wsse:Password>");
+        assert_eq!(
+            username_token(&text),
+            Some(("nvr".to_string(), TokenPassword::Text("p&ss".to_string())))
+        );
+
+        let digest = secured(
+            "<wsse:Password Type=\"...#PasswordDigest\">tuOSpGlFlIXsozq4HFNeeGeFLEI=</wsse:Password>\
+             <wsse:Nonce EncodingType=\"...#Base64Binary\">LKqI6G/AikKCQrN0zqZFlg==</wsse:Nonce>\
+             <wsu:Created>2010-09-16T07:50:45Z</wsu:Created>",
+        );
+        let Some((
+            user,
+            TokenPassword::Digest {
+                digest,
+                nonce,
+                created,
+            },
+        )) = username_token(&digest)
+        else {
+            panic!("no digest");
+        };
+        assert_eq!(user, "nvr");
+        assert_eq!(digest.len(), 20);
+        assert_eq!(nonce.len(), 16);
+        assert_eq!(created, "2010-09-16T07:50:45Z");
+
+        // a digest without its nonce can't be checked
+        let partial =
+            secured("<wsse:Password Type=\"...#PasswordDigest\">tuOSpGlFlIXsozq4HFNeeGeFLEI=</wsse:Password>");
+        assert_eq!(username_token(&partial), None);
+    }
+
+    #[test]
+    fn host_is_escaped_in_addresses() {
+        let device = OnvifDevice::new("camera", "0123");
+        let endpoints = Endpoints {
+            host: "cam\"><x/>&".to_string(),
+            snapshot_path: "/capture",
+            rtsp: Some((554, "/stream")),
+        };
+        let profile = MediaProfile {
+            width: 800,
+            height: 600,
+            fps: 10,
+            quality: 80.0,
+        };
+
+        for action in [
+            "GetCapabilities",
+            "GetServices",
+            "GetSnapshotUri",
+            "GetStreamUri",
+        ] {
+            let reply = respond(
+                &request(action),
+                &device,
+                &endpoints,
+                &profile,
+                SystemTime::now(),
+            )
+            .unwrap();
+            assert!(!reply.contains("<x/>"), "{}: {}", action, reply);
+            assert!(reply.contains("cam&quot;&gt;&lt;x/&gt;&amp;"), "{}", action);
+        }
+    }
+}
@@ -0,0 +1,460 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+
+use crate::frame::Frame;
+
+pub const GLYPH_WIDTH: usize = 5;
+pub const GLYPH_HEIGHT: usize = 7;
+
+// classic 5x7 font, one byte per column, least significant bit on top, covers ' ' to '~'
+static FONT_5X7: [[u8; GLYPH_WIDTH]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x00, 0x00, 0x5f, 0x00, 0x00], // !
+    [0x00, 0x07, 0x00, 0x07, 0x00], // "
+    [0x14, 0x7f, 0x14, 0x7f, 0x14], // #
+    [0x24, 0x2a, 0x7f, 0x2a, 0x12], // $
+    [0x23, 0x13, 0x08, 0x64, 0x62], // %
+    [0x36, 0x49, 0x55, 0x22, 0x50], // &
+    [0x00, 0x05, 0x03, 0x00, 0x00], // '
+    [0x00, 0x1c, 0x22, 0x41, 0x00], // (
+    [0x00, 0x41, 0x22, 0x1c, 0x00], // )
+    [0x08, 0x2a, 0x1c, 0x2a, 0x08], // *
+    [0x08, 0x08, 0x3e, 0x08, 0x08], // +
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ,
+    [0x08, 0x08, 0x08, 0x08, 0x08], // -
+    [0x00, 0x60, 0x60, 0x00, 0x00], // .
+    [0x20, 0x10, 0x08, 0x04, 0x02], // /
+    [0x3e, 0x51, 0x49, 0x45, 0x3e], // 0
+    [0x00, 0x42, 0x7f, 0x40, 0x00], // 1
+    [0x42, 0x61, 0x51, 0x49, 0x46], // 2
+    [0x21, 0x41, 0x45, 0x4b, 0x31], // 3
+    [0x18, 0x14, 0x12, 0x7f, 0x10], // 4
+    [0x27, 0x45, 0x45, 0x45, 0x39], // 5
+    [0x3c, 0x4a, 0x49, 0x49, 0x30], // 6
+    [0x01, 0x71, 0x09, 0x05, 0x03], // 7
+    [0x36, 0x49, 0x49, 0x49, 0x36], // 8
+    [0x06, 0x49, 0x49, 0x29, 0x1e], // 9
+    [0x00, 0x36, 0x36, 0x00, 0x00], // :
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ;
+    [0x08, 0x14, 0x22, 0x41, 0x00], // <
+    [0x14, 0x14, 0x14, 0x14, 0x14], // =
+    [0x00, 0x41, 0x22, 0x14, 0x08], // >
+    [0x02, 0x01, 0x51, 0x09, 0x06], // ?
+    [0x32, 0x49, 0x79, 0x41, 0x3e], // @
+    [0x7e, 0x11, 0x11, 0x11, 0x7e], // A
+    [0x7f, 0x49, 0x49, 0x49, 0x36], // B
+    [0x3e, 0x41, 0x41, 0x41, 0x22], // C
+    [0x7f, 0x41, 0x41, 0x22, 0x1c], // D
+    [0x7f, 0x49, 0x49, 0x49, 0x41], // E
+    [0x7f, 0x09, 0x09, 0x09, 0x01], // F
+    [0x3e, 0x41, 0x49, 0x49, 0x7a], // G
+    [0x7f, 0x08, 0x08, 0x08, 0x7f], // H
+    [0x00, 0x41, 0x7f, 0x41, 0x00], // I
+    [0x20, 0x40, 0x41, 0x3f, 0x01], // J
+    [0x7f, 0x08, 0x14, 0x22, 0x41], // K
+    [0x7f, 0x40, 0x40, 0x40, 0x40], // L
+    [0x7f, 0x02, 0x0c, 0x02, 0x7f], // M
+    [0x7f, 0x04, 0x08, 0x10, 0x7f], // N
+    [0x3e, 0x41, 0x41, 0x41, 0x3e], // O
+    [0x7f, 0x09, 0x09, 0x09, 0x06], // P
+    [0x3e, 0x41, 0x51, 0x21, 0x5e], // Q
+    [0x7f, 0x09, 0x19, 0x29, 0x46], // R
+    [0x46, 0x49, 0x49, 0x49, 0x31], // S
+    [0x01, 0x01, 0x7f, 0x01, 0x01], // T
+    [0x3f, 0x40, 0x40, 0x40, 0x3f], // U
+    [0x1f, 0x20, 0x40, 0x20, 0x1f], // V
+    [0x3f, 0x40, 0x38, 0x40, 0x3f], // W
+    [0x63, 0x14, 0x08, 0x14, 0x63], // X
+    [0x07, 0x08, 0x70, 0x08, 0x07], // Y
+    [0x61, 0x51, 0x49, 0x45, 0x43], // Z
+    [0x00, 0x7f, 0x41, 0x41, 0x00], // [
+    [0x02, 0x04, 0x08, 0x10, 0x20], // \
+    [0x00, 0x41, 0x41, 0x7f, 0x00], // ]
+    [0x04, 0x02, 0x01, 0x02, 0x04], // ^
+    [0x40, 0x40, 0x40, 0x40, 0x40], // _
+    [0x00, 0x01, 0x02, 0x04, 0x00], // `
+    [0x20, 0x54, 0x54, 0x54, 0x78], // a
+    [0x7f, 0x48, 0x44, 0x44, 0x38], // b
+    [0x38, 0x44, 0x44, 0x44, 0x20], // c
+    [0x38, 0x44, 0x44, 0x48, 0x7f], // d
+    [0x38, 0x54, 0x54, 0x54, 0x18], // e
+    [0x08, 0x7e, 0x09, 0x01, 0x02], // f
+    [0x0c, 0x52, 0x52, 0x52, 0x3e], // g
+    [0x7f, 0x08, 0x04, 0x04, 0x78], // h
+    [0x00, 0x44, 0x7d, 0x40, 0x00], // i
+    [0x20, 0x40, 0x44, 0x3d, 0x00], // j
+    [0x7f, 0x10, 0x28, 0x44, 0x00], // k
+    [0x00, 0x41, 0x7f, 0x40, 0x00], // l
+    [0x7c, 0x04, 0x18, 0x04, 0x78], // m
+    [0x7c, 0x08, 0x04, 0x04, 0x78], // n
+    [0x38, 0x44, 0x44, 0x44, 0x38], // o
+    [0x7c, 0x14, 0x14, 0x14, 0x08], // p
+    [0x08, 0x14, 0x14, 0x18, 0x7c], // q
+    [0x7c, 0x08, 0x04, 0x04, 0x08], // r
+    [0x48, 0x54, 0x54, 0x54, 0x20], // s
+    [0x04, 0x3f, 0x44, 0x40, 0x20], // t
+    [0x3c, 0x40, 0x40, 0x20, 0x7c], // u
+    [0x1c, 0x20, 0x40, 0x20, 0x1c], // v
+    [0x3c, 0x40, 0x30, 0x40, 0x3c], // w
+    [0x44, 0x28, 0x10, 0x28, 0x44], // x
+    [0x0c, 0x50, 0x50, 0x50, 0x3c], // y
+    [0x44, 0x64, 0x54, 0x4c, 0x44], // z
+    [0x00, 0x08, 0x36, 0x41, 0x00], // {
+    [0x00, 0x00, 0x7f, 0x00, 0x00], // |
+    [0x00, 0x41, 0x36, 0x08, 0x00], // }
+    [0x02, 0x01, 0x02, 0x04, 0x02], // ~
+];
+
+fn glyph(c: char) -> &'static [u8; GLYPH_WIDTH] {
+    let idx = match c {
+        ' '..='~' => c as usize - ' ' as usize,
+        _ => '?' as usize - ' ' as usize,
+    };
+    &FONT_5X7[idx]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    /// Absolute pixel position of the top left corner of the text box
+    At(usize, usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OverlayContent {
+    /// Date and time of the capture, "YYYY-MM-DD HH:MM:SS"
+    Timestamp,
+    DeviceName,
+    Text(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct OverlayItem {
+    pub content: OverlayContent,
+    pub position: Position,
+    /// Each font pixel becomes a `scale` x `scale` block
+    pub scale: usize,
+    pub color: [u8; 3],
+    pub background: Option<[u8; 3]>,
+}
+
+impl OverlayItem {
+    pub fn new(content: OverlayContent, position: Position) -> Self {
+        Self {
+            content,
+            position,
+            scale: 2,
+            color: [255, 255, 255],
+            background: Some([0, 0, 0]),
+        }
+    }
+
+    pub fn scale(mut self, scale: usize) -> Self {
+        self.scale = scale.max(1);
+        self
+    }
+
+    pub fn color(mut self, color: [u8; 3]) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn background(mut self, background: Option<[u8; 3]>) -> Self {
+        self.background = background;
+        self
+    }
+}
+
+/// Stamps text such as the capture time and the device name onto frames.
+///
+/// Items anchored to the same corner are stacked instead of being drawn on top of each other.
+#[derive(Debug, Clone)]
+pub struct Overlay {
+    device_name: String,
+    utc_offset_secs: i64,
+    margin: usize,
+    items: Vec<OverlayItem>,
+}
+
+impl Overlay {
+    pub fn new(device_name: &str) -> Self {
+        Self {
+            device_name: device_name.to_string(),
+            utc_offset_secs: 0,
+            margin: 4,
+            items: Vec::new(),
+        }
+    }
+
+    /// Timestamp with the device name in the bottom left corner
+    pub fn with_defaults(device_name: &str) -> Self {
+        Self::new(device_name)
+            .item(OverlayItem::new(
+                OverlayContent::Timestamp,
+                Position::BottomLeft,
+            ))
+            .item(OverlayItem::new(
+                OverlayContent::DeviceName,
+                Position::BottomLeft,
+            ))
+    }
+
+    pub fn item(mut self, item: OverlayItem) -> Self {
+        self.items.push(item);
+        self
+    }
+
+    pub fn utc_offset(mut self, secs: i64) -> Self {
+        self.utc_offset_secs = secs;
+        self
+    }
+
+    pub fn margin(mut self, margin: usize) -> Self {
+        self.margin = margin;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Draws every item on a raw frame, `time` is used for [`OverlayContent::Timestamp`].
+    pub fn apply(&self, frame: &mut Frame, time: SystemTime) {
+        let timestamp = format_timestamp(time, self.utc_offset_secs);
+        // how much of each corner has already been used, in rows
+        let mut used = [0usize; 4];
+
+        for item in &self.items {
+            let text = match &item.content {
+                OverlayContent::Timestamp => timestamp.as_str(),
+                OverlayContent::DeviceName => self.device_name.as_str(),
+                OverlayContent::Text(text) => text.as_str(),
+            };
+            let (w, h) = text_size(text, item.scale);
+            let (w, h) = (w + 2 * item.scale, h + 2 * item.scale);
+
+            let (x, y) = match item.position {
+                Position::At(x, y) => (x, y),
+                Position::TopLeft => {
+                    used[0] += h;
+                    (self.margin, self.margin + used[0] - h)
+                }
+                Position::TopRight => {
+                    used[1] += h;
+                    (
+                        frame.width().saturating_sub(w + self.margin),
+                        self.margin + used[1] - h,
+                    )
+                }
+                Position::BottomLeft => {
+                    used[2] += h;
+                    (
+                        self.margin,
+                        frame.height().saturating_sub(used[2] + self.margin),
+                    )
+                }
+                Position::BottomRight => {
+                    used[3] += h;
+                    (
+                        frame.width().saturating_sub(w + self.margin),
+                        frame.height().saturating_sub(used[3] + self.margin),
+                    )
+                }
+            };
+
+            if let Some(background) = item.background {
+                fill_rect(frame, x, y, w, h, background);
+            }
+            draw_text(
+                frame,
+                x + item.scale,
+                y + item.scale,
+                text,
+                item.scale,
+                item.color,
+            );
+        }
+    }
+
+    /// Decodes a JPEG, draws the overlay and encodes it again with `quality` (1-100).
+    pub fn apply_jpeg(&self, jpeg: &[u8], time: SystemTime, quality: u8) -> Result<Vec<u8>> {
+        let mut frame = Frame::decode_jpeg(jpeg)?;
+        self.apply(&mut frame, time);
+        frame.to_jpeg(quality)
+    }
+}
+
+/// Size in pixels of `text` rendered at `scale`, without the background padding
+pub fn text_size(text: &str, scale: usize) -> (usize, usize) {
+    let chars = text.chars().count();
+    if chars == 0 {
+        return (0, 0);
+    }
+    (
+        (chars * (GLYPH_WIDTH + 1) - 1) * scale,
+        GLYPH_HEIGHT * scale,
+    )
+}
+
+/// Draws `text` with its top left corner at `x`, `y`, pixels outside the frame are skipped.
+pub fn draw_text(frame: &mut Frame, x: usize, y: usize, text: &str, scale: usize, color: [u8; 3]) {
+    for (n, c) in text.chars().enumerate() {
+        let gx = x + n * (GLYPH_WIDTH + 1) * scale;
+        for (col, bits) in glyph(c).iter().enumerate() {
+            for row in 0..GLYPH_HEIGHT {
+                if bits & (1 << row) != 0 {
+                    fill_rect(
+                        frame,
+                        gx + col * scale,
+                        y + row * scale,
+                        scale,
+                        scale,
+                        color,
+                    );
+                }
+            }
+        }
+    }
+}
+
+pub fn fill_rect(frame: &mut Frame, x: usize, y: usize, w: usize, h: usize, color: [u8; 3]) {
+    let x_end = (x + w).min(frame.width());
+    let y_end = (y + h).min(frame.height());
+    for py in y.min(y_end)..y_end {
+        for px in x.min(x_end)..x_end {
+            frame.set_pixel(px, py, color);
+        }
+    }
+}
+
+pub fn format_timestamp(time: SystemTime, utc_offset_secs: i64) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+        + utc_offset_secs;
+
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::frame::PixelFormat;
+
+    #[test]
+    fn civil_from_days_around_the_epoch() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(31), (1970, 2, 1));
+        assert_eq!(civil_from_days(365), (1971, 1, 1));
+    }
+
+    #[test]
+    fn civil_from_days_around_leap_days() {
+        // 2000 is a leap year, divisible by 400
+        assert_eq!(civil_from_days(11015), (2000, 2, 28));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(11017), (2000, 3, 1));
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+        assert_eq!(civil_from_days(19783), (2024, 3, 1));
+        // 2100 is not, divisible by 100
+        assert_eq!(civil_from_days(47540), (2100, 2, 28));
+        assert_eq!(civil_from_days(47541), (2100, 3, 1));
+        assert_eq!(civil_from_days(-25508), (1900, 3, 1));
+    }
+
+    #[test]
+    fn days_from_civil_inverts_civil_from_days() {
+        for days in [-25508, -1, 0, 31, 365, 11016, 11017, 19782, 47541] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn timestamp_format_and_offset() {
+        let time = UNIX_EPOCH + Duration::from_secs(951_868_799);
+        assert_eq!(format_timestamp(time, 0), "2000-02-29 23:59:59");
+        assert_eq!(format_timestamp(time, 1), "2000-03-01 00:00:00");
+        assert_eq!(format_timestamp(UNIX_EPOCH, -3600), "1969-12-31 23:00:00");
+    }
+
+    #[test]
+    fn text_size_of_glyphs() {
+        assert_eq!(text_size("", 2), (0, 0));
+        assert_eq!(text_size("A", 1), (5, 7));
+        assert_eq!(text_size("AB", 2), (22, 14));
+    }
+
+    #[test]
+    fn draws_glyph_pixels() {
+        let mut frame = Frame::new(vec![0; 6 * 7], 6, 7, PixelFormat::Grayscale);
+        draw_text(&mut frame, 0, 0, "|", 1, [255, 255, 255]);
+        for y in 0..7 {
+            for x in 0..6 {
+                let lit = x == 2;
+                assert_eq!(frame.luma(x, y) == 255, lit, "pixel {} {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn stacks_items_in_a_corner() {
+        let mut frame = Frame::new(vec![0; 100 * 60], 100, 60, PixelFormat::Grayscale);
+        Overlay::new("cam")
+            .margin(0)
+            .item(OverlayItem::new(OverlayContent::Text("a".into()), Position::TopLeft).scale(1))
+            .item(OverlayItem::new(OverlayContent::Text("b".into()), Position::TopLeft).scale(1))
+            .apply(&mut frame, UNIX_EPOCH);
+        // each item takes 7 rows of glyph and a row of padding above and below, the
+        // first column of 'a' has a single dot on row 5 and the one of 'b' is full
+        assert_eq!(frame.luma(1, 1 + 5), 255);
+        assert_eq!(frame.luma(1, 1 + 4), 0);
+        assert!((10..17).all(|y| frame.luma(1, y) == 255));
+        assert!((0..100).all(|x| frame.luma(x, 18) == 0));
+    }
+}
@@ -0,0 +1,220 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::frame::Frame;
+
+/// 64 bit fingerprint of a frame, similar pictures have hashes a few bits apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ImageHash(pub u64);
+
+impl ImageHash {
+    /// Number of differing bits, from 0 (same picture) to 64
+    pub fn distance(&self, other: &ImageHash) -> u32 {
+        (self.0 ^ other.0).count_ones()
+    }
+}
+
+impl fmt::Display for ImageHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashKind {
+    /// aHash, pixels brighter than the mean, the cheapest
+    Average,
+    /// dHash, pixels brighter than their right neighbour, robust to exposure changes
+    Difference,
+    /// pHash, signs of the low DCT frequencies, the most robust and the slowest
+    Perceptual,
+}
+
+impl HashKind {
+    /// Hashes a raw frame
+    pub fn hash(&self, frame: &Frame) -> ImageHash {
+        match self {
+            HashKind::Average => average_hash(frame),
+            HashKind::Difference => difference_hash(frame),
+            HashKind::Perceptual => perceptual_hash(frame),
+        }
+    }
+}
+
+pub fn average_hash(frame: &Frame) -> ImageHash {
+    let small = shrink(frame, 8, 8);
+    let mean = small.iter().sum::<f32>() / small.len() as f32;
+    bits(small.iter().map(|v| *v > mean))
+}
+
+pub fn difference_hash(frame: &Frame) -> ImageHash {
+    let small = shrink(frame, 9, 8);
+    bits((0..8).flat_map(|y| {
+        let row = &small[y * 9..(y + 1) * 9];
+        (0..8).map(move |x| row[x] > row[x + 1])
+    }))
+}
+
+pub fn perceptual_hash(frame: &Frame) -> ImageHash {
+    const N: usize = 32;
+    let small = shrink(frame, N, N);
+
+    let cos: Vec<f32> = (0..8)
+        .flat_map(|u| {
+            (0..N).map(move |x| {
+                (std::f32::consts::PI * (2 * x + 1) as f32 * u as f32 / (2 * N) as f32).cos()
+            })
+        })
+        .collect();
+
+    // only the 8x8 lowest frequencies are needed
+    let mut dct = [0f32; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            let mut sum = 0.0;
+            for y in 0..N {
+                let row: f32 = (0..N).map(|x| small[x + y * N] * cos[u * N + x]).sum();
+                sum += row * cos[v * N + y];
+            }
+            dct[u + v * 8] = sum;
+        }
+    }
+
+    // the DC term only says how bright the picture is, leave it out of the median
+    let mut sorted = dct[1..].to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let median = sorted[sorted.len() / 2];
+    bits(dct.iter().map(|v| *v > median))
+}
+
+fn bits(values: impl Iterator<Item = bool>) -> ImageHash {
+    ImageHash(values.fold(0u64, |hash, bit| (hash << 1) | bit as u64))
+}
+
+/// Grayscale thumbnail, every cell is the mean of up to 4x4 samples
+fn shrink(frame: &Frame, width: usize, height: usize) -> Vec<f32> {
+    let mut out = Vec::with_capacity(width * height);
+    for cy in 0..height {
+        let y0 = cy * frame.height() / height;
+        let y1 = ((cy + 1) * frame.height() / height).max(y0 + 1);
+        for cx in 0..width {
+            let x0 = cx * frame.width() / width;
+            let x1 = ((cx + 1) * frame.width() / width).max(x0 + 1);
+
+            let mut sum = 0u32;
+            let mut n = 0u32;
+            for y in (y0..y1).step_by(((y1 - y0) / 4).max(1)) {
+                for x in (x0..x1).step_by(((x1 - x0) / 4).max(1)) {
+                    sum += frame.luma(x.min(frame.width() - 1), y.min(frame.height() - 1)) as u32;
+                    n += 1;
+                }
+            }
+            out.push(sum as f32 / n as f32);
+        }
+    }
+    out
+}
+
+/// Drops frames that look like the last one kept, so an empty room is not sent over and over
+pub struct Dedup {
+    kind: HashKind,
+    threshold: u32,
+    last: Option<ImageHash>,
+}
+
+impl Dedup {
+    /// Frames whose hash is at most `threshold` bits away from the last kept one are duplicates
+    pub fn new(kind: HashKind, threshold: u32) -> Self {
+        Self {
+            kind,
+            threshold,
+            last: None,
+        }
+    }
+
+    /// Checks a raw frame, it becomes the new reference when it is not a duplicate
+    pub fn is_duplicate(&mut self, frame: &Frame) -> bool {
+        self.is_duplicate_hash(self.kind.hash(frame))
+    }
+
+    /// Same as [`Dedup::is_duplicate`] for a hash computed elsewhere
+    pub fn is_duplicate_hash(&mut self, hash: ImageHash) -> bool {
+        if let Some(last) = &self.last {
+            if last.distance(&hash) <= self.threshold {
+                return true;
+            }
+        }
+        self.last = Some(hash);
+        false
+    }
+
+    /// Forgets the last kept frame, the next one is always kept
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::PixelFormat;
+
+    const W: usize = 64;
+    const H: usize = 48;
+    const THRESHOLD: u32 = 10;
+    const KINDS: [HashKind; 3] = [
+        HashKind::Average,
+        HashKind::Difference,
+        HashKind::Perceptual,
+    ];
+
+    /// 8x8 pixel blocks of pseudo random brightness, shifted `shift` pixels to the right
+    fn pattern(shift: usize, brighten: u8) -> Frame {
+        let mut seed = 12345u32;
+        let blocks: Vec<u8> = (0..(W / 8) * (H / 8))
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                30 + (seed >> 16) as u8 % 170
+            })
+            .collect();
+        let data = (0..W * H)
+            .map(|i| {
+                let (x, y) = (((i % W) + W - shift) % W, i / W);
+                blocks[x / 8 + (y / 8) * (W / 8)] + brighten
+            })
+            .collect();
+        Frame::new(data, W, H, PixelFormat::Grayscale)
+    }
+
+    #[test]
+    fn brighter_copy_is_close() {
+        for kind in KINDS {
+            let distance = kind
+                .hash(&pattern(0, 0))
+                .distance(&kind.hash(&pattern(0, 20)));
+            assert!(distance <= THRESHOLD, "{:?}: {}", kind, distance);
+        }
+    }
+
+    #[test]
+    fn shifted_pattern_is_far() {
+        for kind in KINDS {
+            let distance = kind
+                .hash(&pattern(0, 0))
+                .distance(&kind.hash(&pattern(16, 0)));
+            assert!(distance > THRESHOLD, "{:?}: {}", kind, distance);
+        }
+    }
+
+    #[test]
+    fn reset_keeps_the_next_frame() {
+        let mut dedup = Dedup::new(HashKind::Difference, THRESHOLD);
+        assert!(!dedup.is_duplicate(&pattern(0, 0)));
+        assert!(dedup.is_duplicate(&pattern(0, 20)));
+        dedup.reset();
+        assert!(!dedup.is_duplicate(&pattern(0, 0)));
+        assert!(!dedup.is_duplicate(&pattern(16, 0)));
+    }
+}
@@ -0,0 +1,216 @@
+use std::time::SystemTime;
+
+use anyhow::Result;
+
+use crate::{
+    color::ColorCalibration, exif::ExifMetadata, frame::Frame, overlay::Overlay,
+    privacy::PrivacyMasks, transform::Orientation,
+};
+
+/// Processing shared by every output path (webserver, telegram, recordings),
+/// so that a frame looks the same wherever it ends up.
+///
+/// The mounting orientation is applied first, so that privacy masks are placed on the upright
+/// picture, then the colour calibration, which works per pixel and so only has to touch what
+/// is left after cropping, then the privacy masks, so that overlays are never covered by them.
+/// The EXIF block is added last, to the encoded picture.
+#[derive(Debug, Clone)]
+pub struct FramePipeline {
+    color: ColorCalibration,
+    orientation: Orientation,
+    privacy: PrivacyMasks,
+    overlay: Option<Overlay>,
+    exif: Option<ExifMetadata>,
+    quality: u8,
+}
+
+impl Default for FramePipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FramePipeline {
+    pub fn new() -> Self {
+        Self {
+            color: ColorCalibration::default(),
+            orientation: Orientation::default(),
+            privacy: PrivacyMasks::default(),
+            overlay: None,
+            exif: None,
+            quality: 80,
+        }
+    }
+
+    pub fn color(mut self, color: ColorCalibration) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn set_color(&mut self, color: ColorCalibration) {
+        self.color = color;
+    }
+
+    pub fn orientation(mut self, orientation: Orientation) -> Self {
+        self.orientation = orientation;
+        self
+    }
+
+    pub fn set_orientation(&mut self, orientation: Orientation) {
+        self.orientation = orientation;
+    }
+
+    pub fn get_orientation(&self) -> Orientation {
+        self.orientation
+    }
+
+    pub fn privacy(mut self, privacy: PrivacyMasks) -> Self {
+        self.privacy = privacy;
+        self
+    }
+
+    pub fn overlay(mut self, overlay: Overlay) -> Self {
+        self.overlay = Some(overlay);
+        self
+    }
+
+    /// Metadata written in every picture, the capture time is filled in by the pipeline
+    pub fn exif(mut self, exif: ExifMetadata) -> Self {
+        self.exif = Some(exif);
+        self
+    }
+
+    /// JPEG quality (1-100) used when a frame has to be encoded again
+    pub fn quality(mut self, quality: u8) -> Self {
+        self.quality = quality;
+        self
+    }
+
+    /// True when processing would leave frames untouched
+    pub fn is_passthrough(&self) -> bool {
+        self.color.is_identity()
+            && self.orientation.is_identity()
+            && self.privacy.is_empty()
+            && self.overlay.as_ref().map_or(true, |o| o.is_empty())
+    }
+
+    /// Applies every step to a raw frame
+    pub fn apply(&self, frame: &Frame, time: SystemTime) -> Result<Frame> {
+        let mut out = self.orientation.apply(frame)?;
+        self.color.apply(&mut out);
+        self.privacy.apply(&mut out);
+        if let Some(overlay) = &self.overlay {
+            overlay.apply(&mut out, time);
+        }
+        Ok(out)
+    }
+
+    /// Processes any frame and returns it JPEG encoded
+    pub fn process(&self, frame: &Frame, time: SystemTime) -> Result<Vec<u8>> {
+        let jpeg = if self.is_passthrough() {
+            frame.to_jpeg(self.quality)?
+        } else {
+            self.apply(&frame.to_raw()?, time)?.to_jpeg(self.quality)?
+        };
+        self.tag(&jpeg, time, &ExifMetadata::new())
+    }
+
+    /// Processes a JPEG, it is only decoded when there is something to draw
+    pub fn process_jpeg(&self, jpeg: &[u8], time: SystemTime) -> Result<Vec<u8>> {
+        self.process_capture(jpeg, time, &ExifMetadata::new())
+    }
+
+    /// Like `process_jpeg`, `capture` holds the metadata only known for this frame,
+    /// like the exposure and gain picked by the sensor
+    pub fn process_capture(
+        &self,
+        jpeg: &[u8],
+        time: SystemTime,
+        capture: &ExifMetadata,
+    ) -> Result<Vec<u8>> {
+        if self.is_passthrough() {
+            return self.tag(jpeg, time, capture);
+        }
+        let frame = Frame::decode_jpeg(jpeg)?;
+        let out = self.apply(&frame, time)?.to_jpeg(self.quality)?;
+        self.tag(&out, time, capture)
+    }
+
+    /// Encodes a frame that went through `apply` and adds the EXIF block, for outputs
+    /// that change the frame some more after the pipeline
+    pub fn encode(
+        &self,
+        frame: &Frame,
+        time: SystemTime,
+        capture: &ExifMetadata,
+    ) -> Result<Vec<u8>> {
+        self.tag(&frame.to_jpeg(self.quality)?, time, capture)
+    }
+
+    fn tag(&self, jpeg: &[u8], time: SystemTime, capture: &ExifMetadata) -> Result<Vec<u8>> {
+        let Some(exif) = &self.exif else {
+            return Ok(jpeg.to_vec());
+        };
+        // the orientation step has already turned the pixels upright
+        exif.clone()
+            .merge(capture)
+            .time(time)
+            .orientation(1)
+            .insert(jpeg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        frame::PixelFormat,
+        overlay::{OverlayContent, OverlayItem, Position},
+        privacy::{MaskFill, MaskShape, PrivacyMask},
+        transform::Rotation,
+    };
+
+    const RED: [u8; 3] = [255, 0, 0];
+    const BLUE: [u8; 3] = [0, 0, 255];
+
+    fn gray(w: usize, h: usize) -> Frame {
+        Frame::new(vec![128; w * h * 3], w, h, PixelFormat::Rgb888)
+    }
+
+    fn masked(x: f32, y: f32, w: f32, h: f32) -> PrivacyMasks {
+        PrivacyMasks::new().mask(PrivacyMask::new(
+            MaskShape::Rect { x, y, w, h },
+            MaskFill::Solid(RED),
+        ))
+    }
+
+    #[test]
+    fn masks_are_placed_on_the_rotated_picture() {
+        let pipeline = FramePipeline::new()
+            .orientation(Orientation::new(Rotation::Cw90))
+            .privacy(masked(0.0, 0.0, 0.5, 0.25));
+
+        let out = pipeline.apply(&gray(40, 20), SystemTime::now()).unwrap();
+        assert_eq!((out.width(), out.height()), (20, 40));
+        assert_eq!(out.pixel(0, 0), RED);
+        assert_eq!(out.pixel(9, 9), RED);
+        assert_eq!(out.pixel(10, 0), [128; 3]);
+        assert_eq!(out.pixel(0, 10), [128; 3]);
+    }
+
+    #[test]
+    fn overlay_is_drawn_over_the_masks() {
+        let overlay = Overlay::new("cam").item(
+            OverlayItem::new(OverlayContent::DeviceName, Position::At(0, 0))
+                .scale(1)
+                .background(Some(BLUE)),
+        );
+        let pipeline = FramePipeline::new()
+            .privacy(masked(0.0, 0.0, 1.0, 1.0))
+            .overlay(overlay);
+
+        let out = pipeline.apply(&gray(40, 20), SystemTime::now()).unwrap();
+        assert_eq!(out.pixel(0, 0), BLUE);
+        assert_eq!(out.pixel(39, 19), RED);
+    }
+}
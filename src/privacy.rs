@@ -0,0 +1,228 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::frame::Frame;
+
+/// Mask outline, every coordinate is relative to the frame size (0.0 - 1.0)
+/// so the same configuration works at any resolution.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaskShape {
+    Rect { x: f32, y: f32, w: f32, h: f32 },
+    Polygon(Vec<(f32, f32)>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaskFill {
+    Solid([u8; 3]),
+    /// Side of the pixelation blocks, relative to the frame width
+    Pixelate(f32),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PrivacyMask {
+    pub shape: MaskShape,
+    pub fill: MaskFill,
+}
+
+impl PrivacyMask {
+    pub fn new(shape: MaskShape, fill: MaskFill) -> Self {
+        Self { shape, fill }
+    }
+
+    /// Horizontal spans covered by the mask, as `(y, x_start, x_end)` with `x_end` excluded
+    fn spans(&self, width: usize, height: usize) -> Vec<(usize, usize, usize)> {
+        let (w, h) = (width as f32, height as f32);
+        let mut spans = Vec::new();
+
+        match &self.shape {
+            MaskShape::Rect { x, y, w: mw, h: mh } => {
+                let x0 = to_px(*x, w, width);
+                let x1 = to_px(x + mw, w, width);
+                let y0 = to_px(*y, h, height);
+                let y1 = to_px(y + mh, h, height);
+                if x0 < x1 {
+                    spans.extend((y0..y1).map(|row| (row, x0, x1)));
+                }
+            }
+            MaskShape::Polygon(points) => {
+                if points.len() < 3 {
+                    return spans;
+                }
+                let points = points
+                    .iter()
+                    .map(|(px, py)| (px * w, py * h))
+                    .collect::<Vec<_>>();
+
+                let mut crossings = Vec::new();
+                for row in 0..height {
+                    // sample at the pixel center, even-odd rule
+                    let cy = row as f32 + 0.5;
+                    crossings.clear();
+                    for (i, a) in points.iter().enumerate() {
+                        let b = points[(i + 1) % points.len()];
+                        if (a.1 <= cy) != (b.1 <= cy) {
+                            crossings.push(a.0 + (cy - a.1) / (b.1 - a.1) * (b.0 - a.0));
+                        }
+                    }
+                    crossings.sort_by(|a, b| a.total_cmp(b));
+
+                    for pair in crossings.chunks_exact(2) {
+                        let x0 = (pair[0] - 0.5).ceil().clamp(0.0, w) as usize;
+                        let x1 = (pair[1] - 0.5).ceil().clamp(0.0, w) as usize;
+                        if x0 < x1 {
+                            spans.push((row, x0, x1));
+                        }
+                    }
+                }
+            }
+        }
+
+        spans
+    }
+
+    pub fn apply(&self, frame: &mut Frame) {
+        let spans = self.spans(frame.width(), frame.height());
+
+        match self.fill {
+            MaskFill::Solid(color) => {
+                for (y, x0, x1) in spans {
+                    for x in x0..x1 {
+                        frame.set_pixel(x, y, color);
+                    }
+                }
+            }
+            MaskFill::Pixelate(block) => {
+                let block = ((block * frame.width() as f32) as usize).max(2);
+                pixelate(frame, &spans, block);
+            }
+        }
+    }
+}
+
+fn to_px(v: f32, scale: f32, max: usize) -> usize {
+    ((v * scale).round().max(0.0) as usize).min(max)
+}
+
+/// Replaces every masked pixel with the average of the masked pixels in its block,
+/// so nothing from outside the mask bleeds in and nothing inside survives.
+fn pixelate(frame: &mut Frame, spans: &[(usize, usize, usize)], block: usize) {
+    let blocks_x = (frame.width() + block - 1) / block;
+    let blocks_y = (frame.height() + block - 1) / block;
+    let mut sums = vec![[0u32; 4]; blocks_x * blocks_y];
+
+    for &(y, x0, x1) in spans {
+        for x in x0..x1 {
+            let px = frame.pixel(x, y);
+            let sum = &mut sums[x / block + (y / block) * blocks_x];
+            sum[0] += px[0] as u32;
+            sum[1] += px[1] as u32;
+            sum[2] += px[2] as u32;
+            sum[3] += 1;
+        }
+    }
+
+    for &(y, x0, x1) in spans {
+        for x in x0..x1 {
+            let sum = sums[x / block + (y / block) * blocks_x];
+            let n = sum[3].max(1);
+            frame.set_pixel(
+                x,
+                y,
+                [(sum[0] / n) as u8, (sum[1] / n) as u8, (sum[2] / n) as u8],
+            );
+        }
+    }
+}
+
+/// The set of masks blacked out of every image leaving the device.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PrivacyMasks {
+    pub masks: Vec<PrivacyMask>,
+}
+
+impl PrivacyMasks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses a json list of masks, such as
+    /// `[{"shape": {"rect": {"x": 0.0, "y": 0.0, "w": 0.25, "h": 0.5}}, "fill": {"solid": [0, 0, 0]}}]`
+    pub fn from_json(json: &str) -> Result<Self> {
+        if json.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        Ok(Self {
+            masks: serde_json::from_str(json)?,
+        })
+    }
+
+    pub fn mask(mut self, mask: PrivacyMask) -> Self {
+        self.masks.push(mask);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.masks.is_empty()
+    }
+
+    pub fn apply(&self, frame: &mut Frame) {
+        for mask in &self.masks {
+            mask.apply(frame);
+        }
+    }
+
+    /// Decodes a JPEG, applies the masks and encodes it again with `quality` (1-100).
+    pub fn apply_jpeg(&self, jpeg: &[u8], quality: u8) -> Result<Vec<u8>> {
+        let mut frame = Frame::decode_jpeg(jpeg)?;
+        self.apply(&mut frame);
+        frame.to_jpeg(quality)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::PixelFormat;
+
+    const RED: [u8; 3] = [255, 0, 0];
+
+    fn gray(w: usize, h: usize) -> Frame {
+        Frame::new(vec![128; w * h * 3], w, h, PixelFormat::Rgb888)
+    }
+
+    #[test]
+    fn rect_is_filled() {
+        let mut frame = gray(20, 10);
+        let mask = PrivacyMask::new(
+            MaskShape::Rect {
+                x: 0.5,
+                y: 0.0,
+                w: 0.5,
+                h: 0.5,
+            },
+            MaskFill::Solid(RED),
+        );
+        mask.apply(&mut frame);
+        assert_eq!(frame.pixel(10, 0), RED);
+        assert_eq!(frame.pixel(19, 4), RED);
+        assert_eq!(frame.pixel(9, 0), [128; 3]);
+        assert_eq!(frame.pixel(19, 5), [128; 3]);
+    }
+
+    #[test]
+    fn pixelation_keeps_nothing_from_outside() {
+        let mut frame = gray(8, 8);
+        frame.set_pixel(1, 1, [255; 3]);
+        let mask = PrivacyMask::new(
+            MaskShape::Polygon(vec![(0.0, 0.0), (0.5, 0.0), (0.5, 0.5), (0.0, 0.5)]),
+            MaskFill::Pixelate(0.5),
+        );
+        mask.apply(&mut frame);
+        // the bright pixel is spread over the masked block only
+        assert_eq!(frame.pixel(1, 1), [135; 3]);
+        assert_eq!(frame.pixel(3, 3), [135; 3]);
+        assert_eq!(frame.pixel(4, 4), [128; 3]);
+    }
+}
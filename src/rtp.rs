@@ -0,0 +1,385 @@
+use std::{io, time::Duration};
+
+use anyhow::{bail, Result};
+
+/// Static RTP payload type of JPEG
+pub const PAYLOAD_TYPE: u8 = 26;
+/// RTP clock of video, in ticks per second
+pub const CLOCK_RATE: u32 = 90_000;
+/// Room left for the IP and UDP headers on a 1500 bytes MTU
+pub const DEFAULT_MAX_PACKET: usize = 1400;
+
+const RTP_HEADER_LEN: usize = 12;
+const JPEG_HEADER_LEN: usize = 8;
+const RESTART_HEADER_LEN: usize = 4;
+const QUANT_HEADER_LEN: usize = 4;
+/// Tells the receiver that the quantization tables come in the first packet of the frame
+const DYNAMIC_Q: u8 = 255;
+/// RFC 2435 types with restart markers have this added
+const RESTART_TYPE: u8 = 64;
+
+/// What RFC 2435 takes from a baseline JPEG, the receiver rebuilds the headers from it
+#[derive(Debug, Clone, PartialEq)]
+pub struct JpegFrame<'a> {
+    pub width: u16,
+    pub height: u16,
+    /// RFC 2435 type, 0 for YUV 4:2:2 and 1 for 4:2:0
+    pub kind: u8,
+    /// MCUs between restart markers, 0 without them
+    pub restart_interval: u16,
+    /// Luma then chroma table, 64 bytes each in zigzag order
+    pub tables: Vec<u8>,
+    /// Entropy coded data, between the SOS header and the EOI marker
+    pub scan: &'a [u8],
+}
+
+/// Splits a baseline JPEG, like the ones of the sensor, into what the
+/// RTP payload carries. Progressive, 12 bit and grayscale pictures are refused.
+pub fn parse_jpeg(jpeg: &[u8]) -> Result<JpegFrame<'_>> {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        bail!("not a jpeg");
+    }
+
+    let mut tables: [Option<&[u8]>; 4] = [None; 4];
+    let mut frame = None;
+    let mut restart_interval = 0;
+    let mut pos = 2;
+    loop {
+        if jpeg.get(pos) != Some(&0xFF) {
+            bail!("bad jpeg marker at {}", pos);
+        }
+        // markers may be padded with any number of 0xFF
+        while jpeg.get(pos + 1) == Some(&0xFF) {
+            pos += 1;
+        }
+        let Some(&marker) = jpeg.get(pos + 1) else {
+            bail!("truncated jpeg");
+        };
+        if marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+            pos += 2;
+            continue;
+        }
+        let Some(len) = jpeg.get(pos + 2..pos + 4) else {
+            bail!("truncated jpeg");
+        };
+        let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+        let Some(segment) = jpeg.get(pos + 4..pos + 2 + len) else {
+            bail!("truncated jpeg segment");
+        };
+
+        match marker {
+            0xDB => {
+                let mut rest = segment;
+                while let [info, data @ ..] = rest {
+                    if info >> 4 != 0 {
+                        bail!("16 bit quantization tables are not supported");
+                    }
+                    if data.len() < 64 {
+                        bail!("truncated quantization table");
+                    }
+                    tables[(info & 0x03) as usize] = Some(&data[..64]);
+                    rest = &data[64..];
+                }
+            }
+            0xC0 => frame = Some(parse_sof(segment)?),
+            0xC1..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                bail!("only baseline jpeg is supported");
+            }
+            0xDD if segment.len() >= 2 => {
+                restart_interval = u16::from_be_bytes([segment[0], segment[1]]);
+            }
+            0xDA => {
+                let Some((width, height, kind, luma, chroma)) = frame else {
+                    bail!("jpeg scan before the frame header");
+                };
+                let (Some(luma), Some(chroma)) = (tables[luma], tables[chroma]) else {
+                    bail!("missing quantization table");
+                };
+                let scan = &jpeg[pos + 2 + len..];
+                let scan = scan.strip_suffix(&[0xFF, 0xD9]).unwrap_or(scan);
+                return Ok(JpegFrame {
+                    width,
+                    height,
+                    kind,
+                    restart_interval,
+                    tables: [luma, chroma].concat(),
+                    scan,
+                });
+            }
+            _ => {}
+        }
+        pos += 2 + len;
+    }
+}
+
+/// Size, RFC 2435 type and table numbers of luma and chroma from a SOF0 segment
+fn parse_sof(segment: &[u8]) -> Result<(u16, u16, u8, usize, usize)> {
+    let [8, h1, h0, w1, w0, 3, components @ ..] = segment else {
+        bail!("only 8 bit YCbCr jpeg is supported");
+    };
+    let [_, y_sampling, y_table, _, cb_sampling, cb_table, _, cr_sampling, cr_table] = components
+    else {
+        bail!("truncated jpeg frame header");
+    };
+    let kind = match (y_sampling, cb_sampling, cr_sampling) {
+        (0x21, 0x11, 0x11) => 0,
+        (0x22, 0x11, 0x11) => 1,
+        _ => bail!("only 4:2:2 and 4:2:0 jpeg are supported"),
+    };
+    if cb_table != cr_table || *y_table > 3 || *cb_table > 3 {
+        bail!("unsupported jpeg quantization tables");
+    }
+
+    let (width, height) = (
+        u16::from_be_bytes([*w1, *w0]),
+        u16::from_be_bytes([*h1, *h0]),
+    );
+    if width == 0 || height == 0 || width > 2040 || height > 2040 {
+        bail!("jpeg of {}x{} does not fit in RTP", width, height);
+    }
+    Ok((width, height, kind, *y_table as usize, *cb_table as usize))
+}
+
+/// RTP timestamp of a moment, `elapsed` after the start of the session
+pub fn rtp_time(elapsed: Duration) -> u32 {
+    (elapsed.as_micros() * CLOCK_RATE as u128 / 1_000_000) as u32
+}
+
+/// Cuts JPEG frames into RTP packets of at most `max_packet` bytes, per RFC 2435
+pub struct JpegPacketizer {
+    ssrc: u32,
+    sequence: u16,
+    max_packet: usize,
+    buffer: Vec<u8>,
+}
+
+impl JpegPacketizer {
+    pub fn new(ssrc: u32, sequence: u16, max_packet: usize) -> Self {
+        Self {
+            ssrc,
+            sequence,
+            // the first packet has to fit the headers and the tables
+            max_packet: max_packet.max(
+                RTP_HEADER_LEN + JPEG_HEADER_LEN + RESTART_HEADER_LEN + QUANT_HEADER_LEN + 256,
+            ),
+            buffer: Vec::with_capacity(max_packet),
+        }
+    }
+
+    /// Sequence number of the next packet
+    pub fn sequence(&self) -> u16 {
+        self.sequence
+    }
+
+    /// Hands every packet of a frame to `send`, the last one has the marker bit
+    pub fn packetize(
+        &mut self,
+        frame: &JpegFrame,
+        timestamp: u32,
+        mut send: impl FnMut(&[u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let kind = if frame.restart_interval > 0 {
+            frame.kind + RESTART_TYPE
+        } else {
+            frame.kind
+        };
+
+        let mut offset = 0;
+        while offset < frame.scan.len() {
+            let packet = &mut self.buffer;
+            packet.clear();
+            packet.extend_from_slice(&[0x80, PAYLOAD_TYPE]);
+            packet.extend_from_slice(&self.sequence.to_be_bytes());
+            packet.extend_from_slice(&timestamp.to_be_bytes());
+            packet.extend_from_slice(&self.ssrc.to_be_bytes());
+
+            packet.push(0);
+            packet.extend_from_slice(&(offset as u32).to_be_bytes()[1..]);
+            packet.extend_from_slice(&[
+                kind,
+                DYNAMIC_Q,
+                ((frame.width + 7) / 8) as u8,
+                ((frame.height + 7) / 8) as u8,
+            ]);
+            if frame.restart_interval > 0 {
+                packet.extend_from_slice(&frame.restart_interval.to_be_bytes());
+                // first and last bits set with a count of 0x3FFF, the whole frame
+                packet.extend_from_slice(&[0xFF, 0xFF]);
+            }
+            if offset == 0 {
+                packet.extend_from_slice(&[0, 0]);
+                packet.extend_from_slice(&(frame.tables.len() as u16).to_be_bytes());
+                packet.extend_from_slice(&frame.tables);
+            }
+
+            let room = self.max_packet.saturating_sub(packet.len()).max(1);
+            let end = frame.scan.len().min(offset + room);
+            packet.extend_from_slice(&frame.scan[offset..end]);
+            if end == frame.scan.len() {
+                packet[1] |= 0x80;
+            }
+            offset = end;
+            self.sequence = self.sequence.wrapping_add(1);
+            send(packet)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jpeg_encoder::{ColorType, Encoder, SamplingFactor};
+
+    use super::*;
+
+    /// A noisy picture, so that the scan is several packets long
+    fn jpeg(sampling: SamplingFactor, restart_interval: u16) -> Vec<u8> {
+        let (width, height) = (64u16, 48u16);
+        let mut seed = 1u32;
+        let pixels: Vec<u8> = (0..width as usize * height as usize * 3)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+
+        let mut out = Vec::new();
+        let mut encoder = Encoder::new(&mut out, 90);
+        encoder.set_sampling_factor(sampling);
+        encoder.set_restart_interval(restart_interval);
+        encoder
+            .encode(&pixels, width, height, ColorType::Rgb)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn parses_422_and_420() {
+        for (sampling, kind) in [(SamplingFactor::R_4_2_2, 0), (SamplingFactor::R_4_2_0, 1)] {
+            let jpeg = jpeg(sampling, 0);
+            let frame = parse_jpeg(&jpeg).unwrap();
+            assert_eq!((frame.width, frame.height), (64, 48));
+            assert_eq!(frame.kind, kind);
+            assert_eq!(frame.restart_interval, 0);
+            assert_eq!(frame.tables.len(), 128);
+
+            // the scan runs up to the end of image marker
+            let scan_end = frame.scan.as_ptr() as usize - jpeg.as_ptr() as usize + frame.scan.len();
+            assert_eq!(&jpeg[scan_end..], [0xFF, 0xD9]);
+        }
+    }
+
+    #[test]
+    fn parses_restart_interval() {
+        let frame_jpeg = jpeg(SamplingFactor::R_4_2_0, 4);
+        let frame = parse_jpeg(&frame_jpeg).unwrap();
+        assert_eq!(frame.restart_interval, 4);
+        assert!(frame.scan.windows(2).any(|w| w == [0xFF, 0xD0]));
+    }
+
+    #[test]
+    fn refuses_what_rtp_cannot_carry() {
+        assert!(parse_jpeg(&jpeg(SamplingFactor::R_4_4_4, 0)).is_err());
+        assert!(parse_jpeg(b"GIF89a").is_err());
+
+        let mut gray = Vec::new();
+        Encoder::new(&mut gray, 90)
+            .encode(&[128; 64], 8, 8, ColorType::Luma)
+            .unwrap();
+        assert!(parse_jpeg(&gray).is_err());
+
+        let mut progressive = jpeg(SamplingFactor::R_4_2_0, 0);
+        let sof = progressive
+            .windows(2)
+            .position(|w| w == [0xFF, 0xC0])
+            .unwrap();
+        progressive[sof + 1] = 0xC2;
+        assert!(parse_jpeg(&progressive).is_err());
+
+        let truncated = jpeg(SamplingFactor::R_4_2_0, 0);
+        assert!(parse_jpeg(&truncated[..100]).is_err());
+    }
+
+    fn packets(frame: &JpegFrame, max_packet: usize) -> Vec<Vec<u8>> {
+        let mut packetizer = JpegPacketizer::new(0x1234_5678, 65534, max_packet);
+        let mut packets = Vec::new();
+        packetizer
+            .packetize(frame, 9000, |p| {
+                packets.push(p.to_vec());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            packetizer.sequence(),
+            65534u16.wrapping_add(packets.len() as u16)
+        );
+        packets
+    }
+
+    fn fragment_offset(packet: &[u8]) -> usize {
+        u32::from_be_bytes([0, packet[13], packet[14], packet[15]]) as usize
+    }
+
+    #[test]
+    fn fragments_the_scan() {
+        let tables: Vec<u8> = (0..128).collect();
+        let scan: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        let frame = JpegFrame {
+            width: 640,
+            height: 480,
+            kind: 1,
+            restart_interval: 0,
+            tables: tables.clone(),
+            scan: &scan,
+        };
+        let packets = packets(&frame, DEFAULT_MAX_PACKET);
+        assert_eq!(packets.len(), 4);
+
+        let mut rebuilt = Vec::new();
+        for (i, packet) in packets.iter().enumerate() {
+            assert!(packet.len() <= DEFAULT_MAX_PACKET);
+            assert_eq!(packet[0], 0x80);
+            // only the last packet of the frame has the marker bit
+            let marker = i == packets.len() - 1;
+            assert_eq!(packet[1], PAYLOAD_TYPE | if marker { 0x80 } else { 0 });
+            let sequence = u16::from_be_bytes([packet[2], packet[3]]);
+            assert_eq!(sequence, 65534u16.wrapping_add(i as u16));
+            assert_eq!(packet[4..8], 9000u32.to_be_bytes());
+            assert_eq!(packet[8..12], 0x1234_5678u32.to_be_bytes());
+
+            // type, Q, width and height in blocks of 8
+            assert_eq!(fragment_offset(packet), rebuilt.len());
+            assert_eq!(packet[16..20], [1, 255, 80, 60]);
+            let mut payload = &packet[20..];
+            if i == 0 {
+                // the tables come first, 8 bit precision
+                assert_eq!(payload[..4], [0, 0, 0, 128]);
+                assert_eq!(payload[4..132], tables[..]);
+                payload = &payload[132..];
+            }
+            rebuilt.extend_from_slice(payload);
+        }
+        assert_eq!(rebuilt, scan);
+    }
+
+    #[test]
+    fn restart_header_follows_the_jpeg_header() {
+        let frame_jpeg = jpeg(SamplingFactor::R_4_2_2, 4);
+        let frame = parse_jpeg(&frame_jpeg).unwrap();
+        let packets = packets(&frame, DEFAULT_MAX_PACKET);
+        assert!(packets.len() > 1);
+
+        let first = &packets[0];
+        assert_eq!(first[16..20], [64, 255, 8, 6]);
+        assert_eq!(first[20..24], [0, 4, 0xFF, 0xFF]);
+        assert_eq!(first[24..28], [0, 0, 0, 128]);
+        assert_eq!(first[28..156], frame.tables[..]);
+        assert_eq!(packets.last().unwrap()[1], PAYLOAD_TYPE | 0x80);
+    }
+
+    #[test]
+    fn rtp_clock() {
+        assert_eq!(rtp_time(Duration::from_secs(1)), CLOCK_RATE);
+        assert_eq!(rtp_time(Duration::from_millis(100)), 9000);
+    }
+}
@@ -0,0 +1,1039 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use crate::{
+    auth::{random_bytes, AuthError, Role, SharedAuthenticator},
+    rtp::{parse_jpeg, rtp_time, JpegPacketizer, DEFAULT_MAX_PACKET, PAYLOAD_TYPE},
+    stream::{query_value, Slot, StreamHub},
+};
+
+/// Methods answered by the server, clients use GET_PARAMETER to keep the session alive
+const PUBLIC: &str = "OPTIONS, DESCRIBE, SETUP, PLAY, PAUSE, TEARDOWN, GET_PARAMETER";
+/// Control URL of the only track, relative to the stream
+const TRACK: &str = "track1";
+/// How often the sender looks at the stop flag while no frame comes
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// Requests and headers past this are refused
+const MAX_LINE_LEN: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct RtspConfig {
+    /// Frames per second sent to the clients, they can ask for less with `?fps=N`
+    pub max_fps: u32,
+    /// Sessions set up at the same time, past this SETUP fails
+    pub max_clients: usize,
+    /// A client that sends nothing for this long is dropped, RTCP over TCP counts
+    pub session_timeout: Duration,
+    /// Size of the RTP packets, headers included
+    pub max_packet: usize,
+    /// Clients must log in when set
+    pub auth: Option<SharedAuthenticator>,
+}
+
+impl Default for RtspConfig {
+    fn default() -> Self {
+        Self {
+            max_fps: 10,
+            max_clients: 2,
+            session_timeout: Duration::from_secs(60),
+            max_packet: DEFAULT_MAX_PACKET,
+            auth: None,
+        }
+    }
+}
+
+/// A request of a client, the body is read and dropped
+#[derive(Debug, Clone, PartialEq)]
+pub struct RtspRequest {
+    pub method: String,
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RtspRequest {
+    /// Reads the next request, `None` when the client closed the connection. Interleaved
+    /// packets coming in between, the RTCP reports of TCP clients, are skipped.
+    pub fn read(reader: &mut impl BufRead) -> io::Result<Option<Self>> {
+        loop {
+            match reader.fill_buf()?.first() {
+                None => return Ok(None),
+                Some(b'$') => {
+                    let mut header = [0; 4];
+                    reader.read_exact(&mut header)?;
+                    let len = u16::from_be_bytes([header[2], header[3]]) as u64;
+                    io::copy(&mut reader.take(len), &mut io::sink())?;
+                }
+                Some(b'\r' | b'\n') => reader.consume(1),
+                Some(_) => break,
+            }
+        }
+
+        let request_line = read_line(reader)?;
+        let mut parts = request_line.split_whitespace();
+        let (Some(method), Some(uri), Some("RTSP/1.0")) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "bad rtsp request",
+            ));
+        };
+        let mut request = Self {
+            method: method.to_string(),
+            uri: uri.to_string(),
+            headers: Vec::new(),
+        };
+
+        loop {
+            let line = read_line(reader)?;
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                request
+                    .headers
+                    .push((name.trim().to_string(), value.trim().to_string()));
+            }
+        }
+        if let Some(len) = request.header("Content-Length") {
+            let len = len.parse::<u64>().unwrap_or(0);
+            io::copy(&mut reader.take(len), &mut io::sink())?;
+        }
+        Ok(Some(request))
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Path of the URI, `rtsp://host:554/stream?fps=5` gives `/stream`
+    pub fn path(&self) -> &str {
+        let uri = self.uri.split('?').next().unwrap_or("");
+        match uri.split_once("://") {
+            Some((_, rest)) => rest.find('/').map_or("/", |start| &rest[start..]),
+            None => uri,
+        }
+    }
+}
+
+fn read_line(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut line = Vec::new();
+    reader
+        .take(MAX_LINE_LEN as u64)
+        .read_until(b'\n', &mut line)?;
+    if line.last() != Some(&b'\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "rtsp line too long or cut",
+        ));
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end().to_string())
+}
+
+/// An answer to a request, the `CSeq` of the request is added when written
+#[derive(Debug, Clone, PartialEq)]
+pub struct RtspResponse {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: String,
+}
+
+impl RtspResponse {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: String::new(),
+        }
+    }
+
+    pub fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn sdp(self, sdp: String) -> Self {
+        Self { body: sdp, ..self }.header("Content-Type", "application/sdp")
+    }
+
+    pub fn write_to(&self, out: &mut impl Write, cseq: Option<&str>) -> io::Result<()> {
+        let mut text = format!("RTSP/1.0 {} {}\r\n", self.status, reason(self.status));
+        if let Some(cseq) = cseq {
+            text += &format!("CSeq: {}\r\n", cseq);
+        }
+        for (name, value) in &self.headers {
+            text += &format!("{}: {}\r\n", name, value);
+        }
+        if !self.body.is_empty() {
+            text += &format!("Content-Length: {}\r\n", self.body.len());
+        }
+        text += "\r\n";
+        text += &self.body;
+        out.write_all(text.as_bytes())?;
+        out.flush()
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        453 => "Not Enough Bandwidth",
+        454 => "Session Not Found",
+        455 => "Method Not Valid in This State",
+        461 => "Unsupported Transport",
+        503 => "Service Unavailable",
+        _ => "Error",
+    }
+}
+
+/// How the RTP packets reach a client, from the `Transport` header of its SETUP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// UDP to the RTP and RTCP ports of the client
+    Udp { client_ports: (u16, u16) },
+    /// `$` framed packets on the RTSP connection, for clients behind NAT or firewalls
+    Interleaved { channels: (u8, u8) },
+}
+
+impl Transport {
+    /// The first unicast transport of the header that the server can do
+    pub fn parse(header: &str) -> Option<Self> {
+        header.split(',').find_map(|spec| {
+            let mut params = spec.split(';').map(str::trim);
+            let profile = params.next()?;
+            let params = params.collect::<Vec<_>>();
+            if params.contains(&"multicast") {
+                return None;
+            }
+            let pair = |key: &str| {
+                let value = params.iter().find_map(|p| p.strip_prefix(key))?;
+                let (first, second) = value.split_once('-').unwrap_or((value, ""));
+                let first = first.parse::<u16>().ok()?;
+                Some((first, second.parse::<u16>().unwrap_or(first + 1)))
+            };
+
+            match profile {
+                "RTP/AVP" | "RTP/AVP/UDP" => Some(Transport::Udp {
+                    client_ports: pair("client_port=")?,
+                }),
+                "RTP/AVP/TCP" => {
+                    let (rtp, rtcp) = pair("interleaved=").unwrap_or((0, 1));
+                    Some(Transport::Interleaved {
+                        channels: (u8::try_from(rtp).ok()?, u8::try_from(rtcp).ok()?),
+                    })
+                }
+                _ => None,
+            }
+        })
+    }
+
+    /// The header of the SETUP response, `server_port` is the RTP port of the server
+    pub fn describe(&self, server_port: u16) -> String {
+        match self {
+            Transport::Udp { client_ports } => format!(
+                "RTP/AVP;unicast;client_port={}-{};server_port={}-{}",
+                client_ports.0,
+                client_ports.1,
+                server_port,
+                server_port + 1
+            ),
+            Transport::Interleaved { channels } => format!(
+                "RTP/AVP/TCP;unicast;interleaved={}-{}",
+                channels.0, channels.1
+            ),
+        }
+    }
+}
+
+/// Session description of the stream, one JPEG video track
+pub fn sdp(session: &str, address: IpAddr, fps: u32) -> String {
+    let family = if address.is_ipv4() { "IP4" } else { "IP6" };
+    format!(
+        "v=0\r\n\
+         o=- {session} 1 IN {family} {address}\r\n\
+         s=espcam\r\n\
+         c=IN {family} {any}\r\n\
+         t=0 0\r\n\
+         a=control:*\r\n\
+         a=range:npt=0-\r\n\
+         m=video 0 RTP/AVP {pt}\r\n\
+         a=rtpmap:{pt} JPEG/90000\r\n\
+         a=framerate:{fps}\r\n\
+         a=control:{TRACK}\r\n",
+        any = if address.is_ipv4() { "0.0.0.0" } else { "::" },
+        pt = PAYLOAD_TYPE,
+    )
+}
+
+fn random_u64() -> u64 {
+    u64::from_le_bytes(random_bytes())
+}
+
+/// Where a session sends its packets
+enum Sink {
+    Udp(UdpSocket),
+    Interleaved {
+        out: Arc<Mutex<TcpStream>>,
+        channel: u8,
+    },
+}
+
+impl Sink {
+    fn send(&self, packet: &[u8]) -> io::Result<()> {
+        match self {
+            Sink::Udp(socket) => socket.send(packet).map(|_| ()),
+            Sink::Interleaved { out, channel } => {
+                // responses go on the same connection, so header and packet are written together
+                let mut out = out.lock().unwrap();
+                out.write_all(&[b'$', *channel])?;
+                out.write_all(&(packet.len() as u16).to_be_bytes())?;
+                out.write_all(packet)
+            }
+        }
+    }
+}
+
+/// The thread sending the frames of a playing session, it gives the packetizer back
+/// when stopped so that the sequence numbers go on after a PAUSE
+struct Player {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<JpegPacketizer>,
+}
+
+impl Player {
+    fn stop(self) -> Option<JpegPacketizer> {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread.join().ok()
+    }
+}
+
+/// A client that went through SETUP
+struct Session {
+    id: String,
+    sink: Arc<Sink>,
+    fps: u32,
+    /// Start of the RTP clock, with a random origin as RFC 3550 asks
+    epoch: Instant,
+    time_origin: u32,
+    packetizer: Option<JpegPacketizer>,
+    player: Option<Player>,
+    _slot: Slot,
+}
+
+impl Session {
+    fn timestamp(&self) -> u32 {
+        self.time_origin
+            .wrapping_add(rtp_time(self.epoch.elapsed()))
+    }
+
+    fn play(&mut self, hub: &StreamHub) -> io::Result<()> {
+        let Some(mut packetizer) = self.packetizer.take() else {
+            return Ok(());
+        };
+        let stop = Arc::new(AtomicBool::new(false));
+        let (sink, epoch, time_origin) = (self.sink.clone(), self.epoch, self.time_origin);
+        let mut viewer = hub.subscribe(Some(self.fps));
+        let stopped = stop.clone();
+        let thread = std::thread::Builder::new()
+            .stack_size(8 * 1024)
+            .spawn(move || {
+                let mut warned = false;
+                while !stopped.load(Ordering::Relaxed) {
+                    let Some(jpeg) = viewer.next_frame(POLL_INTERVAL) else {
+                        continue;
+                    };
+                    let frame = match parse_jpeg(&jpeg) {
+                        Ok(frame) => frame,
+                        Err(err) => {
+                            // the same for every frame, once is enough
+                            if !warned {
+                                log::warn!("frames not sent over rtp: {:?}", err);
+                                warned = true;
+                            }
+                            continue;
+                        }
+                    };
+                    let timestamp = time_origin.wrapping_add(rtp_time(epoch.elapsed()));
+                    if let Err(err) = packetizer.packetize(&frame, timestamp, |p| sink.send(p)) {
+                        // lwIP runs out of buffers in a burst now and then, over UDP the
+                        // rest of the frame is lost and the next one goes out
+                        if let Sink::Udp(_) = *sink {
+                            log::debug!("rtp frame dropped: {:?}", err);
+                            continue;
+                        }
+                        log::info!("rtsp client gone: {:?}", err);
+                        break;
+                    }
+                }
+                packetizer
+            })?;
+        self.player = Some(Player { stop, thread });
+        Ok(())
+    }
+
+    /// Takes back the packetizer of a player that stopped on a send error, so that PLAY
+    /// starts it again
+    fn reap(&mut self) {
+        if self.player.as_ref().is_some_and(|p| p.thread.is_finished()) {
+            self.pause();
+        }
+    }
+
+    fn pause(&mut self) {
+        if let Some(player) = self.player.take() {
+            self.packetizer = player.stop();
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.pause();
+    }
+}
+
+/// RTSP server streaming the frames of the hub as RTP/JPEG, over UDP or interleaved in
+/// the RTSP connection. Every connection gets a thread, and every playing session another
+/// one sending the packets.
+///
+/// The stream is at `rtsp://<address>:<port><path>`, clients can lower the frame rate
+/// with `?fps=N`. Frames have to be baseline JPEG with 4:2:2 or 4:2:0 sampling, like the
+/// ones of the sensor and of [`Frame::to_jpeg`](crate::frame::Frame::to_jpeg).
+pub fn spawn_rtsp_server(
+    port: u16,
+    path: &'static str,
+    hub: StreamHub,
+    config: RtspConfig,
+) -> Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    let clients = Arc::new(AtomicUsize::new(0));
+
+    Ok(std::thread::Builder::new()
+        .stack_size(8 * 1024)
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        log::error!("rtsp accept error: {:?}", err);
+                        continue;
+                    }
+                };
+
+                let hub = hub.clone();
+                let config = config.clone();
+                let clients = clients.clone();
+                let spawned = std::thread::Builder::new()
+                    .stack_size(8 * 1024)
+                    .spawn(move || {
+                        let mut connection = Connection {
+                            path,
+                            hub: &hub,
+                            config: &config,
+                            clients: &clients,
+                            session: None,
+                            fps: None,
+                        };
+                        if let Err(err) = connection.run(stream) {
+                            log::info!("rtsp client gone: {:?}", err);
+                        }
+                    });
+                if let Err(err) = spawned {
+                    log::error!("could not start an rtsp thread: {:?}", err);
+                }
+            }
+        })?)
+}
+
+struct Connection<'a> {
+    path: &'a str,
+    hub: &'a StreamHub,
+    config: &'a RtspConfig,
+    clients: &'a Arc<AtomicUsize>,
+    session: Option<Session>,
+    /// Frame rate asked for with `?fps=N`, kept for the requests on the track URL
+    fps: Option<u32>,
+}
+
+impl Connection<'_> {
+    fn run(&mut self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(self.config.session_timeout))?;
+        stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+        let local = stream.local_addr()?;
+        let peer = stream.peer_addr()?;
+        let out = Arc::new(Mutex::new(stream.try_clone()?));
+        let mut reader = BufReader::new(stream);
+
+        while let Some(request) = RtspRequest::read(&mut reader)? {
+            let response = self.handle(&request, local, peer, &out)?;
+            response.write_to(&mut *out.lock().unwrap(), request.header("CSeq"))?;
+            if request.method == "PLAY" && response.status == 200 {
+                // packets only after the response, clients drop them otherwise
+                if let Some(session) = self.session.as_mut() {
+                    session.play(self.hub)?;
+                }
+            }
+            if request.method == "TEARDOWN" {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(
+        &mut self,
+        request: &RtspRequest,
+        local: SocketAddr,
+        peer: SocketAddr,
+        out: &Arc<Mutex<TcpStream>>,
+    ) -> io::Result<RtspResponse> {
+        if request.method == "OPTIONS" {
+            return Ok(RtspResponse::new(200).header("Public", PUBLIC));
+        }
+        if request.uri != "*" && !self.is_stream(request) {
+            return Ok(RtspResponse::new(404));
+        }
+        if let Some(response) = self
+            .config
+            .auth
+            .as_ref()
+            .and_then(|auth| refusal(auth, peer.ip(), request.header("Authorization")))
+        {
+            return Ok(response);
+        }
+
+        match request.method.as_str() {
+            "DESCRIBE" => {
+                let session = self.session.as_ref().map_or_else(
+                    || (random_u64() >> 1).to_string(),
+                    |session| session.id.clone(),
+                );
+                // the query stays out, clients append the track to the base
+                let base = request.uri.split('?').next().unwrap_or("");
+                let base = base.trim_end_matches('/');
+                Ok(RtspResponse::new(200)
+                    .header("Content-Base", format!("{}/", base))
+                    .sdp(sdp(&session, local.ip(), self.fps(&request.uri))))
+            }
+            "SETUP" => self.setup(request, local, peer, out),
+            "PLAY" | "PAUSE" | "TEARDOWN" | "GET_PARAMETER" => {
+                let Some(session) = self.session.as_mut() else {
+                    let status = if request.method == "GET_PARAMETER" {
+                        200
+                    } else {
+                        455
+                    };
+                    return Ok(RtspResponse::new(status));
+                };
+                let given = request.header("Session").and_then(|s| s.split(';').next());
+                if given.map(str::trim) != Some(session.id.as_str()) {
+                    return Ok(RtspResponse::new(454));
+                }
+                session.reap();
+
+                let response = RtspResponse::new(200).header("Session", session.id.clone());
+                match request.method.as_str() {
+                    "PLAY" if session.player.is_none() => {
+                        let rtp_info = format!(
+                            "url={};seq={};rtptime={}",
+                            request.uri,
+                            session.packetizer.as_ref().map_or(0, |p| p.sequence()),
+                            session.timestamp()
+                        );
+                        Ok(response
+                            .header("Range", "npt=0.000-")
+                            .header("RTP-Info", rtp_info))
+                    }
+                    "PAUSE" => {
+                        session.pause();
+                        Ok(response)
+                    }
+                    "TEARDOWN" => {
+                        self.session = None;
+                        Ok(response)
+                    }
+                    _ => Ok(response),
+                }
+            }
+            _ => Ok(RtspResponse::new(405).header("Allow", PUBLIC)),
+        }
+    }
+
+    fn setup(
+        &mut self,
+        request: &RtspRequest,
+        local: SocketAddr,
+        peer: SocketAddr,
+        out: &Arc<Mutex<TcpStream>>,
+    ) -> io::Result<RtspResponse> {
+        if self.session.is_some() {
+            return Ok(RtspResponse::new(455));
+        }
+        let Some(transport) = request.header("Transport").and_then(Transport::parse) else {
+            return Ok(RtspResponse::new(461).header("Transport", "RTP/AVP/TCP;unicast"));
+        };
+        let Some(slot) = Slot::take(self.clients, self.config.max_clients) else {
+            return Ok(RtspResponse::new(453));
+        };
+
+        let (sink, server_port) = match transport {
+            Transport::Udp { client_ports } => {
+                let socket = UdpSocket::bind(SocketAddr::new(local.ip(), 0))?;
+                // connected, so that a client gone away shows up as an error
+                socket.connect(SocketAddr::new(peer.ip(), client_ports.0))?;
+                let port = socket.local_addr()?.port();
+                (Sink::Udp(socket), port)
+            }
+            Transport::Interleaved { channels } => (
+                Sink::Interleaved {
+                    out: out.clone(),
+                    channel: channels.0,
+                },
+                0,
+            ),
+        };
+
+        let random = random_u64();
+        let session = Session {
+            id: format!("{:016X}", random_u64()),
+            sink: Arc::new(sink),
+            fps: self.fps(&request.uri),
+            epoch: Instant::now(),
+            time_origin: random as u32,
+            packetizer: Some(JpegPacketizer::new(
+                (random >> 32) as u32,
+                random_u64() as u16,
+                self.config.max_packet,
+            )),
+            player: None,
+            _slot: slot,
+        };
+        let response = RtspResponse::new(200)
+            .header("Transport", transport.describe(server_port))
+            .header(
+                "Session",
+                format!(
+                    "{};timeout={}",
+                    session.id,
+                    self.config.session_timeout.as_secs()
+                ),
+            );
+        self.session = Some(session);
+        Ok(response)
+    }
+
+    /// The stream or its track, clients differ in which one they ask for
+    fn is_stream(&self, request: &RtspRequest) -> bool {
+        request
+            .path()
+            .strip_prefix(self.path)
+            .map(|rest| rest.trim_end_matches('/'))
+            .is_some_and(|rest| rest.is_empty() || rest.strip_prefix('/') == Some(TRACK))
+    }
+
+    /// Frame rate of the session, the one asked for in `uri` or in an earlier request
+    fn fps(&mut self, uri: &str) -> u32 {
+        if let Some(fps) = query_value(uri, "fps")
+            .and_then(|fps| fps.parse::<u32>().ok())
+            .filter(|&fps| fps > 0)
+        {
+            self.fps = Some(fps);
+        }
+        self.fps
+            .map_or(self.config.max_fps, |fps| fps.min(self.config.max_fps))
+    }
+}
+
+/// Response for a client whose credentials fall short, `None` when it may watch. RTSP
+/// has no 429, a locked out client gets a 503 with `Retry-After`.
+fn refusal(
+    auth: &SharedAuthenticator,
+    peer: IpAddr,
+    authorization: Option<&str>,
+) -> Option<RtspResponse> {
+    let result = auth
+        .lock()
+        .unwrap()
+        .authorize(peer, authorization, Role::Viewer, Instant::now());
+    match result {
+        Ok(_) => None,
+        Err(AuthError::Missing | AuthError::Invalid) => {
+            Some(RtspResponse::new(401).header("WWW-Authenticate", "Basic realm=\"espcam\""))
+        }
+        Err(AuthError::Forbidden) => Some(RtspResponse::new(403)),
+        Err(AuthError::LockedOut(retry)) => {
+            Some(RtspResponse::new(503).header("Retry-After", retry.as_secs().to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, thread};
+
+    use crate::{
+        auth::{AuthConfig, Authenticator, PasswordHash, User},
+        frame::{Frame, PixelFormat},
+    };
+
+    use super::*;
+
+    const URI: &str = "rtsp://127.0.0.1/stream";
+
+    #[test]
+    fn parses_transports() {
+        assert_eq!(
+            Transport::parse("RTP/AVP;unicast;client_port=5000-5001"),
+            Some(Transport::Udp {
+                client_ports: (5000, 5001)
+            })
+        );
+        assert_eq!(
+            Transport::parse("RTP/AVP/UDP;unicast;client_port=6000"),
+            Some(Transport::Udp {
+                client_ports: (6000, 6001)
+            })
+        );
+        assert_eq!(
+            Transport::parse("RTP/AVP/TCP;unicast;interleaved=2-3"),
+            Some(Transport::Interleaved { channels: (2, 3) })
+        );
+        assert_eq!(
+            Transport::parse("RTP/AVP/TCP;unicast"),
+            Some(Transport::Interleaved { channels: (0, 1) })
+        );
+        // the first one the server can do
+        assert_eq!(
+            Transport::parse(
+                "RTP/AVP;multicast;port=5000-5001, RTP/SAVP;unicast;client_port=1-2, \
+                 RTP/AVP/TCP;interleaved=4-5"
+            ),
+            Some(Transport::Interleaved { channels: (4, 5) })
+        );
+        assert_eq!(Transport::parse("RTP/AVP;unicast"), None);
+        assert_eq!(Transport::parse("RTP/AVP/TCP;interleaved=300-301"), None);
+        assert_eq!(
+            Transport::Udp {
+                client_ports: (5000, 5001)
+            }
+            .describe(7000),
+            "RTP/AVP;unicast;client_port=5000-5001;server_port=7000-7001"
+        );
+    }
+
+    #[test]
+    fn reads_requests_between_packets() {
+        let data = b"$\x01\x00\x03abcOPTIONS rtsp://cam:554/stream?fps=5 RTSP/1.0\r\n\
+                     CSeq: 2\r\nContent-Length: 4\r\n\r\nbody\r\n";
+        let mut reader = BufReader::new(&data[..]);
+        let request = RtspRequest::read(&mut reader).unwrap().unwrap();
+        assert_eq!(request.method, "OPTIONS");
+        assert_eq!(request.path(), "/stream");
+        assert_eq!(request.header("cseq"), Some("2"));
+        assert_eq!(RtspRequest::read(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn fps_of_the_stream_uri_applies_to_the_track() {
+        let (hub, config, clients) = (StreamHub::new(), RtspConfig::default(), Arc::default());
+        let mut connection = Connection {
+            path: "/stream",
+            hub: &hub,
+            config: &config,
+            clients: &clients,
+            session: None,
+            fps: None,
+        };
+        assert_eq!(connection.fps(URI), 10);
+        assert_eq!(connection.fps(&format!("{}?fps=2", URI)), 2);
+        assert_eq!(connection.fps(&format!("{}/track1", URI)), 2);
+        assert_eq!(connection.fps(&format!("{}?fps=50", URI)), 10);
+    }
+
+    /// A hub that a thread keeps publishing frames to
+    fn publishing_hub() -> StreamHub {
+        let hub = StreamHub::new();
+        let mut frame = Frame::new(vec![0; 160 * 120 * 3], 160, 120, PixelFormat::Rgb888);
+        for y in 0..120 {
+            for x in 0..160 {
+                frame.set_pixel(x, y, [((x * 7) ^ (y * 3)) as u8, (x * y) as u8, y as u8]);
+            }
+        }
+        let jpeg = frame.to_jpeg(90).unwrap();
+        let publisher = hub.clone();
+        thread::spawn(move || loop {
+            publisher.publish(jpeg.clone());
+            thread::sleep(Duration::from_millis(20));
+        });
+        hub
+    }
+
+    /// Runs a [`Connection`] on a local port, frames are published meanwhile
+    fn serve(config: RtspConfig) -> TcpStream {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let hub = publishing_hub();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let clients = Arc::default();
+            let mut connection = Connection {
+                path: "/stream",
+                hub: &hub,
+                config: &config,
+                clients: &clients,
+                session: None,
+                fps: None,
+            };
+            connection.run(stream).unwrap();
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+    }
+
+    #[derive(Debug)]
+    struct Response {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+
+    impl Response {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        }
+    }
+
+    fn request(
+        stream: &mut BufReader<TcpStream>,
+        cseq: u32,
+        request: &str,
+        headers: &[&str],
+    ) -> Response {
+        let mut text = format!("{} RTSP/1.0\r\nCSeq: {}\r\n", request, cseq);
+        for header in headers {
+            text += &format!("{}\r\n", header);
+        }
+        text += "\r\n";
+        stream.get_mut().write_all(text.as_bytes()).unwrap();
+
+        // packets of a playing session may come first
+        while stream.fill_buf().unwrap()[0] == b'$' {
+            read_packet(stream);
+        }
+
+        let status_line = read_line(stream).unwrap();
+        let status = status_line.split(' ').nth(1).unwrap().parse().unwrap();
+        let mut headers = Vec::new();
+        loop {
+            let line = read_line(stream).unwrap();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').unwrap();
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+        let mut response = Response {
+            status,
+            headers,
+            body: String::new(),
+        };
+        if let Some(len) = response.header("Content-Length") {
+            let mut body = vec![0; len.parse().unwrap()];
+            stream.read_exact(&mut body).unwrap();
+            response.body = String::from_utf8(body).unwrap();
+        }
+        assert_eq!(response.header("CSeq"), Some(cseq.to_string().as_str()));
+        response
+    }
+
+    /// Channel and content of an interleaved packet
+    fn read_packet(stream: &mut BufReader<TcpStream>) -> (u8, Vec<u8>) {
+        let mut header = [0; 4];
+        stream.read_exact(&mut header).unwrap();
+        assert_eq!(header[0], b'$');
+        let mut packet = vec![0; u16::from_be_bytes([header[2], header[3]]) as usize];
+        stream.read_exact(&mut packet).unwrap();
+        (header[1], packet)
+    }
+
+    #[test]
+    fn plays_over_tcp() {
+        let mut stream = BufReader::new(serve(RtspConfig::default()));
+
+        let options = request(&mut stream, 1, &format!("OPTIONS {}", URI), &[]);
+        assert_eq!(options.status, 200);
+        assert_eq!(options.header("Public"), Some(PUBLIC));
+
+        let uri = format!("{}?fps=5", URI);
+        let describe = request(&mut stream, 2, &format!("DESCRIBE {}", uri), &[]);
+        assert_eq!(describe.status, 200);
+        assert_eq!(
+            describe.header("Content-Base"),
+            Some("rtsp://127.0.0.1/stream/")
+        );
+        assert_eq!(describe.header("Content-Type"), Some("application/sdp"));
+        assert!(describe.body.contains("a=rtpmap:26 JPEG/90000\r\n"));
+        assert!(describe.body.contains("a=framerate:5\r\n"));
+        assert!(describe.body.contains("a=control:track1\r\n"));
+
+        let wrong = request(&mut stream, 3, "DESCRIBE rtsp://127.0.0.1/other", &[]);
+        assert_eq!(wrong.status, 404);
+
+        let track = format!("{}/track1", URI);
+        let setup = request(
+            &mut stream,
+            4,
+            &format!("SETUP {}", track),
+            &["Transport: RTP/AVP/TCP;unicast;interleaved=0-1"],
+        );
+        assert_eq!(setup.status, 200);
+        assert_eq!(
+            setup.header("Transport"),
+            Some("RTP/AVP/TCP;unicast;interleaved=0-1")
+        );
+        let session = setup.header("Session").unwrap();
+        assert_eq!(session.split(';').nth(1), Some("timeout=60"));
+        let session = format!("Session: {}", session.split(';').next().unwrap());
+
+        let play = request(&mut stream, 5, &format!("PLAY {}", uri), &[&session]);
+        assert_eq!(play.status, 200);
+        assert!(play.header("RTP-Info").unwrap().starts_with("url="));
+
+        // a whole frame comes through, its last packet has the marker bit
+        let mut packets = vec![read_packet(&mut stream)];
+        while packets.last().unwrap().1[1] & 0x80 == 0 {
+            packets.push(read_packet(&mut stream));
+        }
+        assert!(packets.iter().all(|(channel, packet)| *channel == 0
+            && packet.len() <= DEFAULT_MAX_PACKET
+            && packet[1] & 0x7F == PAYLOAD_TYPE));
+
+        let unknown = request(
+            &mut stream,
+            6,
+            &format!("PAUSE {}", uri),
+            &["Session: 1234"],
+        );
+        assert_eq!(unknown.status, 454);
+
+        let teardown = request(&mut stream, 7, &format!("TEARDOWN {}", uri), &[&session]);
+        assert_eq!(teardown.status, 200);
+        // the connection closes after the teardown, no packet is left behind
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    fn session(sink: Sink, clients: &Arc<AtomicUsize>) -> Session {
+        Session {
+            id: "1".to_string(),
+            sink: Arc::new(sink),
+            fps: 25,
+            epoch: Instant::now(),
+            time_origin: 0,
+            packetizer: Some(JpegPacketizer::new(1, 0, DEFAULT_MAX_PACKET)),
+            player: None,
+            _slot: Slot::take(clients, 1).unwrap(),
+        }
+    }
+
+    #[test]
+    fn udp_players_outlive_send_errors() {
+        let hub = publishing_hub();
+        // nobody listens there, sends fail once the port unreachable comes back
+        let closed = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket.connect(closed.local_addr().unwrap()).unwrap();
+        drop(closed);
+
+        let mut session = session(Sink::Udp(socket), &Arc::default());
+        session.play(&hub).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert!(!session.player.as_ref().unwrap().thread.is_finished());
+    }
+
+    #[test]
+    fn stopped_players_play_again() {
+        let hub = publishing_hub();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let out = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        drop(listener.accept().unwrap());
+        let sink = Sink::Interleaved {
+            out: Arc::new(Mutex::new(out)),
+            channel: 0,
+        };
+
+        let mut session = session(sink, &Arc::default());
+        session.play(&hub).unwrap();
+        let start = Instant::now();
+        while !session.player.as_ref().unwrap().thread.is_finished() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        session.reap();
+        assert!(session.player.is_none());
+        assert!(session.packetizer.as_ref().unwrap().sequence() > 0);
+        session.play(&hub).unwrap();
+        assert!(session.player.is_some());
+    }
+
+    #[test]
+    fn asks_for_credentials() {
+        let auth = Authenticator::new(AuthConfig {
+            users: vec![User {
+                name: "nvr".to_string(),
+                password: PasswordHash::new("secret", b"salt", 10),
+                role: Role::Viewer,
+            }],
+            tokens: Vec::new(),
+            onvif: None,
+        });
+        let mut stream = BufReader::new(serve(RtspConfig {
+            auth: Some(Arc::new(Mutex::new(auth))),
+            ..RtspConfig::default()
+        }));
+
+        let options = request(&mut stream, 1, &format!("OPTIONS {}", URI), &[]);
+        assert_eq!(options.status, 200);
+        let refused = request(&mut stream, 2, &format!("DESCRIBE {}", URI), &[]);
+        assert_eq!(refused.status, 401);
+        assert_eq!(
+            refused.header("WWW-Authenticate"),
+            Some("Basic realm=\"espcam\"")
+        );
+        let describe = request(
+            &mut stream,
+            3,
+            &format!("DESCRIBE {}", URI),
+            &["Authorization: Basic bnZyOnNlY3JldA=="],
+        );
+        assert_eq!(describe.status, 200);
+    }
+}
@@ -0,0 +1,587 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+
+use crate::{
+    events::{Event, EventBus},
+    frame::Frame,
+};
+
+/// Number of rows sampled when looking for 1D barcodes
+const SCAN_ROWS: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Symbology {
+    Qr,
+    Ean13,
+    Code128,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ScanResult {
+    pub symbology: Symbology,
+    pub content: String,
+}
+
+/// Looks for every supported code in a raw frame, grayscale frames work best.
+/// JPEG frames have to be decoded first.
+pub fn scan(frame: &Frame) -> Result<Vec<ScanResult>> {
+    let mut results = scan_qr(frame)?;
+    for result in scan_barcodes(frame)? {
+        if !results.contains(&result) {
+            results.push(result);
+        }
+    }
+    Ok(results)
+}
+
+fn check_raw(frame: &Frame) -> Result<()> {
+    if !frame.is_raw() {
+        bail!("code scanning needs a decoded frame");
+    }
+    Ok(())
+}
+
+pub fn scan_qr(frame: &Frame) -> Result<Vec<ScanResult>> {
+    check_raw(frame)?;
+    let mut img =
+        rqrr::PreparedImage::prepare_from_greyscale(frame.width(), frame.height(), |x, y| {
+            frame.luma(x, y)
+        });
+
+    Ok(img
+        .detect_grids()
+        .into_iter()
+        .filter_map(|grid| match grid.decode() {
+            Ok((_, content)) => Some(ScanResult {
+                symbology: Symbology::Qr,
+                content,
+            }),
+            Err(err) => {
+                log::debug!("qr decode error: {:?}", err);
+                None
+            }
+        })
+        .collect())
+}
+
+/// Looks for EAN-13 and Code 128 barcodes along horizontal lines, in both directions
+pub fn scan_barcodes(frame: &Frame) -> Result<Vec<ScanResult>> {
+    check_raw(frame)?;
+    let mut results = Vec::new();
+    let mut row = Vec::with_capacity(frame.width());
+
+    for n in 0..SCAN_ROWS {
+        let y = (n * 2 + 1) * frame.height() / (SCAN_ROWS * 2);
+        row.clear();
+        row.extend((0..frame.width()).map(|x| frame.luma(x, y)));
+
+        let mut runs = runs(&row);
+        for _ in 0..2 {
+            for result in decode_runs(&runs) {
+                if !results.contains(&result) {
+                    results.push(result);
+                }
+            }
+            runs.reverse();
+        }
+    }
+
+    Ok(results)
+}
+
+/// Run lengths of the binarized row, `true` for dark runs
+fn runs(row: &[u8]) -> Vec<(bool, usize)> {
+    let (min, max) = row
+        .iter()
+        .fold((255u8, 0u8), |(lo, hi), v| (lo.min(*v), hi.max(*v)));
+    if max - min < 32 {
+        return Vec::new();
+    }
+    let threshold = ((min as u16 + max as u16) / 2) as u8;
+
+    let mut runs: Vec<(bool, usize)> = Vec::new();
+    for v in row {
+        let dark = *v < threshold;
+        match runs.last_mut() {
+            Some((d, len)) if *d == dark => *len += 1,
+            _ => runs.push((dark, 1)),
+        }
+    }
+    runs
+}
+
+fn decode_runs(runs: &[(bool, usize)]) -> Vec<ScanResult> {
+    let widths = runs.iter().map(|(_, w)| *w as f32).collect::<Vec<_>>();
+    let mut results = Vec::new();
+
+    for i in 0..runs.len() {
+        if !runs[i].0 {
+            continue;
+        }
+        // a barcode needs some quiet zone before it
+        if i > 0 && widths[i - 1] < widths[i] * 3.0 {
+            continue;
+        }
+
+        let found = decode_ean13(&widths[i..])
+            .map(|content| (Symbology::Ean13, content))
+            .or_else(|| decode_code128(&widths[i..]).map(|content| (Symbology::Code128, content)));
+
+        if let Some((symbology, content)) = found {
+            results.push(ScanResult { symbology, content });
+        }
+    }
+
+    results
+}
+
+/// Scales `widths` so they add up to `modules` and compares them to `pattern`
+fn pattern_error(widths: &[f32], pattern: &[u8], modules: f32) -> f32 {
+    let sum: f32 = widths.iter().sum();
+    widths
+        .iter()
+        .zip(pattern)
+        .map(|(w, p)| (w * modules / sum - *p as f32).abs())
+        .sum()
+}
+
+/// Index of the pattern closest to `widths`, if it is close enough
+fn best_match(widths: &[f32], patterns: &[&[u8]], modules: f32, max_error: f32) -> Option<usize> {
+    patterns
+        .iter()
+        .map(|p| pattern_error(widths, p, modules))
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .filter(|(_, err)| *err < max_error)
+        .map(|(idx, _)| idx)
+}
+
+// space, bar, space, bar widths of the left hand odd parity digits,
+// even parity digits are the same reversed and right hand digits start with a bar
+static EAN_L: [&[u8]; 10] = [
+    &[3, 2, 1, 1],
+    &[2, 2, 2, 1],
+    &[2, 1, 2, 2],
+    &[1, 4, 1, 1],
+    &[1, 1, 3, 2],
+    &[1, 2, 3, 1],
+    &[1, 1, 1, 4],
+    &[1, 3, 1, 2],
+    &[1, 2, 1, 3],
+    &[3, 1, 1, 2],
+];
+
+static EAN_G: [&[u8]; 10] = [
+    &[1, 1, 2, 3],
+    &[1, 2, 2, 2],
+    &[2, 2, 1, 2],
+    &[1, 1, 4, 1],
+    &[2, 3, 1, 1],
+    &[1, 3, 2, 1],
+    &[4, 1, 1, 1],
+    &[2, 1, 3, 1],
+    &[3, 1, 2, 1],
+    &[2, 1, 1, 3],
+];
+
+// parity of the six left digits encodes the first digit, true for even parity
+static EAN_FIRST_DIGIT: [[bool; 6]; 10] = [
+    [false, false, false, false, false, false],
+    [false, false, true, false, true, true],
+    [false, false, true, true, false, true],
+    [false, false, true, true, true, false],
+    [false, true, false, false, true, true],
+    [false, true, true, false, false, true],
+    [false, true, true, true, false, false],
+    [false, true, false, true, false, true],
+    [false, true, false, true, true, false],
+    [false, true, true, false, true, false],
+];
+
+/// Decodes an EAN-13 starting at the first bar of the start guard
+fn decode_ean13(widths: &[f32]) -> Option<String> {
+    // guard, 6 digits, middle guard, 6 digits, guard
+    const RUNS: usize = 3 + 24 + 5 + 24 + 3;
+    if widths.len() < RUNS {
+        return None;
+    }
+    let mut widths = &widths[..RUNS];
+    let module = widths.iter().sum::<f32>() / 95.0;
+
+    let guard = |w: &[f32]| w.iter().all(|w| *w > module * 0.4 && *w < module * 1.8);
+    if !guard(&widths[0..3]) || !guard(&widths[27..32]) || !guard(&widths[56..59]) {
+        return None;
+    }
+
+    let (mut digits, mut parity) = decode_ean13_left(&widths[3..27])?;
+    // the first left digit is always odd, all even means the barcode was read from its
+    // end: the right hand digits came first, their modules reversed
+    let reversed;
+    if parity == [true; 6] {
+        reversed = widths.iter().rev().copied().collect::<Vec<_>>();
+        widths = &reversed;
+        (digits, parity) = decode_ean13_left(&widths[3..27])?;
+    }
+
+    for digit in widths[32..56].chunks_exact(4) {
+        digits.push(best_match(digit, &EAN_L, 7.0, 1.5)? as u8);
+    }
+
+    let first = EAN_FIRST_DIGIT.iter().position(|p| *p == parity)? as u8;
+    digits.insert(0, first);
+
+    let sum: u32 = digits[..12]
+        .iter()
+        .enumerate()
+        .map(|(i, d)| *d as u32 * if i % 2 == 0 { 1 } else { 3 })
+        .sum();
+    if (10 - sum % 10) % 10 != digits[12] as u32 {
+        return None;
+    }
+
+    Some(digits.iter().map(|d| (b'0' + d) as char).collect())
+}
+
+/// Digits and parities, true for even, of the left half of an EAN-13
+fn decode_ean13_left(widths: &[f32]) -> Option<(Vec<u8>, [bool; 6])> {
+    let mut digits = Vec::with_capacity(13);
+    let mut parity = [false; 6];
+
+    let both = EAN_L
+        .iter()
+        .chain(EAN_G.iter())
+        .copied()
+        .collect::<Vec<_>>();
+    for (n, digit) in widths.chunks_exact(4).enumerate() {
+        let idx = best_match(digit, &both, 7.0, 1.5)?;
+        parity[n] = idx >= 10;
+        digits.push(idx as u8 % 10);
+    }
+    Some((digits, parity))
+}
+
+// bar, space, bar, space, bar, space widths of the code 128 symbols 0 to 105
+static CODE128: [&[u8]; 106] = [
+    &[2, 1, 2, 2, 2, 2],
+    &[2, 2, 2, 1, 2, 2],
+    &[2, 2, 2, 2, 2, 1],
+    &[1, 2, 1, 2, 2, 3],
+    &[1, 2, 1, 3, 2, 2],
+    &[1, 3, 1, 2, 2, 2],
+    &[1, 2, 2, 2, 1, 3],
+    &[1, 2, 2, 3, 1, 2],
+    &[1, 3, 2, 2, 1, 2],
+    &[2, 2, 1, 2, 1, 3],
+    &[2, 2, 1, 3, 1, 2],
+    &[2, 3, 1, 2, 1, 2],
+    &[1, 1, 2, 2, 3, 2],
+    &[1, 2, 2, 1, 3, 2],
+    &[1, 2, 2, 2, 3, 1],
+    &[1, 1, 3, 2, 2, 2],
+    &[1, 2, 3, 1, 2, 2],
+    &[1, 2, 3, 2, 2, 1],
+    &[2, 2, 3, 2, 1, 1],
+    &[2, 2, 1, 1, 3, 2],
+    &[2, 2, 1, 2, 3, 1],
+    &[2, 1, 3, 2, 1, 2],
+    &[2, 2, 3, 1, 1, 2],
+    &[3, 1, 2, 1, 3, 1],
+    &[3, 1, 1, 2, 2, 2],
+    &[3, 2, 1, 1, 2, 2],
+    &[3, 2, 1, 2, 2, 1],
+    &[3, 1, 2, 2, 1, 2],
+    &[3, 2, 2, 1, 1, 2],
+    &[3, 2, 2, 2, 1, 1],
+    &[2, 1, 2, 1, 2, 3],
+    &[2, 1, 2, 3, 2, 1],
+    &[2, 3, 2, 1, 2, 1],
+    &[1, 1, 1, 3, 2, 3],
+    &[1, 3, 1, 1, 2, 3],
+    &[1, 3, 1, 3, 2, 1],
+    &[1, 1, 2, 3, 1, 3],
+    &[1, 3, 2, 1, 1, 3],
+    &[1, 3, 2, 3, 1, 1],
+    &[2, 1, 1, 3, 1, 3],
+    &[2, 3, 1, 1, 1, 3],
+    &[2, 3, 1, 3, 1, 1],
+    &[1, 1, 2, 1, 3, 3],
+    &[1, 1, 2, 3, 3, 1],
+    &[1, 3, 2, 1, 3, 1],
+    &[1, 1, 3, 1, 2, 3],
+    &[1, 1, 3, 3, 2, 1],
+    &[1, 3, 3, 1, 2, 1],
+    &[3, 1, 3, 1, 2, 1],
+    &[2, 1, 1, 3, 3, 1],
+    &[2, 3, 1, 1, 3, 1],
+    &[2, 1, 3, 1, 1, 3],
+    &[2, 1, 3, 3, 1, 1],
+    &[2, 1, 3, 1, 3, 1],
+    &[3, 1, 1, 1, 2, 3],
+    &[3, 1, 1, 3, 2, 1],
+    &[3, 3, 1, 1, 2, 1],
+    &[3, 1, 2, 1, 1, 3],
+    &[3, 1, 2, 3, 1, 1],
+    &[3, 3, 2, 1, 1, 1],
+    &[3, 1, 4, 1, 1, 1],
+    &[2, 2, 1, 4, 1, 1],
+    &[4, 3, 1, 1, 1, 1],
+    &[1, 1, 1, 2, 2, 4],
+    &[1, 1, 1, 4, 2, 2],
+    &[1, 2, 1, 1, 2, 4],
+    &[1, 2, 1, 4, 2, 1],
+    &[1, 4, 1, 1, 2, 2],
+    &[1, 4, 1, 2, 2, 1],
+    &[1, 1, 2, 2, 1, 4],
+    &[1, 1, 2, 4, 1, 2],
+    &[1, 2, 2, 1, 1, 4],
+    &[1, 2, 2, 4, 1, 1],
+    &[1, 4, 2, 1, 1, 2],
+    &[1, 4, 2, 2, 1, 1],
+    &[2, 4, 1, 2, 1, 1],
+    &[2, 2, 1, 1, 1, 4],
+    &[4, 1, 3, 1, 1, 1],
+    &[2, 4, 1, 1, 1, 2],
+    &[1, 3, 4, 1, 1, 1],
+    &[1, 1, 1, 2, 4, 2],
+    &[1, 2, 1, 1, 4, 2],
+    &[1, 2, 1, 2, 4, 1],
+    &[1, 1, 4, 2, 1, 2],
+    &[1, 2, 4, 1, 1, 2],
+    &[1, 2, 4, 2, 1, 1],
+    &[4, 1, 1, 2, 1, 2],
+    &[4, 2, 1, 1, 1, 2],
+    &[4, 2, 1, 2, 1, 1],
+    &[2, 1, 2, 1, 4, 1],
+    &[2, 1, 4, 1, 2, 1],
+    &[4, 1, 2, 1, 2, 1],
+    &[1, 1, 1, 1, 4, 3],
+    &[1, 1, 1, 3, 4, 1],
+    &[1, 3, 1, 1, 4, 1],
+    &[1, 1, 4, 1, 1, 3],
+    &[1, 1, 4, 3, 1, 1],
+    &[4, 1, 1, 1, 1, 3],
+    &[4, 1, 1, 3, 1, 1],
+    &[1, 1, 3, 1, 4, 1],
+    &[1, 1, 4, 1, 3, 1],
+    &[3, 1, 1, 1, 4, 1],
+    &[4, 1, 1, 1, 3, 1],
+    &[2, 1, 1, 4, 1, 2],
+    &[2, 1, 1, 2, 1, 4],
+    &[2, 1, 1, 2, 3, 2],
+];
+
+static CODE128_STOP: [u8; 7] = [2, 3, 3, 1, 1, 1, 2];
+
+const CODE128_START_A: usize = 103;
+const CODE128_START_C: usize = 105;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CodeSet {
+    A,
+    B,
+    C,
+}
+
+/// Decodes a Code 128 starting at the first bar of the start symbol
+fn decode_code128(widths: &[f32]) -> Option<String> {
+    if widths.len() < 6 * 3 + 7 {
+        return None;
+    }
+
+    let start = best_match(&widths[..6], &CODE128, 11.0, 1.5)?;
+    if start < CODE128_START_A {
+        return None;
+    }
+
+    let mut values = Vec::new();
+    let mut pos = 6;
+    loop {
+        if widths.len() >= pos + 7
+            && pattern_error(&widths[pos..pos + 7], &CODE128_STOP, 13.0) < 1.5
+        {
+            break;
+        }
+        if widths.len() < pos + 6 {
+            return None;
+        }
+        let value = best_match(&widths[pos..pos + 6], &CODE128, 11.0, 1.5)?;
+        values.push(value);
+        pos += 6;
+    }
+
+    let checksum = values.pop()?;
+    let sum = values
+        .iter()
+        .enumerate()
+        .fold(start, |sum, (i, v)| sum + (i + 1) * v);
+    if sum % 103 != checksum || values.is_empty() {
+        return None;
+    }
+
+    let mut set = match start {
+        CODE128_START_A => CodeSet::A,
+        CODE128_START_C => CodeSet::C,
+        _ => CodeSet::B,
+    };
+    let mut shift = false;
+    let mut out = String::new();
+
+    for value in values {
+        let current = match (shift, set) {
+            (true, CodeSet::A) => CodeSet::B,
+            (true, CodeSet::B) => CodeSet::A,
+            _ => set,
+        };
+        shift = false;
+
+        match (current, value) {
+            (CodeSet::C, 0..=99) => out.push_str(&format!("{:02}", value)),
+            (CodeSet::C, 100) => set = CodeSet::B,
+            (CodeSet::C, 101) => set = CodeSet::A,
+            (_, 0..=63) => out.push((value as u8 + 32) as char),
+            (CodeSet::A, 64..=95) => out.push((value as u8 - 64) as char),
+            (CodeSet::B, 64..=95) => out.push((value as u8 + 32) as char),
+            (_, 98) => shift = true,
+            (_, 99) => set = CodeSet::C,
+            (CodeSet::A, 100) => set = CodeSet::B,
+            (CodeSet::B, 101) => set = CodeSet::A,
+            // FNC codes carry no text
+            _ => {}
+        }
+    }
+
+    Some(out)
+}
+
+/// Scans frames and reports every code as an [`Event::CodeScanned`], a code that stays
+/// in view is reported again only after `holdoff`.
+pub struct Scanner {
+    events: EventBus,
+    holdoff: Duration,
+    seen: Vec<(ScanResult, Instant)>,
+}
+
+impl Scanner {
+    pub fn new(events: EventBus, holdoff: Duration) -> Self {
+        Self {
+            events,
+            holdoff,
+            seen: Vec::new(),
+        }
+    }
+
+    /// Returns the codes that were reported
+    pub fn process(&mut self, frame: &Frame) -> Result<Vec<ScanResult>> {
+        let now = Instant::now();
+        self.seen
+            .retain(|(_, at)| now.duration_since(*at) < self.holdoff);
+
+        let mut reported = Vec::new();
+        for result in scan(frame)? {
+            if self.seen.iter().any(|(seen, _)| *seen == result) {
+                continue;
+            }
+            self.seen.push((result.clone(), now));
+            self.events.publish(Event::CodeScanned {
+                symbology: result.symbology,
+                content: result.content.clone(),
+            });
+            reported.push(result);
+        }
+        Ok(reported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::PixelFormat;
+
+    fn sample(png: &[u8]) -> Frame {
+        let img = image::load_from_memory(png).unwrap().into_luma8();
+        let (width, height) = img.dimensions();
+        Frame::new(
+            img.into_raw(),
+            width as usize,
+            height as usize,
+            PixelFormat::Grayscale,
+        )
+    }
+
+    fn found(symbology: Symbology, content: &str) -> Vec<ScanResult> {
+        vec![ScanResult {
+            symbology,
+            content: content.to_string(),
+        }]
+    }
+
+    #[test]
+    fn decodes_ean13() {
+        let frame = sample(include_bytes!("../testdata/ean13.png"));
+        assert_eq!(
+            scan(&frame).unwrap(),
+            found(Symbology::Ean13, "4006381333931")
+        );
+    }
+
+    #[test]
+    fn decodes_upside_down_ean13() {
+        let frame = sample(include_bytes!("../testdata/ean13_upside_down.png"));
+        assert_eq!(
+            scan(&frame).unwrap(),
+            found(Symbology::Ean13, "4006381333931")
+        );
+    }
+
+    #[test]
+    fn decodes_ean13_read_from_its_end() {
+        let frame = sample(include_bytes!("../testdata/ean13.png"));
+        let row = (0..frame.width())
+            .map(|x| frame.luma(x, frame.height() / 2))
+            .collect::<Vec<_>>();
+        let mut widths = runs(&row)
+            .into_iter()
+            .skip_while(|(dark, _)| !dark)
+            .map(|(_, width)| width as f32)
+            .collect::<Vec<_>>();
+        widths.truncate(59);
+        widths.reverse();
+        assert_eq!(decode_ean13(&widths).as_deref(), Some("4006381333931"));
+    }
+
+    #[test]
+    fn decodes_code128() {
+        let frame = sample(include_bytes!("../testdata/code128.png"));
+        assert_eq!(
+            scan(&frame).unwrap(),
+            found(Symbology::Code128, "ESP32-CAM")
+        );
+    }
+
+    #[test]
+    fn decodes_qr() {
+        let frame = sample(include_bytes!("../testdata/qr.png"));
+        assert_eq!(
+            scan(&frame).unwrap(),
+            found(Symbology::Qr, "https://github.com/Kezii/esp32cam_rs")
+        );
+    }
+
+    #[test]
+    fn finds_nothing_in_a_flat_frame() {
+        let frame = Frame::new(vec![128; 320 * 240], 320, 240, PixelFormat::Grayscale);
+        assert!(scan(&frame).unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuses_jpeg_frames() {
+        let jpeg = Frame::new(vec![128; 32 * 32], 32, 32, PixelFormat::Grayscale)
+            .to_jpeg(80)
+            .unwrap();
+        let frame = Frame::new(jpeg, 32, 32, PixelFormat::Jpeg);
+        assert!(scan(&frame).is_err());
+    }
+}
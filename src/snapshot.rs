@@ -0,0 +1,103 @@
+use anyhow::{anyhow, bail, Result};
+use esp_idf_sys::camera;
+
+use crate::{
+    espcam::{framesize_from_name, SensorSettings},
+    stream::query_value,
+    transform::Rotation,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ImageFormat {
+    #[default]
+    Jpeg,
+    Png,
+}
+
+impl ImageFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Png => "image/png",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Png => "png",
+        }
+    }
+}
+
+/// How a single picture should be taken, the sensor settings only last for that picture
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SnapshotOptions {
+    pub size: Option<camera::framesize_t>,
+    /// JPEG quality of the sensor, 0 (best) to 63
+    pub quality: Option<i32>,
+    pub flash: bool,
+    pub format: ImageFormat,
+    /// Applied after the pipeline, on top of the mounting orientation
+    pub rotate: Rotation,
+}
+
+impl SnapshotOptions {
+    /// Reads `?size=vga&quality=10&flash=1&format=png&rotate=90`, every key is optional and
+    /// unknown ones are ignored
+    pub fn from_query(uri: &str) -> Result<Self> {
+        let mut options = Self::default();
+        if let Some(size) = query_value(uri, "size") {
+            options.size =
+                Some(framesize_from_name(size).ok_or_else(|| anyhow!("unknown size {}", size))?);
+        }
+        if let Some(quality) = query_value(uri, "quality") {
+            let quality: i32 = quality.parse()?;
+            if !(0..=63).contains(&quality) {
+                bail!("quality goes from 0 (best) to 63");
+            }
+            options.quality = Some(quality);
+        }
+        if let Some(flash) = query_value(uri, "flash") {
+            options.flash = match flash {
+                "1" | "true" | "on" => true,
+                "0" | "false" | "off" => false,
+                _ => bail!("flash is 1 or 0"),
+            };
+        }
+        if let Some(format) = query_value(uri, "format") {
+            options.format = match format.to_ascii_lowercase().as_str() {
+                "jpeg" | "jpg" => ImageFormat::Jpeg,
+                "png" => ImageFormat::Png,
+                _ => bail!("unknown format {}, jpeg or png", format),
+            };
+        }
+        if let Some(rotate) = query_value(uri, "rotate") {
+            options.rotate = rotate
+                .parse()
+                .ok()
+                .and_then(Rotation::from_degrees)
+                .ok_or_else(|| anyhow!("rotate is 0, 90, 180 or 270"))?;
+        }
+        Ok(options)
+    }
+
+    /// True when the picture can be taken as the sensor is configured
+    pub fn is_plain(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The sensor settings to change for this picture
+    pub fn sensor_settings(&self) -> SensorSettings {
+        SensorSettings {
+            framesize: self.size,
+            quality: self.quality,
+            ..Default::default()
+        }
+    }
+
+    /// True when the frame has to be decoded after the pipeline
+    pub fn needs_decoding(&self) -> bool {
+        self.format != ImageFormat::Jpeg || self.rotate != Rotation::None
+    }
+}
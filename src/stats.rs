@@ -0,0 +1,255 @@
+use anyhow::{bail, Result};
+use serde::Serialize;
+
+use crate::frame::Frame;
+
+/// Luma at or above this counts as a clipped highlight
+pub const HIGHLIGHT_LEVEL: u8 = 250;
+/// Luma at or below this counts as a crushed shadow
+pub const SHADOW_LEVEL: u8 = 5;
+
+/// Exposure statistics of a frame, computed on a subsampled grid of pixels
+/// so they stay cheap even on large frames.
+#[derive(Debug, Clone, Serialize)]
+pub struct FrameStats {
+    /// Number of pixels that were sampled
+    pub samples: u32,
+    pub luma_histogram: Vec<u32>,
+    pub red_histogram: Vec<u32>,
+    pub green_histogram: Vec<u32>,
+    pub blue_histogram: Vec<u32>,
+    pub mean: f32,
+    pub median: u8,
+    /// Percentage of samples at or above [`HIGHLIGHT_LEVEL`]
+    pub clipped_highlights: f32,
+    /// Percentage of samples at or below [`SHADOW_LEVEL`]
+    pub crushed_shadows: f32,
+}
+
+impl FrameStats {
+    /// Computes the statistics of a raw frame, sampling at most `max_side` pixels
+    /// along its longest side. JPEG frames have to be decoded first.
+    pub fn compute(frame: &Frame, max_side: usize) -> Result<Self> {
+        if !frame.is_raw() {
+            bail!("frame statistics need a decoded frame");
+        }
+        let step = (frame.width().max(frame.height()) / max_side.max(1)).max(1);
+
+        let mut luma = vec![0u32; 256];
+        let mut red = vec![0u32; 256];
+        let mut green = vec![0u32; 256];
+        let mut blue = vec![0u32; 256];
+        let mut samples = 0u32;
+        let mut sum = 0u64;
+
+        for y in (0..frame.height()).step_by(step) {
+            for x in (0..frame.width()).step_by(step) {
+                let px = frame.pixel(x, y);
+                let l = crate::frame::luma(px);
+                luma[l as usize] += 1;
+                red[px[0] as usize] += 1;
+                green[px[1] as usize] += 1;
+                blue[px[2] as usize] += 1;
+                sum += l as u64;
+                samples += 1;
+            }
+        }
+
+        let total = samples.max(1) as f32;
+        let clipped: u32 = luma[HIGHLIGHT_LEVEL as usize..].iter().sum();
+        let crushed: u32 = luma[..=SHADOW_LEVEL as usize].iter().sum();
+
+        Ok(Self {
+            samples,
+            mean: sum as f32 / total,
+            median: percentile(&luma, 0.5),
+            clipped_highlights: clipped as f32 * 100.0 / total,
+            crushed_shadows: crushed as f32 * 100.0 / total,
+            luma_histogram: luma,
+            red_histogram: red,
+            green_histogram: green,
+            blue_histogram: blue,
+        })
+    }
+
+    /// Mean brightness of each channel
+    pub fn channel_means(&self) -> [f32; 3] {
+        let mean = |hist: &[u32]| {
+            let sum: u64 = hist
+                .iter()
+                .enumerate()
+                .map(|(v, n)| v as u64 * *n as u64)
+                .sum();
+            sum as f32 / self.samples.max(1) as f32
+        };
+        [
+            mean(&self.red_histogram),
+            mean(&self.green_histogram),
+            mean(&self.blue_histogram),
+        ]
+    }
+
+    /// Whether the scene is dark enough that the flash led would help
+    pub fn needs_flash(&self, dark_mean: f32) -> bool {
+        self.mean < dark_mean && self.clipped_highlights < 1.0
+    }
+}
+
+/// Luma value below which `fraction` of the samples fall
+pub fn percentile(histogram: &[u32], fraction: f32) -> u8 {
+    let total: u32 = histogram.iter().sum();
+    let target = (total as f32 * fraction).ceil() as u32;
+    let mut seen = 0;
+    for (value, count) in histogram.iter().enumerate() {
+        seen += count;
+        if seen >= target.max(1) {
+            return value as u8;
+        }
+    }
+    255
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum LightProfile {
+    Day,
+    Night,
+}
+
+/// Chooses between day and night sensor profiles from the mean brightness,
+/// with some hysteresis so it does not flicker around the threshold.
+#[derive(Debug, Clone)]
+pub struct DayNightSwitch {
+    profile: LightProfile,
+    night_below: f32,
+    day_above: f32,
+}
+
+impl DayNightSwitch {
+    pub fn new(night_below: f32, day_above: f32) -> Self {
+        Self {
+            profile: LightProfile::Day,
+            night_below,
+            day_above: day_above.max(night_below),
+        }
+    }
+
+    pub fn profile(&self) -> LightProfile {
+        self.profile
+    }
+
+    /// Returns the new profile when it changes
+    pub fn update(&mut self, stats: &FrameStats) -> Option<LightProfile> {
+        let next = match self.profile {
+            LightProfile::Day if stats.mean < self.night_below => LightProfile::Night,
+            LightProfile::Night if stats.mean > self.day_above => LightProfile::Day,
+            _ => return None,
+        };
+        self.profile = next;
+        Some(next)
+    }
+}
+
+impl Default for DayNightSwitch {
+    fn default() -> Self {
+        Self::new(40.0, 70.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::PixelFormat;
+
+    fn gray(width: usize, height: usize, value: impl Fn(usize, usize) -> u8) -> Frame {
+        let data = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| value(x, y))
+            .collect();
+        Frame::new(data, width, height, PixelFormat::Grayscale)
+    }
+
+    #[test]
+    fn histogram_of_a_flat_frame() {
+        let stats = FrameStats::compute(&gray(8, 4, |_, _| 100), 160).unwrap();
+        assert_eq!(stats.samples, 32);
+        assert_eq!(stats.luma_histogram[100], 32);
+        assert_eq!(stats.luma_histogram.iter().sum::<u32>(), 32);
+        assert_eq!(stats.red_histogram[100], 32);
+        assert_eq!(stats.mean, 100.0);
+        assert_eq!(stats.median, 100);
+        assert_eq!(stats.channel_means(), [100.0; 3]);
+    }
+
+    #[test]
+    fn mean_and_median_of_a_ramp() {
+        // a quarter of the pixels at each of 0, 10, 20 and 200
+        let stats = FrameStats::compute(&gray(4, 4, |x, _| [0, 10, 20, 200][x]), 160).unwrap();
+        assert_eq!(stats.mean, 57.5);
+        assert_eq!(stats.median, 10);
+        assert_eq!(percentile(&stats.luma_histogram, 0.75), 20);
+        assert_eq!(percentile(&stats.luma_histogram, 1.0), 200);
+    }
+
+    #[test]
+    fn clipped_highlights_and_crushed_shadows() {
+        let stats = FrameStats::compute(
+            &gray(10, 10, |x, _| match x {
+                0 => 255,
+                1 => HIGHLIGHT_LEVEL,
+                2..=4 => SHADOW_LEVEL,
+                _ => 128,
+            }),
+            160,
+        )
+        .unwrap();
+        assert_eq!(stats.clipped_highlights, 20.0);
+        assert_eq!(stats.crushed_shadows, 30.0);
+    }
+
+    #[test]
+    fn samples_large_frames() {
+        let stats = FrameStats::compute(&gray(640, 480, |_, _| 0), 160).unwrap();
+        assert_eq!(stats.samples, 160 * 120);
+        assert_eq!(stats.crushed_shadows, 100.0);
+    }
+
+    #[test]
+    fn channels_of_a_colour_frame() {
+        let data = [[255, 0, 0], [0, 0, 255]].repeat(2).concat();
+        let frame = Frame::new(data, 2, 2, PixelFormat::Rgb888);
+        let stats = FrameStats::compute(&frame, 160).unwrap();
+        assert_eq!(stats.red_histogram[255], 2);
+        assert_eq!(stats.blue_histogram[255], 2);
+        assert_eq!(stats.green_histogram[0], 4);
+        assert_eq!(stats.channel_means(), [127.5, 0.0, 127.5]);
+    }
+
+    #[test]
+    fn refuses_jpeg_frames() {
+        let frame = Frame::new(vec![0xFF, 0xD8], 1, 1, PixelFormat::Jpeg);
+        assert!(FrameStats::compute(&frame, 160).is_err());
+    }
+
+    #[test]
+    fn flash_only_helps_dark_scenes() {
+        let dark = FrameStats::compute(&gray(4, 4, |_, _| 20), 160).unwrap();
+        assert!(dark.needs_flash(40.0));
+        let bright = FrameStats::compute(&gray(4, 4, |_, _| 120), 160).unwrap();
+        assert!(!bright.needs_flash(40.0));
+        // a dark scene with a lamp in view is lit already
+        let lamp = FrameStats::compute(&gray(4, 4, |x, y| if x + y == 0 { 255 } else { 10 }), 160)
+            .unwrap();
+        assert!(!lamp.needs_flash(40.0));
+    }
+
+    #[test]
+    fn day_night_switch_has_hysteresis() {
+        let mean = |value| FrameStats::compute(&gray(2, 2, |_, _| value), 160).unwrap();
+        let mut switch = DayNightSwitch::new(40.0, 70.0);
+        assert_eq!(switch.update(&mean(50)), None);
+        assert_eq!(switch.update(&mean(30)), Some(LightProfile::Night));
+        assert_eq!(switch.update(&mean(60)), None);
+        assert_eq!(switch.profile(), LightProfile::Night);
+        assert_eq!(switch.update(&mean(80)), Some(LightProfile::Day));
+    }
+}
@@ -0,0 +1,39 @@
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Biggest value that can be loaded back
+const MAX_VALUE_LEN: usize = 16 * 1024;
+
+/// Settings that survive a reboot, stored as json blobs in the default NVS partition.
+///
+/// Keys are limited to 15 characters by NVS.
+pub struct Storage {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl Storage {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self> {
+        Ok(Self {
+            nvs: EspNvs::new(partition, "espcam", true)?,
+        })
+    }
+
+    pub fn load<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        let mut buf = vec![0; MAX_VALUE_LEN];
+        match self.nvs.get_raw(key, &mut buf)? {
+            Some(data) => Ok(Some(serde_json::from_slice(data)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn store<T: Serialize>(&mut self, key: &str, value: &T) -> Result<()> {
+        let data = serde_json::to_vec(value)?;
+        self.nvs.set_raw(key, &data)?;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &str) -> Result<bool> {
+        Ok(self.nvs.remove(key)?)
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{IpAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use crate::auth::{AuthError, Role, SharedAuthenticator};
+
+pub const BOUNDARY: &str = "frame";
+pub const CONTENT_TYPE: &str = "multipart/x-mixed-replace;boundary=frame";
+
+#[derive(Debug, Clone)]
+pub struct StreamConfig {
+    /// Frames per second of the capture loop, viewers can ask for less but not for more
+    pub max_fps: u32,
+    /// Every viewer has its own thread, past this they get a 503. Only the viewers of this
+    /// server count, not the other subscribers of the hub
+    pub max_viewers: usize,
+    /// A viewer that does not take a frame for this long is dropped
+    pub write_timeout: Duration,
+    /// Viewers must log in when set
+    pub auth: Option<SharedAuthenticator>,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            max_fps: 10,
+            max_viewers: 3,
+            write_timeout: Duration::from_secs(5),
+            auth: None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    frame: Option<Arc<Vec<u8>>>,
+    sequence: u64,
+    viewers: usize,
+}
+
+/// Hands the frames of a single capture loop to any number of viewers.
+///
+/// Viewers always get the latest frame, the ones that are slower than the capture
+/// skip frames instead of queueing them. Cloning gives another handle to the same hub.
+#[derive(Clone, Default)]
+pub struct StreamHub {
+    inner: Arc<(Mutex<State>, Condvar)>,
+}
+
+impl StreamHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn viewers(&self) -> usize {
+        self.inner.0.lock().unwrap().viewers
+    }
+
+    /// Makes a JPEG the latest frame and wakes up the viewers
+    pub fn publish(&self, jpeg: Vec<u8>) {
+        let (state, frames) = &*self.inner;
+        let mut state = state.lock().unwrap();
+        state.frame = Some(Arc::new(jpeg));
+        state.sequence += 1;
+        frames.notify_all();
+    }
+
+    /// Adds a viewer, limited to `max_fps` frames per second when set
+    pub fn subscribe(&self, max_fps: Option<u32>) -> Viewer {
+        let (state, frames) = &*self.inner;
+        let mut state = state.lock().unwrap();
+        state.viewers += 1;
+        frames.notify_all();
+
+        Viewer {
+            hub: self.clone(),
+            sequence: state.sequence,
+            min_interval: max_fps
+                .filter(|&fps| fps > 0)
+                .map(|fps| Duration::from_secs(1) / fps),
+            last_frame: None,
+        }
+    }
+
+    /// Capture loop, calls `capture` at most `max_fps` times per second while someone is
+    /// watching and sleeps otherwise. Errors are logged and the loop goes on.
+    pub fn run(&self, max_fps: u32, mut capture: impl FnMut() -> Result<Vec<u8>>) -> ! {
+        let interval = Duration::from_secs(1) / max_fps.max(1);
+        let (state, frames) = &*self.inner;
+
+        loop {
+            drop(
+                frames
+                    .wait_while(state.lock().unwrap(), |s| s.viewers == 0)
+                    .unwrap(),
+            );
+
+            let start = Instant::now();
+            match capture() {
+                Ok(jpeg) => self.publish(jpeg),
+                Err(err) => log::error!("stream capture error: {:?}", err),
+            }
+            if let Some(rest) = interval.checked_sub(start.elapsed()) {
+                std::thread::sleep(rest);
+            }
+        }
+    }
+}
+
+/// One client of a [`StreamHub`], it stops counting as a viewer when dropped
+pub struct Viewer {
+    hub: StreamHub,
+    sequence: u64,
+    min_interval: Option<Duration>,
+    last_frame: Option<Instant>,
+}
+
+impl Viewer {
+    /// Waits for a frame newer than the last one returned, `None` after `timeout`
+    pub fn next_frame(&mut self, timeout: Duration) -> Option<Arc<Vec<u8>>> {
+        if let (Some(interval), Some(last)) = (self.min_interval, self.last_frame) {
+            if let Some(rest) = interval.checked_sub(last.elapsed()) {
+                std::thread::sleep(rest);
+            }
+        }
+
+        let (state, frames) = &*self.hub.inner;
+        let (state, _) = frames
+            .wait_timeout_while(state.lock().unwrap(), timeout, |s| {
+                s.sequence == self.sequence || s.frame.is_none()
+            })
+            .unwrap();
+        if state.sequence == self.sequence {
+            return None;
+        }
+
+        self.sequence = state.sequence;
+        self.last_frame = Some(Instant::now());
+        state.frame.clone()
+    }
+
+    /// Writes frames as multipart parts until the client goes away or no frame
+    /// comes for `timeout`
+    pub fn serve(mut self, out: &mut impl Write, timeout: Duration) -> io::Result<()> {
+        loop {
+            let Some(frame) = self.next_frame(timeout) else {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "no frames"));
+            };
+            write_part(out, &frame)?;
+        }
+    }
+}
+
+impl Drop for Viewer {
+    fn drop(&mut self) {
+        self.hub.inner.0.lock().unwrap().viewers -= 1;
+    }
+}
+
+/// One of a limited number of clients of a server, given back when dropped
+pub(crate) struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    pub(crate) fn take(clients: &Arc<AtomicUsize>, max_clients: usize) -> Option<Self> {
+        clients
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (n < max_clients).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Self(clients.clone()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// One JPEG of a `multipart/x-mixed-replace` response
+pub fn write_part(out: &mut impl Write, jpeg: &[u8]) -> io::Result<()> {
+    write!(
+        out,
+        "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+        BOUNDARY,
+        jpeg.len()
+    )?;
+    out.write_all(jpeg)?;
+    out.write_all(b"\r\n")?;
+    out.flush()
+}
+
+/// Value of `key` in the query string of `uri`
+pub fn query_value<'a>(uri: &'a str, key: &str) -> Option<&'a str> {
+    let (_, query) = uri.split_once('?')?;
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+/// MJPEG server on its own port, every viewer gets a thread.
+///
+/// The ESP-IDF HTTP server runs every handler on the same task, so an endless
+/// response there would block every other route; like the Arduino CameraWebServer,
+/// the stream lives on a second port instead. Viewers can lower their frame rate with
+/// `?fps=N`.
+pub fn spawn_stream_server(
+    port: u16,
+    path: &'static str,
+    hub: StreamHub,
+    config: StreamConfig,
+) -> Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    let viewers = Arc::new(AtomicUsize::new(0));
+
+    Ok(std::thread::Builder::new()
+        .stack_size(8 * 1024)
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        log::error!("stream accept error: {:?}", err);
+                        continue;
+                    }
+                };
+
+                let hub = hub.clone();
+                let config = config.clone();
+                let viewers = viewers.clone();
+                let spawned = std::thread::Builder::new()
+                    .stack_size(8 * 1024)
+                    .spawn(move || {
+                        if let Err(err) = handle_client(stream, path, &hub, &viewers, &config) {
+                            log::info!("stream client gone: {:?}", err);
+                        }
+                    });
+                if let Err(err) = spawned {
+                    log::error!("could not start a stream thread: {:?}", err);
+                }
+            }
+        })?)
+}
+
+fn handle_client(
+    mut stream: TcpStream,
+    path: &str,
+    hub: &StreamHub,
+    viewers: &Arc<AtomicUsize>,
+    config: &StreamConfig,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(config.write_timeout))?;
+    stream.set_write_timeout(Some(config.write_timeout))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // only the credentials are needed, but every header has to be read before answering
+    let mut authorization = None;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            }
+        }
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, uri) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    if method != "GET" || uri.split('?').next() != Some(path) {
+        return stream.write_all(b"HTTP/1.1 404 Not Found\r\nConnection: close\r\n\r\n");
+    }
+    // browsers don't send the credentials of another port, the control panel passes a ticket
+    let authorization = authorization
+        .or_else(|| query_value(uri, "token").map(|token| format!("Bearer {}", token)));
+    let peer = stream.peer_addr()?.ip();
+    if let Some(response) = config
+        .auth
+        .as_ref()
+        .and_then(|auth| refusal(auth, peer, authorization.as_deref()))
+    {
+        return stream.write_all(response.as_bytes());
+    }
+    let Some(_slot) = Slot::take(viewers, config.max_viewers) else {
+        return stream.write_all(b"HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\n\r\n");
+    };
+
+    let fps = query_value(uri, "fps")
+        .and_then(|fps| fps.parse::<u32>().ok())
+        .map_or(config.max_fps, |fps| fps.min(config.max_fps));
+    let viewer = hub.subscribe(Some(fps));
+
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nCache-Control: no-cache\r\n\
+         Access-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n",
+        CONTENT_TYPE
+    )?;
+    viewer.serve(&mut stream, config.write_timeout)
+}
+
+/// Response for a client whose credentials fall short, `None` when it may watch
+fn refusal(
+    auth: &SharedAuthenticator,
+    peer: IpAddr,
+    authorization: Option<&str>,
+) -> Option<String> {
+    let result = auth
+        .lock()
+        .unwrap()
+        .authorize(peer, authorization, Role::Viewer, Instant::now());
+    let status = match result {
+        Ok(_) => return None,
+        Err(AuthError::Missing | AuthError::Invalid) => {
+            "401 Unauthorized\r\nWWW-Authenticate: Basic realm=\"espcam\"".to_string()
+        }
+        Err(AuthError::Forbidden) => "403 Forbidden".to_string(),
+        Err(AuthError::LockedOut(retry)) => {
+            format!("429 Too Many Requests\r\nRetry-After: {}", retry.as_secs())
+        }
+    };
+    Some(format!("HTTP/1.1 {}\r\nConnection: close\r\n\r\n", status))
+}
@@ -0,0 +1,292 @@
+use serde::Serialize;
+
+use crate::{
+    events::Event,
+    focus::{tenengrad, LumaPlane},
+    frame::Frame,
+};
+
+const PLANE_SIDE: usize = 160;
+const THUMB_W: usize = 16;
+const THUMB_H: usize = 12;
+const BLOCK: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TamperKind {
+    /// Most of the picture is flat, the lens is covered or painted over
+    Covered,
+    /// The picture lost most of its detail, the lens is out of focus or smeared
+    Defocused,
+    /// The picture does not look like the reference scene anymore, the camera was moved
+    SceneChanged,
+}
+
+impl TamperKind {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            TamperKind::Covered => "lens covered",
+            TamperKind::Defocused => "lost focus",
+            TamperKind::SceneChanged => "camera moved",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TamperConfig {
+    /// Defocused when the sharpness falls below this fraction of the reference
+    pub detail_ratio: f32,
+    /// Blocks with a luma standard deviation below this are considered flat
+    pub flat_block_stddev: f32,
+    /// Covered when more than this fraction of the blocks is flat
+    pub flat_fraction: f32,
+    /// Scene changed when the mean difference from the reference thumbnail exceeds this
+    pub scene_difference: f32,
+    /// Consecutive frames a condition must hold before it is reported
+    pub confirm_frames: u32,
+}
+
+impl Default for TamperConfig {
+    fn default() -> Self {
+        Self {
+            detail_ratio: 0.3,
+            flat_block_stddev: 4.0,
+            flat_fraction: 0.75,
+            scene_difference: 30.0,
+            confirm_frames: 3,
+        }
+    }
+}
+
+struct Reference {
+    sharpness: f32,
+    thumbnail: Vec<f32>,
+}
+
+/// Compares frames against a reference scene to find out when the camera has been
+/// covered, defocused or turned away.
+pub struct TamperDetector {
+    config: TamperConfig,
+    reference: Option<Reference>,
+    pending: Option<(Option<TamperKind>, u32)>,
+    active: Option<TamperKind>,
+}
+
+impl TamperDetector {
+    pub fn new(config: TamperConfig) -> Self {
+        Self {
+            config,
+            reference: None,
+            pending: None,
+            active: None,
+        }
+    }
+
+    pub fn has_reference(&self) -> bool {
+        self.reference.is_some()
+    }
+
+    /// Uses a raw frame as the reference scene and clears any active tampering
+    pub fn set_reference(&mut self, frame: &Frame) {
+        let plane = LumaPlane::from_frame(frame, PLANE_SIDE);
+        self.reference = Some(Reference {
+            sharpness: tenengrad(&plane),
+            thumbnail: thumbnail(&plane),
+        });
+        self.pending = None;
+        self.active = None;
+    }
+
+    /// Classifies a single raw frame, without any debouncing
+    pub fn check(&self, frame: &Frame) -> Option<TamperKind> {
+        let reference = self.reference.as_ref()?;
+        let plane = LumaPlane::from_frame(frame, PLANE_SIDE);
+
+        if flat_fraction(&plane, self.config.flat_block_stddev) > self.config.flat_fraction {
+            return Some(TamperKind::Covered);
+        }
+        if tenengrad(&plane) < reference.sharpness * self.config.detail_ratio {
+            return Some(TamperKind::Defocused);
+        }
+        if thumbnail_difference(&thumbnail(&plane), &reference.thumbnail)
+            > self.config.scene_difference
+        {
+            return Some(TamperKind::SceneChanged);
+        }
+        None
+    }
+
+    /// Feeds a raw frame, returns an event when the tamper state changes
+    /// for [`TamperConfig::confirm_frames`] frames in a row.
+    ///
+    /// The first frame becomes the reference scene.
+    pub fn update(&mut self, frame: &Frame) -> Option<Event> {
+        if self.reference.is_none() {
+            self.set_reference(frame);
+            return None;
+        }
+
+        let current = self.check(frame);
+        if current == self.active {
+            self.pending = None;
+            return None;
+        }
+
+        let count = match self.pending {
+            Some((state, count)) if state == current => count + 1,
+            _ => 1,
+        };
+        if count < self.config.confirm_frames {
+            self.pending = Some((current, count));
+            return None;
+        }
+
+        self.pending = None;
+        self.active = current;
+        Some(match current {
+            Some(kind) => Event::Tamper { kind },
+            None => Event::TamperCleared,
+        })
+    }
+}
+
+/// Block averages, with the global mean removed so lighting changes do not count
+fn thumbnail(plane: &LumaPlane) -> Vec<f32> {
+    let mut thumb = vec![0f32; THUMB_W * THUMB_H];
+    let mut counts = vec![0f32; THUMB_W * THUMB_H];
+
+    for y in 0..plane.height {
+        for x in 0..plane.width {
+            let idx = x * THUMB_W / plane.width + (y * THUMB_H / plane.height) * THUMB_W;
+            thumb[idx] += plane.get(x, y) as f32;
+            counts[idx] += 1.0;
+        }
+    }
+    for (v, n) in thumb.iter_mut().zip(&counts) {
+        *v /= n.max(1.0);
+    }
+
+    let mean = thumb.iter().sum::<f32>() / thumb.len() as f32;
+    thumb.iter_mut().for_each(|v| *v -= mean);
+    thumb
+}
+
+fn thumbnail_difference(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| (a - b).abs()).sum::<f32>() / a.len() as f32
+}
+
+fn flat_fraction(plane: &LumaPlane, max_stddev: f32) -> f32 {
+    let mut blocks = 0;
+    let mut flat = 0;
+
+    for by in 0..plane.height / BLOCK {
+        for bx in 0..plane.width / BLOCK {
+            let mut sum = 0f32;
+            let mut sum_sq = 0f32;
+            for y in by * BLOCK..(by + 1) * BLOCK {
+                for x in bx * BLOCK..(bx + 1) * BLOCK {
+                    let v = plane.get(x, y) as f32;
+                    sum += v;
+                    sum_sq += v * v;
+                }
+            }
+            let n = (BLOCK * BLOCK) as f32;
+            let variance = sum_sq / n - (sum / n) * (sum / n);
+            blocks += 1;
+            if variance < max_stddev * max_stddev {
+                flat += 1;
+            }
+        }
+    }
+
+    flat as f32 / (blocks as f32).max(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::PixelFormat;
+
+    const W: usize = 160;
+    const H: usize = 120;
+
+    /// Checkerboard over a bright half and a dark half, `swapped` puts the bright half right
+    fn scene(swapped: bool) -> Frame {
+        let data = (0..W * H)
+            .map(|i| {
+                let (x, y) = (i % W, i / W);
+                let bright = (x < W / 2) != swapped;
+                let base = if bright { 180 } else { 70 };
+                if (x / 10 + y / 10) % 2 == 0 {
+                    base - 40
+                } else {
+                    base + 40
+                }
+            })
+            .collect();
+        Frame::new(data, W, H, PixelFormat::Grayscale)
+    }
+
+    fn blur(frame: &Frame, radius: usize) -> Frame {
+        let mut data = Vec::with_capacity(W * H);
+        for y in 0..H {
+            for x in 0..W {
+                let (mut sum, mut n) = (0u32, 0u32);
+                for sy in y.saturating_sub(radius)..(y + radius + 1).min(H) {
+                    for sx in x.saturating_sub(radius)..(x + radius + 1).min(W) {
+                        sum += frame.luma(sx, sy) as u32;
+                        n += 1;
+                    }
+                }
+                data.push((sum / n) as u8);
+            }
+        }
+        Frame::new(data, W, H, PixelFormat::Grayscale)
+    }
+
+    fn detector() -> TamperDetector {
+        let mut detector = TamperDetector::new(TamperConfig::default());
+        detector.set_reference(&scene(false));
+        detector
+    }
+
+    #[test]
+    fn classifies_tampering() {
+        let detector = detector();
+        let flat = Frame::new(vec![20; W * H], W, H, PixelFormat::Grayscale);
+
+        assert_eq!(detector.check(&scene(false)), None);
+        assert_eq!(detector.check(&flat), Some(TamperKind::Covered));
+        assert_eq!(
+            detector.check(&blur(&scene(false), 4)),
+            Some(TamperKind::Defocused)
+        );
+        assert_eq!(detector.check(&scene(true)), Some(TamperKind::SceneChanged));
+    }
+
+    #[test]
+    fn changes_are_confirmed_before_they_are_reported() {
+        let mut detector = TamperDetector::new(TamperConfig::default());
+        assert_eq!(detector.update(&scene(false)), None);
+        assert!(detector.has_reference());
+
+        let flat = Frame::new(vec![20; W * H], W, H, PixelFormat::Grayscale);
+        assert_eq!(detector.update(&flat), None);
+        assert_eq!(detector.update(&flat), None);
+        assert_eq!(
+            detector.update(&flat),
+            Some(Event::Tamper {
+                kind: TamperKind::Covered
+            })
+        );
+        assert_eq!(detector.update(&flat), None);
+
+        // a single good frame does not clear it
+        assert_eq!(detector.update(&scene(false)), None);
+        assert_eq!(detector.update(&flat), None);
+        for _ in 0..2 {
+            assert_eq!(detector.update(&scene(false)), None);
+        }
+        assert_eq!(detector.update(&scene(false)), Some(Event::TamperCleared));
+    }
+}
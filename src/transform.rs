@@ -0,0 +1,266 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::frame::Frame;
+
+/// Clockwise rotation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Rotation {
+    #[default]
+    None,
+    Cw90,
+    Cw180,
+    Cw270,
+}
+
+impl Rotation {
+    pub fn from_degrees(degrees: u32) -> Option<Self> {
+        match degrees % 360 {
+            0 => Some(Rotation::None),
+            90 => Some(Rotation::Cw90),
+            180 => Some(Rotation::Cw180),
+            270 => Some(Rotation::Cw270),
+            _ => None,
+        }
+    }
+
+    pub fn degrees(&self) -> u32 {
+        match self {
+            Rotation::None => 0,
+            Rotation::Cw90 => 90,
+            Rotation::Cw180 => 180,
+            Rotation::Cw270 => 270,
+        }
+    }
+
+    /// The next rotation, 90 degrees further clockwise
+    pub fn next(&self) -> Self {
+        Rotation::from_degrees(self.degrees() + 90).unwrap_or_default()
+    }
+}
+
+/// Region to keep, relative to the frame size (0.0 - 1.0)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Crop {
+    pub x: f32,
+    pub y: f32,
+    pub w: f32,
+    pub h: f32,
+}
+
+fn bytes_per_pixel(frame: &Frame) -> Result<usize> {
+    match frame.format().bytes_per_pixel() {
+        Some(bpp) => Ok(bpp),
+        None => bail!("transforms need a raw frame, decode jpeg frames first"),
+    }
+}
+
+/// Rotates a raw frame, pixels are moved as they are so any raw format works.
+pub fn rotate(frame: &Frame, rotation: Rotation) -> Result<Frame> {
+    let bpp = bytes_per_pixel(frame)?;
+    let (w, h) = (frame.width(), frame.height());
+    let src = frame.data();
+
+    let (out_w, out_h) = match rotation {
+        Rotation::None => return Ok(frame.clone()),
+        Rotation::Cw180 => (w, h),
+        Rotation::Cw90 | Rotation::Cw270 => (h, w),
+    };
+    let mut out = vec![0; out_w * out_h * bpp];
+
+    for y in 0..h {
+        for x in 0..w {
+            let (ox, oy) = match rotation {
+                Rotation::Cw90 => (h - 1 - y, x),
+                Rotation::Cw180 => (w - 1 - x, h - 1 - y),
+                Rotation::Cw270 => (y, w - 1 - x),
+                Rotation::None => (x, y),
+            };
+            let s = (x + y * w) * bpp;
+            let d = (ox + oy * out_w) * bpp;
+            out[d..d + bpp].copy_from_slice(&src[s..s + bpp]);
+        }
+    }
+
+    Ok(Frame::new(out, out_w, out_h, frame.format()))
+}
+
+/// Mirrors a raw frame left to right
+pub fn flip_horizontal(frame: &mut Frame) -> Result<()> {
+    let bpp = bytes_per_pixel(frame)?;
+    let w = frame.width();
+    for row in frame.data_mut().chunks_exact_mut(w * bpp) {
+        for x in 0..w / 2 {
+            let (a, b) = (x * bpp, (w - 1 - x) * bpp);
+            for i in 0..bpp {
+                row.swap(a + i, b + i);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Mirrors a raw frame top to bottom
+pub fn flip_vertical(frame: &mut Frame) -> Result<()> {
+    let stride = frame.width() * bytes_per_pixel(frame)?;
+    let h = frame.height();
+    let data = frame.data_mut();
+    for y in 0..h / 2 {
+        let (top, bottom) = data.split_at_mut((h - 1 - y) * stride);
+        top[y * stride..(y + 1) * stride].swap_with_slice(&mut bottom[..stride]);
+    }
+    Ok(())
+}
+
+pub fn crop(frame: &Frame, crop: Crop) -> Result<Frame> {
+    let bpp = bytes_per_pixel(frame)?;
+    let (w, h) = (frame.width(), frame.height());
+
+    let x0 = ((crop.x.clamp(0.0, 1.0) * w as f32) as usize).min(w);
+    let y0 = ((crop.y.clamp(0.0, 1.0) * h as f32) as usize).min(h);
+    let x1 = (((crop.x + crop.w).clamp(0.0, 1.0) * w as f32) as usize).min(w);
+    let y1 = (((crop.y + crop.h).clamp(0.0, 1.0) * h as f32) as usize).min(h);
+    if x1 <= x0 || y1 <= y0 {
+        bail!("empty crop region");
+    }
+
+    let mut out = Vec::with_capacity((x1 - x0) * (y1 - y0) * bpp);
+    for y in y0..y1 {
+        let row = (x0 + y * w) * bpp;
+        out.extend_from_slice(&frame.data()[row..row + (x1 - x0) * bpp]);
+    }
+
+    Ok(Frame::new(out, x1 - x0, y1 - y0, frame.format()))
+}
+
+/// How the board is mounted, applied to every output so pictures come out upright.
+///
+/// The crop is applied first, on the frame as it comes from the sensor, then the flips and
+/// finally the rotation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Orientation {
+    pub rotation: Rotation,
+    pub hflip: bool,
+    pub vflip: bool,
+    pub crop: Option<Crop>,
+}
+
+pub const ORIENTATION_STORAGE_KEY: &str = "orientation";
+
+impl Orientation {
+    pub fn new(rotation: Rotation) -> Self {
+        Self {
+            rotation,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.rotation == Rotation::None && !self.hflip && !self.vflip && self.crop.is_none()
+    }
+
+    pub fn apply(&self, frame: &Frame) -> Result<Frame> {
+        let mut out = match self.crop {
+            Some(region) => crop(frame, region)?,
+            None => frame.clone(),
+        };
+        if self.hflip {
+            flip_horizontal(&mut out)?;
+        }
+        if self.vflip {
+            flip_vertical(&mut out)?;
+        }
+        if self.rotation == Rotation::None {
+            return Ok(out);
+        }
+        rotate(&out, self.rotation)
+    }
+
+    /// Decodes a JPEG, transforms it and encodes it again with `quality` (1-100).
+    pub fn apply_jpeg(&self, jpeg: &[u8], quality: u8) -> Result<Vec<u8>> {
+        let frame = Frame::decode_jpeg(jpeg)?;
+        self.apply(&frame)?.to_jpeg(quality)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::PixelFormat;
+
+    /// Grayscale frame whose pixels hold their own index
+    fn numbered(w: usize, h: usize) -> Frame {
+        Frame::new(
+            (0..w * h).map(|i| i as u8).collect(),
+            w,
+            h,
+            PixelFormat::Grayscale,
+        )
+    }
+
+    #[test]
+    fn rotating_a_non_square_frame() {
+        // 0 1 2
+        // 3 4 5
+        let frame = numbered(3, 2);
+
+        let cw90 = rotate(&frame, Rotation::Cw90).unwrap();
+        assert_eq!((cw90.width(), cw90.height()), (2, 3));
+        assert_eq!(cw90.data(), &[3, 0, 4, 1, 5, 2]);
+        assert_eq!(cw90.luma(1, 0), 0, "the top left corner goes top right");
+
+        let cw270 = rotate(&frame, Rotation::Cw270).unwrap();
+        assert_eq!((cw270.width(), cw270.height()), (2, 3));
+        assert_eq!(cw270.luma(0, 2), 0, "the top left corner goes bottom left");
+
+        let cw180 = rotate(&frame, Rotation::Cw180).unwrap();
+        assert_eq!(cw180.data(), &[5, 4, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn four_quarter_turns_are_no_turn() {
+        let frame = numbered(5, 3);
+        let mut turned = frame.clone();
+        for _ in 0..4 {
+            turned = rotate(&turned, Rotation::Cw90).unwrap();
+        }
+        assert_eq!((turned.width(), turned.height()), (5, 3));
+        assert_eq!(turned.data(), frame.data());
+    }
+
+    #[test]
+    fn crop_is_clamped_to_the_frame() {
+        let frame = numbered(4, 4);
+
+        let out = crop(
+            &frame,
+            Crop {
+                x: 0.5,
+                y: -0.5,
+                w: 1.0,
+                h: 1.0,
+            },
+        )
+        .unwrap();
+        assert_eq!((out.width(), out.height()), (2, 2));
+        assert_eq!(out.data(), &[2, 3, 6, 7]);
+
+        let outside = Crop {
+            x: 1.5,
+            y: 0.0,
+            w: 0.5,
+            h: 1.0,
+        };
+        assert!(crop(&frame, outside).is_err());
+    }
+
+    #[test]
+    fn rotation_from_degrees() {
+        assert_eq!(Rotation::from_degrees(0), Some(Rotation::None));
+        assert_eq!(Rotation::from_degrees(90), Some(Rotation::Cw90));
+        assert_eq!(Rotation::from_degrees(270), Some(Rotation::Cw270));
+        assert_eq!(Rotation::from_degrees(450), Some(Rotation::Cw90));
+        assert_eq!(Rotation::from_degrees(45), None);
+        assert_eq!(Rotation::Cw270.next(), Rotation::None);
+    }
+}
@@ -0,0 +1,289 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use embedded_svc::ws::FrameType;
+use esp_idf_svc::http::server::ws::EspHttpWsDetachedSender;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::Role,
+    counting::Crossing,
+    espcam::SensorSettings,
+    http::CameraStatus,
+    motion::Blob,
+    stream::{StreamHub, Viewer},
+};
+
+/// Events waiting for a slow client, past this the oldest are dropped
+const MAX_QUEUED_EVENTS: usize = 32;
+/// How long the push loop waits for a frame before looking at the events again
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// JSON messages from the client, like `{"command": "stream", "fps": 5}`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum WsCommand {
+    /// Browsers cannot set headers on a WebSocket, so the credentials come as a message,
+    /// `Basic <base64>` or `Bearer <token>`
+    Auth {
+        authorization: String,
+    },
+    /// Sensor settings to change, the keys of [`SensorSettings`] next to the command
+    Settings(SensorSettings),
+    Status,
+    /// A picture sent as a binary message, `options` are the snapshot query parameters,
+    /// like `size=vga&flash=1`
+    Capture {
+        #[serde(default)]
+        options: String,
+    },
+    /// Frames per second of the stream, 0 pauses it
+    Stream {
+        fps: u32,
+    },
+}
+
+impl WsCommand {
+    pub fn role(&self) -> Role {
+        match self {
+            WsCommand::Settings(_) => Role::Admin,
+            _ => Role::Viewer,
+        }
+    }
+}
+
+/// JSON messages to the clients
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WsEvent {
+    Status(CameraStatus),
+    Motion {
+        blobs: Vec<Blob>,
+    },
+    Crossing(Crossing),
+    /// Comes right before the binary message with the picture
+    Capture {
+        content_type: &'static str,
+        length: usize,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// What the push loop sends besides the frames
+enum Outgoing {
+    Event(Arc<String>),
+    /// A `capture` event and its picture, no frame may come between them
+    Picture(Arc<String>, Vec<u8>),
+}
+
+struct ClientState {
+    role: Option<Role>,
+    fps: u32,
+    events: VecDeque<Outgoing>,
+    closed: bool,
+}
+
+/// One WebSocket connection, its push loop sends what is queued here
+pub struct WsClient {
+    state: Mutex<ClientState>,
+    changed: Condvar,
+}
+
+impl WsClient {
+    fn new(role: Option<Role>, fps: u32) -> Self {
+        Self {
+            state: Mutex::new(ClientState {
+                role,
+                fps,
+                events: VecDeque::new(),
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn role(&self) -> Option<Role> {
+        self.state.lock().unwrap().role
+    }
+
+    pub fn set_role(&self, role: Role) {
+        self.state.lock().unwrap().role = Some(role);
+        self.changed.notify_all();
+    }
+
+    pub fn set_fps(&self, fps: u32) {
+        self.state.lock().unwrap().fps = fps;
+        self.changed.notify_all();
+    }
+
+    /// Queues a picture taken for this client, it goes out after the events already queued
+    pub fn push_picture(&self, content_type: &'static str, picture: Vec<u8>) -> Result<()> {
+        let event = serde_json::to_string(&WsEvent::Capture {
+            content_type,
+            length: picture.len(),
+        })?;
+        self.push(Outgoing::Picture(Arc::new(event), picture));
+        Ok(())
+    }
+
+    /// Queues a message, only for clients that logged in
+    fn push(&self, event: Outgoing) {
+        let mut state = self.state.lock().unwrap();
+        if state.role.is_none() {
+            return;
+        }
+        if state.events.len() >= MAX_QUEUED_EVENTS {
+            state.events.pop_front();
+        }
+        state.events.push_back(event);
+        self.changed.notify_all();
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+}
+
+/// The WebSocket clients of a server, events published here reach all of them.
+///
+/// Every client has a thread pushing the frames of the [`StreamHub`] and the events,
+/// a client that is slower than the camera skips frames and, past a few, old events.
+#[derive(Clone)]
+pub struct WsHub {
+    clients: Arc<Mutex<HashMap<i32, Arc<WsClient>>>>,
+    stream: StreamHub,
+    max_clients: usize,
+    max_fps: u32,
+}
+
+impl WsHub {
+    pub fn new(stream: StreamHub, max_clients: usize, max_fps: u32) -> Self {
+        Self {
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            stream,
+            max_clients,
+            max_fps,
+        }
+    }
+
+    pub fn clients(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    pub fn client(&self, session: i32) -> Option<Arc<WsClient>> {
+        self.clients.lock().unwrap().get(&session).cloned()
+    }
+
+    pub fn max_fps(&self) -> u32 {
+        self.max_fps
+    }
+
+    /// Sends an event to every client that logged in
+    pub fn publish<T: Serialize>(&self, event: &T) {
+        let event = match serde_json::to_string(event) {
+            Ok(event) => Arc::new(event),
+            Err(err) => {
+                log::error!("could not serialize a websocket event: {:?}", err);
+                return;
+            }
+        };
+        for client in self.clients.lock().unwrap().values() {
+            client.push(Outgoing::Event(event.clone()));
+        }
+    }
+
+    /// Registers a new connection and starts its push loop, `role` is `None` until it logs in
+    pub fn connect(
+        &self,
+        session: i32,
+        sender: EspHttpWsDetachedSender,
+        role: Option<Role>,
+    ) -> Result<Arc<WsClient>> {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= self.max_clients {
+            anyhow::bail!("too many websocket clients");
+        }
+        let client = Arc::new(WsClient::new(role, self.max_fps));
+
+        // registered first, so that a loop ending right away does not leave it behind
+        clients.insert(session, client.clone());
+        drop(clients);
+
+        let pushed = client.clone();
+        let stream = self.stream.clone();
+        let hub = self.clone();
+        let spawned = std::thread::Builder::new()
+            .stack_size(8 * 1024)
+            .spawn(move || {
+                push_loop(&pushed, sender, &stream);
+                hub.disconnect(session);
+            });
+        if let Err(err) = spawned {
+            self.disconnect(session);
+            return Err(err.into());
+        }
+        Ok(client)
+    }
+
+    pub fn disconnect(&self, session: i32) {
+        if let Some(client) = self.clients.lock().unwrap().remove(&session) {
+            client.close();
+        }
+    }
+}
+
+/// Sends the queued events and the latest frames until the connection goes away
+fn push_loop(client: &WsClient, mut sender: EspHttpWsDetachedSender, stream: &StreamHub) {
+    let mut viewer: Option<(u32, Viewer)> = None;
+
+    loop {
+        let (events, fps) = {
+            let state = client.state.lock().unwrap();
+            let mut state = if state.events.is_empty() && (state.role.is_none() || state.fps == 0) {
+                // nothing to send until an event comes or the stream is switched on
+                client.changed.wait_timeout(state, POLL_INTERVAL).unwrap().0
+            } else {
+                state
+            };
+            if state.closed || sender.is_closed() {
+                return;
+            }
+            let fps = if state.role.is_some() { state.fps } else { 0 };
+            (state.events.drain(..).collect::<Vec<_>>(), fps)
+        };
+
+        for event in events {
+            let sent = match event {
+                Outgoing::Event(event) => sender.send(FrameType::Text(false), event.as_bytes()),
+                Outgoing::Picture(event, picture) => sender
+                    .send(FrameType::Text(false), event.as_bytes())
+                    .and_then(|()| sender.send(FrameType::Binary(false), &picture)),
+            };
+            if let Err(err) = sent {
+                log::info!("websocket client gone: {:?}", err);
+                return;
+            }
+        }
+
+        // the frame rate is set when subscribing, so a new one needs a new viewer
+        if viewer.as_ref().map_or(0, |(f, _)| *f) != fps {
+            viewer = (fps > 0).then(|| (fps, stream.subscribe(Some(fps))));
+        }
+        let Some((_, viewer)) = viewer.as_mut() else {
+            continue;
+        };
+        if let Some(frame) = viewer.next_frame(POLL_INTERVAL) {
+            if let Err(err) = sender.send(FrameType::Binary(false), &frame) {
+                log::info!("websocket client gone: {:?}", err);
+                return;
+            }
+        }
+    }
+}